@@ -0,0 +1,138 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! fallback holds `Fallback`, serving redirects from a snapshot on S3 while Redis is unreachable
+//! (see `shorty::snapshot`), and the degraded mode metrics it emits.
+//!
+//! The snapshot is loaded on the first redirect needing it, and loaded again once older than
+//! `SHORTENER_FALLBACK_SNAPSHOT_MAX_AGE`: a snapshot that can't be loaded again keeps being used.
+//! Metrics are written to stdout in the CloudWatch embedded metric format, under the `shorty`
+//! namespace.
+
+use std::error::Error;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Map, Value};
+use shorty::snapshot::Snapshot;
+use shorty::Redirect;
+
+/// `Fallback` resolves IDs with the snapshot stored at `s3://<bucket>/<key>`
+pub struct Fallback {
+    bucket: String,
+    key: String,
+    max_age: Duration,
+    snapshot: Option<(Snapshot, Instant)>,
+}
+
+impl Fallback {
+    /// Creates a new Fallback from an `s3://bucket/key` location, validated by shorty-conf
+    pub fn new(location: &str, max_age: Duration) -> Fallback {
+        let (bucket, key) = location
+            .trim_start_matches("s3://")
+            .split_once('/')
+            .unwrap_or((location, ""));
+
+        Fallback {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            max_age,
+            snapshot: None,
+        }
+    }
+
+    /// Returns the redirect of `id` in the snapshot, counting it as a degraded redirect, or a
+    /// degraded miss if the snapshot doesn't have it
+    pub async fn redirect(&mut self, id: &str) -> Option<Redirect> {
+        let redirect = self
+            .snapshot()
+            .await
+            .and_then(|snapshot| snapshot.redirect(id).cloned());
+
+        match &redirect {
+            Some(_) => emit_metric("DegradedRedirects"),
+            None => emit_metric("DegradedMisses"),
+        }
+        redirect
+    }
+
+    /// Returns the snapshot, loading it if missing or older than `max_age`
+    async fn snapshot(&mut self) -> Option<&Snapshot> {
+        let stale = self
+            .snapshot
+            .as_ref()
+            .is_none_or(|(_, loaded_at)| loaded_at.elapsed() >= self.max_age);
+
+        if stale {
+            match self.load().await {
+                Ok(snapshot) => {
+                    log::info!(
+                        "loaded fallback snapshot s3://{}/{} with {} links",
+                        self.bucket,
+                        self.key,
+                        snapshot.len()
+                    );
+                    self.snapshot = Some((snapshot, Instant::now()));
+                }
+                Err(err) => {
+                    log::error!(
+                        "unable to load fallback snapshot s3://{}/{}: {}",
+                        self.bucket,
+                        self.key,
+                        err
+                    );
+                    emit_metric("SnapshotLoadFailures");
+                }
+            }
+        }
+
+        self.snapshot.as_ref().map(|(snapshot, _)| snapshot)
+    }
+
+    async fn load(&self) -> Result<Snapshot, Box<dyn Error>> {
+        let aws = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+        let object = aws_sdk_s3::Client::new(&aws)
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .send()
+            .await?;
+        let bytes = object.body.collect().await?.into_bytes();
+
+        Ok(Snapshot::parse(std::str::from_utf8(&bytes)?)?)
+    }
+}
+
+/// Writes a count of one of metric `name`, by function name, in the CloudWatch embedded metric
+/// format
+pub fn emit_metric(name: &str) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64);
+    let function_name = std::env::var("AWS_LAMBDA_FUNCTION_NAME").unwrap_or_default();
+
+    let mut metric = Map::new();
+    metric.insert(
+        String::from("_aws"),
+        json!({
+            "Timestamp": timestamp,
+            "CloudWatchMetrics": [{
+                "Namespace": "shorty",
+                "Dimensions": [["FunctionName"]],
+                "Metrics": [{ "Name": name, "Unit": "Count" }],
+            }],
+        }),
+    );
+    metric.insert(String::from("FunctionName"), Value::from(function_name));
+    metric.insert(String::from(name), Value::from(1));
+    println!("{}", Value::Object(metric));
+}
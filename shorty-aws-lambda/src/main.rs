@@ -15,102 +15,591 @@
 #[macro_use]
 extern crate serde_derive;
 
-use std::env;
-use std::error::Error;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use http::{Method, StatusCode};
-use lambda_http::{lambda, Body, Request, Response};
-use lambda_runtime::error::HandlerError;
-use lambda_runtime::Context;
-use redis::Client;
-
-use shorty::redis_facade::RedisFacade;
+use http::header::HeaderValue;
+use http::response::Builder;
+use http::{Method, Request, Response, StatusCode};
+use lambda_runtime::{service_fn, Error, LambdaEvent};
+use redis::RedisResult;
+use serde::Serialize;
+use shorty::ascii_json::ascii_json;
+use shorty::challenge::HUMAN_COOKIE;
+use shorty::click_latency::LatencyReport;
+use shorty::content_security_policy::{
+    ContentSecurityPolicy, CSP_REPORT_PATH, MAX_CSP_REPORT_SIZE,
+};
+use shorty::error_page::ErrorPage;
+use shorty::interstitial::Interstitial;
+use shorty::link_metadata::Creator;
+use shorty::schedule::Schedule;
+use shorty::templates::Templates;
 use shorty::Shortener;
+use shorty::{LinkOptions, Redirect, VERIFIED_PUBLISHER_HEADER};
 use shorty_conf::Config;
+use tokio::sync::Mutex;
+use tracing::Instrument;
+
+use crate::fallback::{emit_metric, Fallback};
+use crate::proxy::{Body, ProxyRequest, ProxyResponse, RequestExt};
+
+mod fallback;
+mod proxy;
+
+/// How long to wait for Redis to answer, before resolving a missing ID with the fallback snapshot
+const STORAGE_PING_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The least time between two attempts to connect to Redis while it's unreachable
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The `Shortener` of the instance, missing while Redis is unreachable
+struct Connection {
+    shortener: Option<Shortener>,
+    last_attempt: Instant,
+}
+
+impl Connection {
+    /// Returns the `Shortener`, connecting to Redis again if missing, at most once every
+    /// `RECONNECT_INTERVAL`
+    async fn shortener(&mut self, config: &Config) -> Option<&Shortener> {
+        if self.shortener.is_none() && self.last_attempt.elapsed() >= RECONNECT_INTERVAL {
+            self.last_attempt = Instant::now();
+            match new_shortener(config).await {
+                Ok(shortener) => {
+                    log::info!("connected to Redis again, leaving the fallback snapshot");
+                    self.shortener = Some(shortener);
+                }
+                Err(err) => log::warn!("Redis is still unreachable: {}", err),
+            }
+        }
+        self.shortener.as_ref()
+    }
+}
+
+/// What invocations served by an instance share and change
+struct Instance {
+    connection: Connection,
+    fallback: Option<Fallback>,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Error> {
+    let started = Instant::now();
+    let config = Config::from_env()?;
+    shorty_bootstrap::init_logging(&config);
+
+    let fallback = config
+        .fallback_snapshot
+        .as_ref()
+        .map(|location| Fallback::new(location, config.fallback_snapshot_max_age));
+
+    // created once and reused by every invocation served by this instance, with its connections.
+    // With a fallback snapshot, an instance starting while Redis is unreachable serves redirects
+    // from the snapshot, connecting again at most every `RECONNECT_INTERVAL`.
+    let shortener = match new_shortener(&config).await {
+        Ok(shortener) => Some(shortener),
+        Err(err) if fallback.is_some() => {
+            log::error!(
+                "unable to connect to Redis, serving the fallback snapshot: {}",
+                err
+            );
+            None
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    if let Some(shortener) = &shortener {
+        if !shortener.check_eviction_policy().await && config.refuse_unsafe_eviction_policy {
+            return Err(Error::from(
+                "refusing to start: Redis eviction policy may delete shortened URLs",
+            ));
+        }
+    }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    env::set_var(
-        "RUST_LOG",
-        env::var("RUST_LOG").unwrap_or_else(|_| String::from("info")),
+    // invocations of an instance never overlap: the lock is never waited for
+    let instance = &Mutex::new(Instance {
+        connection: Connection {
+            shortener,
+            last_attempt: Instant::now(),
+        },
+        fallback,
+    });
+    let templates = &shorty_bootstrap::templates(&config);
+    // the cold start of this instance, but loading the binary: see "Minimal build" in the README
+    log::info!("cold start: ready in {} ms", started.elapsed().as_millis());
+    let config = &config;
+    lambda_runtime::run(service_fn(
+        move |event: LambdaEvent<ProxyRequest>| async move {
+            let mut instance = instance.lock().await;
+            let response = traced_handler(&mut instance, config, templates, event).await;
+            Ok::<_, Error>(ProxyResponse::from(response))
+        },
+    ))
+    .await
+}
+
+/// Serves a request in a `request` span carrying its `X-Request-Id`, or the ID of the invocation
+/// if missing, sent back with the response: see the `request_id` module of shorty-http
+async fn traced_handler(
+    instance: &mut Instance,
+    config: &Config,
+    templates: &Templates,
+    event: LambdaEvent<ProxyRequest>,
+) -> Response<Body> {
+    let e = match event.payload.into_request() {
+        Ok(e) => e,
+        Err(err) => {
+            log::error!("unable to read the request: {}", err);
+            return bad_request(String::from("Malformed request"));
+        }
+    };
+    let request_id = e
+        .headers()
+        .get("X-Request-Id")
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(str::to_owned)
+        .unwrap_or(event.context.request_id);
+    let span = tracing::info_span!(
+        "request",
+        request_id = request_id.as_str(),
+        method = %e.method(),
+        path = e.uri().path(),
     );
-    env_logger::init();
-    lambda!(handler);
 
-    Ok(())
+    let mut response = handler(instance, config, templates, e)
+        .instrument(span.clone())
+        .await;
+    let _entered = span.enter();
+    if config.ascii_json {
+        response = ascii_json_response(response);
+    }
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert("X-Request-Id", value);
+    }
+    tracing::info!(status = response.status().as_u16(), "request served");
+    response
+}
+
+/// Rewrites the body of a JSON response with `shorty::ascii_json::ascii_json`, leaving other
+/// responses alone
+fn ascii_json_response(response: Response<Body>) -> Response<Body> {
+    let json = response
+        .headers()
+        .get("Content-Type")
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    if !json {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let rewritten = match &body {
+        Body::Text(text) => ascii_json(text.as_bytes()),
+        Body::Binary(bytes) => ascii_json(bytes),
+        Body::Empty => None,
+    };
+    Response::from_parts(parts, rewritten.map(Body::from).unwrap_or(body))
 }
 
-fn goto(shortener: &mut Shortener, key: &str) -> Result<Response<Body>, HandlerError> {
+/// Redirects to the URL of `key`. For clients accepting `text/html`, the body is an HTML page
+/// linking to the URL, for those ignoring the `Location` header.
+///
+/// Keys not found while Redis is unreachable are resolved with the `fallback` snapshot, if any.
+async fn goto(
+    shortener: Option<&Shortener>,
+    fallback: Option<&mut Fallback>,
+    templates: &Templates,
+    key: &str,
+    accept: Option<&str>,
+) -> Response<Body> {
     log::trace!("resolving key '{}'", key);
 
-    match shortener.lookup(key) {
-        Some(url) => {
-            log::trace!("Url found {}", url);
+    let redirect = match shortener {
+        Some(shortener) => shortener.redirect(key).await,
+        None => None,
+    };
+    // links re-issued with a new ID are answered with `410 Gone`, pointing to it
+    let rotated_to = match (&redirect, shortener) {
+        (None, Some(shortener)) => shortener.rotated_to(key).await,
+        _ => None,
+    };
+    let redirect = match (redirect, fallback) {
+        (Some(redirect), _) => Some(redirect),
+        (None, Some(fallback)) => {
+            let degraded = match shortener {
+                Some(shortener) => {
+                    !shortener
+                        .check_storage(STORAGE_PING_TIMEOUT)
+                        .await
+                        .reachable
+                }
+                None => true,
+            };
+            if degraded {
+                fallback.redirect(key).await
+            } else {
+                None
+            }
+        }
+        (None, None) => None,
+    };
+
+    match redirect {
+        Some(redirect) => {
+            log::trace!("Url found {}", redirect.url);
 
-            Ok(Response::builder()
-                .status(StatusCode::FOUND)
-                .header("Location", url)
-                .body(Body::Empty)
-                .expect("failed to render 302 response"))
+            let mut response = Response::builder()
+                .status(redirect.status_code())
+                .header("Cache-Control", redirect.cache_control())
+                .header("Location", redirect.url.as_str())
+                .header("Vary", "Accept");
+            if let Some(link) = redirect.link_header() {
+                response = response.header("Link", link.as_str());
+            }
+            if redirect.verified {
+                response = response.header(VERIFIED_PUBLISHER_HEADER, "true");
+            }
+
+            if Redirect::accepts_html(accept) {
+                html_response(
+                    response,
+                    ContentSecurityPolicy::page(),
+                    redirect.html(templates),
+                )
+            } else {
+                response.body(Body::Empty)
+            }
+            .expect("failed to render redirect response")
         }
         None => {
             log::trace!("NO Url found");
 
-            Ok(Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(Body::Empty)
-                .expect("failed to render 404 response"))
+            let page = match rotated_to {
+                Some(rotated_to) => ErrorPage::rotated(format!("/{}", rotated_to)),
+                None => ErrorPage::new(404),
+            };
+            error_page_response(templates, page, accept)
+        }
+    }
+}
+
+/// Shows the interstitial of the link `id`, see `shorty::interstitial`
+async fn interstitial(
+    shortener: &Shortener,
+    templates: &Templates,
+    id: &str,
+    accept: Option<&str>,
+) -> Response<Body> {
+    match shortener.interstitial(id).await {
+        Some(interstitial) => html_response(
+            Response::builder().header("Cache-Control", "no-store"),
+            if shortener.latency_beacon() {
+                ContentSecurityPolicy::beacon()
+            } else {
+                ContentSecurityPolicy::page()
+            },
+            interstitial.html(templates),
+        )
+        .expect("failed to render interstitial response"),
+        None => error_page_response(templates, ErrorPage::new(404), accept),
+    }
+}
+
+/// Completes a response with an HTML body, and the headers hardening every HTML page with
+/// `policy`, see `shorty::content_security_policy`
+fn html_response(
+    mut response: Builder,
+    policy: ContentSecurityPolicy,
+    body: String,
+) -> http::Result<Response<Body>> {
+    response = response.header("Content-Type", "text/html; charset=utf-8");
+    for (name, value) in policy.headers() {
+        response = response.header(name, value);
+    }
+    response.body(Body::from(body))
+}
+
+/// Logs a violation of the `Content-Security-Policy` of a page reported by a browser, see
+/// `shorty::content_security_policy`
+fn csp_report(report: &str) -> Response<Body> {
+    let report = &report.as_bytes()[..report.len().min(MAX_CSP_REPORT_SIZE)];
+    log::warn!(
+        "Content-Security-Policy violation reported: {}",
+        String::from_utf8_lossy(report)
+    );
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::Empty)
+        .expect("failed to render response")
+}
+
+/// Counts a report of the latency beacon of an interstitial for the link `id`, see
+/// `shorty::click_latency`
+async fn latency(shortener: &Shortener, id: &str, report: &str) -> Response<Body> {
+    let report = match serde_json::from_str::<LatencyReport>(report) {
+        Ok(report) => report,
+        Err(err) => return bad_request(format!("Invalid latency report: {}", err)),
+    };
+    match shortener.record_latency(id, &report).await {
+        Ok(()) => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::Empty)
+            .expect("failed to render response"),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Returns the pass of the visitor in the `shorty_human` cookie, if any, see `shorty::challenge`
+fn human_pass(e: &Request<Body>) -> Option<&str> {
+    e.headers()
+        .get_all("Cookie")
+        .iter()
+        .filter_map(|cookies| cookies.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == HUMAN_COOKIE)
+        .map(|(_, pass)| pass)
+}
+
+/// Shows a new challenge of the link `id`, allowing the answer to be posted back, see
+/// `shorty::challenge`
+fn challenge_response(
+    shortener: &Shortener,
+    templates: &Templates,
+    id: &str,
+    failed: bool,
+    accept: Option<&str>,
+) -> Response<Body> {
+    let status = if failed {
+        StatusCode::FORBIDDEN
+    } else {
+        StatusCode::OK
+    };
+    match shortener.challenge(id, failed) {
+        Some(challenge) => html_response(
+            Response::builder()
+                .status(status)
+                .header("Cache-Control", "no-store"),
+            ContentSecurityPolicy::form(),
+            challenge.html(templates),
+        )
+        .expect("failed to render challenge response"),
+        None => error_page_response(templates, ErrorPage::new(404), accept),
+    }
+}
+
+/// Checks the form answering the challenge of the link `id`: a right answer sets the pass of the
+/// visitor in the `shorty_human` cookie and goes back to the link, a wrong one shows a new
+/// challenge
+fn answer_challenge(
+    shortener: &Shortener,
+    templates: &Templates,
+    id: &str,
+    form: &str,
+    accept: Option<&str>,
+) -> Response<Body> {
+    let field = |name| {
+        url::form_urlencoded::parse(form.as_bytes())
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.into_owned())
+            .unwrap_or_default()
+    };
+    let pass = shortener.pass_challenge(id, &field("answer"), &field("token"));
+
+    match (pass, shortener.human_challenge()) {
+        (Some(pass), Some(human_challenge)) => Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header("Cache-Control", "no-store")
+            .header("Location", format!("/{}", id).as_str())
+            .header(
+                "Set-Cookie",
+                format!(
+                    "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+                    HUMAN_COOKIE,
+                    pass,
+                    human_challenge.pass_ttl()
+                )
+                .as_str(),
+            )
+            .body(Body::Empty)
+            .expect("failed to render challenge response"),
+        _ => challenge_response(shortener, templates, id, true, accept),
+    }
+}
+
+/// Builds the error response of `page`, with the page as HTML or JSON body for clients accepting
+/// either, and an empty body for the others. The successor of a rotated link is linked as
+/// `successor-version`.
+fn error_page_response(
+    templates: &Templates,
+    page: ErrorPage,
+    accept: Option<&str>,
+) -> Response<Body> {
+    let mut response = Response::builder()
+        .status(page.status)
+        .header("Vary", "Accept");
+    if let Some(successor) = &page.successor {
+        response = response.header(
+            "Link",
+            format!("<{}>; rel=\"successor-version\"", successor).as_str(),
+        );
+    }
+    if Redirect::accepts_html(accept) {
+        html_response(
+            response,
+            ContentSecurityPolicy::page(),
+            page.html(templates),
+        )
+    } else if ErrorPage::accepts_json(accept) {
+        response
+            .header("Content-Type", "application/json")
+            .body(Body::from(page.json()))
+    } else {
+        response.body(Body::Empty)
+    }
+    .expect("failed to render error response")
+}
+
+async fn stats(
+    shortener: &Shortener,
+    templates: &Templates,
+    key: &str,
+    accept: Option<&str>,
+) -> Response<Body> {
+    match shortener.stats(key).await {
+        Some(stats) => json_response(StatusCode::OK, &stats),
+        None => {
+            let page = match shortener.rotated_to(key).await {
+                Some(rotated_to) => ErrorPage::rotated(format!("/{}", rotated_to)),
+                None => ErrorPage::new(404),
+            };
+            error_page_response(templates, page, accept)
         }
     }
 }
 
+async fn delete(
+    shortener: &Shortener,
+    key: &str,
+    delete_request: &DeleteRequest,
+) -> Response<Body> {
+    match shortener.delete(&delete_request.api_key, key).await {
+        Ok(_) => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::Empty)
+            .expect("failed to render response"),
+        Err(err) => error_response(err),
+    }
+}
+
 #[derive(Serialize)]
 struct ShortenerError {
     err: String,
 }
 
-fn shorten(
-    shortener: &mut Shortener,
+/// Builds a response with `status` and `body` as JSON, the only kind of body rewritten when
+/// `SHORTENER_ASCII_JSON` is set
+fn json_response(status: StatusCode, body: &impl Serialize) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::Text(serde_json::to_string(body).unwrap()))
+        .expect("failed to render response")
+}
+
+/// Builds a JSON response with `status` telling the error `err`
+fn error_json_response(status: StatusCode, err: String) -> Response<Body> {
+    json_response(status, &ShortenerError { err })
+}
+
+/// Answers a `shorty::ShortenerError` with its status, see `shorty::ShortenerError::status_code`,
+/// telling rate limited clients when to retry
+fn error_response(err: shorty::ShortenerError) -> Response<Body> {
+    let status = StatusCode::from_u16(err.status_code()).expect("invalid error status");
+    let mut response = error_json_response(status, err.to_string());
+    if let Some(retry_after) = err.retry_after() {
+        response.headers_mut().insert(
+            "Retry-After",
+            HeaderValue::from_str(&retry_after.to_string()).expect("invalid Retry-After"),
+        );
+    }
+    response
+}
+
+/// Who is shortening a link: the client IP address and the user agent. The address is the source
+/// of the request as seen by API Gateway, or else the last hop of `X-Forwarded-For`, the one
+/// added by API Gateway: clients can add the others.
+fn creator(e: &Request<Body>) -> Creator {
+    let header = |name| e.headers().get(name).and_then(|value| value.to_str().ok());
+    let forwarded_for = header("X-Forwarded-For")
+        .and_then(|forwarded_for| forwarded_for.rsplit(',').next())
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty());
+    Creator {
+        ip: e.source_ip().or(forwarded_for).map(String::from),
+        user_agent: header("User-Agent").map(String::from),
+    }
+}
+
+async fn shorten(
+    shortener: &Shortener,
     api_key_mandatory: bool,
-    api_key: &Option<String>,
     host: Option<&str>,
-    url: &str,
-) -> Result<Response<Body>, HandlerError> {
-    if api_key.is_none() && api_key_mandatory {
-        return Ok(Response::builder()
-            .status(StatusCode::FORBIDDEN)
-            .body(Body::Text(
-                serde_json::to_string(&ShortenerError {
-                    err: String::from("Missing API key"),
-                })
-                .unwrap(),
-            ))
-            .expect("failed to render response"));
-    }
-
-    let api_key = &api_key.as_ref().map(String::as_str);
-
-    match shortener.shorten(api_key, host, url) {
-        Ok(shorten_result) => Ok(Response::builder()
-            .body(Body::Text(serde_json::to_string(&shorten_result).unwrap()))
-            .expect("failed to render response")),
-
-        Err(err) => Ok(Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Body::Text(
-                serde_json::to_string(&ShortenerError {
-                    err: err.to_string(),
-                })
-                .unwrap(),
-            ))
-            .expect("failed to render response")),
+    shorten_request: &ShortenRequest,
+    creator: Creator,
+) -> Response<Body> {
+    if shorten_request.api_key.is_none() && api_key_mandatory {
+        return error_json_response(StatusCode::FORBIDDEN, String::from("Missing API key"));
+    }
+
+    let api_key = &shorten_request.api_key.as_deref();
+    let url = &shorten_request.url;
+
+    let expires_in =
+        match shorty::expires_in(shorten_request.expires_in, shorten_request.expires_at) {
+            Ok(expires_in) => expires_in,
+            Err(err) => return error_response(err),
+        };
+    let options = LinkOptions {
+        expires_in,
+        permanent: shorten_request.permanent,
+        campaign: shorten_request.campaign.clone(),
+        redirect_status: shorten_request.redirect_status,
+        schedule: shorten_request.schedule.clone(),
+        preconnect: shorten_request.preconnect,
+        creator,
+    };
+    let shorten_result = match &shorten_request.custom_id {
+        Some(custom_id) => {
+            shortener
+                .shorten_with_id(api_key, host, custom_id, url, &options)
+                .await
+        }
+        None => shortener.shorten(api_key, host, url, &options).await,
+    };
+
+    match shorten_result {
+        Ok(shorten_result) => json_response(StatusCode::OK, &shorten_result),
+        Err(err) => error_response(err),
     }
 }
 
 #[derive(Deserialize)]
 struct ShortenRequest {
     api_key: Option<String>,
+    custom_id: Option<String>,
     url: String,
+    expires_in: Option<usize>,
+    expires_at: Option<u64>,
+    #[serde(default)]
+    permanent: bool,
+    campaign: Option<String>,
+    redirect_status: Option<u16>,
+    schedule: Option<Schedule>,
+    #[serde(default)]
+    preconnect: bool,
 }
 
 impl FromStr for ShortenRequest {
@@ -121,38 +610,198 @@ impl FromStr for ShortenRequest {
     }
 }
 
-fn handler(e: Request, _c: Context) -> Result<Response<Body>, HandlerError> {
-    let config = Config::new();
+impl ShortenRequest {
+    /// Reads the request of `GET /shorten` from its query string, with the API key in the
+    /// `X-API-Key` header if present. Schedules can't be set in a query.
+    fn from_query(e: &Request<Body>) -> Result<ShortenRequest, String> {
+        let query = e.query_string_parameters();
+        let api_key = e
+            .headers()
+            .get("X-API-Key")
+            .and_then(|api_key| api_key.to_str().ok())
+            .or_else(|| query.get("api_key"));
 
-    let redis =
-        Client::open(format!("redis://{}:{}/", config.redis_host, config.redis_port).as_str())
-            .unwrap()
-            .get_connection()
-            .unwrap();
-
-    let mut shortener = Shortener::new(
-        config.id_length,
-        config.id_alphabet,
-        config.id_generation_max_attempts,
-        RedisFacade::new(redis),
-        config.rate_limit_period,
-        config.rate_limit,
-    );
+        Ok(ShortenRequest {
+            api_key: api_key.map(String::from),
+            custom_id: query.get("custom_id").map(String::from),
+            url: query.get("url").ok_or("missing field `url`")?.to_owned(),
+            expires_in: parse_param(query.get("expires_in"), "expires_in")?,
+            expires_at: parse_param(query.get("expires_at"), "expires_at")?,
+            permanent: parse_param(query.get("permanent"), "permanent")?.unwrap_or(false),
+            campaign: query.get("campaign").map(String::from),
+            redirect_status: parse_param(query.get("redirect_status"), "redirect_status")?,
+            schedule: None,
+            preconnect: parse_param(query.get("preconnect"), "preconnect")?.unwrap_or(false),
+        })
+    }
+}
 
-    let path = e.uri().path().split('/').last();
-    let host = e.uri().host().unwrap();
-
-    match (path, e.method(), e.body()) {
-        (Some(key), &Method::GET, Body::Empty) => goto(&mut shortener, key),
-        (Some(""), &Method::POST, Body::Text(body)) => {
-            let shorten_request = body.parse::<ShortenRequest>().unwrap();
-            shorten(
-                &mut shortener,
-                config.api_key_mandatory,
-                &shorten_request.api_key,
-                Some(host),
-                &shorten_request.url,
-            )
+/// Parses the query parameter `name`, if present
+fn parse_param<T: FromStr>(value: Option<&str>, name: &str) -> Result<Option<T>, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|err| format!("invalid `{}`: {}", name, err))
+        })
+        .transpose()
+}
+
+#[derive(Deserialize)]
+struct DeleteRequest {
+    api_key: String,
+}
+
+impl FromStr for DeleteRequest {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s)
+    }
+}
+
+/// Creates the `Shortener` of the lambda. Instances are short lived, so the storage is always
+/// Redis, whatever `SHORTENER_STORAGE` says. Instances are frozen as soon as they answer, so
+/// there is no click queue: redirects are counted before answering, see `shorty::click_queue`.
+async fn new_shortener(config: &Config) -> RedisResult<Shortener> {
+    let redis = shorty_bootstrap::connect_redis(config).await?;
+    Ok(shorty_bootstrap::shortener(
+        config,
+        shorty_bootstrap::redis_storage(config, redis),
+    ))
+}
+
+/// The response to requests needing Redis while it's unreachable
+fn storage_unavailable_response() -> Response<Body> {
+    emit_metric("DegradedRejections");
+    error_json_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        String::from("Storage unavailable"),
+    )
+}
+
+/// The `400 Bad Request` response, telling what's wrong with the request
+fn bad_request(err: String) -> Response<Body> {
+    error_json_response(StatusCode::BAD_REQUEST, err)
+}
+
+async fn handler(
+    instance: &mut Instance,
+    config: &Config,
+    templates: &Templates,
+    e: Request<Body>,
+) -> Response<Body> {
+    let mut segments = e.uri().path().rsplit('/');
+    let path = segments.next();
+    let host = e.uri().host();
+    let accept = e
+        .headers()
+        .get("Accept")
+        .and_then(|accept| accept.to_str().ok());
+    let Instance {
+        connection,
+        fallback,
+    } = instance;
+    let fallback = fallback.as_mut();
+    match (
+        path,
+        e.method(),
+        e.body(),
+        connection.shortener(config).await,
+    ) {
+        (Some("stats"), &Method::GET, Body::Empty, shortener) => match segments.next() {
+            Some(key) if !key.is_empty() => match shortener {
+                Some(shortener) => stats(shortener, templates, key, accept).await,
+                None => storage_unavailable_response(),
+            },
+            _ => goto(shortener, fallback, templates, "stats", accept).await,
+        },
+        (Some("shorten"), &Method::GET, Body::Empty, None) if e.uri().path() == "/shorten" => {
+            storage_unavailable_response()
+        }
+        (Some("shorten"), &Method::GET, Body::Empty, Some(shortener))
+            if e.uri().path() == "/shorten" =>
+        {
+            match ShortenRequest::from_query(&e) {
+                Ok(shorten_request) => {
+                    shorten(
+                        shortener,
+                        config.api_key_mandatory,
+                        host,
+                        &shorten_request,
+                        creator(&e),
+                    )
+                    .await
+                }
+                Err(err) => bad_request(err),
+            }
+        }
+        (Some(key), &Method::GET, Body::Empty, shortener) => {
+            let query = match e.query_string_parameters().get("preview") {
+                Some(preview) => format!("preview={}", preview),
+                None => String::new(),
+            };
+            let requested_id = Interstitial::requested_id(key, &query);
+            // the interstitial shows where the link goes, so it's challenged as well
+            let link_id = requested_id.unwrap_or(key);
+            let challenged = match shortener {
+                Some(shortener) => shortener.requires_challenge(link_id, human_pass(&e)).await,
+                None => false,
+            };
+            match (requested_id, shortener) {
+                (_, Some(shortener)) if challenged => {
+                    challenge_response(shortener, templates, link_id, false, accept)
+                }
+                (Some(id), Some(shortener)) => interstitial(shortener, templates, id, accept).await,
+                (Some(_), None) => storage_unavailable_response(),
+                (None, Some(shortener)) if shortener.requires_interstitial(key).await => {
+                    interstitial(shortener, templates, key, accept).await
+                }
+                (None, shortener) => goto(shortener, fallback, templates, key, accept).await,
+            }
+        }
+        (Some(key), &Method::DELETE, Body::Text(_), None) if !key.is_empty() => {
+            storage_unavailable_response()
+        }
+        (Some(key), &Method::DELETE, Body::Text(body), Some(shortener)) if !key.is_empty() => {
+            match body.parse::<DeleteRequest>() {
+                Ok(delete_request) => delete(shortener, key, &delete_request).await,
+                Err(err) => bad_request(err.to_string()),
+            }
+        }
+        (Some("csp-report"), &Method::POST, Body::Text(report), _)
+            if e.uri().path() == CSP_REPORT_PATH =>
+        {
+            csp_report(report)
+        }
+        (Some("latency"), &Method::POST, Body::Text(_), None) => storage_unavailable_response(),
+        (Some("latency"), &Method::POST, Body::Text(report), Some(shortener)) => {
+            let id = segments.next().unwrap_or_default();
+            latency(shortener, id, report).await
+        }
+        (Some("challenge"), &Method::POST, Body::Text(_), None) => storage_unavailable_response(),
+        (Some("challenge"), &Method::POST, Body::Text(body), Some(shortener)) => {
+            let id = segments.next().unwrap_or_default();
+            answer_challenge(shortener, templates, id, body, accept)
+        }
+        (Some(""), &Method::POST, Body::Text(_), None) => storage_unavailable_response(),
+        (Some(""), &Method::POST, Body::Text(body), Some(shortener)) => {
+            match body.parse::<ShortenRequest>() {
+                Ok(shorten_request) => {
+                    shorten(
+                        shortener,
+                        config.api_key_mandatory,
+                        host,
+                        &shorten_request,
+                        creator(&e),
+                    )
+                    .await
+                }
+                Err(err) => bad_request(err.to_string()),
+            }
         }
         _ => {
             log::error!(
@@ -160,10 +809,84 @@ fn handler(e: Request, _c: Context) -> Result<Response<Body>, HandlerError> {
                 path,
                 e.method()
             );
-            Ok(Response::builder()
+            Response::builder()
                 .status(StatusCode::BAD_REQUEST)
                 .body(Body::Empty)
-                .expect("failed to render response"))
+                .expect("failed to render response")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shorty::memory_storage::MemoryStorage;
+
+    use super::*;
+
+    fn instance(config: &Config) -> Instance {
+        let storage = Box::new(MemoryStorage::new());
+        Instance {
+            connection: Connection {
+                shortener: Some(shorty_bootstrap::shortener(config, storage)),
+                last_attempt: Instant::now(),
+            },
+            fallback: None,
+        }
+    }
+
+    fn request(method: Method, path: &str, body: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(format!("https://sho.rt{}", path))
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    fn error(response: &Response<Body>) -> String {
+        match response.body() {
+            Body::Text(text) => serde_json::from_str::<serde_json::Value>(text).unwrap()["err"]
+                .as_str()
+                .unwrap()
+                .to_owned(),
+            body => panic!("unexpected body {:?}", body),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_malformed_bodies() {
+        let config = Config::new();
+        let mut instance = instance(&config);
+        let templates = Templates::new();
+
+        let e = request(Method::POST, "/", "{\"uri\":");
+        let response = handler(&mut instance, &config, &templates, e).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(error(&response).contains("EOF"));
+
+        let e = request(Method::DELETE, "/abc", "{}");
+        let response = handler(&mut instance, &config, &templates, e).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(error(&response).contains("api_key"));
+    }
+
+    #[tokio::test]
+    async fn test_ascii_json() {
+        let mut config = Config::new();
+        config.api_key_mandatory = false;
+        config.ascii_json = true;
+        let mut instance = instance(&config);
+        let templates = Templates::new();
+
+        let e = request(Method::POST, "/", r#"{"url":"https://example.com/café"}"#);
+        let response = ascii_json_response(handler(&mut instance, &config, &templates, e).await);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get("Content-Type").unwrap(),
+            "application/json"
+        );
+        match response.body() {
+            Body::Text(text) => assert!(text.contains(r#""url":"https://example.com/caf\u00e9""#)),
+            body => panic!("unexpected body {:?}", body),
         }
     }
 }
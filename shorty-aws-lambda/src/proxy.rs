@@ -0,0 +1,273 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! proxy holds the events of the API Gateway lambda proxy integration, and their conversion from
+//! and to the `http` requests and responses served by the lambda.
+//!
+//! Requests carry their query string parameters and the IP address of the client, as seen by API
+//! Gateway, as extensions, read with `RequestExt`.
+
+use std::collections::HashMap;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use http::{Method, Request, Response, Uri};
+
+/// The body of a request or response
+#[derive(Debug, PartialEq)]
+pub enum Body {
+    Empty,
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Self {
+        Body::Text(text)
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Self {
+        Body::Text(text.to_owned())
+    }
+}
+
+/// The event of a request, see
+/// https://docs.aws.amazon.com/apigateway/latest/developerguide/set-up-lambda-proxy-integrations.html
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ProxyRequest {
+    pub http_method: String,
+    pub path: String,
+    pub headers: Option<HashMap<String, String>>,
+    pub multi_value_headers: Option<HashMap<String, Vec<String>>>,
+    pub query_string_parameters: Option<HashMap<String, String>>,
+    pub body: Option<String>,
+    pub is_base64_encoded: bool,
+    pub request_context: RequestContext,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RequestContext {
+    pub identity: Identity,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Identity {
+    pub source_ip: Option<String>,
+}
+
+/// The query string parameters of a request
+#[derive(Clone, Default)]
+pub struct QueryStringParameters(HashMap<String, String>);
+
+impl QueryStringParameters {
+    /// Returns the value of the parameter `name`, if present
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+}
+
+/// The IP address of the client, as seen by API Gateway
+#[derive(Clone)]
+struct SourceIp(String);
+
+/// `RequestExt` reads what API Gateway tells about a request beyond its `http` parts
+pub trait RequestExt {
+    fn query_string_parameters(&self) -> QueryStringParameters;
+
+    fn source_ip(&self) -> Option<&str>;
+}
+
+impl RequestExt for Request<Body> {
+    fn query_string_parameters(&self) -> QueryStringParameters {
+        self.extensions()
+            .get::<QueryStringParameters>()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn source_ip(&self) -> Option<&str> {
+        self.extensions()
+            .get::<SourceIp>()
+            .map(|source_ip| source_ip.0.as_str())
+    }
+}
+
+impl ProxyRequest {
+    /// Converts the event into the request it carries. The URI has the host of the `Host` header,
+    /// if any.
+    pub fn into_request(self) -> Result<Request<Body>, http::Error> {
+        let headers = self.headers.unwrap_or_default();
+        let host = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Host"))
+            .map(|(_, host)| host.clone());
+        let uri = match host {
+            Some(host) => Uri::builder()
+                .scheme("https")
+                .authority(host.as_str())
+                .path_and_query(self.path.as_str())
+                .build()?,
+            None => Uri::builder().path_and_query(self.path.as_str()).build()?,
+        };
+
+        let mut request = Request::builder()
+            .method(Method::from_bytes(self.http_method.as_bytes())?)
+            .uri(uri);
+        // single value headers are repeated among the multi value ones, when both are sent
+        match self
+            .multi_value_headers
+            .filter(|headers| !headers.is_empty())
+        {
+            Some(multi_value_headers) => {
+                for (name, values) in multi_value_headers {
+                    for value in values {
+                        request = request.header(name.as_str(), value);
+                    }
+                }
+            }
+            None => {
+                for (name, value) in headers {
+                    request = request.header(name, value);
+                }
+            }
+        }
+        if let Some(query) = self.query_string_parameters {
+            request = request.extension(QueryStringParameters(query));
+        }
+        if let Some(source_ip) = self.request_context.identity.source_ip {
+            request = request.extension(SourceIp(source_ip));
+        }
+
+        let body = match self.body {
+            None => Body::Empty,
+            Some(body) if body.is_empty() => Body::Empty,
+            Some(body) if self.is_base64_encoded => match STANDARD.decode(&body) {
+                Ok(bytes) => match String::from_utf8(bytes) {
+                    Ok(text) => Body::Text(text),
+                    Err(err) => Body::Binary(err.into_bytes()),
+                },
+                Err(_) => Body::Text(body),
+            },
+            Some(body) => Body::Text(body),
+        };
+        request.body(body)
+    }
+}
+
+/// The event of a response, see `ProxyRequest`
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyResponse {
+    pub status_code: u16,
+    pub multi_value_headers: HashMap<String, Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    pub is_base64_encoded: bool,
+}
+
+impl From<Response<Body>> for ProxyResponse {
+    fn from(response: Response<Body>) -> Self {
+        let (parts, body) = response.into_parts();
+        let mut multi_value_headers = HashMap::<String, Vec<String>>::new();
+        for (name, value) in &parts.headers {
+            if let Ok(value) = value.to_str() {
+                multi_value_headers
+                    .entry(name.to_string())
+                    .or_default()
+                    .push(value.to_owned());
+            }
+        }
+
+        let (body, is_base64_encoded) = match body {
+            Body::Empty => (None, false),
+            Body::Text(text) => (Some(text), false),
+            Body::Binary(bytes) => (Some(STANDARD.encode(bytes)), true),
+        };
+        ProxyResponse {
+            status_code: parts.status.as_u16(),
+            multi_value_headers,
+            body,
+            is_base64_encoded,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_into_request() {
+        let event: ProxyRequest = serde_json::from_value(serde_json::json!({
+            "httpMethod": "POST",
+            "path": "/abc/challenge",
+            "headers": { "Host": "sho.rt", "Cookie": "a=b" },
+            "multiValueHeaders": { "Host": ["sho.rt"], "Cookie": ["a=b", "c=d"] },
+            "queryStringParameters": { "preview": "1" },
+            "body": "YW5zd2VyPTQ=",
+            "isBase64Encoded": true,
+            "requestContext": { "identity": { "sourceIp": "203.0.113.7" } },
+        }))
+        .unwrap();
+
+        let request = event.into_request().unwrap();
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.uri().host(), Some("sho.rt"));
+        assert_eq!(request.uri().path(), "/abc/challenge");
+        assert_eq!(request.headers().get_all("Cookie").iter().count(), 2);
+        assert_eq!(request.query_string_parameters().get("preview"), Some("1"));
+        assert_eq!(request.source_ip(), Some("203.0.113.7"));
+        assert_eq!(request.body(), &Body::from("answer=4"));
+    }
+
+    #[test]
+    fn test_into_request_without_body() {
+        let event: ProxyRequest = serde_json::from_value(serde_json::json!({
+            "httpMethod": "GET",
+            "path": "/abc",
+            "headers": null,
+            "queryStringParameters": null,
+            "body": null,
+        }))
+        .unwrap();
+
+        let request = event.into_request().unwrap();
+        assert_eq!(request.uri().host(), None);
+        assert_eq!(request.query_string_parameters().get("preview"), None);
+        assert_eq!(request.body(), &Body::Empty);
+    }
+
+    #[test]
+    fn test_from_response() {
+        let response = Response::builder()
+            .status(303)
+            .header("Set-Cookie", "a=b")
+            .header("Set-Cookie", "c=d")
+            .body(Body::Binary(vec![0xff]))
+            .unwrap();
+
+        let response = ProxyResponse::from(response);
+        assert_eq!(response.status_code, 303);
+        assert_eq!(
+            response.multi_value_headers["set-cookie"],
+            vec!["a=b", "c=d"]
+        );
+        assert_eq!(response.body.as_deref(), Some("/w=="));
+        assert!(response.is_base64_encoded);
+    }
+}
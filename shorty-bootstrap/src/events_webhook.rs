@@ -0,0 +1,49 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! events_webhook holds `EventsWebhook`, the `EventSink` posting the events of the outbox of
+//! shorty to `SHORTENER_EVENTS_WEBHOOK`.
+
+use std::error::Error;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use shorty::outbox::{EventSink, OutboxEvent};
+
+/// `EventsWebhook` posts each event as JSON to a URL, waiting at most `timeout`. Statuses other
+/// than 2xx fail the delivery, retried later.
+pub struct EventsWebhook {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl EventsWebhook {
+    pub fn new(url: String, timeout: Duration) -> Result<EventsWebhook, reqwest::Error> {
+        let client = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(EventsWebhook { client, url })
+    }
+}
+
+#[async_trait]
+impl EventSink for EventsWebhook {
+    async fn deliver(&self, event: &OutboxEvent) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.client
+            .post(&self.url)
+            .json(event)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
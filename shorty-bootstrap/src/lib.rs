@@ -0,0 +1,369 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! shorty-bootstrap builds what every shorty frontend needs from the `Config` of shorty-conf: the
+//! `Storage`, and the `Shortener` with all its options and event sinks, and the logger. Frontends
+//! only add their own transport, so that the same configuration behaves the same on each of them.
+
+#[cfg(feature = "outbound-http")]
+use std::time::Duration;
+
+use redis::RedisResult;
+use shorty::challenge::HumanChallenge;
+use shorty::collision_alert::CollisionAlert;
+use shorty::memory_storage::MemoryStorage;
+use shorty::phishing_screen::PhishingScreen;
+use shorty::redis_facade::{ConnectionOptions, RedisFacade};
+use shorty::shadow_storage::ShadowStorage;
+use shorty::signed_id::IdSigner;
+use shorty::storage::Storage;
+use shorty::templates::{self, Templates, ABUSE_TEMPLATE, REDIRECT_TEMPLATE};
+use shorty::Shortener;
+use shorty_conf::{Config, LogFormat, RedisMode, StorageBackend};
+#[cfg(feature = "outbound-http")]
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+#[cfg(feature = "outbound-http")]
+use crate::events_webhook::EventsWebhook;
+#[cfg(feature = "mailer")]
+use crate::mailer::Mailer;
+#[cfg(feature = "outbound-http")]
+use crate::redirects::HttpRedirectResolver;
+#[cfg(feature = "outbound-http")]
+use crate::safe_browsing::SafeBrowsing;
+
+#[cfg(feature = "outbound-http")]
+pub mod events_webhook;
+#[cfg(feature = "mailer")]
+pub mod mailer;
+#[cfg(feature = "outbound-http")]
+pub mod redirects;
+#[cfg(feature = "outbound-http")]
+pub mod safe_browsing;
+
+/// Installs the global logger, writing to stdout the events at `RUST_LOG` level in the
+/// `SHORTENER_LOG_FORMAT` format. Records of the `log` crate, such as those of shorty, are logged
+/// too, with the fields of the spans they happen in, like the ID of the request being served.
+pub fn init_logging(config: &Config) {
+    let builder = tracing_subscriber::fmt().with_env_filter(EnvFilter::new(&config.log_level));
+    match config.log_format {
+        LogFormat::Json => builder.json().init(),
+        LogFormat::Text => builder.init(),
+    }
+}
+
+/// Connects to Redis as deployed according to `SHORTENER_REDIS_MODE`
+pub async fn connect_redis(config: &Config) -> RedisResult<RedisFacade> {
+    let options = ConnectionOptions {
+        password: config.redis_password.clone(),
+        tls: config.redis_tls,
+        db: config.redis_db,
+    };
+
+    match config.redis_mode {
+        RedisMode::Standalone => {
+            RedisFacade::connect(&config.redis_shards, config.redis_pool_size, &options).await
+        }
+        RedisMode::Sentinel => {
+            RedisFacade::connect_sentinel(
+                &config.redis_nodes,
+                &config.redis_sentinel_master,
+                &options,
+            )
+            .await
+        }
+        RedisMode::Cluster => RedisFacade::connect_cluster(&config.redis_nodes, &options).await,
+    }
+}
+
+/// Wraps a connected `RedisFacade` into the storage of a `Shortener`, retrying commands as
+/// configured
+pub fn redis_storage(config: &Config, redis: RedisFacade) -> Box<dyn Storage> {
+    Box::new(redis.with_retries(config.redis_retries, config.redis_retry_backoff))
+}
+
+/// Opens the storage selected by `SHORTENER_STORAGE`, shadowed by the one of
+/// `SHORTENER_SHADOW_STORAGE` if set, see `shorty::shadow_storage`. Panics if they can't be
+/// opened, as frontends can't start without them.
+pub async fn storage(config: &Config) -> Box<dyn Storage> {
+    let storage = match config.storage {
+        StorageBackend::Redis => redis_storage(config, connect_redis(config).await.unwrap()),
+        StorageBackend::Memory => Box::new(MemoryStorage::new()),
+        StorageBackend::Sqlite => sqlite_storage(&config.sqlite_path),
+    };
+
+    let shadow = match config.shadow_storage {
+        Some(StorageBackend::Redis) => {
+            let options = ConnectionOptions {
+                password: config.redis_password.clone(),
+                tls: config.redis_tls,
+                db: config.redis_db,
+            };
+            let shadow = RedisFacade::connect(
+                &config.shadow_redis_shards,
+                config.redis_pool_size,
+                &options,
+            )
+            .await
+            .unwrap();
+            redis_storage(config, shadow)
+        }
+        Some(StorageBackend::Memory) => Box::new(MemoryStorage::new()),
+        Some(StorageBackend::Sqlite) => sqlite_storage(&config.shadow_sqlite_path),
+        None => return storage,
+    };
+    log::info!(
+        "shadowing the {:?} storage with the {:?} one",
+        config.storage,
+        config.shadow_storage.unwrap()
+    );
+    Box::new(ShadowStorage::new(storage, shadow))
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_storage(path: &str) -> Box<dyn Storage> {
+    Box::new(shorty::sqlite_storage::SqliteStorage::open(path).unwrap())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn sqlite_storage(_path: &str) -> Box<dyn Storage> {
+    panic!("the sqlite storage is not available: build with the sqlite feature")
+}
+
+/// Creates the `Shortener` of a frontend on `storage`, with every option of `config`
+pub fn shortener(config: &Config, storage: Box<dyn Storage>) -> Shortener {
+    let mut shortener = Shortener::new(
+        config.id_length.get(),
+        config.id_alphabet.to_vec(),
+        config.id_generation_max_attempts,
+        storage,
+        config.rate_limit_period.as_secs() as usize,
+        config.rate_limit,
+    )
+    .with_concurrency_limit(
+        config.concurrency_limit,
+        config.concurrency_lease_period.as_secs() as usize,
+    )
+    .with_url_deduplication(config.deduplicate_urls)
+    .with_allowed_schemes(config.allowed_schemes.clone())
+    .with_strict_urls(config.strict_urls)
+    .with_assume_https(config.assume_https)
+    .with_interstitial_required(config.require_interstitial)
+    .with_latency_beacon(config.latency_beacon)
+    .with_private_targets_rejected(config.reject_private_targets)
+    .with_blocked_domains(config.blocked_domains.clone())
+    .with_redirect_status(config.redirect_status)
+    .with_public_hosts(config.public_hosts.clone())
+    .with_public_base_url(config.public_base_url.clone())
+    .with_time_zone(config.time_zone.clone())
+    .with_click_sampling(config.click_sampling);
+
+    if config.id_collision_alert_threshold > 0 {
+        shortener = shortener.with_collision_alert(collision_alert(config));
+    }
+    if let Some(id_signing_key) = &config.id_signing_key {
+        shortener = shortener.with_id_signer(IdSigner::new(
+            id_signing_key.as_bytes(),
+            config.id_alphabet.to_vec(),
+            config.id_tag_length,
+        ));
+    }
+    shortener = with_outbound_http(shortener, config);
+    if !config.phishing_keywords.is_empty() {
+        shortener = shortener.with_phishing_screen(PhishingScreen::new(
+            config.phishing_keywords.clone(),
+            config.phishing_threshold,
+            config.phishing_young_domain_age.as_secs(),
+        ));
+    }
+    if config.challenge_threshold > 0 {
+        shortener = shortener.with_human_challenge(human_challenge(config));
+    }
+
+    shortener
+}
+
+/// Returns the human challenge of suspicious links. Without `SHORTENER_CHALLENGE_KEY`, passes are
+/// signed with a random key, not surviving restarts nor shared between instances.
+fn human_challenge(config: &Config) -> HumanChallenge {
+    let key = match &config.challenge_key {
+        Some(key) => key.clone(),
+        None => {
+            log::warn!(
+                "SHORTENER_CHALLENGE_KEY is not set: challenge passes won't survive restarts"
+            );
+            nanoid::nanoid!(32)
+        }
+    };
+    HumanChallenge::new(
+        key.as_bytes(),
+        config.challenge_threshold,
+        config.challenge_pass_ttl.as_secs(),
+    )
+}
+
+/// Adds the HTTP calls `config` enables to `shortener`: the Safe Browsing lookups, the
+/// resolution of the redirects of new links and the webhook of the events of the outbox
+#[cfg(feature = "outbound-http")]
+fn with_outbound_http(mut shortener: Shortener, config: &Config) -> Shortener {
+    if let Some(api_key) = &config.safe_browsing_api_key {
+        shortener = shortener.with_url_checker(SafeBrowsing::new(
+            api_key.clone(),
+            config.safe_browsing_timeout,
+        ));
+    }
+    if config.max_redirect_depth > 0 {
+        match HttpRedirectResolver::new(config.redirect_check_timeout) {
+            Ok(resolver) => {
+                shortener = shortener.with_redirect_resolver(resolver, config.max_redirect_depth)
+            }
+            Err(err) => log::error!("unable to follow the redirects of new links: {}", err),
+        }
+    }
+    if let Some(webhook) = &config.events_webhook {
+        match EventsWebhook::new(webhook.clone(), config.events_webhook_timeout) {
+            Ok(webhook) => shortener = shortener.with_event_sink(webhook),
+            Err(err) => log::error!("unable to call the events webhook: {}", err),
+        }
+    }
+    shortener
+}
+
+/// Warns about the HTTP calls `config` enables, which builds without the `outbound-http` feature
+/// can't make
+#[cfg(not(feature = "outbound-http"))]
+fn with_outbound_http(shortener: Shortener, config: &Config) -> Shortener {
+    let ignored = [
+        (
+            "SHORTENER_SAFE_BROWSING_API_KEY",
+            config.safe_browsing_api_key.is_some(),
+        ),
+        (
+            "SHORTENER_MAX_REDIRECT_DEPTH",
+            config.max_redirect_depth > 0,
+        ),
+        (
+            "SHORTENER_ID_COLLISION_WEBHOOK",
+            config.id_collision_webhook.is_some(),
+        ),
+        ("SHORTENER_EVENTS_WEBHOOK", config.events_webhook.is_some()),
+    ];
+    for (variable, _) in ignored.iter().filter(|(_, set)| *set) {
+        log::warn!(
+            "{} is ignored: built without the outbound-http feature",
+            variable
+        );
+    }
+    shortener
+}
+
+/// Loads the templates of the HTML pages: the built-in ones, replaced by those of
+/// `SHORTENER_TEMPLATES_DIR` and then by the single pages of `SHORTENER_REDIRECT_TEMPLATE`,
+/// `SHORTENER_ERROR_TEMPLATES_DIR` and `SHORTENER_ABUSE_PAGE`. Panics if a template is invalid, as
+/// it would never render.
+pub fn templates(config: &Config) -> Templates {
+    let overrides = config
+        .redirect_template
+        .iter()
+        .map(|source| (REDIRECT_TEMPLATE.to_owned(), source))
+        .chain(
+            config
+                .error_templates
+                .iter()
+                .map(|(status, source)| (format!("{}.html", status), source)),
+        )
+        .chain(
+            config
+                .abuse_page
+                .iter()
+                .map(|source| (ABUSE_TEMPLATE.to_owned(), source)),
+        );
+
+    let mut loaded = Ok(Templates::new());
+    if let Some(dir) = &config.templates_dir {
+        loaded = loaded.and_then(|loaded: Templates| loaded.with_dir(dir));
+    }
+    for (name, source) in overrides {
+        loaded = loaded.and_then(|loaded| loaded.with_template(&name, source));
+    }
+    loaded.unwrap_or_else(|err| panic!("invalid template: {}", templates::error_chain(&err)))
+}
+
+/// Builds the `Mailer` sending through `SHORTENER_SMTP_URL`, if set. An invalid SMTP URL or sender
+/// is logged, and no email is sent.
+#[cfg(feature = "mailer")]
+pub fn mailer(config: &Config) -> Option<Mailer> {
+    let smtp_url = config.smtp_url.as_ref()?;
+    let from = config.abuse_email.as_deref().unwrap_or_default();
+    match Mailer::new(smtp_url, from) {
+        Ok(mailer) => Some(mailer),
+        Err(err) => {
+            log::error!("unable to send emails: {}", err);
+            None
+        }
+    }
+}
+
+/// Builds the alert on ID collisions, calling `SHORTENER_ID_COLLISION_WEBHOOK`, if set, with a JSON
+/// `CollisionReport`. Webhooks are called on a task of the runtime running the `Shortener`,
+/// errors being just logged.
+fn collision_alert(config: &Config) -> CollisionAlert {
+    let collision_alert = CollisionAlert::new(config.id_collision_alert_threshold);
+    match &config.id_collision_webhook {
+        Some(webhook) => with_collision_webhook(collision_alert, webhook.clone()),
+        None => collision_alert,
+    }
+}
+
+/// Calls `webhook` with each alert of `collision_alert`
+#[cfg(feature = "outbound-http")]
+fn with_collision_webhook(collision_alert: CollisionAlert, webhook: String) -> CollisionAlert {
+    let client = reqwest::Client::new();
+    collision_alert.with_hook(move |report| {
+        let runtime = match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(_) => {
+                log::warn!("unable to call the ID collision webhook: no runtime");
+                return;
+            }
+        };
+
+        let request = client
+            .post(&webhook)
+            .timeout(Duration::from_secs(10))
+            .json(report);
+        runtime.spawn(
+            async move {
+                match request
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                {
+                    Ok(_) => log::info!("ID collision webhook called"),
+                    Err(err) => log::warn!("unable to call the ID collision webhook: {}", err),
+                }
+            }
+            // logged as part of the request generating the ID
+            .in_current_span(),
+        );
+    })
+}
+
+/// Leaves `collision_alert` as it is: builds without the `outbound-http` feature can't call
+/// webhooks, see `with_outbound_http`
+#[cfg(not(feature = "outbound-http"))]
+fn with_collision_webhook(collision_alert: CollisionAlert, _webhook: String) -> CollisionAlert {
+    collision_alert
+}
@@ -0,0 +1,57 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! redirects holds `HttpRedirectResolver`, the `RedirectResolver` following the redirects of new
+//! links with HTTP, enabled by `SHORTENER_MAX_REDIRECT_DEPTH`.
+
+use std::error::Error;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::header::LOCATION;
+use reqwest::redirect::Policy;
+use shorty::link_loop::RedirectResolver;
+
+/// `HttpRedirectResolver` sends a `HEAD` request to each URL, waiting at most `timeout`, and
+/// reads the `Location` of redirects
+pub struct HttpRedirectResolver {
+    client: reqwest::Client,
+}
+
+impl HttpRedirectResolver {
+    pub fn new(timeout: Duration) -> Result<HttpRedirectResolver, reqwest::Error> {
+        let client = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .timeout(timeout)
+            .build()?;
+        Ok(HttpRedirectResolver { client })
+    }
+}
+
+#[async_trait]
+impl RedirectResolver for HttpRedirectResolver {
+    async fn resolve(&self, url: &str) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        let response = self.client.head(url).send().await?;
+        if !response.status().is_redirection() {
+            return Ok(None);
+        }
+
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .map(|location| location.to_str().map(String::from))
+            .transpose()?;
+        Ok(location)
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! safe_browsing holds `SafeBrowsing`, the `UrlChecker` looking URLs up with the Google Safe
+//! Browsing Lookup API (v4), enabled by `SHORTENER_SAFE_BROWSING_API_KEY`.
+
+use std::error::Error;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use shorty::url_checker::{UrlChecker, Verdict};
+
+const LOOKUP_URL: &str = "https://safebrowsing.googleapis.com/v4/threatMatches:find";
+
+/// The threats URLs are checked for
+const THREAT_TYPES: [&str; 4] = [
+    "MALWARE",
+    "SOCIAL_ENGINEERING",
+    "UNWANTED_SOFTWARE",
+    "POTENTIALLY_HARMFUL_APPLICATION",
+];
+
+/// `SafeBrowsing` checks each URL with a call to the Lookup API, waiting at most `timeout`
+pub struct SafeBrowsing {
+    client: reqwest::Client,
+    api_key: String,
+    timeout: Duration,
+}
+
+impl SafeBrowsing {
+    pub fn new(api_key: String, timeout: Duration) -> SafeBrowsing {
+        SafeBrowsing {
+            client: reqwest::Client::new(),
+            api_key,
+            timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl UrlChecker for SafeBrowsing {
+    async fn check(&self, url: &str) -> Result<Verdict, Box<dyn Error + Send + Sync>> {
+        let request = json!({
+            "client": {
+                "clientId": "shorty",
+                "clientVersion": env!("CARGO_PKG_VERSION"),
+            },
+            "threatInfo": {
+                "threatTypes": THREAT_TYPES,
+                "platformTypes": ["ANY_PLATFORM"],
+                "threatEntryTypes": ["URL"],
+                "threatEntries": [{ "url": url }],
+            },
+        });
+
+        let response = self
+            .client
+            .post(LOOKUP_URL)
+            .query(&[("key", &self.api_key)])
+            .timeout(self.timeout)
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Value>()
+            .await?;
+
+        // no matches is an empty object
+        let threat = response["matches"][0]["threatType"].as_str();
+        Ok(match threat {
+            Some(threat) => Verdict::Malicious {
+                threat: threat.to_owned(),
+            },
+            None => Verdict::Safe,
+        })
+    }
+}
@@ -0,0 +1,465 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! client holds `Client`, running the link and API key commands of shorty-cli either on the
+//! storage, through a `Shortener` configured as the one of shorty-http, or against a running
+//! shorty-http, authenticated with its admin key.
+
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use reqwest::redirect::Policy;
+use reqwest::{header, Method, RequestBuilder, Response};
+use serde_json::{json, Value};
+
+use shorty::link_transfer::TransferFormat;
+use shorty::{LinkOptions, Shortener};
+
+/// The commands about links or API keys, parsed by `Command::parse`
+const COMMANDS: &[&str] = &["shorten", "lookup", "delete", "keys", "export", "import"];
+
+/// A command of shorty-cli about links or API keys, see `USAGE`
+pub enum Command {
+    Shorten {
+        url: String,
+        api_key: Option<String>,
+        custom_id: Option<String>,
+        expires_in: Option<usize>,
+    },
+    Lookup {
+        id: String,
+    },
+    Delete {
+        id: String,
+        api_key: String,
+    },
+    CreateKey {
+        expires_in: Option<usize>,
+        rate_limit: Option<i64>,
+    },
+    RevokeKey {
+        key: String,
+    },
+    ListKeys,
+    Export {
+        format: TransferFormat,
+    },
+    Import {
+        format: TransferFormat,
+    },
+}
+
+impl Command {
+    /// Parses `command` and the arguments following it, see `USAGE`. Returns `None` if
+    /// `command` isn't about links or API keys.
+    pub fn parse(
+        command: &str,
+        args: impl Iterator<Item = String>,
+    ) -> Option<Result<Command, String>> {
+        if !COMMANDS.contains(&command) {
+            return None;
+        }
+
+        let command = Arguments::parse(args).and_then(|mut args| {
+            let command = match command {
+                "shorten" => args.shorten(),
+                "lookup" => args.positional("<id>").map(|id| Command::Lookup { id }),
+                "delete" => args.delete(),
+                "keys" => args.keys(),
+                "export" => args.format().map(|format| Command::Export { format }),
+                _ => args.format().map(|format| Command::Import { format }),
+            }?;
+            args.finish()?;
+            Ok(command)
+        });
+        Some(command)
+    }
+}
+
+/// The arguments following a command: its `--name value` flags and its positional arguments,
+/// taken as the command reads them
+struct Arguments {
+    flags: Vec<(String, String)>,
+    positional: VecDeque<String>,
+}
+
+impl Arguments {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Arguments, String> {
+        let mut arguments = Arguments {
+            flags: Vec::new(),
+            positional: VecDeque::new(),
+        };
+        while let Some(arg) = args.next() {
+            if arg.starts_with("--") {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("missing value of {}", arg))?;
+                arguments.flags.push((arg, value));
+            } else {
+                arguments.positional.push_back(arg);
+            }
+        }
+        Ok(arguments)
+    }
+
+    fn shorten(&mut self) -> Result<Command, String> {
+        Ok(Command::Shorten {
+            url: self.positional("<url>")?,
+            api_key: self.flag("--api-key"),
+            custom_id: self.flag("--custom-id"),
+            expires_in: self.number("--expires-in")?,
+        })
+    }
+
+    fn delete(&mut self) -> Result<Command, String> {
+        Ok(Command::Delete {
+            id: self.positional("<id>")?,
+            api_key: self.flag("--api-key").ok_or("missing --api-key")?,
+        })
+    }
+
+    fn keys(&mut self) -> Result<Command, String> {
+        match self.positional("keys create, revoke or list")?.as_str() {
+            "create" => Ok(Command::CreateKey {
+                expires_in: self.number("--expires-in")?,
+                rate_limit: self.number("--rate-limit")?,
+            }),
+            "revoke" => Ok(Command::RevokeKey {
+                key: self.positional("<key>")?,
+            }),
+            "list" => Ok(Command::ListKeys),
+            command => Err(format!("unknown command keys {}", command)),
+        }
+    }
+
+    /// Takes the `--format` of an export or import, `json` if missing
+    fn format(&mut self) -> Result<TransferFormat, String> {
+        self.flag("--format")
+            .as_deref()
+            .unwrap_or("json")
+            .parse()
+            .map_err(|err: shorty::ShortenerError| err.to_string())
+    }
+
+    fn flag(&mut self, name: &str) -> Option<String> {
+        let index = self.flags.iter().position(|(flag, _)| flag == name)?;
+        Some(self.flags.remove(index).1)
+    }
+
+    fn number<T>(&mut self, name: &str) -> Result<Option<T>, String>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.flag(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|err| format!("invalid {} '{}': {}", name, value, err))
+            })
+            .transpose()
+    }
+
+    fn positional(&mut self, name: &str) -> Result<String, String> {
+        self.positional
+            .pop_front()
+            .ok_or_else(|| format!("missing {}", name))
+    }
+
+    /// Fails if some arguments were not taken by the command
+    fn finish(self) -> Result<(), String> {
+        if let Some((flag, _)) = self.flags.first() {
+            return Err(format!("unknown argument {}", flag));
+        }
+        if let Some(arg) = self.positional.front() {
+            return Err(format!("unexpected argument {}", arg));
+        }
+        Ok(())
+    }
+}
+
+/// Where commands run: on the storage, or against a running shorty-http
+pub enum Client {
+    Storage(Box<Shortener>),
+    Http(Http),
+}
+
+/// A running shorty-http, at `base_url`, called with `admin_key` by the admin commands
+pub struct Http {
+    client: reqwest::Client,
+    base_url: String,
+    admin_key: Option<String>,
+}
+
+impl Http {
+    /// Creates a client of the shorty-http at `base_url`. Redirects aren't followed, so that
+    /// lookups read where links redirect to.
+    pub fn new(base_url: &str, admin_key: Option<String>) -> Result<Http, String> {
+        let client = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .user_agent(concat!("shorty-cli/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(|err| err.to_string())?;
+        Ok(Http {
+            client,
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            admin_key,
+        })
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(method, format!("{}{}", self.base_url, path))
+    }
+
+    fn admin_request(&self, method: Method, path: &str) -> Result<RequestBuilder, String> {
+        let admin_key = self
+            .admin_key
+            .as_deref()
+            .ok_or("missing admin key: set SHORTENER_ADMIN_KEY")?;
+        Ok(self.request(method, path).header("X-Admin-Key", admin_key))
+    }
+
+    /// Sends `request`, returning the response if it isn't an error, and otherwise its status
+    /// with the `err` of its body
+    async fn send(request: RequestBuilder) -> Result<Response, String> {
+        let response = request.send().await.map_err(|err| err.to_string())?;
+        let status = response.status();
+        if !status.is_client_error() && !status.is_server_error() {
+            return Ok(response);
+        }
+
+        let body = response.text().await.unwrap_or_default();
+        let err = serde_json::from_str::<Value>(&body)
+            .ok()
+            .and_then(|body| body["err"].as_str().map(str::to_owned))
+            .unwrap_or(body);
+        match err.as_str() {
+            "" => Err(status.to_string()),
+            err => Err(format!("{}: {}", status, err)),
+        }
+    }
+
+    async fn text(request: RequestBuilder) -> Result<String, String> {
+        let response = Http::send(request).await?;
+        response.text().await.map_err(|err| err.to_string())
+    }
+}
+
+impl Client {
+    /// Runs `command`, printing what it returns on stdout, and what it did on stderr
+    pub async fn run(&self, command: Command) -> Result<(), String> {
+        match command {
+            Command::Shorten {
+                url,
+                api_key,
+                custom_id,
+                expires_in,
+            } => {
+                let result = self.shorten(&url, api_key, custom_id, expires_in).await?;
+                println!("{}", result);
+            }
+            Command::Lookup { id } => println!("{}", self.lookup(&id).await?),
+            Command::Delete { id, api_key } => {
+                self.delete(&id, &api_key).await?;
+                eprintln!("deleted {}", id);
+            }
+            Command::CreateKey {
+                expires_in,
+                rate_limit,
+            } => println!("{}", self.create_key(expires_in, rate_limit).await?),
+            Command::RevokeKey { key } => {
+                self.revoke_key(&key).await?;
+                eprintln!("revoked {}", key);
+            }
+            Command::ListKeys => {
+                for key in self.list_keys().await? {
+                    println!("{}", key);
+                }
+            }
+            Command::Export { format } => self.export(format).await?,
+            Command::Import { format } => println!("{}", self.import(format).await?),
+        }
+        Ok(())
+    }
+
+    /// Shortens `url`, returning the result as JSON
+    async fn shorten(
+        &self,
+        url: &str,
+        api_key: Option<String>,
+        custom_id: Option<String>,
+        expires_in: Option<usize>,
+    ) -> Result<String, String> {
+        match self {
+            Client::Storage(shortener) => {
+                let api_key = api_key.as_deref();
+                let options = LinkOptions {
+                    expires_in,
+                    ..LinkOptions::default()
+                };
+                let result = match custom_id {
+                    Some(id) => {
+                        shortener
+                            .shorten_with_id(&api_key, None, &id, url, &options)
+                            .await
+                    }
+                    None => shortener.shorten(&api_key, None, url, &options).await,
+                }
+                .map_err(|err| err.to_string())?;
+                serde_json::to_string(&result).map_err(|err| err.to_string())
+            }
+            Client::Http(http) => {
+                let body = json!({
+                    "url": url,
+                    "api_key": api_key,
+                    "custom_id": custom_id,
+                    "expires_in": expires_in,
+                });
+                Http::text(http.request(Method::POST, "/").json(&body)).await
+            }
+        }
+    }
+
+    /// Returns the URL the link with the given ID redirects to. Over HTTP, the lookup counts as
+    /// a hit of the link.
+    async fn lookup(&self, id: &str) -> Result<String, String> {
+        match self {
+            Client::Storage(shortener) => shortener
+                .destination(id)
+                .await
+                .ok_or_else(|| format!("{} not found", id)),
+            Client::Http(http) => {
+                let response = Http::send(http.request(Method::GET, &format!("/{}", id))).await?;
+                response
+                    .headers()
+                    .get(header::LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .map(str::to_owned)
+                    .ok_or_else(|| format!("{} doesn't redirect: {}", id, response.status()))
+            }
+        }
+    }
+
+    async fn delete(&self, id: &str, api_key: &str) -> Result<(), String> {
+        match self {
+            Client::Storage(shortener) => shortener
+                .delete(api_key, id)
+                .await
+                .map_err(|err| err.to_string()),
+            Client::Http(http) => {
+                let request = http
+                    .request(Method::DELETE, &format!("/{}", id))
+                    .json(&json!({ "api_key": api_key }));
+                Http::send(request).await.map(|_| ())
+            }
+        }
+    }
+
+    /// Creates an API key, returning it as JSON
+    async fn create_key(
+        &self,
+        expires_in: Option<usize>,
+        rate_limit: Option<i64>,
+    ) -> Result<String, String> {
+        match self {
+            Client::Storage(shortener) => {
+                let key = shortener
+                    .api_key_manager()
+                    .create(expires_in, rate_limit, None)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                serde_json::to_string(&key).map_err(|err| err.to_string())
+            }
+            Client::Http(http) => {
+                let body = json!({ "expires_in": expires_in, "rate_limit": rate_limit });
+                Http::text(http.admin_request(Method::POST, "/admin/keys")?.json(&body)).await
+            }
+        }
+    }
+
+    async fn revoke_key(&self, key: &str) -> Result<(), String> {
+        match self {
+            Client::Storage(shortener) => shortener
+                .api_key_manager()
+                .revoke(key)
+                .await
+                .map_err(|err| err.to_string()),
+            Client::Http(http) => {
+                let request =
+                    http.admin_request(Method::DELETE, &format!("/admin/keys/{}", key))?;
+                Http::send(request).await.map(|_| ())
+            }
+        }
+    }
+
+    async fn list_keys(&self) -> Result<Vec<String>, String> {
+        match self {
+            Client::Storage(shortener) => shortener
+                .api_key_manager()
+                .list()
+                .await
+                .map_err(|err| err.to_string()),
+            Client::Http(http) => {
+                let response = Http::send(http.admin_request(Method::GET, "/admin/keys")?).await?;
+                response.json().await.map_err(|err| err.to_string())
+            }
+        }
+    }
+
+    /// Writes every link to stdout, a page at a time
+    async fn export(&self, format: TransferFormat) -> Result<(), String> {
+        match self {
+            Client::Storage(shortener) => {
+                let exported = shortener
+                    .export(io::stdout(), format)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                eprintln!("exported {} links", exported);
+            }
+            Client::Http(http) => {
+                let path = format!("/admin/export?format={}", format.name());
+                let mut response = Http::send(http.admin_request(Method::GET, &path)?).await?;
+                let mut stdout = io::stdout();
+                while let Some(chunk) = response.chunk().await.map_err(|err| err.to_string())? {
+                    stdout.write_all(&chunk).map_err(|err| err.to_string())?;
+                }
+                stdout.flush().map_err(|err| err.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Imports the links read from stdin, returning the summary of the import as JSON
+    async fn import(&self, format: TransferFormat) -> Result<String, String> {
+        match self {
+            Client::Storage(shortener) => {
+                let summary = shortener
+                    .import(io::stdin(), format)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                serde_json::to_string(&summary).map_err(|err| err.to_string())
+            }
+            Client::Http(http) => {
+                let mut body = Vec::new();
+                io::stdin()
+                    .read_to_end(&mut body)
+                    .map_err(|err| err.to_string())?;
+                let path = format!("/admin/import?format={}", format.name());
+                Http::text(http.admin_request(Method::POST, &path)?.body(body)).await
+            }
+        }
+    }
+}
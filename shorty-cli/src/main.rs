@@ -0,0 +1,243 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! shorty-cli runs commands against the storage of a shorty deployment, configured by the same
+//! `SHORTENER_*` variables as shorty-http, or against a running shorty-http: see `USAGE`.
+
+use std::env;
+use std::process;
+
+use shorty::storage::Storage;
+use shorty_conf::{Config, StorageBackend};
+
+use crate::client::{Client, Command, Http};
+use crate::rebalance::RebalanceOptions;
+use crate::rekey::RekeyOptions;
+use crate::seed::SeedOptions;
+
+mod client;
+mod rebalance;
+mod rekey;
+mod seed;
+
+const USAGE: &str = "Usage: shorty-cli [--url <url>] shorten <url> [--api-key <key>] [--custom-id <id>] [--expires-in <seconds>]
+       shorty-cli [--url <url>] lookup <id>
+       shorty-cli [--url <url>] delete <id> --api-key <key>
+       shorty-cli [--url <url>] keys create [--expires-in <seconds>] [--rate-limit <n>]
+       shorty-cli [--url <url>] keys revoke <key>
+       shorty-cli [--url <url>] keys list
+       shorty-cli [--url <url>] export [--format csv|json] > links
+       shorty-cli [--url <url>] import [--format csv|json] < links
+       shorty-cli seed [--links <n>] [--keys <n>] [--clicks <n>] [--seed <n>] [--concurrency <n>]
+       shorty-cli rekey --from <prefix> --to <prefix> [--batch <n>] [--rate <n>] [--concurrency <n>] [--cursor <cursor>]
+       shorty-cli rebalance [--batch <n>] [--rate <n>] [--cursor <cursor>]
+
+shorten, lookup, delete, keys, export and import run on the storage, as shorty-http would, or
+against the shorty-http running at --url, such as https://shorty.example.com, authenticated with
+SHORTENER_ADMIN_KEY for keys, export and import. shorten prints the shortened link as JSON, lookup
+the URL a link redirects to, without counting a hit but over HTTP, and keys create the new key as
+JSON. export writes the ID and URL of every link to stdout, as JSON lines by default, and import
+creates the links read from stdin, as written by export, printing a summary as JSON.
+
+seed fills the storage with synthetic data, for load tests and staging environments: <keys> API
+keys owning up to three campaigns each, and <links> links spread over the keys, with varied
+expirations and campaigns. Then it follows the links <clicks> times, with a Zipf distribution:
+a few links get most of the clicks, as in real traffic. The created API keys are printed.
+
+The same --seed gives the same links, with the same IDs, and the same clicks: seed an empty
+storage to reproduce a data set. API keys are generated at random.
+
+Defaults: 100000 links, 50 keys, as many clicks as links, seed 0, 32 concurrent calls.
+
+rekey renames the keys starting with the --from prefix, possibly empty, to start with the --to
+prefix instead, such as --from \"\" --to \"shorty:\". Keys are scanned and renamed <batch> at a
+time, <concurrency> at once, at most <rate> keys per second, 0 for no limit. Keys whose new name
+is taken are reported and left as they are. The cursor of each batch is printed once it's renamed:
+pass the last one to --cursor to resume a rekey stopped midway.
+
+Defaults: batches of 1000 keys, 5000 keys per second, 16 concurrent renames, from the start.
+
+rebalance moves the keys of the Redis servers of SHORTENER_REDIS_SHARDS to the server each key
+belongs to, once the list has changed. Each server is scanned <batch> keys at a time, at most
+<rate> keys per second, 0 for no limit. Keys found on their server too are reported and left where
+they are. The cursor of each batch is printed once it's moved: pass the last one to --cursor to
+resume a rebalance stopped midway.
+
+Defaults: batches of 1000 keys, 5000 keys per second, from the start.
+
+Every SHORTENER_* environment variable of shorty-http applies as well: SHORTENER_STORAGE and the
+SHORTENER_REDIS_* variables select the storage to run on.";
+
+#[tokio::main]
+async fn main() {
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("{}", errors);
+            process::exit(1);
+        }
+    };
+
+    let mut args = env::args().skip(1).peekable();
+    let url = match args.peek().map(String::as_str) {
+        Some("--url") => {
+            args.next();
+            Some(
+                args.next()
+                    .unwrap_or_else(|| usage_error("missing value of --url")),
+            )
+        }
+        _ => None,
+    };
+
+    let command = args.next();
+    if let Some(command) = command
+        .as_deref()
+        .and_then(|command| Command::parse(command, args.by_ref()))
+    {
+        let command = command.unwrap_or_else(|err| usage_error(&err));
+        if let Err(err) = run_command(&config, url.as_deref(), command).await {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        return;
+    }
+    if url.is_some() {
+        usage_error("--url only applies to shorten, lookup, delete, keys, export and import");
+    }
+
+    match command.as_deref() {
+        Some("seed") => {
+            let options = SeedOptions::parse(args).unwrap_or_else(|err| usage_error(&err));
+            if let Err(err) = run_seed(&config, &options).await {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+        Some("rekey") => {
+            let options = RekeyOptions::parse(args).unwrap_or_else(|err| usage_error(&err));
+            if let Err(err) = run_rekey(&config, &options).await {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+        Some("rebalance") => {
+            let options = RebalanceOptions::parse(args).unwrap_or_else(|err| usage_error(&err));
+            if let Err(err) = run_rebalance(&config, &options).await {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+        Some("--help") | Some("-h") => println!("{}", USAGE),
+        Some(command) => usage_error(&format!("unknown command {}", command)),
+        None => usage_error("missing command"),
+    }
+}
+
+fn usage_error(err: &str) -> ! {
+    eprintln!("{}\n\n{}", err, USAGE);
+    process::exit(2)
+}
+
+async fn run_command(config: &Config, url: Option<&str>, command: Command) -> Result<(), String> {
+    if let Some(url) = url {
+        let http = Http::new(url, config.admin_key.clone())?;
+        return Client::Http(http).run(command).await;
+    }
+
+    if config.storage == StorageBackend::Memory {
+        return Err(String::from(
+            "the memory storage starts empty: set SHORTENER_STORAGE to redis or sqlite, or pass --url",
+        ));
+    }
+
+    shorty_bootstrap::init_logging(config);
+    let storage = shorty_bootstrap::storage(config).await;
+    let client = Client::Storage(Box::new(shorty_bootstrap::shortener(config, storage)));
+
+    let result = client.run(command).await;
+    if let Client::Storage(shortener) = client {
+        shortener.close().await;
+    }
+    result
+}
+
+async fn run_seed(config: &Config, options: &SeedOptions) -> Result<(), String> {
+    if config.storage == StorageBackend::Memory {
+        return Err(String::from(
+            "the memory storage is lost on exit: set SHORTENER_STORAGE to redis or sqlite",
+        ));
+    }
+
+    shorty_bootstrap::init_logging(config);
+    let storage = shorty_bootstrap::storage(config).await;
+    let shortener = shorty_bootstrap::shortener(config, storage);
+
+    let summary = seed::seed(&shortener, config, options).await?;
+    for key in &summary.keys {
+        println!("{}", key);
+    }
+    eprintln!(
+        "seeded {} API keys, {} campaigns, {} links and {} clicks",
+        summary.keys.len(),
+        summary.campaigns,
+        summary.links,
+        summary.clicks
+    );
+
+    shortener.close().await;
+    Ok(())
+}
+
+async fn run_rekey(config: &Config, options: &RekeyOptions) -> Result<(), String> {
+    if config.storage == StorageBackend::Memory {
+        return Err(String::from(
+            "the memory storage starts empty: set SHORTENER_STORAGE to redis or sqlite",
+        ));
+    }
+
+    shorty_bootstrap::init_logging(config);
+    let storage = shorty_bootstrap::storage(config).await;
+
+    let summary = rekey::rekey(storage.as_ref(), options).await;
+    storage.close().await;
+    let summary = summary?;
+    eprintln!(
+        "renamed {} keys from '{}' to '{}', {} conflicting",
+        summary.renamed, options.from, options.to, summary.conflicting
+    );
+    Ok(())
+}
+
+async fn run_rebalance(config: &Config, options: &RebalanceOptions) -> Result<(), String> {
+    if config.storage != StorageBackend::Redis {
+        return Err(String::from(
+            "only Redis is sharded: set SHORTENER_STORAGE to redis",
+        ));
+    }
+
+    shorty_bootstrap::init_logging(config);
+    let redis = shorty_bootstrap::connect_redis(config)
+        .await
+        .map_err(|err| format!("unable to connect to Redis: {}", err))?
+        .with_retries(config.redis_retries, config.redis_retry_backoff);
+
+    let summary = rebalance::rebalance(&redis, options).await;
+    redis.close().await;
+    let summary = summary?;
+    eprintln!(
+        "scanned {} keys, moved {}, {} conflicting",
+        summary.scanned, summary.moved, summary.conflicting
+    );
+    Ok(())
+}
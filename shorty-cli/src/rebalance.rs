@@ -0,0 +1,114 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! rebalance holds `rebalance`, moving the keys of a sharded Redis to the servers the hash ring
+//! gives them, once `SHORTENER_REDIS_SHARDS` has changed.
+//!
+//! Each server is scanned a batch at a time with `RedisFacade::rebalance_page`, at most
+//! `RebalanceOptions::rate` keys per second, so that Redis keeps serving meanwhile. Until a key is
+//! moved, shorty looks for it on its new server and doesn't find it. The cursor of each batch is
+//! printed once the batch is moved: a rebalance stopped midway resumes from the last cursor
+//! printed.
+
+use std::time::{Duration, Instant};
+
+use shorty::redis_facade::RedisFacade;
+
+/// The options of `rebalance`, from the arguments of `shorty-cli rebalance`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RebalanceOptions {
+    pub batch: usize,
+    pub rate: usize,
+    pub cursor: String,
+}
+
+impl RebalanceOptions {
+    /// Parses the arguments following `rebalance`, see `USAGE`
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<RebalanceOptions, String> {
+        let mut options = RebalanceOptions {
+            batch: 1000,
+            rate: 5000,
+            cursor: String::new(),
+        };
+
+        while let Some(arg) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value of {}", arg))?;
+            let number = || {
+                value
+                    .parse::<usize>()
+                    .map_err(|err| format!("invalid {} '{}': {}", arg, value, err))
+            };
+            match arg.as_str() {
+                "--batch" => options.batch = number()?.max(1),
+                "--rate" => options.rate = number()?,
+                "--cursor" => options.cursor = value,
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// What `rebalance` did: the number of keys `scanned`, `moved`, and `conflicting` with a key of
+/// their server, left where they are
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub scanned: usize,
+    pub moved: usize,
+    pub conflicting: usize,
+}
+
+/// Moves the keys of `redis` stored on another server than their own, see the module
+/// documentation. Keys found on their server too are reported, and left where they are.
+pub async fn rebalance(redis: &RedisFacade, options: &RebalanceOptions) -> Result<Summary, String> {
+    let mut summary = Summary::default();
+    let mut cursor = options.cursor.clone();
+
+    loop {
+        let started = Instant::now();
+        let page = redis
+            .rebalance_page(&cursor, options.batch)
+            .await
+            .map_err(|err| {
+                format!(
+                    "failed to rebalance keys after cursor '{}': {}",
+                    cursor, err
+                )
+            })?;
+
+        summary.scanned += page.scanned;
+        summary.moved += page.moved;
+        summary.conflicting += page.conflicting.len();
+        for key in &page.conflicting {
+            eprintln!("{} not moved: it exists on its server too", key);
+        }
+
+        cursor = match page.cursor {
+            Some(next) => next,
+            None => return Ok(summary),
+        };
+        eprintln!(
+            "scanned {} keys, moved {}, next cursor: {}",
+            summary.scanned, summary.moved, cursor
+        );
+
+        if options.rate > 0 {
+            let budget = Duration::from_secs_f64(page.scanned as f64 / options.rate as f64);
+            if let Some(wait) = budget.checked_sub(started.elapsed()) {
+                tokio::time::sleep(wait).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! rekey holds `rekey`, moving the keys of a storage from one prefix to another, such as from no
+//! prefix to `shorty:`.
+//!
+//! Keys are walked with `Storage::scan_page` and renamed with `Storage::rename` a batch at a time,
+//! at most `RekeyOptions::rate` keys per second, so that the storage keeps serving meanwhile. The
+//! cursor of each batch is printed once the batch is renamed: a rekey stopped midway resumes from
+//! the last cursor printed.
+
+use std::time::{Duration, Instant};
+
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+
+use shorty::storage::Storage;
+
+/// The options of `rekey`, from the arguments of `shorty-cli rekey`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RekeyOptions {
+    pub from: String,
+    pub to: String,
+    pub batch: usize,
+    pub rate: usize,
+    pub concurrency: usize,
+    pub cursor: String,
+}
+
+impl RekeyOptions {
+    /// Parses the arguments following `rekey`, see `USAGE`
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<RekeyOptions, String> {
+        let mut from = None;
+        let mut to = None;
+        let mut options = RekeyOptions {
+            from: String::new(),
+            to: String::new(),
+            batch: 1000,
+            rate: 5000,
+            concurrency: 16,
+            cursor: String::new(),
+        };
+
+        while let Some(arg) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value of {}", arg))?;
+            let number = || {
+                value
+                    .parse::<usize>()
+                    .map_err(|err| format!("invalid {} '{}': {}", arg, value, err))
+            };
+            match arg.as_str() {
+                "--from" => from = Some(value),
+                "--to" => to = Some(value),
+                "--batch" => options.batch = number()?.max(1),
+                "--rate" => options.rate = number()?,
+                "--concurrency" => options.concurrency = number()?.max(1),
+                "--cursor" => options.cursor = value,
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+
+        options.from = from.ok_or("missing --from")?;
+        options.to = to.ok_or("missing --to")?;
+        if options.from == options.to {
+            return Err(String::from("--from and --to are the same prefix"));
+        }
+        Ok(options)
+    }
+
+    /// Tells whether `key` is left as it is. When the new prefix extends the old one, such as
+    /// `shorty:` extending no prefix, keys with the new prefix are renamed already.
+    fn skips(&self, key: &str) -> bool {
+        self.to.starts_with(&self.from) && key.starts_with(&self.to)
+    }
+}
+
+/// What `rekey` did: the number of keys `renamed`, and of those `conflicting` with an existing
+/// key with the new name, left as they are
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub renamed: usize,
+    pub conflicting: usize,
+}
+
+/// Renames the keys starting with `options.from` to start with `options.to` instead, see the
+/// module documentation. Keys whose new name is taken are reported, and left as they are.
+pub async fn rekey(storage: &dyn Storage, options: &RekeyOptions) -> Result<Summary, String> {
+    let mut summary = Summary::default();
+    let mut cursor = options.cursor.clone();
+
+    loop {
+        let started = Instant::now();
+        let (keys, next) = storage
+            .scan_page(&options.from, &cursor, options.batch)
+            .await
+            .map_err(|err| format!("failed to scan keys after cursor '{}': {}", cursor, err))?;
+
+        let keys = keys
+            .into_iter()
+            .filter(|key| !options.skips(key))
+            .collect::<Vec<_>>();
+        let renamed = stream::iter(&keys)
+            .map(|key| async move {
+                let new_key = format!("{}{}", options.to, &key[options.from.len()..]);
+                match storage.rename(key, &new_key).await {
+                    Ok(renamed) => Ok((key, new_key, renamed)),
+                    Err(err) => Err(format!("failed to rename {}: {}", key, err)),
+                }
+            })
+            .buffer_unordered(options.concurrency)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        for (key, new_key, renamed) in renamed {
+            if renamed {
+                summary.renamed += 1;
+            } else if storage.exists(key).await.unwrap_or(false) {
+                eprintln!("{} not renamed: {} exists", key, new_key);
+                summary.conflicting += 1;
+            }
+        }
+
+        cursor = match next {
+            Some(next) => next,
+            None => return Ok(summary),
+        };
+        eprintln!("renamed {} keys, next cursor: {}", summary.renamed, cursor);
+
+        if options.rate > 0 {
+            let budget = Duration::from_secs_f64(keys.len() as f64 / options.rate as f64);
+            if let Some(wait) = budget.checked_sub(started.elapsed()) {
+                tokio::time::sleep(wait).await;
+            }
+        }
+    }
+}
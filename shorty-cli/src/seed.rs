@@ -0,0 +1,337 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! seed holds `seed`, filling a storage with synthetic API keys, campaigns, links and clicks for
+//! load tests and staging environments.
+//!
+//! The whole data set is planned up front with a pseudo random generator seeded by
+//! `SeedOptions::seed`, so that the same seed plans the same links and clicks whatever the
+//! concurrency they are stored with. Links get custom IDs drawn from the generator as well.
+
+use std::collections::HashSet;
+
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+
+use shorty::{LinkOptions, Shortener};
+use shorty_conf::Config;
+
+/// The exponent of the Zipf distribution of clicks: the link of rank `r` gets about
+/// `1 / r^ZIPF_EXPONENT` of them
+const ZIPF_EXPONENT: f64 = 1.07;
+
+/// The most campaigns an API key owns
+const MAX_CAMPAIGNS_PER_KEY: u64 = 3;
+
+const DOMAINS: [&str; 8] = [
+    "www.example.com",
+    "blog.example.com",
+    "shop.example.com",
+    "docs.example.org",
+    "news.example.org",
+    "app.example.net",
+    "static.example.net",
+    "example.edu",
+];
+
+const WORDS: [&str; 16] = [
+    "spring",
+    "summer",
+    "autumn",
+    "winter",
+    "launch",
+    "promo",
+    "guide",
+    "release",
+    "event",
+    "webinar",
+    "newsletter",
+    "pricing",
+    "careers",
+    "support",
+    "download",
+    "report",
+];
+
+const SOURCES: [&str; 5] = ["newsletter", "twitter", "linkedin", "qr", "partner"];
+
+/// The options of `seed`, from the arguments of `shorty-cli seed`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeedOptions {
+    pub links: usize,
+    pub keys: usize,
+    pub clicks: usize,
+    pub seed: u64,
+    pub concurrency: usize,
+}
+
+impl SeedOptions {
+    /// Parses the arguments following `seed`, see `USAGE`
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<SeedOptions, String> {
+        let mut options = SeedOptions {
+            links: 100_000,
+            keys: 50,
+            clicks: 0,
+            seed: 0,
+            concurrency: 32,
+        };
+        let mut clicks = None;
+
+        while let Some(arg) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value of {}", arg))?;
+            let number = || {
+                value
+                    .parse::<usize>()
+                    .map_err(|err| format!("invalid {} '{}': {}", arg, value, err))
+            };
+            match arg.as_str() {
+                "--links" => options.links = number()?,
+                "--keys" => options.keys = number()?,
+                "--clicks" => clicks = Some(number()?),
+                "--seed" => {
+                    options.seed = value
+                        .parse::<u64>()
+                        .map_err(|err| format!("invalid --seed '{}': {}", value, err))?
+                }
+                "--concurrency" => options.concurrency = number()?.max(1),
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+
+        options.clicks = clicks.unwrap_or(options.links);
+        Ok(options)
+    }
+}
+
+/// What `seed` stored: the created API `keys`, and the number of `campaigns`, `links` and
+/// `clicks`
+pub struct Summary {
+    pub keys: Vec<String>,
+    pub campaigns: usize,
+    pub links: usize,
+    pub clicks: usize,
+}
+
+/// A link to store, owned by the API key at index `key`, if any
+struct PlannedLink {
+    id: String,
+    key: Option<usize>,
+    url: String,
+    options: LinkOptions,
+}
+
+/// Fills the storage of `shortener` as described by `options`, making up to
+/// `SeedOptions::concurrency` calls at once. Links get IDs of the length and alphabet of `config`,
+/// so seeding fails if one of them is already taken.
+pub async fn seed(
+    shortener: &Shortener,
+    config: &Config,
+    options: &SeedOptions,
+) -> Result<Summary, String> {
+    let mut rng = SplitMix64(options.seed);
+
+    let mut keys = Vec::with_capacity(options.keys);
+    for _ in 0..options.keys {
+        // without rate limit, so that seeding isn't throttled
+        let api_key = shortener
+            .api_key_manager()
+            .create(None, Some(0), None)
+            .await
+            .map_err(|err| format!("unable to create an API key: {}", err))?;
+        keys.push(api_key.key);
+    }
+
+    let mut campaigns = vec![Vec::new(); keys.len()];
+    for (index, key) in keys.iter().enumerate() {
+        let first = rng.below(WORDS.len() as u64) as usize;
+        for n in 0..rng.below(MAX_CAMPAIGNS_PER_KEY + 1) as usize {
+            let name = format!(
+                "{}-{}-{}",
+                WORDS[(first + n) % WORDS.len()],
+                options.seed,
+                index
+            );
+            shortener
+                .create_campaign(key, &name, None)
+                .await
+                .map_err(|err| format!("unable to create campaign {}: {}", name, err))?;
+            campaigns[index].push(name);
+        }
+    }
+
+    let links = plan_links(&mut rng, config, options, &campaigns)?;
+    let api_keys = &keys;
+    stream::iter(links.iter().map(Ok))
+        .try_for_each_concurrent(options.concurrency, |link| async move {
+            let api_key = link.key.map(|key| api_keys[key].as_str());
+            shortener
+                .shorten_with_id(&api_key, None, &link.id, &link.url, &link.options)
+                .await
+                .map(|_| ())
+                .map_err(|err| format!("unable to shorten {}: {}", link.id, err))
+        })
+        .await?;
+
+    let clicks = plan_clicks(&mut rng, links.len(), options.clicks);
+    stream::iter(links.iter().zip(clicks).filter(|(_, clicks)| *clicks > 0))
+        .for_each_concurrent(options.concurrency, |(link, clicks)| async move {
+            for _ in 0..clicks {
+                shortener.lookup(&link.id).await;
+            }
+        })
+        .await;
+
+    Ok(Summary {
+        campaigns: campaigns.iter().map(Vec::len).sum(),
+        keys,
+        links: links.len(),
+        clicks: options.clicks,
+    })
+}
+
+/// Plans the links: a tenth has no API key, and the others are spread evenly over the keys, most
+/// in a campaign of their key. About half of the links never expire, a quarter expire within
+/// three months and a tenth within a day, while the rest are permanent.
+fn plan_links(
+    rng: &mut SplitMix64,
+    config: &Config,
+    options: &SeedOptions,
+    campaigns: &[Vec<String>],
+) -> Result<Vec<PlannedLink>, String> {
+    let alphabet = config
+        .id_alphabet
+        .chars()
+        .iter()
+        .copied()
+        .filter(char::is_ascii_alphanumeric)
+        .collect::<Vec<_>>();
+    let id_length = config.id_length.get();
+    let keyspace = (alphabet.len() as f64).powi(id_length as i32);
+    if alphabet.is_empty() || options.links as f64 > keyspace / 2.0 {
+        return Err(format!(
+            "{} links don't fit IDs of {} characters: seed fewer links, or use longer IDs",
+            options.links, id_length
+        ));
+    }
+
+    let mut ids = HashSet::with_capacity(options.links);
+    let mut links = Vec::with_capacity(options.links);
+    while links.len() < options.links {
+        let id = (0..id_length)
+            .map(|_| alphabet[rng.below(alphabet.len() as u64) as usize])
+            .collect::<String>();
+        if !ids.insert(id.clone()) {
+            continue;
+        }
+
+        let key = if campaigns.is_empty() || rng.unit() < 0.1 {
+            None
+        } else {
+            Some(rng.below(campaigns.len() as u64) as usize)
+        };
+        let campaign = key
+            .map(|key| &campaigns[key])
+            .filter(|campaigns| !campaigns.is_empty() && rng.unit() < 0.7)
+            .map(|campaigns| campaigns[rng.below(campaigns.len() as u64) as usize].clone());
+
+        let ttl = rng.unit();
+        let (expires_in, permanent) = if ttl < 0.1 {
+            (Some(3600 + rng.below(23 * 3600) as usize), false)
+        } else if ttl < 0.35 {
+            (Some(86400 + rng.below(89 * 86400) as usize), false)
+        } else if ttl < 0.4 {
+            (None, true)
+        } else {
+            (None, false)
+        };
+
+        links.push(PlannedLink {
+            id,
+            key,
+            url: plan_url(rng),
+            options: LinkOptions {
+                expires_in,
+                permanent,
+                campaign,
+                ..LinkOptions::default()
+            },
+        });
+    }
+
+    Ok(links)
+}
+
+/// Plans a URL of one to three path segments, a third of them with a `utm_source`
+fn plan_url(rng: &mut SplitMix64) -> String {
+    let mut url = format!(
+        "https://{}",
+        DOMAINS[rng.below(DOMAINS.len() as u64) as usize]
+    );
+    for _ in 0..=rng.below(3) {
+        url.push('/');
+        url.push_str(WORDS[rng.below(WORDS.len() as u64) as usize]);
+    }
+    if rng.below(3) == 0 {
+        url.push_str("?utm_source=");
+        url.push_str(SOURCES[rng.below(SOURCES.len() as u64) as usize]);
+    }
+    url
+}
+
+/// Spreads `clicks` over `links` links with a Zipf distribution, the first links getting the most
+fn plan_clicks(rng: &mut SplitMix64, links: usize, clicks: usize) -> Vec<usize> {
+    let mut counts = vec![0; links];
+    if links == 0 {
+        return counts;
+    }
+
+    let mut cumulative = Vec::with_capacity(links);
+    let mut total = 0.0;
+    for rank in 1..=links {
+        total += 1.0 / (rank as f64).powf(ZIPF_EXPONENT);
+        cumulative.push(total);
+    }
+    for _ in 0..clicks {
+        let target = rng.unit() * total;
+        let link = cumulative
+            .partition_point(|weight| *weight < target)
+            .min(links - 1);
+        counts[link] += 1;
+    }
+    counts
+}
+
+/// The SplitMix64 generator: fast, and giving the same numbers for the same seed on every
+/// platform and version, unlike the generators of `rand`
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number from 0 to `bound`, excluded
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    /// A number from 0 to 1, excluded
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
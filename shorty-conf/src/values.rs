@@ -0,0 +1,173 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! values holds the typed values of the configuration, validated when read from the environment:
+//! durations, byte sizes, ID lengths and ID alphabets.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Parses a duration, such as `600`, `250ms`, `10m` or `1d`. Numbers without a unit are taken in
+/// `unit`, the unit each variable has always been documented with, so that existing values keep
+/// their meaning.
+pub fn parse_duration(value: &str, unit: Duration) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, suffix) = value.split_at(split);
+    let amount = amount
+        .parse::<u32>()
+        .map_err(|_| String::from("expected a number, optionally followed by ms, s, m, h or d"))?;
+
+    let unit = match suffix.trim() {
+        "" => unit,
+        "ms" => Duration::from_millis(1),
+        "s" => Duration::from_secs(1),
+        "m" => Duration::from_secs(60),
+        "h" => Duration::from_secs(60 * 60),
+        "d" => Duration::from_secs(24 * 60 * 60),
+        suffix => {
+            return Err(format!(
+                "unknown unit '{}', expected ms, s, m, h or d",
+                suffix
+            ))
+        }
+    };
+    Ok(unit * amount)
+}
+
+/// A number of bytes, such as `1024`, `16KiB` or `1MB`: `KiB`, `MiB` and `GiB` are powers of 1024,
+/// `KB`, `MB` and `GB` powers of 1000
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(u64);
+
+impl ByteSize {
+    pub fn bytes(self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (amount, suffix) = s.split_at(split);
+        let amount = amount
+            .parse::<u64>()
+            .map_err(|_| String::from("expected a number of bytes, such as 1024 or 16KiB"))?;
+
+        let multiplier: u64 = match suffix.trim().to_lowercase().as_str() {
+            "" | "b" => 1,
+            "kb" => 1000,
+            "mb" => 1000 * 1000,
+            "gb" => 1000 * 1000 * 1000,
+            "kib" => 1 << 10,
+            "mib" => 1 << 20,
+            "gib" => 1 << 30,
+            _ => {
+                return Err(format!(
+                    "unknown unit '{}', expected B, KB, MB, GB, KiB, MiB or GiB",
+                    suffix.trim()
+                ))
+            }
+        };
+        amount
+            .checked_mul(multiplier)
+            .map(ByteSize)
+            .ok_or_else(|| String::from("too large"))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}B", self.0)
+    }
+}
+
+/// The length of generated IDs, at least one character
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NonZeroIdLength(usize);
+
+impl NonZeroIdLength {
+    pub fn get(self) -> usize {
+        self.0
+    }
+}
+
+impl FromStr for NonZeroIdLength {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().parse::<usize>() {
+            Ok(0) => Err(String::from("must be greater than zero")),
+            Ok(length) => Ok(NonZeroIdLength(length)),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
+
+/// The characters of generated IDs: at least two, all different, and safe in a URL path without
+/// escaping (letters, digits, `-`, `_`, `.` and `~`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alphabet(Vec<char>);
+
+impl Alphabet {
+    pub fn chars(&self) -> &[char] {
+        &self.0
+    }
+
+    pub fn to_vec(&self) -> Vec<char> {
+        self.0.clone()
+    }
+}
+
+/// The default alphabet: lowercase and uppercase letters, and digits
+impl Default for Alphabet {
+    fn default() -> Self {
+        Alphabet(
+            ('a'..='z')
+                .chain('A'..='Z')
+                .chain('0'..='9')
+                .collect::<Vec<char>>(),
+        )
+    }
+}
+
+impl FromStr for Alphabet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let chars = s.trim().chars().collect::<Vec<char>>();
+        if chars.len() < 2 {
+            return Err(String::from("must have at least two characters"));
+        }
+        if let Some(c) = chars
+            .iter()
+            .find(|c| !(c.is_ascii_alphanumeric() || "-_.~".contains(**c)))
+        {
+            return Err(format!("'{}' is not safe in a URL", c));
+        }
+        if let Some((i, c)) = chars
+            .iter()
+            .enumerate()
+            .find(|(i, c)| chars[..*i].contains(c))
+        {
+            return Err(format!("'{}' is repeated at position {}", c, i + 1));
+        }
+        Ok(Alphabet(chars))
+    }
+}
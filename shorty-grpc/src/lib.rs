@@ -0,0 +1,271 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! shorty-grpc serves the `Shortener` of shorty over gRPC, for microservices preferring protobuf
+//! over the JSON of shorty-http: the `shorty.v1.Shortener` service of `proto/shorty.proto` has the
+//! `Shorten`, `Lookup`, `Delete` and `Stats` RPCs, behaving as their shorty-http counterparts.
+//!
+//! Calls authenticate with the API key in the `x-api-key` metadata. Errors are answered with the
+//! gRPC code matching their HTTP status, see `status`, and rate limited calls carry the seconds to
+//! wait in the `retry-after` metadata. Deadlines set by clients with `grpc-timeout` are honored,
+//! as is `SHORTENER_REQUEST_TIMEOUT`.
+
+use std::error::Error;
+use std::process;
+use std::sync::Arc;
+
+use tokio::sync::oneshot;
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::transport::Server;
+use tonic::{Code, Request, Response, Status};
+
+use shorty::link_metadata::Creator;
+use shorty::{LinkOptions, Shortener, ShortenerError};
+use shorty_conf::Config;
+
+use crate::proto::shortener_server::ShortenerServer;
+use crate::proto::{
+    DeleteReply, DeleteRequest, Link, LookupReply, LookupRequest, ShortenRequest, StatsReply,
+    StatsRequest,
+};
+
+/// The messages and the service generated from `proto/shorty.proto`
+pub mod proto {
+    tonic::include_proto!("shorty.v1");
+}
+
+/// The metadata holding the API key of a call
+pub const API_KEY_METADATA: &str = "x-api-key";
+
+/// `ShortenerService` implements the `shorty.v1.Shortener` service with a `Shortener`
+pub struct ShortenerService {
+    shortener: Arc<Shortener>,
+    api_key_mandatory: bool,
+}
+
+impl ShortenerService {
+    pub fn new(shortener: Arc<Shortener>, api_key_mandatory: bool) -> ShortenerService {
+        ShortenerService {
+            shortener,
+            api_key_mandatory,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl proto::shortener_server::Shortener for ShortenerService {
+    async fn shorten(&self, request: Request<ShortenRequest>) -> Result<Response<Link>, Status> {
+        let api_key = api_key(request.metadata());
+        if api_key.is_none() && self.api_key_mandatory {
+            return Err(Status::unauthenticated("Missing API key"));
+        }
+        let creator = Creator {
+            ip: request.remote_addr().map(|addr| addr.ip().to_string()),
+            user_agent: request
+                .metadata()
+                .get("user-agent")
+                .and_then(|user_agent| user_agent.to_str().ok())
+                .map(String::from),
+        };
+        let request = request.into_inner();
+
+        let expires_in = shorty::expires_in(
+            request.expires_in.map(|expires_in| expires_in as usize),
+            request.expires_at,
+        )
+        .map_err(status)?;
+        let options = LinkOptions {
+            expires_in,
+            permanent: request.permanent,
+            campaign: request.campaign,
+            redirect_status: request.redirect_status.map(|status| status as u16),
+            preconnect: request.preconnect,
+            creator,
+            ..LinkOptions::default()
+        };
+
+        let api_key = api_key.as_deref();
+        let result = match &request.custom_id {
+            Some(custom_id) => {
+                self.shortener
+                    .shorten_with_id(&api_key, None, custom_id, &request.url, &options)
+                    .await
+            }
+            None => {
+                self.shortener
+                    .shorten(&api_key, None, &request.url, &options)
+                    .await
+            }
+        }
+        .map_err(status)?;
+
+        Ok(Response::new(Link {
+            id: result.id().to_owned(),
+            url: result.url().to_owned(),
+            short_url: result.short_url().map(str::to_owned),
+            expires_at: result.expires_at(),
+            quarantined: result.quarantined(),
+        }))
+    }
+
+    async fn lookup(
+        &self,
+        request: Request<LookupRequest>,
+    ) -> Result<Response<LookupReply>, Status> {
+        match self.shortener.redirect(&request.get_ref().id).await {
+            Some(redirect) => Ok(Response::new(LookupReply { url: redirect.url })),
+            None => Err(Status::not_found("Link not found")),
+        }
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteReply>, Status> {
+        let api_key = match api_key(request.metadata()) {
+            Some(api_key) => api_key,
+            None => return Err(Status::unauthenticated("Missing API key")),
+        };
+
+        self.shortener
+            .delete(&api_key, &request.get_ref().id)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(DeleteReply {}))
+    }
+
+    async fn stats(&self, request: Request<StatsRequest>) -> Result<Response<StatsReply>, Status> {
+        match self.shortener.stats(&request.get_ref().id).await {
+            Some(stats) => Ok(Response::new(StatsReply {
+                id: stats.id,
+                url: stats.url,
+                hits: stats.hits,
+                created_at: stats.created_at,
+                permanent: stats.permanent,
+                click_sampling: stats.click_sampling,
+            })),
+            None => Err(Status::not_found("Link not found")),
+        }
+    }
+}
+
+/// Reads the API key of a call from its `x-api-key` metadata
+fn api_key(metadata: &MetadataMap) -> Option<String> {
+    metadata
+        .get(API_KEY_METADATA)
+        .and_then(|api_key| api_key.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// Answers a `ShortenerError` with the gRPC code of its HTTP status, see
+/// `ShortenerError::status_code`, telling rate limited clients when to retry
+pub fn status(err: ShortenerError) -> Status {
+    let code = match &err {
+        ShortenerError::InvalidApiKey => Code::Unauthenticated,
+        _ => match err.status_code() {
+            400 => Code::InvalidArgument,
+            403 => Code::PermissionDenied,
+            404 => Code::NotFound,
+            409 => Code::AlreadyExists,
+            429 => Code::ResourceExhausted,
+            503 => Code::Unavailable,
+            504 => Code::DeadlineExceeded,
+            _ => Code::Internal,
+        },
+    };
+
+    let mut metadata = MetadataMap::new();
+    if let Some(retry_after) = err.retry_after() {
+        metadata.insert("retry-after", MetadataValue::from(retry_after));
+    }
+    Status::with_metadata(code, err.to_string(), metadata)
+}
+
+/// Runs the gRPC server on `SHORTENER_HOST` and `SHORTENER_GRPC_PORT` until interrupted, then
+/// records the clicks still queued and closes the storage. As in shorty-http, the clicks of
+/// `Lookup` are counted off the call path unless `SHORTENER_CLICK_QUEUE_CAPACITY` is 0, see
+/// `shorty::click_queue`.
+pub async fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    shorty_bootstrap::init_logging(&config);
+
+    let address = tokio::net::lookup_host(format!("{}:{}", config.host, config.grpc_port))
+        .await?
+        .next()
+        .ok_or("SHORTENER_HOST has no address")?;
+
+    let storage = shorty_bootstrap::storage(&config).await;
+    let mut shortener = shorty_bootstrap::shortener(&config, storage);
+    if config.click_queue_capacity > 0 {
+        shortener = shortener.with_click_queue(config.click_queue_capacity);
+    }
+    if !shortener.check_eviction_policy().await && config.refuse_unsafe_eviction_policy {
+        log::error!("refusing to start: Redis eviction policy may delete shortened URLs");
+        process::exit(1);
+    }
+    let shortener = Arc::new(shortener);
+
+    let (stop_clicks, clicks_stopped) = oneshot::channel::<()>();
+    let clicks = tokio::spawn({
+        let shortener = shortener.clone();
+        async move {
+            shortener
+                .record_queued_clicks(async {
+                    clicks_stopped.await.ok();
+                })
+                .await
+        }
+    });
+
+    let (stop_events, events_stopped) = oneshot::channel::<()>();
+    let events = tokio::spawn({
+        let shortener = shortener.clone();
+        let interval = config.events_dispatch_interval;
+        async move {
+            shortener
+                .dispatch_events(interval, async {
+                    events_stopped.await.ok();
+                })
+                .await
+        }
+    });
+
+    let service = ShortenerService::new(shortener.clone(), config.api_key_mandatory);
+    let mut server = Server::builder();
+    if let Some(request_timeout) = config.request_timeout {
+        server = server.timeout(request_timeout);
+    }
+
+    log::info!(
+        "Running gRPC on {} with profile {:?} and {:?} storage",
+        address,
+        config.profile,
+        config.storage
+    );
+    server
+        .add_service(ShortenerServer::new(service))
+        .serve_with_shutdown(address, async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await?;
+
+    stop_clicks.send(()).ok();
+    if let Err(err) = clicks.await {
+        log::error!("unable to record the queued clicks: {}", err);
+    }
+    stop_events.send(()).ok();
+    if let Err(err) = events.await {
+        log::error!("unable to dispatch the events of the outbox: {}", err);
+    }
+    shortener.close().await;
+    Ok(())
+}
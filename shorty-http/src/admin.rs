@@ -0,0 +1,735 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! admin holds the routes managing API keys, see `shorty::api_key_manager`, the roll-up stats of
+//! `shorty::global_stats`, the snapshot of `shorty::snapshot`, the review of `shorty::quarantine`,
+//! the takedowns of `shorty::takedown`, the blocklist of `shorty::domain_blocklist`, the sync of
+//! `shorty::link_sync` and the bulk transfers of `shorty::link_transfer`. They are protected by
+//! the admin key of the configuration, sent in the `X-Admin-Key` header, and disabled without one.
+
+use std::future::Future;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures_util::stream::{self, Stream};
+
+use shorty::api_key_manager::Feature;
+use shorty::crypto;
+use shorty::link_index::MAX_LINKS;
+use shorty::link_sync::MAX_CHANGES;
+use shorty::link_transfer::{ImportSummary, TransferFormat};
+use shorty::ShortenerError;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{error_response, AppState, ErrorResponse};
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateKeyRequest {
+    expires_in: Option<usize>,
+    rate_limit: Option<i64>,
+    features: Option<Vec<Feature>>,
+}
+
+/// The body of `PUT /admin/keys/{key}/features`: the features of the key, `null` for all of them
+#[derive(Deserialize, ToSchema)]
+pub struct FeaturesRequest {
+    features: Option<Vec<Feature>>,
+}
+
+/// The body of `PUT /admin/keys/{key}/verified`
+#[derive(Deserialize, ToSchema)]
+pub struct VerifiedRequest {
+    verified: bool,
+}
+
+/// The query of the routes listing at most `limit` items
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LimitQuery {
+    limit: Option<usize>,
+}
+
+/// The query of `GET /admin/snapshot`: the cursor to resume the export from, if any
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SnapshotQuery {
+    cursor: Option<String>,
+}
+
+/// The query of `GET /admin/export` and `POST /admin/import`: the format of the links, `json` if
+/// missing
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TransferQuery {
+    format: Option<String>,
+}
+
+/// The max size of the body of `POST /admin/import`
+pub const IMPORT_BODY_LIMIT: usize = 256 * 1024 * 1024;
+
+/// What a streamed export sends next: a chunk exported already, with the cursor of the page after
+/// it, the page at a cursor, or nothing
+enum NextPage {
+    Exported(String, Option<String>),
+    At(String),
+    Done,
+}
+
+/// Streams an export a page at a time, from the `first` chunk, exported before answering so that
+/// a failing export gets an error status, and the cursor of the page after it. `export` exports
+/// the page at a cursor the same way.
+///
+/// The next page is exported once the previous one is sent, so that a slow client slows the
+/// export down instead of piling pages up in memory.
+fn stream_pages<F, Fut>(
+    first: (String, Option<String>),
+    export: F,
+) -> impl Stream<Item = Result<web::Bytes, ShortenerError>>
+where
+    F: Fn(String) -> Fut + Clone,
+    Fut: Future<Output = Result<(String, Option<String>), ShortenerError>>,
+{
+    let (chunk, cursor) = first;
+    stream::unfold(NextPage::Exported(chunk, cursor), move |next| {
+        let export = export.clone();
+        async move {
+            let (chunk, cursor) = match next {
+                NextPage::Exported(chunk, cursor) => (chunk, cursor),
+                NextPage::At(cursor) => match export(cursor.clone()).await {
+                    Ok(page) => page,
+                    Err(err) => {
+                        log::error!("export failed at cursor {}: {}", cursor, err);
+                        return Some((Err(err), NextPage::Done));
+                    }
+                },
+                NextPage::Done => return None,
+            };
+            let next = match cursor {
+                Some(cursor) => NextPage::At(cursor),
+                None => NextPage::Done,
+            };
+            Some((Ok(web::Bytes::from(chunk)), next))
+        }
+    })
+}
+
+/// The query of `GET /internal/sync`: the cursor to sync from, `0` if missing, and the most
+/// changes to return
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SyncQuery {
+    since: Option<u64>,
+    limit: Option<usize>,
+}
+
+/// Returns the 403 response rejecting the request, unless it carries the configured admin key
+pub(crate) fn reject_unauthorized(req: &HttpRequest, app_state: &AppState) -> Option<HttpResponse> {
+    let admin_key = match &app_state.admin_key {
+        Some(admin_key) => admin_key,
+        None => {
+            return Some(HttpResponse::Forbidden().json(ErrorResponse {
+                err: String::from("Admin API disabled"),
+            }))
+        }
+    };
+
+    let provided = req
+        .headers()
+        .get("X-Admin-Key")
+        .map(|provided| provided.as_bytes())
+        .unwrap_or_default();
+
+    if crypto::constant_time_eq(provided, admin_key.as_bytes()) {
+        None
+    } else {
+        Some(HttpResponse::Forbidden().json(ErrorResponse {
+            err: String::from("Invalid admin key"),
+        }))
+    }
+}
+
+/// Creates an API key
+#[utoipa::path(
+    post,
+    path = "/admin/keys",
+    tag = "admin",
+    security(("admin_key" = [])),
+    request_body = CreateKeyRequest,
+    responses(
+        (status = 200, body = shorty::api_key_manager::ApiKey),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+    )
+)]
+pub async fn create_key(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    payload: web::Json<CreateKeyRequest>,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    match app_state
+        .shortener
+        .api_key_manager()
+        .create(
+            payload.expires_in,
+            payload.rate_limit,
+            payload.features.clone(),
+        )
+        .await
+    {
+        Ok(api_key) => HttpResponse::Ok().json(api_key),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Lists the API keys created with the admin API
+#[utoipa::path(
+    get,
+    path = "/admin/keys",
+    tag = "admin",
+    security(("admin_key" = [])),
+    responses(
+        (status = 200, body = Vec<String>),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+    )
+)]
+pub async fn list_keys(req: HttpRequest, app_state: web::Data<AppState>) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    match app_state.shortener.api_key_manager().list().await {
+        Ok(keys) => HttpResponse::Ok().json(keys),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Revokes an API key
+#[utoipa::path(
+    delete,
+    path = "/admin/keys/{key}",
+    tag = "admin",
+    security(("admin_key" = [])),
+    params(("key" = String, Path, description = "The API key")),
+    responses(
+        (status = 204, description = "The key was revoked"),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+    )
+)]
+pub async fn revoke_key(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    key: web::Path<String>,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    match app_state.shortener.api_key_manager().revoke(&key).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Restricts an API key to some features
+#[utoipa::path(
+    put,
+    path = "/admin/keys/{key}/features",
+    tag = "admin",
+    security(("admin_key" = [])),
+    params(("key" = String, Path, description = "The API key")),
+    request_body = FeaturesRequest,
+    responses(
+        (status = 204, description = "The features were set"),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+    )
+)]
+pub async fn set_features(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    key: web::Path<String>,
+    payload: web::Json<FeaturesRequest>,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    match app_state
+        .shortener
+        .api_key_manager()
+        .set_features(&key, payload.features.as_deref())
+        .await
+    {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Marks an API key as verified, or not, see `shorty::api_key_manager::ApiKeyManager::set_verified`
+#[utoipa::path(
+    put,
+    path = "/admin/keys/{key}/verified",
+    tag = "admin",
+    security(("admin_key" = [])),
+    params(("key" = String, Path, description = "The API key")),
+    request_body = VerifiedRequest,
+    responses(
+        (status = 204, description = "The key was marked"),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+        (status = 404, body = ErrorResponse, description = "The API key doesn't exist"),
+    )
+)]
+pub async fn set_verified(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    key: web::Path<String>,
+    payload: web::Json<VerifiedRequest>,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    match app_state
+        .shortener
+        .api_key_manager()
+        .set_verified(&key, payload.verified)
+        .await
+    {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Returns the roll-up statistics of all the links
+#[utoipa::path(
+    get,
+    path = "/admin/stats",
+    tag = "admin",
+    security(("admin_key" = [])),
+    responses(
+        (status = 200, body = shorty::global_stats::GlobalStats),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+    )
+)]
+pub async fn stats(req: HttpRequest, app_state: web::Data<AppState>) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    match app_state.shortener.global_stats().await {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Streams the snapshot of `shorty::snapshot`, as JSON lines, a page at a time, see
+/// `stream_pages`. A `{"cursor"}` line follows each page but the last: an export cut short resumes
+/// from the last cursor received.
+#[utoipa::path(
+    get,
+    path = "/admin/snapshot",
+    tag = "admin",
+    security(("admin_key" = [])),
+    params(SnapshotQuery),
+    responses(
+        (status = 200, body = String, content_type = "application/x-ndjson"),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+    )
+)]
+pub async fn snapshot(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    query: web::Query<SnapshotQuery>,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    let cursor = query.cursor.as_deref().unwrap_or_default();
+    let first = match app_state.shortener.export_snapshot_page(cursor).await {
+        Ok(page) => (page.chunk(), page.cursor),
+        Err(err) => return error_response(err),
+    };
+
+    let pages = stream_pages(first, move |cursor| {
+        let app_state = app_state.clone();
+        async move {
+            let page = app_state.shortener.export_snapshot_page(&cursor).await?;
+            Ok((page.chunk(), page.cursor))
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(pages)
+}
+
+/// Streams the ID and URL of every link, as CSV or JSON lines, a page at a time, see
+/// `stream_pages` and `shorty::link_transfer`
+#[utoipa::path(
+    get,
+    path = "/admin/export",
+    tag = "admin",
+    security(("admin_key" = [])),
+    params(TransferQuery),
+    responses(
+        (status = 200, body = String, content_type = "text/csv"),
+        (status = 200, body = String, content_type = "application/x-ndjson"),
+        (status = 400, body = ErrorResponse, description = "The format is neither csv nor json"),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+    )
+)]
+pub async fn export(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    query: web::Query<TransferQuery>,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    let format = match transfer_format(&query) {
+        Ok(format) => format,
+        Err(err) => return error_response(err),
+    };
+    let first = match app_state.shortener.export_page("", format).await {
+        Ok(page) => (page.data, page.cursor),
+        Err(err) => return error_response(err),
+    };
+
+    let pages = stream_pages(first, move |cursor| {
+        let app_state = app_state.clone();
+        async move {
+            let page = app_state.shortener.export_page(&cursor, format).await?;
+            Ok((page.data, page.cursor))
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .streaming(pages)
+}
+
+/// Creates links from the IDs and URLs of the body, as written by `GET /admin/export`, see
+/// `Shortener::import`
+#[utoipa::path(
+    post,
+    path = "/admin/import",
+    tag = "admin",
+    security(("admin_key" = [])),
+    params(TransferQuery),
+    request_body(content = String, content_type = "text/csv"),
+    responses(
+        (status = 200, body = ImportSummary),
+        (status = 400, body = ErrorResponse, description = "The format is neither csv nor json"),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+    )
+)]
+pub async fn import(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    query: web::Query<TransferQuery>,
+    body: web::Bytes,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    let format = match transfer_format(&query) {
+        Ok(format) => format,
+        Err(err) => return error_response(err),
+    };
+    match app_state.shortener.import(body.as_ref(), format).await {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(err) => error_response(err),
+    }
+}
+
+/// The format of a transfer, JSON lines by default
+fn transfer_format(query: &TransferQuery) -> Result<TransferFormat, ShortenerError> {
+    query.format.as_deref().unwrap_or("json").parse()
+}
+
+/// Streams the links changed since a cursor, as JSON lines, oldest first. The `X-Sync-Cursor`
+/// header holds the cursor to sync from next: the cursor of the last change, or the given one if
+/// nothing changed.
+#[utoipa::path(
+    get,
+    path = "/internal/sync",
+    tag = "admin",
+    security(("admin_key" = [])),
+    params(SyncQuery),
+    responses(
+        (status = 200, body = Vec<shorty::link_sync::LinkChange>, content_type = "application/x-ndjson"),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+    )
+)]
+pub async fn sync(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    query: web::Query<SyncQuery>,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    let since = query.since.unwrap_or(0);
+    match app_state
+        .shortener
+        .sync(since, query.limit.unwrap_or(MAX_CHANGES))
+        .await
+    {
+        Ok(changes) => {
+            let cursor = changes.last().map_or(since, |change| change.cursor);
+            let body = changes
+                .iter()
+                .map(|change| serde_json::to_string(change).unwrap() + "\n")
+                .collect::<String>();
+            HttpResponse::Ok()
+                .content_type("application/x-ndjson")
+                .insert_header(("X-Sync-Cursor", cursor.to_string()))
+                .body(body)
+        }
+        Err(err) => error_response(err),
+    }
+}
+
+/// Lists the quarantined links, newest first
+#[utoipa::path(
+    get,
+    path = "/admin/quarantine",
+    tag = "admin",
+    security(("admin_key" = [])),
+    params(LimitQuery),
+    responses(
+        (status = 200, body = Vec<shorty::quarantine::QuarantinedLink>),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+    )
+)]
+pub async fn list_quarantined(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    query: web::Query<LimitQuery>,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    match app_state
+        .shortener
+        .quarantined_links(query.limit.unwrap_or(MAX_LINKS))
+        .await
+    {
+        Ok(links) => HttpResponse::Ok().json(links),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Releases a quarantined link
+#[utoipa::path(
+    post,
+    path = "/admin/quarantine/{id}/release",
+    tag = "admin",
+    security(("admin_key" = [])),
+    params(("id" = String, Path, description = "The ID of the link")),
+    responses(
+        (status = 204, description = "The link was released"),
+        (status = 404, body = ErrorResponse, description = "The link isn't quarantined"),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+    )
+)]
+pub async fn release_quarantined(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    match app_state.shortener.release_quarantined(&id).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Rejects a quarantined link, deleting it
+#[utoipa::path(
+    delete,
+    path = "/admin/quarantine/{id}",
+    tag = "admin",
+    security(("admin_key" = [])),
+    params(("id" = String, Path, description = "The ID of the link")),
+    responses(
+        (status = 204, description = "The link was deleted"),
+        (status = 404, body = ErrorResponse, description = "The link isn't quarantined"),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+    )
+)]
+pub async fn reject_quarantined(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    match app_state.shortener.reject_quarantined(&id).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Lists the takedown requests, newest first
+#[utoipa::path(
+    get,
+    path = "/admin/takedowns",
+    tag = "admin",
+    security(("admin_key" = [])),
+    params(LimitQuery),
+    responses(
+        (status = 200, body = Vec<shorty::takedown::Takedown>),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+    )
+)]
+pub async fn list_takedowns(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    query: web::Query<LimitQuery>,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    match app_state
+        .shortener
+        .takedowns(query.limit.unwrap_or(MAX_LINKS))
+        .await
+    {
+        Ok(takedowns) => HttpResponse::Ok().json(takedowns),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Returns a takedown request, with its audit trail
+#[utoipa::path(
+    get,
+    path = "/admin/takedowns/{ticket}",
+    tag = "admin",
+    security(("admin_key" = [])),
+    params(("ticket" = String, Path, description = "The ticket of the request")),
+    responses(
+        (status = 200, body = shorty::takedown::Takedown),
+        (status = 404, body = ErrorResponse, description = "The ticket doesn't exist"),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+    )
+)]
+pub async fn get_takedown(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    ticket: web::Path<String>,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    match app_state.shortener.takedown(&ticket).await {
+        Ok(Some(takedown)) => HttpResponse::Ok().json(takedown),
+        Ok(None) => error_response(ShortenerError::NotFound("Takedown not found")),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Lists the blocked domains
+#[utoipa::path(
+    get,
+    path = "/admin/blocked-domains",
+    tag = "admin",
+    security(("admin_key" = [])),
+    responses(
+        (status = 200, body = shorty::domain_blocklist::BlockedDomains),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+    )
+)]
+pub async fn list_blocked_domains(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    match app_state.shortener.blocked_domains().await {
+        Ok(domains) => HttpResponse::Ok().json(domains),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Blocks a domain, answering `201 Created`, or `204 No Content` if it was already blocked
+#[utoipa::path(
+    put,
+    path = "/admin/blocked-domains/{domain}",
+    tag = "admin",
+    security(("admin_key" = [])),
+    params(("domain" = String, Path, description = "The domain")),
+    responses(
+        (status = 201, description = "The domain was blocked"),
+        (status = 204, description = "The domain was already blocked"),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+    )
+)]
+pub async fn block_domain(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    domain: web::Path<String>,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    match app_state.shortener.block_domain(&domain).await {
+        Ok(true) => HttpResponse::Created().finish(),
+        Ok(false) => HttpResponse::NoContent().finish(),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Unblocks a domain blocked with the admin API
+#[utoipa::path(
+    delete,
+    path = "/admin/blocked-domains/{domain}",
+    tag = "admin",
+    security(("admin_key" = [])),
+    params(("domain" = String, Path, description = "The domain")),
+    responses(
+        (status = 204, description = "The domain was unblocked"),
+        (status = 403, body = ErrorResponse, description = "The admin key is wrong, or the admin API disabled"),
+    )
+)]
+pub async fn unblock_domain(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    domain: web::Path<String>,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthorized(&req, &app_state) {
+        return response;
+    }
+
+    match app_state.shortener.unblock_domain(&domain).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => error_response(err),
+    }
+}
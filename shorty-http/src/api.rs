@@ -0,0 +1,362 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! api holds the `/api/v1` routes, shaped for no-code platforms such as Zapier and IFTTT: the API
+//! key is only read from the `X-API-Key` header, and replies are flat JSON objects with the full
+//! short URL.
+//!
+//! `GET /api/v1/links` is a polling trigger, listing the newest links first, and
+//! `POST /api/v1/links` is an action, creating a link. `GET /api/v1/domains/{domain}/links` lists
+//! the links pointing to a domain, those of the API key or, with the `X-Admin-Key` header, all of
+//! them. `GET /api/v1/links/{id}/events` is the timeline of a link, see `shorty::link_events`.
+//! `POST /api/v1/qr/batch` renders a printable sheet of the QR codes of many links, see
+//! `qr_sheet`. `POST /api/v1/takedowns` files the takedown request of a rights holder, see
+//! `shorty::takedown`. `GET` and `PUT /api/v1/defaults` read and replace the options the API key
+//! applies to its links by default, see `shorty::link_defaults`.
+
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use shorty::link_defaults::LinkDefaults;
+use shorty::link_index::MAX_LINKS;
+use shorty::takedown::TakedownRequest;
+use shorty::{LinkOptions, ShortenerError};
+use tracing::Instrument;
+
+use crate::admin::reject_unauthorized;
+use crate::qr_sheet::{self, MAX_QR_SHEET_CODES};
+use crate::{
+    api_key_header, base_url, creator, error_response, host_domain, missing_api_key_response,
+    AppState, ErrorResponse,
+};
+
+#[derive(Deserialize)]
+pub struct LinksQuery {
+    #[serde(default)]
+    since: u64,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct DomainLinksQuery {
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateLinkRequest {
+    url: String,
+    custom_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct QrSheetRequest {
+    ids: Vec<String>,
+}
+
+/// A link as seen by no-code platforms, which use `id` to tell new links apart. The creation time
+/// is listed by the trigger only.
+#[derive(Serialize)]
+struct Link {
+    id: String,
+    url: String,
+    short_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<u64>,
+}
+
+/// A page of links, read with the `next_cursor` of the previous page
+#[derive(Serialize)]
+struct LinkPage {
+    links: Vec<Link>,
+    next_cursor: Option<String>,
+}
+
+impl Link {
+    fn new(req: &HttpRequest, id: &str, url: &str, created_at: Option<u64>) -> Link {
+        Link {
+            id: id.to_owned(),
+            url: url.to_owned(),
+            short_url: format!("{}{}", base_url(req), id),
+            created_at,
+        }
+    }
+}
+
+pub async fn list_links(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    query: web::Query<LinksQuery>,
+) -> HttpResponse {
+    let api_key = match api_key_header(&req) {
+        Some(api_key) => api_key,
+        None => return missing_api_key_response(),
+    };
+
+    match app_state
+        .shortener
+        .links(&api_key, query.since, query.limit.unwrap_or(MAX_LINKS))
+        .await
+    {
+        Ok(links) => HttpResponse::Ok().json(
+            links
+                .into_iter()
+                .map(|link| Link::new(&req, &link.id, &link.url, Some(link.created_at)))
+                .collect::<Vec<_>>(),
+        ),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn list_domain_links(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    domain: web::Path<String>,
+    query: web::Query<DomainLinksQuery>,
+) -> HttpResponse {
+    let api_key = if req.headers().contains_key("X-Admin-Key") {
+        if let Some(response) = reject_unauthorized(&req, &app_state) {
+            return response;
+        }
+        None
+    } else {
+        match api_key_header(&req) {
+            Some(api_key) => Some(api_key),
+            None => return missing_api_key_response(),
+        }
+    };
+
+    match app_state
+        .shortener
+        .list_by_domain(
+            &domain,
+            api_key.as_deref(),
+            query.cursor.as_deref(),
+            query.limit.unwrap_or(MAX_LINKS),
+        )
+        .await
+    {
+        Ok(page) => HttpResponse::Ok().json(LinkPage {
+            links: page
+                .links
+                .into_iter()
+                .map(|link| Link::new(&req, &link.id, &link.url, Some(link.created_at)))
+                .collect(),
+            next_cursor: page.next_cursor,
+        }),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn link_events(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let api_key = match api_key_header(&req) {
+        Some(api_key) => api_key,
+        None => return missing_api_key_response(),
+    };
+
+    match app_state.shortener.link_events(&api_key, &id).await {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn link_defaults(req: HttpRequest, app_state: web::Data<AppState>) -> HttpResponse {
+    let api_key = match api_key_header(&req) {
+        Some(api_key) => api_key,
+        None => return missing_api_key_response(),
+    };
+
+    match app_state.shortener.link_defaults(&api_key).await {
+        Ok(defaults) => HttpResponse::Ok().json(defaults),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn set_link_defaults(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    payload: web::Json<LinkDefaults>,
+) -> HttpResponse {
+    let api_key = match api_key_header(&req) {
+        Some(api_key) => api_key,
+        None => return missing_api_key_response(),
+    };
+
+    match app_state
+        .shortener
+        .set_link_defaults(&api_key, &payload)
+        .await
+    {
+        Ok(()) => HttpResponse::Ok().json(payload.into_inner()),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn create_link(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    payload: web::Json<CreateLinkRequest>,
+) -> HttpResponse {
+    let api_key = match api_key_header(&req) {
+        Some(api_key) => api_key,
+        None => return missing_api_key_response(),
+    };
+    let api_key = Some(api_key.as_str());
+    let host_domain = host_domain(&req);
+    let options = LinkOptions {
+        creator: creator(&req, &app_state.trusted_proxies),
+        ..LinkOptions::default()
+    };
+
+    let shorten_result = match &payload.custom_id {
+        Some(custom_id) => {
+            app_state
+                .shortener
+                .shorten_with_id(
+                    &api_key,
+                    Some(&host_domain),
+                    custom_id,
+                    &payload.url,
+                    &options,
+                )
+                .await
+        }
+        None => {
+            app_state
+                .shortener
+                .shorten(&api_key, Some(&host_domain), &payload.url, &options)
+                .await
+        }
+    };
+
+    match shorten_result {
+        Ok(shorten_result) => HttpResponse::Ok().json(Link::new(
+            &req,
+            shorten_result.id(),
+            shorten_result.url(),
+            None,
+        )),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Renders the QR codes of the short URLs of `ids` as a PDF sheet, in order, see `qr_sheet`. Each
+/// code counts as a call for the rate limit, and every ID must be a link that can be followed.
+pub async fn qr_sheet(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    payload: web::Json<QrSheetRequest>,
+) -> HttpResponse {
+    let api_key = match api_key_header(&req) {
+        Some(api_key) => api_key,
+        None => return missing_api_key_response(),
+    };
+    if !app_state.shortener.verify_key(&api_key).await.valid {
+        return error_response(ShortenerError::InvalidApiKey);
+    }
+    if payload.ids.is_empty() || payload.ids.len() > MAX_QR_SHEET_CODES {
+        return error_response(ShortenerError::InvalidInput(
+            "Invalid IDs: a sheet has between 1 and 500 QR codes",
+        ));
+    }
+    if let Err(err) = app_state
+        .shortener
+        .rate_limiter()
+        .check_calls(&api_key, payload.ids.len() as i64)
+        .await
+    {
+        return error_response(err);
+    }
+
+    for id in &payload.ids {
+        if app_state.shortener.destination(id).await.is_none() {
+            return HttpResponse::NotFound().json(ErrorResponse {
+                err: format!("Link not found: {}", id),
+            });
+        }
+    }
+
+    let base_url = base_url(&req);
+    let short_urls = payload
+        .ids
+        .iter()
+        .map(|id| format!("{}{}", base_url, id))
+        .collect::<Vec<_>>();
+    match web::block(move || qr_sheet::render(&short_urls)).await {
+        Ok(Ok(pdf)) => HttpResponse::Ok()
+            .content_type("application/pdf")
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"qr-codes.pdf\"",
+            ))
+            .body(pdf),
+        Ok(Err(err)) => {
+            log::error!("unable to render QR code sheet: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+        Err(err) => {
+            log::error!("unable to render QR code sheet: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Files a takedown request, see `shorty::takedown`. Rights holders need no API key: the links
+/// are quarantined until an operator reviews them. If `SHORTENER_SMTP_URL` is set, the rights
+/// holder is sent an acknowledgement once the request is answered.
+pub async fn create_takedown(
+    app_state: web::Data<AppState>,
+    payload: web::Json<TakedownRequest>,
+) -> HttpResponse {
+    let takedown = match app_state
+        .shortener
+        .submit_takedown(payload.into_inner())
+        .await
+    {
+        Ok(takedown) => takedown,
+        Err(err) => return error_response(err),
+    };
+
+    if app_state.mailer.is_some() {
+        let app_state = app_state.clone();
+        let takedown = takedown.clone();
+        tokio::spawn(
+            async move {
+                let mailer = app_state.mailer.as_ref().unwrap();
+                let sent = match mailer.acknowledge_takedown(&takedown).await {
+                    Ok(_) => true,
+                    Err(err) => {
+                        log::warn!(
+                            "unable to acknowledge takedown {}: {}",
+                            takedown.ticket,
+                            err
+                        );
+                        false
+                    }
+                };
+                app_state
+                    .shortener
+                    .record_acknowledgement(&takedown.ticket, sent)
+                    .await;
+            }
+            .in_current_span(),
+        );
+    }
+
+    HttpResponse::Ok().json(takedown)
+}
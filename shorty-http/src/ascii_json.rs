@@ -0,0 +1,50 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! ascii_json rewrites the JSON responses of shorty-http for legacy parsers when
+//! `SHORTENER_ASCII_JSON` is set: see `shorty::ascii_json`.
+
+use actix_web::body::{self, BoxBody, MessageBody};
+use actix_web::dev::ServiceResponse;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header;
+
+/// Rewrites the body of a JSON response with `shorty::ascii_json::ascii_json`, leaving other
+/// responses alone. It must run before `Compress` handles the response.
+pub async fn rewrite<B>(
+    res: ServiceResponse<B>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error>
+where
+    B: MessageBody + 'static,
+{
+    let json = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    if !json {
+        return Ok(res.map_into_boxed_body());
+    }
+
+    let (req, res) = res.into_parts();
+    let (res, body) = res.into_parts();
+    let body = body::to_bytes(body)
+        .await
+        .map_err(|err| ErrorInternalServerError(err.into()))?;
+    let res = match shorty::ascii_json::ascii_json(&body) {
+        Some(json) => res.set_body(BoxBody::new(json)),
+        None => res.set_body(BoxBody::new(body)),
+    };
+
+    Ok(ServiceResponse::new(req, res))
+}
@@ -0,0 +1,108 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! badge holds the rendering of link badges: small SVG images, in the style of the badges of
+//! READMEs, telling whether a link is active, disabled or expired, and how many clicks it got.
+//!
+//! Badges are drawn with the fonts of the viewer: text widths are estimated from the widths of
+//! Verdana, the first font asked for, and the text is stretched to them with `textLength`.
+
+use shorty::link_health::{LinkHealth, LinkStatus};
+
+const LABEL: &str = "link";
+const HEIGHT: u32 = 20;
+/// The space left and right of each text
+const PADDING: u32 = 6;
+
+const LABEL_COLOR: &str = "#555";
+const ACTIVE_COLOR: &str = "#4c1";
+const DISABLED_COLOR: &str = "#dfb317";
+const EXPIRED_COLOR: &str = "#9f9f9f";
+
+/// Renders the badge of a link
+pub fn render(health: &LinkHealth) -> String {
+    let (status, color) = match health.status {
+        LinkStatus::Active => ("active", ACTIVE_COLOR),
+        LinkStatus::Disabled => ("disabled", DISABLED_COLOR),
+        LinkStatus::Expired => ("expired", EXPIRED_COLOR),
+    };
+    let message = match health.clicks {
+        Some(1) => format!("{} · 1 click", status),
+        Some(clicks) => format!("{} · {} clicks", status, format_count(clicks)),
+        None => String::from(status),
+    };
+
+    let label_text = text_width(LABEL);
+    let message_text = text_width(&message);
+    let label_width = label_text + 2 * PADDING;
+    let message_width = message_text + 2 * PADDING;
+    let width = label_width + message_width;
+
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" role="img" aria-label="{label}: {message}">
+<title>{label}: {message}</title>
+<linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient>
+<clipPath id="r"><rect width="{width}" height="{height}" rx="3" fill="#fff"/></clipPath>
+<g clip-path="url(#r)"><rect width="{label_width}" height="{height}" fill="{label_color}"/><rect x="{label_width}" width="{message_width}" height="{height}" fill="{color}"/><rect width="{width}" height="{height}" fill="url(#s)"/></g>
+<g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11">
+<text x="{label_x}" y="15" fill="#010101" fill-opacity=".3" textLength="{label_text}">{label}</text><text x="{label_x}" y="14" textLength="{label_text}">{label}</text>
+<text x="{message_x}" y="15" fill="#010101" fill-opacity=".3" textLength="{message_text}">{message}</text><text x="{message_x}" y="14" textLength="{message_text}">{message}</text>
+</g>
+</svg>
+"##,
+        width = width,
+        height = HEIGHT,
+        label = LABEL,
+        message = message,
+        label_width = label_width,
+        message_width = message_width,
+        label_color = LABEL_COLOR,
+        color = color,
+        label_text = label_text,
+        message_text = message_text,
+        label_x = label_width / 2,
+        message_x = label_width + message_width / 2,
+    )
+}
+
+/// Formats a count the way badges do, such as `999`, `1.2k` or `3.4M`
+fn format_count(count: i64) -> String {
+    let count = count.max(0) as f64;
+    for (unit, suffix) in &[(1e9, "G"), (1e6, "M"), (1e3, "k")] {
+        if count >= *unit {
+            let scaled = count / unit;
+            return if scaled < 10.0 {
+                format!("{:.1}{}", (scaled * 10.0).floor() / 10.0, suffix)
+            } else {
+                format!("{}{}", scaled.floor(), suffix)
+            };
+        }
+    }
+    count.to_string()
+}
+
+/// Estimates the width of `text` in Verdana 11px, in pixels
+fn text_width(text: &str) -> u32 {
+    let width = text
+        .chars()
+        .map(|c| match c {
+            'i' | 'j' | 'l' | '.' | ',' | ':' | ';' | '\'' | '!' | '|' => 3.5,
+            ' ' | '·' | 'f' | 'r' | 't' | 'I' => 4.5,
+            'm' | 'w' | 'M' | 'W' => 10.5,
+            'A'..='Z' | '0'..='9' => 7.5,
+            _ => 6.8,
+        })
+        .sum::<f64>();
+    width.ceil() as u32
+}
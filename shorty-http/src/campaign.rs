@@ -0,0 +1,150 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! campaign holds the routes managing campaigns, see `shorty::campaign`
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use shorty::ShortenerError;
+
+use crate::{api_key_header, error_response, missing_api_key_response, ApiKeyRequest, AppState};
+
+#[derive(Deserialize)]
+pub struct CreateCampaignRequest {
+    api_key: String,
+    name: String,
+    parent: Option<String>,
+}
+
+/// The body of `POST /campaigns/{name}/expire`: when the links of the campaign expire, in seconds
+/// (`expires_in`) or as a Unix timestamp (`expires_at`)
+#[derive(Deserialize)]
+pub struct ExpireCampaignRequest {
+    api_key: String,
+    expires_in: Option<usize>,
+    expires_at: Option<u64>,
+}
+
+pub async fn create(
+    app_state: web::Data<AppState>,
+    payload: web::Json<CreateCampaignRequest>,
+) -> HttpResponse {
+    match app_state
+        .shortener
+        .create_campaign(&payload.api_key, &payload.name, payload.parent.as_deref())
+        .await
+    {
+        Ok(campaign) => HttpResponse::Ok().json(campaign),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn list(req: HttpRequest, app_state: web::Data<AppState>) -> HttpResponse {
+    let api_key = match api_key_header(&req) {
+        Some(api_key) => api_key,
+        None => return missing_api_key_response(),
+    };
+
+    match app_state.shortener.campaigns(&api_key).await {
+        Ok(campaigns) => HttpResponse::Ok().json(campaigns),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn stats(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    name: web::Path<String>,
+) -> HttpResponse {
+    let api_key = match api_key_header(&req) {
+        Some(api_key) => api_key,
+        None => return missing_api_key_response(),
+    };
+
+    match app_state.shortener.campaign_stats(&api_key, &name).await {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn pause(
+    app_state: web::Data<AppState>,
+    name: web::Path<String>,
+    payload: web::Json<ApiKeyRequest>,
+) -> HttpResponse {
+    match app_state
+        .shortener
+        .pause_campaign(&payload.api_key, &name)
+        .await
+    {
+        Ok(campaign) => HttpResponse::Ok().json(campaign),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn resume(
+    app_state: web::Data<AppState>,
+    name: web::Path<String>,
+    payload: web::Json<ApiKeyRequest>,
+) -> HttpResponse {
+    match app_state
+        .shortener
+        .resume_campaign(&payload.api_key, &name)
+        .await
+    {
+        Ok(campaign) => HttpResponse::Ok().json(campaign),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Makes the links of a campaign expire together, see `shorty::Shortener::expire_campaign`
+pub async fn expire(
+    app_state: web::Data<AppState>,
+    name: web::Path<String>,
+    payload: web::Json<ExpireCampaignRequest>,
+) -> HttpResponse {
+    let expires_in = match shorty::expires_in(payload.expires_in, payload.expires_at) {
+        Ok(Some(expires_in)) => expires_in,
+        Ok(None) => {
+            return error_response(ShortenerError::InvalidInput(
+                "Invalid expiration: expires_in or expires_at is required",
+            ))
+        }
+        Err(err) => return error_response(err),
+    };
+
+    match app_state
+        .shortener
+        .expire_campaign(&payload.api_key, &name, expires_in)
+        .await
+    {
+        Ok(campaign) => HttpResponse::Ok().json(campaign),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Re-issues the links of a campaign with new IDs, see `shorty::Shortener::rotate_campaign`
+pub async fn rotate(
+    app_state: web::Data<AppState>,
+    name: web::Path<String>,
+    payload: web::Json<ApiKeyRequest>,
+) -> HttpResponse {
+    match app_state
+        .shortener
+        .rotate_campaign(&payload.api_key, &name)
+        .await
+    {
+        Ok(rotated) => HttpResponse::Ok().json(rotated),
+        Err(err) => error_response(err),
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! card holds the rendering of social cards: PNG images showing a short URL, the domain it
+//! redirects to and a QR code of the short URL, meant for newsletters and link unfurls.
+//!
+//! Text is drawn with the ASCII bitmap fonts of `embedded-graphics`, so no font has to be
+//! installed on the server: other characters are drawn as `?`.
+
+use std::convert::Infallible;
+
+use embedded_graphics::mono_font::ascii::FONT_10X20;
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::Rgb888;
+use embedded_graphics::prelude::*;
+use embedded_graphics::text::{Baseline, Text};
+use qrcode::QrCode;
+
+/// The size recommended by Open Graph and Twitter for large images
+const WIDTH: u32 = 1200;
+const HEIGHT: u32 = 630;
+const MARGIN: u32 = 60;
+const QR_CODE_SIZE: u32 = 450;
+
+const BACKGROUND: Rgb888 = Rgb888::new(0xff, 0xff, 0xff);
+const TEXT: Rgb888 = Rgb888::new(0x1f, 0x23, 0x28);
+const LABEL: Rgb888 = Rgb888::new(0x65, 0x6d, 0x76);
+const ACCENT: Rgb888 = Rgb888::new(0x09, 0x69, 0xda);
+
+/// Renders the social card of `short_url`, redirecting to `domain`, as a PNG image
+pub fn render(short_url: &str, domain: &str) -> Result<Vec<u8>, String> {
+    let mut canvas = Canvas::new(WIDTH, HEIGHT, BACKGROUND);
+
+    // the QR code takes a square on the right, the text the rest
+    draw_qr_code(
+        &mut canvas,
+        short_url,
+        WIDTH - MARGIN - QR_CODE_SIZE,
+        (HEIGHT - QR_CODE_SIZE) / 2,
+        QR_CODE_SIZE,
+    )?;
+    let text_width = WIDTH - 3 * MARGIN - QR_CODE_SIZE;
+
+    let displayed_url = short_url
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    draw_text(&mut canvas, "Short link", LABEL, 2, MARGIN, 130, text_width);
+    draw_text(&mut canvas, displayed_url, TEXT, 3, MARGIN, 190, text_width);
+    draw_text(
+        &mut canvas,
+        "Redirects to",
+        LABEL,
+        2,
+        MARGIN,
+        340,
+        text_width,
+    );
+    // the end of a domain tells where the link goes, its start is dropped when too long
+    let max_chars = (text_width / (FONT_10X20.character_size.width * 2)) as usize;
+    let domain_chars = domain.chars().count();
+    let domain = if domain_chars > max_chars {
+        let end = domain
+            .chars()
+            .skip(domain_chars - max_chars + 3)
+            .collect::<String>();
+        format!("...{}", end)
+    } else {
+        domain.to_owned()
+    };
+    draw_text(&mut canvas, &domain, ACCENT, 3, MARGIN, 400, text_width);
+
+    canvas.encode()
+}
+
+/// Draws `text` with its top left corner at `x`, `y`, scaling the font by `scale`. Text wider than
+/// `max_width` is drawn at a smaller scale, down to 2, and truncated if still too wide.
+fn draw_text(
+    canvas: &mut Canvas,
+    text: &str,
+    color: Rgb888,
+    scale: u32,
+    x: u32,
+    y: u32,
+    max_width: u32,
+) {
+    let char_width = FONT_10X20.character_size.width;
+    let scale = (2..=scale)
+        .rev()
+        .find(|scale| text.chars().count() as u32 * char_width * scale <= max_width)
+        .unwrap_or(2);
+
+    let max_chars = (max_width / (char_width * scale)) as usize;
+    let text = if text.chars().count() > max_chars {
+        let truncated = text
+            .chars()
+            .take(max_chars.saturating_sub(3))
+            .collect::<String>();
+        format!("{}...", truncated)
+    } else {
+        text.to_owned()
+    };
+
+    let mut target = Scaled {
+        canvas,
+        scale,
+        x,
+        y,
+    };
+    let style = MonoTextStyle::new(&FONT_10X20, color);
+    let _ = Text::with_baseline(&text, Point::zero(), style, Baseline::Top).draw(&mut target);
+}
+
+/// Draws the QR code of `data` in the square of side `size` with its top left corner at `x`, `y`
+fn draw_qr_code(canvas: &mut Canvas, data: &str, x: u32, y: u32, size: u32) -> Result<(), String> {
+    let code = QrCode::new(data.as_bytes()).map_err(|err| err.to_string())?;
+    let modules = code.width() as u32;
+    // the quiet zone around the code is 4 modules wide
+    let module_size = size / (modules + 8);
+    let offset = (size - module_size * modules) / 2;
+
+    for (index, color) in code.to_colors().into_iter().enumerate() {
+        if color == qrcode::Color::Dark {
+            let index = index as u32;
+            canvas.fill(
+                x + offset + (index % modules) * module_size,
+                y + offset + (index / modules) * module_size,
+                module_size,
+                module_size,
+                TEXT,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// An RGB image being drawn
+struct Canvas {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: u32, height: u32, background: Rgb888) -> Canvas {
+        let pixels = [background.r(), background.g(), background.b()]
+            .iter()
+            .copied()
+            .cycle()
+            .take((width * height * 3) as usize)
+            .collect();
+
+        Canvas {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    fn set(&mut self, x: u32, y: u32, color: Rgb888) {
+        if x < self.width && y < self.height {
+            let index = ((y * self.width + x) * 3) as usize;
+            self.pixels[index..index + 3].copy_from_slice(&[color.r(), color.g(), color.b()]);
+        }
+    }
+
+    fn fill(&mut self, x: u32, y: u32, width: u32, height: u32, color: Rgb888) {
+        for y in y..y + height {
+            for x in x..x + width {
+                self.set(x, y, color);
+            }
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, String> {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&self.pixels))
+            .map_err(|err| err.to_string())?;
+        Ok(png)
+    }
+}
+
+/// A `DrawTarget` drawing on a `Canvas` from `x`, `y`, each pixel being drawn as a square of side
+/// `scale`: bitmap fonts are too small for cards otherwise
+struct Scaled<'a> {
+    canvas: &'a mut Canvas,
+    scale: u32,
+    x: u32,
+    y: u32,
+}
+
+impl OriginDimensions for Scaled<'_> {
+    fn size(&self) -> Size {
+        Size::new(
+            self.canvas.width.saturating_sub(self.x) / self.scale,
+            self.canvas.height.saturating_sub(self.y) / self.scale,
+        )
+    }
+}
+
+impl DrawTarget for Scaled<'_> {
+    type Color = Rgb888;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if point.x < 0 || point.y < 0 {
+                continue;
+            }
+            self.canvas.fill(
+                self.x + point.x as u32 * self.scale,
+                self.y + point.y as u32 * self.scale,
+                self.scale,
+                self.scale,
+                color,
+            );
+        }
+        Ok(())
+    }
+}
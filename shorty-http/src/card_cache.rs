@@ -0,0 +1,135 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! card_cache holds the cache of social cards on disk, see `card`, enabled by
+//! `SHORTENER_CARD_CACHE_DIR`.
+//!
+//! Cards are named after the SHA-256 of their short URL and of the URL the link redirects to, so
+//! that a link whose URL changed, or a new link reusing an ID, gets a new card rather than the one
+//! of the previous URL. Cards no longer requested are left behind, until evicted: once the cards
+//! take more than the max size of the cache, the oldest ones are deleted.
+//!
+//! Calls read and write files: frontends make them off their runtime, such as with `web::block`.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use shorty::crypto::{encode_hex, sha256};
+
+/// `CardCache` stores the PNG images of social cards in `dir`, up to `max_size` bytes
+pub struct CardCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl CardCache {
+    pub fn new(dir: PathBuf, max_size: u64) -> CardCache {
+        CardCache { dir, max_size }
+    }
+
+    /// Returns the cached card of `short_url` redirecting to `url`, if any
+    pub fn get(&self, short_url: &str, url: &str) -> Option<Vec<u8>> {
+        fs::read(self.file(short_url, url)).ok()
+    }
+
+    /// Caches the card of `short_url` redirecting to `url`, evicting the oldest cards if the cache
+    /// grew past its max size. Failures are just logged: the card is rendered again next time.
+    pub fn put(&self, short_url: &str, url: &str, png: &[u8]) {
+        let file = self.file(short_url, url);
+        if let Err(err) = fs::write(&file, png) {
+            log::warn!("unable to cache card {}: {}", file.display(), err);
+            return;
+        }
+        if let Err(err) = self.evict() {
+            log::warn!("unable to evict cards of {}: {}", self.dir.display(), err);
+        }
+    }
+
+    fn file(&self, short_url: &str, url: &str) -> PathBuf {
+        // the zero byte tells apart the two URLs, which never contain one
+        let hash = sha256(&[short_url.as_bytes(), &[0], url.as_bytes()]);
+        self.dir.join(format!("{}.png", encode_hex(&hash)))
+    }
+
+    /// Deletes the least recently written cards until the others fit in the max size
+    fn evict(&self) -> io::Result<()> {
+        let mut cards = Vec::new();
+        let mut size = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != "png") {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            size += metadata.len();
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            cards.push((modified, metadata.len(), path));
+        }
+        if size <= self.max_size {
+            return Ok(());
+        }
+
+        cards.sort();
+        for (_, len, path) in cards {
+            if size <= self.max_size {
+                break;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => size -= len,
+                // another worker evicted it already
+                Err(err) if err.kind() == io::ErrorKind::NotFound => size -= len,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_card_cache() {
+        let dir = env::temp_dir().join(format!("shorty-cards-{}", nanoid::nanoid!()));
+        fs::create_dir(&dir).unwrap();
+        let cache = CardCache::new(dir.clone(), 10);
+
+        cache.put("https://sho.rt/abc", "https://example.com", b"first");
+        assert_eq!(
+            Some(b"first".to_vec()),
+            cache.get("https://sho.rt/abc", "https://example.com")
+        );
+        // a new URL gets a new card
+        assert_eq!(None, cache.get("https://sho.rt/abc", "https://example.org"));
+
+        // past 10 bytes, the oldest card is evicted
+        sleep(Duration::from_millis(10));
+        cache.put("https://sho.rt/abc", "https://example.org", b"second");
+        assert_eq!(None, cache.get("https://sho.rt/abc", "https://example.com"));
+        assert_eq!(
+            Some(b"second".to_vec()),
+            cache.get("https://sho.rt/abc", "https://example.org")
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,43 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! compression holds the rules deciding which responses are compressed by the `Compress`
+//! middleware, picking gzip, brotli or zstd from the `Accept-Encoding` of the request.
+//!
+//! Redirects are never compressed, as their bodies are meant for ancient clients, and neither are
+//! bodies smaller than the configured minimum size, which would barely shrink.
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{self, HeaderValue};
+
+/// Marks a response as not to be compressed, if it's a redirect or smaller than `min_size` bytes.
+/// `Compress` leaves alone responses with a `Content-Encoding`, so it's set to `identity`: it must
+/// run before `Compress` handles the response.
+pub fn skip_compression<B: MessageBody>(res: &mut ServiceResponse<B>, min_size: u64) {
+    let small = match res.response().body().size() {
+        BodySize::Sized(size) => size < min_size,
+        BodySize::None => true,
+        BodySize::Stream => false,
+    };
+    let skip = small || res.status().is_redirection();
+
+    let headers = res.headers_mut();
+    if skip && !headers.contains_key(header::CONTENT_ENCODING) {
+        headers.insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static("identity"),
+        );
+    }
+}
@@ -0,0 +1,65 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! deadline reads the deadlines of requests, from their `X-Request-Deadline` or `grpc-timeout`
+//! headers, see `shorty::deadline`, and answers `504 Gateway Timeout` to the requests served
+//! past them, abandoning the storage calls and the checks they were waiting for.
+
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+use actix_web::http::header::HeaderMap;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+
+use shorty::deadline::{Deadline, GRPC_TIMEOUT_HEADER, REQUEST_DEADLINE_HEADER};
+use shorty::ShortenerError;
+
+/// Returns the deadline of a request, the earliest of its headers and of `default`, if any
+pub fn deadline(headers: &HeaderMap, default: Option<Duration>) -> Option<Deadline> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    Deadline::from_headers(
+        header(REQUEST_DEADLINE_HEADER),
+        header(GRPC_TIMEOUT_HEADER),
+        default,
+    )
+}
+
+/// The error of the requests abandoned past their deadline. It's an error rather than a
+/// response, as the request the response would be built from is dropped with its handler.
+#[derive(Debug)]
+pub struct DeadlineExceeded;
+
+impl DeadlineExceeded {
+    /// Logs the abandoned request and returns the error
+    pub fn abandoned() -> actix_web::Error {
+        tracing::warn!("request abandoned past its deadline");
+        DeadlineExceeded.into()
+    }
+}
+
+impl Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        Display::fmt(&ShortenerError::DeadlineExceeded, f)
+    }
+}
+
+impl ResponseError for DeadlineExceeded {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::GATEWAY_TIMEOUT
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        crate::error_response(ShortenerError::DeadlineExceeded)
+    }
+}
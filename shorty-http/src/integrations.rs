@@ -0,0 +1,337 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! integrations holds the routes of chat integrations, shortening links on behalf of their users
+//! with the integrations API key of the configuration.
+//!
+//! Slack sends the `/shorten <url>` slash command to `POST /integrations/slack`, signing each
+//! request with the signing secret of the Slack app. Discord sends the interactions of the
+//! `/shorten url:<url>` application command to `POST /integrations/discord`, signing each with the
+//! Ed25519 key of the Discord application.
+
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use shorty::crypto::{self, SignatureVerifier};
+use shorty::LinkOptions;
+
+use crate::{base_url, host_domain, AppState};
+
+#[derive(Deserialize)]
+struct SlashCommand {
+    command: String,
+    text: String,
+}
+
+/// A message in Slack format: `ephemeral` messages are shown to the user running the command
+/// only, while `in_channel` messages are shown to the whole channel
+#[derive(Serialize)]
+struct SlackMessage {
+    response_type: &'static str,
+    text: String,
+}
+
+impl SlackMessage {
+    fn ephemeral(text: String) -> HttpResponse {
+        HttpResponse::Ok().json(SlackMessage {
+            response_type: "ephemeral",
+            text,
+        })
+    }
+
+    fn in_channel(text: String) -> HttpResponse {
+        HttpResponse::Ok().json(SlackMessage {
+            response_type: "in_channel",
+            text,
+        })
+    }
+}
+
+/// Handles Slack slash commands. Slack shows non 200 responses as a generic failure, so errors
+/// are replied as ephemeral messages, except for requests not coming from Slack.
+pub async fn slack(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    body: web::Bytes,
+) -> HttpResponse {
+    let signing_secret = match &app_state.slack_signing_secret {
+        Some(signing_secret) => signing_secret,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    if !verify_slack_signature(&req, &app_state.signature_verifier, signing_secret, &body) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let command = match serde_urlencoded::from_bytes::<SlashCommand>(&body) {
+        Ok(command) => command,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+    if command.command != "/shorten" {
+        return SlackMessage::ephemeral(format!("Unknown command {}", command.command));
+    }
+
+    let url = match slack_url(&command.text) {
+        Some(url) => url,
+        None => return SlackMessage::ephemeral(String::from("Usage: /shorten <url>")),
+    };
+
+    let api_key = app_state.integrations_api_key.as_deref();
+    if api_key.is_none() && app_state.api_key_mandatory {
+        return SlackMessage::ephemeral(String::from("Missing API key"));
+    }
+
+    match app_state
+        .shortener
+        .shorten(
+            &api_key,
+            Some(&host_domain(&req)),
+            url,
+            &LinkOptions::default(),
+        )
+        .await
+    {
+        Ok(shorten_result) => {
+            SlackMessage::in_channel(format!("{}{}", base_url(&req), shorten_result.id()))
+        }
+        Err(err) => SlackMessage::ephemeral(err.to_string()),
+    }
+}
+
+/// An interaction sent by Discord: a `PING`, checking the endpoint, or an `APPLICATION_COMMAND`
+#[derive(Deserialize)]
+struct Interaction {
+    #[serde(rename = "type")]
+    kind: u8,
+    data: Option<CommandData>,
+}
+
+const PING: u8 = 1;
+const APPLICATION_COMMAND: u8 = 2;
+
+#[derive(Deserialize)]
+struct CommandData {
+    name: String,
+    #[serde(default)]
+    options: Vec<CommandOption>,
+}
+
+#[derive(Deserialize)]
+struct CommandOption {
+    name: String,
+    value: serde_json::Value,
+}
+
+/// The response to an interaction: a `PONG`, or a message in the channel of the command.
+/// Messages with the `EPHEMERAL` flag are shown to the user running the command only.
+#[derive(Serialize)]
+struct InteractionResponse {
+    #[serde(rename = "type")]
+    kind: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<DiscordMessage>,
+}
+
+#[derive(Serialize)]
+struct DiscordMessage {
+    content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flags: Option<u64>,
+}
+
+const PONG: u8 = 1;
+const CHANNEL_MESSAGE_WITH_SOURCE: u8 = 4;
+const EPHEMERAL: u64 = 1 << 6;
+
+impl InteractionResponse {
+    fn pong() -> HttpResponse {
+        HttpResponse::Ok().json(InteractionResponse {
+            kind: PONG,
+            data: None,
+        })
+    }
+
+    fn message(content: String, ephemeral: bool) -> HttpResponse {
+        HttpResponse::Ok().json(InteractionResponse {
+            kind: CHANNEL_MESSAGE_WITH_SOURCE,
+            data: Some(DiscordMessage {
+                content,
+                flags: Some(EPHEMERAL).filter(|_| ephemeral),
+            }),
+        })
+    }
+}
+
+/// Handles Discord interactions. Discord checks that requests with an invalid signature are
+/// answered with `401 Unauthorized`, and shows other errors as a generic failure, so command
+/// errors are replied as ephemeral messages.
+pub async fn discord(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    body: web::Bytes,
+) -> HttpResponse {
+    let public_key = match &app_state.discord_public_key {
+        Some(public_key) => public_key,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    if !verify_discord_signature(&req, &app_state.signature_verifier, public_key, &body) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let interaction = match serde_json::from_slice::<Interaction>(&body) {
+        Ok(interaction) => interaction,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+    let command = match (interaction.kind, interaction.data) {
+        (PING, _) => return InteractionResponse::pong(),
+        (APPLICATION_COMMAND, Some(command)) => command,
+        _ => return HttpResponse::BadRequest().finish(),
+    };
+    if command.name != "shorten" {
+        return InteractionResponse::message(format!("Unknown command /{}", command.name), true);
+    }
+
+    let url = command
+        .options
+        .iter()
+        .find(|option| option.name == "url")
+        .and_then(|option| option.value.as_str())
+        .map(str::trim)
+        .filter(|url| !url.is_empty());
+    let url = match url {
+        Some(url) => url,
+        None => {
+            return InteractionResponse::message(String::from("Usage: /shorten url:<url>"), true)
+        }
+    };
+
+    let api_key = app_state.integrations_api_key.as_deref();
+    if api_key.is_none() && app_state.api_key_mandatory {
+        return InteractionResponse::message(String::from("Missing API key"), true);
+    }
+
+    match app_state
+        .shortener
+        .shorten(
+            &api_key,
+            Some(&host_domain(&req)),
+            url,
+            &LinkOptions::default(),
+        )
+        .await
+    {
+        Ok(shorten_result) => InteractionResponse::message(
+            format!("{}{}", base_url(&req), shorten_result.id()),
+            false,
+        ),
+        Err(err) => InteractionResponse::message(err.to_string(), true),
+    }
+}
+
+/// Verifies the `X-Signature-Ed25519` of a request: the hex encoded Ed25519 signature of
+/// `<timestamp><body>`, checked with the public key of the Discord application, and its
+/// `X-Signature-Timestamp` within the clock skew of `verifier`
+fn verify_discord_signature(
+    req: &HttpRequest,
+    verifier: &SignatureVerifier,
+    public_key: &[u8],
+    body: &[u8],
+) -> bool {
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+
+    let timestamp = match header("X-Signature-Timestamp") {
+        Some(timestamp) => timestamp,
+        None => return false,
+    };
+    let signed_at = match timestamp.parse::<u64>() {
+        Ok(signed_at) => signed_at,
+        Err(_) => return false,
+    };
+
+    let signature = match header("X-Signature-Ed25519").and_then(crypto::decode_hex) {
+        Some(signature) => signature,
+        None => return false,
+    };
+
+    verifier.verify_ed25519(
+        public_key,
+        signed_at,
+        &[timestamp.as_bytes(), body],
+        &signature,
+    )
+}
+
+/// Verifies the `X-Slack-Signature` of a request: the hex encoded HMAC-SHA256, keyed with the
+/// signing secret, of `v0:<timestamp>:<body>`, prefixed with `v0=`, and its timestamp within the
+/// clock skew of `verifier`
+fn verify_slack_signature(
+    req: &HttpRequest,
+    verifier: &SignatureVerifier,
+    signing_secret: &str,
+    body: &[u8],
+) -> bool {
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+
+    let timestamp = match header("X-Slack-Request-Timestamp") {
+        Some(timestamp) => timestamp,
+        None => return false,
+    };
+    let signed_at = match timestamp.parse::<u64>() {
+        Ok(signed_at) => signed_at,
+        Err(_) => return false,
+    };
+
+    let signature = match header("X-Slack-Signature")
+        .and_then(|signature| signature.strip_prefix("v0="))
+        .and_then(crypto::decode_hex)
+    {
+        Some(signature) => signature,
+        None => return false,
+    };
+
+    let prefix = format!("v0:{}:", timestamp);
+    verifier.verify(
+        signing_secret.as_bytes(),
+        signed_at,
+        &[prefix.as_bytes(), body],
+        &signature,
+    )
+}
+
+/// Extracts the URL from the text of a slash command. Slack may escape links as `<url>` or
+/// `<url|label>`.
+fn slack_url(text: &str) -> Option<&str> {
+    let text = text.trim();
+    let url = match text
+        .strip_prefix('<')
+        .and_then(|text| text.strip_suffix('>'))
+    {
+        Some(escaped) => escaped.split('|').next().unwrap_or_default(),
+        None => text,
+    };
+
+    if url.is_empty() || url.contains(char::is_whitespace) {
+        None
+    } else {
+        Some(url)
+    }
+}
@@ -15,103 +15,1305 @@
 #[macro_use]
 extern crate serde_derive;
 
-use actix_web::http::StatusCode;
-use actix_web::{HttpRequest, HttpResponse, Json, Path};
-use redis::Client;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use shorty::redis_facade::RedisFacade;
-use shorty::Shortener;
+use actix_web::cookie::time::Duration as CookieDuration;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse, HttpResponseBuilder};
+
+use shorty::challenge::HUMAN_COOKIE;
+use shorty::click_latency::LatencyReport;
+use shorty::content_security_policy::{ContentSecurityPolicy, MAX_CSP_REPORT_SIZE};
+use shorty::crypto::SignatureVerifier;
+use shorty::error_page::ErrorPage;
+use shorty::interstitial::Interstitial;
+use shorty::link_index::MAX_LINKS;
+use shorty::link_metadata::Creator;
+use shorty::schedule::Schedule;
+use shorty::storage::Storage;
+use shorty::takedown;
+use shorty::templates::Templates;
+use shorty::{LinkOptions, Redirect, Shortener, StorageHealth, VERIFIED_PUBLISHER_HEADER};
+use shorty_bootstrap::mailer::Mailer;
+use shorty_conf::Config;
+use url::Url;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::card_cache::CardCache;
+use crate::supervisor::TasksHealth;
+
+pub mod admin;
+pub mod api;
+pub mod ascii_json;
+pub mod badge;
+pub mod campaign;
+pub mod card;
+pub mod card_cache;
+pub mod compression;
+pub mod deadline;
+pub mod integrations;
+pub mod openapi;
+pub mod qr_sheet;
+pub mod request_id;
+pub mod server;
+pub mod supervisor;
 
 pub struct AppState {
     shortener: Shortener,
     api_key_mandatory: bool,
+    admin_key: Option<String>,
+    integrations_api_key: Option<String>,
+    slack_signing_secret: Option<String>,
+    discord_public_key: Option<Vec<u8>>,
+    signature_verifier: SignatureVerifier,
+    templates: Templates,
+    abuse_page: String,
+    mailer: Option<Mailer>,
+    card_cache: Option<CardCache>,
+    eviction_policy_safe: AtomicBool,
+    trusted_proxies: Vec<IpAddr>,
 }
 
 impl AppState {
-    pub fn new(
-        redis_host: &str,
-        redis_port: &str,
-        id_length: usize,
-        id_generation_max_attempts: u8,
-        rate_limit_period: usize,
-        rate_limit: i64,
-        api_key_mandatory: bool,
-    ) -> AppState {
-        let redis = Client::open(format!("redis://{}:{}/", redis_host, redis_port).as_str())
-            .unwrap()
-            .get_connection()
-            .unwrap();
-
-        let alphabet = vec![
-            (b'a'..=b'z').map(char::from).collect::<Vec<_>>(),
-            (b'A'..=b'Z').map(char::from).collect::<Vec<_>>(),
-            (b'0'..=b'9').map(char::from).collect::<Vec<_>>(),
-        ]
-        .into_iter()
-        .flatten()
-        .collect::<Vec<char>>();
+    pub fn new(storage: Box<dyn Storage>, config: &Config) -> AppState {
+        let mut shortener = shorty_bootstrap::shortener(config, storage);
+        if config.click_queue_capacity > 0 {
+            shortener = shortener.with_click_queue(config.click_queue_capacity);
+        }
+        let templates = shorty_bootstrap::templates(config);
+        let abuse_page = takedown::abuse_page(&templates, config.abuse_email.as_deref());
 
         AppState {
-            shortener: Shortener::new(
-                id_length,
-                alphabet,
-                id_generation_max_attempts,
-                RedisFacade::new(redis),
-                rate_limit_period,
-                rate_limit,
-            ),
-            api_key_mandatory,
+            shortener,
+            api_key_mandatory: config.api_key_mandatory,
+            admin_key: config.admin_key.clone(),
+            integrations_api_key: config.integrations_api_key.clone(),
+            slack_signing_secret: config.slack_signing_secret.clone(),
+            discord_public_key: config
+                .discord_public_key
+                .as_deref()
+                .and_then(shorty::crypto::decode_hex),
+            signature_verifier: SignatureVerifier::new(config.signature_max_clock_skew),
+            templates,
+            abuse_page,
+            mailer: shorty_bootstrap::mailer(config),
+            card_cache: config
+                .card_cache_dir
+                .as_ref()
+                .map(|dir| CardCache::new(PathBuf::from(dir), config.card_cache_max_size.bytes())),
+            eviction_policy_safe: AtomicBool::new(true),
+            trusted_proxies: config.trusted_proxies.clone(),
         }
     }
+
+    /// Checks that Redis won't evict shortened URLs, see `Shortener::check_eviction_policy`. The
+    /// outcome is reported by `readyz`.
+    pub async fn check_eviction_policy(&self) -> bool {
+        let safe = self.shortener.check_eviction_policy().await;
+        self.eviction_policy_safe.store(safe, Ordering::Relaxed);
+        safe
+    }
 }
 
-pub fn goto((req, id): (HttpRequest<AppState>, Path<String>)) -> HttpResponse {
-    let app_state: &AppState = &req.state();
+/// Redirects to the URL of a link, or shows its interstitial if asked for or required, see
+/// `shorty::interstitial`, or a challenge to visitors of suspicious links, see `shorty::challenge`
+#[utoipa::path(
+    get,
+    path = "/{shorty_id}",
+    tag = "links",
+    params(
+        ("shorty_id" = String, Path, description = "The ID of the link, followed by `+` for its interstitial"),
+        ("preview" = Option<u8>, Query, description = "`1` for the interstitial of the link"),
+    ),
+    responses(
+        (status = 302, description = "Redirects to the URL of the link, with `301`, `307` or `308` for links with their own redirect status"),
+        (status = 200, description = "The interstitial of the link, asked for or required, or the human challenge of a suspicious link", content_type = "text/html"),
+        (status = 404, description = "The link doesn't exist"),
+        (status = 410, description = "The link was re-issued with a new ID, linked as `successor-version`"),
+    )
+)]
+pub async fn goto(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let requested_id = Interstitial::requested_id(&id, req.query_string());
+    // the interstitial shows where the link goes, so it's challenged as well
+    let pass = req.cookie(HUMAN_COOKIE);
+    let pass = pass.as_ref().map(|pass| pass.value());
+    let link_id = requested_id.unwrap_or(&id);
+    if app_state.shortener.requires_challenge(link_id, pass).await {
+        return challenge_response(&mut HttpResponse::Ok(), &req, &app_state, link_id, false);
+    }
+    let interstitial_id = match requested_id {
+        Some(id) => Some(id),
+        None if app_state.shortener.requires_interstitial(&id).await => Some(id.as_str()),
+        None => None,
+    };
+    if let Some(id) = interstitial_id {
+        return match app_state.shortener.interstitial(id).await {
+            Some(interstitial) => hardened_response(
+                HttpResponse::Ok().insert_header((header::CACHE_CONTROL, "no-store")),
+                interstitial_policy(&app_state.shortener),
+                interstitial.html(&app_state.templates),
+            ),
+            None => missing_link_response(&req, &app_state, id).await,
+        };
+    }
+
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok());
+    let templates = if Redirect::accepts_html(accept) {
+        Some(&app_state.templates)
+    } else {
+        None
+    };
 
-    match app_state.shortener.lookup(&id) {
-        Some(url) => HttpResponse::Found().header("Location", url).finish(),
-        None => HttpResponse::NotFound().finish(),
+    match app_state.shortener.redirect(&id).await {
+        Some(redirect) => redirect_response(redirect, templates),
+        None => missing_link_response(&req, &app_state, &id).await,
     }
 }
 
+/// The form answering the human challenge of a link, see `shorty::challenge`
 #[derive(Deserialize)]
+pub struct ChallengeAnswer {
+    answer: String,
+    token: String,
+}
+
+/// Checks the answer to the challenge of a link: a right answer sets the pass of the visitor in
+/// the `shorty_human` cookie and goes back to the link, a wrong one shows a new challenge
+pub async fn answer_challenge(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    id: web::Path<String>,
+    form: web::Form<ChallengeAnswer>,
+) -> HttpResponse {
+    let pass = app_state
+        .shortener
+        .pass_challenge(&id, &form.answer, &form.token);
+    let (pass, pass_ttl) = match (pass, app_state.shortener.human_challenge()) {
+        (Some(pass), Some(human_challenge)) => (pass, human_challenge.pass_ttl()),
+        _ => {
+            return challenge_response(&mut HttpResponse::Forbidden(), &req, &app_state, &id, true)
+        }
+    };
+
+    let cookie = Cookie::build(HUMAN_COOKIE, pass)
+        .path("/")
+        .max_age(CookieDuration::seconds(pass_ttl as i64))
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(req.connection_info().scheme() == "https")
+        .finish();
+    HttpResponse::SeeOther()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .insert_header((header::LOCATION, format!("{}{}", base_url(&req), id)))
+        .cookie(cookie)
+        .finish()
+}
+
+/// Shows a new challenge of the link `id`, allowing the answer to be posted back, see
+/// `shorty::content_security_policy`
+fn challenge_response(
+    response: &mut HttpResponseBuilder,
+    req: &HttpRequest,
+    app_state: &AppState,
+    id: &str,
+    failed: bool,
+) -> HttpResponse {
+    let challenge = match app_state.shortener.challenge(id, failed) {
+        Some(challenge) => challenge,
+        None => return error_page_response(req, app_state, StatusCode::NOT_FOUND),
+    };
+
+    response.insert_header((header::CACHE_CONTROL, "no-store"));
+    form_response(response, challenge.html(&app_state.templates))
+}
+
+/// Builds the response redirecting to a shortened URL: status and caching depend on the link, see
+/// `shorty::Redirect`. With `templates`, the body is an HTML page linking to
+/// the URL, for clients ignoring the `Location` header.
+fn redirect_response(redirect: Redirect, templates: Option<&Templates>) -> HttpResponse {
+    let status = StatusCode::from_u16(redirect.status_code()).unwrap_or(StatusCode::FOUND);
+
+    let mut response = HttpResponse::build(status);
+    response
+        .insert_header((header::CACHE_CONTROL, redirect.cache_control()))
+        .insert_header((header::LOCATION, redirect.url.as_str()))
+        .insert_header((header::VARY, "Accept"));
+    if let Some(link) = redirect.link_header() {
+        response.insert_header((header::LINK, link));
+    }
+    if redirect.verified {
+        response.insert_header((VERIFIED_PUBLISHER_HEADER, "true"));
+    }
+
+    match templates {
+        Some(templates) => html_response(&mut response, redirect.html(templates)),
+        None => response.finish(),
+    }
+}
+
+/// Answers a link that can't be followed: `410 Gone` if it was re-issued with a new ID, linking
+/// to it (see `shorty::campaign`), `404 Not Found` otherwise
+async fn missing_link_response(req: &HttpRequest, app_state: &AppState, id: &str) -> HttpResponse {
+    match app_state.shortener.rotated_to(id).await {
+        Some(rotated_to) => {
+            let successor = format!("{}{}", base_url(req), rotated_to);
+            let mut response = HttpResponse::Gone();
+            response.insert_header((
+                header::LINK,
+                format!("<{}>; rel=\"successor-version\"", successor),
+            ));
+            page_response(&mut response, req, app_state, ErrorPage::rotated(successor))
+        }
+        None => error_page_response(req, app_state, StatusCode::NOT_FOUND),
+    }
+}
+
+/// Builds an error response, with the `ErrorPage` of its status as HTML or JSON body for clients
+/// accepting either, and an empty body for the others
+fn error_page_response(
+    req: &HttpRequest,
+    app_state: &AppState,
+    status: StatusCode,
+) -> HttpResponse {
+    let page = ErrorPage::new(status.as_u16());
+    page_response(&mut HttpResponse::build(status), req, app_state, page)
+}
+
+/// Builds the error response of `page`, see `error_page_response`
+fn page_response(
+    response: &mut HttpResponseBuilder,
+    req: &HttpRequest,
+    app_state: &AppState,
+    page: ErrorPage,
+) -> HttpResponse {
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok());
+
+    response.insert_header((header::VARY, "Accept"));
+    if Redirect::accepts_html(accept) {
+        html_response(response, page.html(&app_state.templates))
+    } else if ErrorPage::accepts_json(accept) {
+        response.content_type("application/json").body(page.json())
+    } else {
+        response.finish()
+    }
+}
+
+/// Serves the abuse policy page, see `shorty::takedown::abuse_page`
+pub async fn abuse(app_state: web::Data<AppState>) -> HttpResponse {
+    html_response(&mut HttpResponse::Ok(), app_state.abuse_page.clone())
+}
+
+/// Completes a response with an HTML body, and the headers hardening every HTML page, see
+/// `shorty::content_security_policy`
+fn html_response(response: &mut HttpResponseBuilder, body: String) -> HttpResponse {
+    hardened_response(response, ContentSecurityPolicy::page(), body)
+}
+
+/// Completes a response with an HTML body posting a form back to shorty, as `html_response` does
+fn form_response(response: &mut HttpResponseBuilder, body: String) -> HttpResponse {
+    hardened_response(response, ContentSecurityPolicy::form(), body)
+}
+
+/// Returns the policy of interstitials, running the latency beacon if on, see
+/// `shorty::click_latency`
+fn interstitial_policy(shortener: &Shortener) -> ContentSecurityPolicy {
+    if shortener.latency_beacon() {
+        ContentSecurityPolicy::beacon()
+    } else {
+        ContentSecurityPolicy::page()
+    }
+}
+
+fn hardened_response(
+    response: &mut HttpResponseBuilder,
+    policy: ContentSecurityPolicy,
+    body: String,
+) -> HttpResponse {
+    response.content_type("text/html; charset=utf-8");
+    for header in policy.headers() {
+        response.insert_header(header);
+    }
+    response.body(body)
+}
+
+/// Logs a violation of the `Content-Security-Policy` of a page reported by a browser, see
+/// `shorty::content_security_policy`
+pub async fn csp_report(body: web::Bytes) -> HttpResponse {
+    let report = String::from_utf8_lossy(&body[..body.len().min(MAX_CSP_REPORT_SIZE)]);
+    log::warn!("Content-Security-Policy violation reported: {}", report);
+    HttpResponse::NoContent().finish()
+}
+
+/// Counts a report of the latency beacon of an interstitial, see `shorty::click_latency`. Browsers
+/// send it with `navigator.sendBeacon`, as plain text.
+#[utoipa::path(
+    post,
+    path = "/{shorty_id}/latency",
+    tag = "links",
+    params(("shorty_id" = String, Path, description = "The ID of the link")),
+    request_body(content = String, description = "`{\"ms\": 120}` if the destination answered in 120 milliseconds, `{\"failed\": true}` if it couldn't be reached", content_type = "text/plain"),
+    responses(
+        (status = 204, description = "The report was counted"),
+        (status = 400, body = ErrorResponse, description = "The report is invalid"),
+        (status = 404, body = ErrorResponse, description = "The link doesn't exist, or the beacon is off"),
+    )
+)]
+pub async fn latency(
+    app_state: web::Data<AppState>,
+    id: web::Path<String>,
+    body: web::Bytes,
+) -> HttpResponse {
+    let report = match serde_json::from_slice::<LatencyReport>(&body) {
+        Ok(report) => report,
+        Err(err) => {
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                err: format!("Invalid latency report: {}", err),
+            })
+        }
+    };
+    match app_state.shortener.record_latency(&id, &report).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Serves the social card of a link as a PNG image, see `card`. Cards are rendered for the domain
+/// of the request, or for `SHORTENER_PUBLIC_BASE_URL` if set, and cached on disk if
+/// `SHORTENER_CARD_CACHE_DIR` is set, see `card_cache`.
+pub async fn card(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let url = match app_state.shortener.destination(&id).await {
+        Some(url) => url,
+        None => return error_page_response(&req, &app_state, StatusCode::NOT_FOUND),
+    };
+    let domain = Url::parse(&url)
+        .ok()
+        .and_then(|url| url.host_str().map(String::from))
+        .unwrap_or_else(|| url.clone());
+    let short_url = format!("{}{}", base_url(&req), id);
+
+    let state = app_state.clone();
+    let png = web::block(move || -> Result<Vec<u8>, String> {
+        let cache = state.card_cache.as_ref();
+        if let Some(png) = cache.and_then(|cache| cache.get(&short_url, &url)) {
+            return Ok(png);
+        }
+
+        let png = card::render(&short_url, &domain)?;
+        if let Some(cache) = cache {
+            cache.put(&short_url, &url, &png);
+        }
+        Ok(png)
+    })
+    .await;
+
+    match png {
+        Ok(Ok(png)) => HttpResponse::Ok()
+            .content_type("image/png")
+            .insert_header((header::CACHE_CONTROL, "public, max-age=86400"))
+            .body(png),
+        Ok(Err(err)) => {
+            log::error!("unable to render card of '{}': {}", id, err);
+            HttpResponse::InternalServerError().finish()
+        }
+        Err(err) => {
+            log::error!("unable to render card of '{}': {}", id, err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Serves the badge of a link as an SVG image, see `badge`. Badges are cached for a few minutes,
+/// so that their status and clicks stay fresh in the pages embedding them.
+pub async fn badge(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    match app_state.shortener.link_health(&id).await {
+        Some(health) => HttpResponse::Ok()
+            .content_type("image/svg+xml")
+            .insert_header((header::CACHE_CONTROL, "public, max-age=300"))
+            .insert_header((
+                header::CONTENT_SECURITY_POLICY,
+                BADGE_CONTENT_SECURITY_POLICY,
+            ))
+            .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+            .body(badge::render(&health)),
+        None => error_page_response(&req, &app_state, StatusCode::NOT_FOUND),
+    }
+}
+
+/// The policy of badges, which are SVG documents when opened on their own: they load nothing and
+/// run no script
+const BADGE_CONTENT_SECURITY_POLICY: &str = "default-src 'none'; style-src 'unsafe-inline'";
+
+/// How long `readyz` waits for the storage to answer before deeming it unreachable
+const STORAGE_PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+struct LivenessReport {
+    status: &'static str,
+}
+
+#[derive(Serialize)]
+struct ReadinessReport {
+    storage: StorageHealth,
+    eviction_policy_safe: bool,
+    tasks: Vec<supervisor::TaskHealth>,
+}
+
+/// Reports that shorty-http is alive, without checking its dependencies: a failing storage calls
+/// for taking the instance out of rotation (see `readyz`), not for restarting it
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(LivenessReport { status: "ok" })
+}
+
+/// Reports whether shorty-http is ready to serve, with the latency of the storage, answering
+/// `503 Service Unavailable` while the storage can't be reached or a background task is waiting
+/// to be restarted
+pub async fn readyz(
+    app_state: web::Data<AppState>,
+    health: web::Data<TasksHealth>,
+) -> HttpResponse {
+    let report = ReadinessReport {
+        storage: app_state
+            .shortener
+            .check_storage(STORAGE_PING_TIMEOUT)
+            .await,
+        eviction_policy_safe: app_state.eviction_policy_safe.load(Ordering::Relaxed),
+        tasks: health.tasks(),
+    };
+
+    if report.storage.reachable && health.is_healthy() {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+/// Returns the statistics of a link
+#[utoipa::path(
+    get,
+    path = "/{shorty_id}/stats",
+    tag = "links",
+    params(("shorty_id" = String, Path, description = "The ID of the link")),
+    responses(
+        (status = 200, body = shorty::Stats),
+        (status = 404, description = "The link doesn't exist, or can't be followed"),
+        (status = 410, description = "The link was re-issued with a new ID"),
+    )
+)]
+pub async fn stats(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    match app_state.shortener.stats(&id).await {
+        Some(stats) => HttpResponse::Ok().json(stats),
+        None => missing_link_response(&req, &app_state, &id).await,
+    }
+}
+
+/// Returns how a link was created, see `shorty::link_metadata`: to the API key it was shortened
+/// with, or to anyone with the admin key
+#[utoipa::path(
+    get,
+    path = "/{shorty_id}/info",
+    tag = "links",
+    params(("shorty_id" = String, Path, description = "The ID of the link")),
+    responses(
+        (status = 200, body = shorty::link_metadata::LinkMetadata),
+        (status = 403, description = "Missing or invalid API key or admin key, or the link wasn't shortened with the API key"),
+        (status = 404, description = "The link doesn't exist"),
+    )
+)]
+pub async fn info(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if req.headers().contains_key("X-Admin-Key") {
+        if let Some(response) = admin::reject_unauthorized(&req, &app_state) {
+            return response;
+        }
+        return match app_state.shortener.metadata(&id).await {
+            Some(metadata) => HttpResponse::Ok().json(metadata),
+            None => error_response(shorty::ShortenerError::NotFound("Link not found")),
+        };
+    }
+
+    let api_key = match api_key_header(&req) {
+        Some(api_key) => api_key,
+        None => return missing_api_key_response(),
+    };
+    match app_state.shortener.owned_metadata(&api_key, &id).await {
+        Ok(metadata) => HttpResponse::Ok().json(metadata),
+        Err(err) => error_response(err),
+    }
+}
+
+/// The body of `POST /`: the `url` to shorten, with a `custom_id` instead of a generated one, and
+/// the options of the link
+#[derive(Deserialize, ToSchema)]
 pub struct ShortenRequest {
     api_key: Option<String>,
+    custom_id: Option<String>,
     url: String,
+    expires_in: Option<usize>,
+    expires_at: Option<u64>,
+    #[serde(default)]
+    permanent: bool,
+    campaign: Option<String>,
+    redirect_status: Option<u16>,
+    schedule: Option<Schedule>,
+    #[serde(default)]
+    preconnect: bool,
 }
 
+/// The body of `POST /batch`: the `urls` to shorten, all with the same options
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    api_key: Option<String>,
+    urls: Vec<String>,
+    expires_in: Option<usize>,
+    expires_at: Option<u64>,
+    #[serde(default)]
+    permanent: bool,
+    campaign: Option<String>,
+    redirect_status: Option<u16>,
+    schedule: Option<Schedule>,
+    #[serde(default)]
+    preconnect: bool,
+}
+
+/// The max size of the body of `POST /batch`, fitting `shorty::MAX_BATCH_SIZE` long URLs
+pub const BATCH_BODY_LIMIT: usize = 4 * 1024 * 1024;
+
+/// The result of shortening a URL of a batch: the link, or why it couldn't be shortened
 #[derive(Serialize)]
+#[serde(untagged)]
+enum BatchResult {
+    Link(shorty::ShortenerResult),
+    Error(ErrorResponse),
+}
+
+/// The body of requests only needing an API key, such as deletions
+#[derive(Deserialize, ToSchema)]
+pub struct ApiKeyRequest {
+    api_key: String,
+}
+
+/// Reads the API key of requests without a body, from the `X-API-Key` header
+fn api_key_header(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get("X-API-Key")
+        .and_then(|api_key| api_key.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// Answers a `ShortenerError` with its status, see `ShortenerError::status_code`, telling rate
+/// limited clients when to retry
+fn error_response(err: shorty::ShortenerError) -> HttpResponse {
+    let status = StatusCode::from_u16(err.status_code()).unwrap();
+    let mut response = HttpResponse::build(status);
+    if let Some(retry_after) = err.retry_after() {
+        response.insert_header((header::RETRY_AFTER, retry_after));
+    }
+    response.json(ErrorResponse {
+        err: err.to_string(),
+    })
+}
+
+fn missing_api_key_response() -> HttpResponse {
+    HttpResponse::Forbidden().json(ErrorResponse {
+        err: String::from("Missing API key"),
+    })
+}
+
+/// Deletes a link shortened with the API key
+#[utoipa::path(
+    delete,
+    path = "/{shorty_id}",
+    tag = "links",
+    params(("shorty_id" = String, Path, description = "The ID of the link")),
+    request_body = ApiKeyRequest,
+    responses(
+        (status = 204, description = "The link was deleted"),
+        (status = 403, body = ErrorResponse, description = "The API key is invalid, or didn't shorten the link"),
+        (status = 404, body = ErrorResponse, description = "The link doesn't exist"),
+    )
+)]
+pub async fn delete(
+    app_state: web::Data<AppState>,
+    id: web::Path<String>,
+    payload: web::Json<ApiKeyRequest>,
+) -> HttpResponse {
+    match app_state.shortener.delete(&payload.api_key, &id).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => error_response(err),
+    }
+}
+
+/// The body of `PUT /{shorty_id}`, the new destination of a link
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateRequest {
+    api_key: String,
+    url: String,
+}
+
+/// Updates the destination URL of a link shortened with the API key, keeping its ID, options and
+/// expiration
+#[utoipa::path(
+    put,
+    path = "/{shorty_id}",
+    tag = "links",
+    params(("shorty_id" = String, Path, description = "The ID of the link")),
+    request_body = UpdateRequest,
+    responses(
+        (status = 200, body = shorty::ShortenerResult),
+        (status = 400, body = ErrorResponse, description = "The URL is invalid, or the ID is signed"),
+        (status = 403, body = ErrorResponse, description = "The API key is invalid, or didn't shorten the link"),
+        (status = 404, body = ErrorResponse, description = "The link doesn't exist"),
+        (status = 409, body = ErrorResponse, description = "The link is quarantined"),
+    )
+)]
+pub async fn update(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    id: web::Path<String>,
+    payload: web::Json<UpdateRequest>,
+) -> HttpResponse {
+    let host_domain = host_domain(&req);
+    match app_state
+        .shortener
+        .update(&payload.api_key, Some(&host_domain), &id, &payload.url)
+        .await
+    {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(err) => error_response(err),
+    }
+}
+
+#[derive(Serialize, ToSchema)]
 struct ErrorResponse {
     err: String,
 }
 
-pub fn shorten((req, payload): (HttpRequest<AppState>, Json<ShortenRequest>)) -> HttpResponse {
-    let app_state: &AppState = &req.state();
+/// The query of `GET /links`. The API key is better sent in the `X-API-Key` header, keeping it out
+/// of access logs.
+#[derive(Deserialize)]
+pub struct ListLinksQuery {
+    api_key: Option<String>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
 
-    if payload.api_key.is_none() && app_state.api_key_mandatory {
-        return HttpResponse::Ok()
-            .status(StatusCode::FORBIDDEN)
-            .json(ErrorResponse {
-                err: String::from("Missing API key"),
-            });
+/// Lists the links shortened with an API key, a page at a time, see
+/// `Shortener::list_by_api_key`
+pub async fn list_links(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    query: web::Query<ListLinksQuery>,
+) -> HttpResponse {
+    let api_key = match api_key_header(&req).or_else(|| query.api_key.clone()) {
+        Some(api_key) => api_key,
+        None => return missing_api_key_response(),
+    };
+
+    match app_state
+        .shortener
+        .list_by_api_key(
+            &api_key,
+            query.cursor.as_deref(),
+            query.limit.unwrap_or(MAX_LINKS),
+        )
+        .await
+    {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(err) => error_response(err),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PreviewRequest {
+    custom_id: Option<String>,
+}
+
+/// The short URL a shorten request would get. Without a custom ID, only the `base_url` generated
+/// IDs are appended to is known.
+#[derive(Serialize)]
+struct PreviewResponse {
+    base_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    short_url: Option<String>,
+}
+
+/// Previews the short URL a shorten request with the same custom ID would get, checking that the
+/// custom ID is valid and not taken yet, so that UIs can show it before shortening.
+///
+/// Links are served from the domain shorten requests are sent to, so the preview uses the domain
+/// and scheme of the request itself.
+pub async fn preview(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    payload: web::Json<PreviewRequest>,
+) -> HttpResponse {
+    if let Some(custom_id) = &payload.custom_id {
+        if let Err(err) = app_state.shortener.check_custom_id(custom_id).await {
+            return error_response(err);
+        }
+    }
+
+    let base_url = base_url(&req);
+    let short_url = payload
+        .custom_id
+        .as_ref()
+        .map(|custom_id| format!("{}{}", base_url, custom_id));
+
+    HttpResponse::Ok().json(PreviewResponse {
+        base_url,
+        short_url,
+    })
+}
+
+/// The URL shortened IDs are appended to: `SHORTENER_PUBLIC_BASE_URL` if set, otherwise links are
+/// served from the domain requests are sent to
+fn base_url(req: &HttpRequest) -> String {
+    let public_base_url = req
+        .app_data::<web::Data<AppState>>()
+        .and_then(|app_state| app_state.shortener.public_base_url());
+    if let Some(public_base_url) = public_base_url {
+        return format!("{}/", public_base_url);
+    }
+
+    let connection_info = req.connection_info();
+    format!("{}://{}/", connection_info.scheme(), connection_info.host())
+}
+
+/// The IP address of the client: the peer of the connection, unless it's one of the
+/// `trusted_proxies`, whose `X-Forwarded-For` tells the client as the last address that isn't one
+/// of them. Addresses added by clients themselves come first, and are never trusted.
+fn client_ip(req: &HttpRequest, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
     }
 
-    let api_key = payload.api_key.as_ref().map(String::as_str);
+    let forwarded_for = req
+        .headers()
+        .get_all("X-Forwarded-For")
+        .filter_map(|forwarded_for| forwarded_for.to_str().ok())
+        .flat_map(|forwarded_for| forwarded_for.split(','))
+        .map(|hop| hop.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+    for hop in forwarded_for.into_iter().rev() {
+        match hop {
+            Some(hop) if trusted_proxies.contains(&hop) => continue,
+            Some(hop) => return Some(hop),
+            // a malformed hop can't be trusted, nor anything before it
+            None => break,
+        }
+    }
+    Some(peer)
+}
 
-    let host_domain = &req
-        .connection_info()
+/// The domain requests are sent to, without port, refusing to shorten its own links
+fn host_domain(req: &HttpRequest) -> String {
+    req.connection_info()
         .host()
-        .split(":")
+        .split(':')
         .take(1)
-        .collect::<String>();
+        .collect::<String>()
+}
+
+/// Who is shortening a link: the client IP address, see `client_ip`, and the user agent
+fn creator(req: &HttpRequest, trusted_proxies: &[IpAddr]) -> Creator {
+    Creator {
+        ip: client_ip(req, trusted_proxies).map(|ip| ip.to_string()),
+        user_agent: req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|user_agent| user_agent.to_str().ok())
+            .map(String::from),
+    }
+}
+
+/// Shortens a URL
+#[utoipa::path(
+    post,
+    path = "/",
+    tag = "links",
+    request_body = ShortenRequest,
+    responses(
+        (status = 200, body = shorty::ShortenerResult),
+        (status = 400, body = ErrorResponse, description = "The URL or the options are invalid"),
+        (status = 403, body = ErrorResponse, description = "The API key is missing or invalid"),
+        (status = 409, body = ErrorResponse, description = "The custom ID is taken"),
+        (status = 429, body = ErrorResponse, description = "The API key is rate limited, or has too many calls in flight, see `Retry-After`"),
+    )
+)]
+pub async fn shorten(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    payload: web::Json<ShortenRequest>,
+) -> HttpResponse {
+    shorten_link(&req, &app_state, &payload).await
+}
+
+/// The query of `GET /shorten`: the fields of `ShortenRequest` but `schedule`. The API key is
+/// better sent in the `X-API-Key` header, keeping it out of access logs.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ShortenQuery {
+    api_key: Option<String>,
+    custom_id: Option<String>,
+    url: String,
+    expires_in: Option<usize>,
+    expires_at: Option<u64>,
+    #[serde(default)]
+    permanent: bool,
+    campaign: Option<String>,
+    redirect_status: Option<u16>,
+    #[serde(default)]
+    preconnect: bool,
+}
+
+/// Shortens a URL as `POST /` does, for tools that can only send GET requests
+#[utoipa::path(
+    get,
+    path = "/shorten",
+    tag = "links",
+    params(ShortenQuery),
+    responses(
+        (status = 200, body = shorty::ShortenerResult),
+        (status = 400, body = ErrorResponse, description = "The URL or the options are invalid"),
+        (status = 403, body = ErrorResponse, description = "The API key is missing or invalid"),
+        (status = 409, body = ErrorResponse, description = "The custom ID is taken"),
+        (status = 429, body = ErrorResponse, description = "The API key is rate limited, or has too many calls in flight, see `Retry-After`"),
+    )
+)]
+pub async fn shorten_query(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    query: web::Query<ShortenQuery>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    let request = ShortenRequest {
+        api_key: api_key_header(&req).or(query.api_key),
+        custom_id: query.custom_id,
+        url: query.url,
+        expires_in: query.expires_in,
+        expires_at: query.expires_at,
+        permanent: query.permanent,
+        campaign: query.campaign,
+        redirect_status: query.redirect_status,
+        schedule: None,
+        preconnect: query.preconnect,
+    };
+    shorten_link(&req, &app_state, &request).await
+}
+
+/// Shortens the URL of `payload`, with a custom ID if any, answering with the link
+async fn shorten_link(
+    req: &HttpRequest,
+    app_state: &AppState,
+    payload: &ShortenRequest,
+) -> HttpResponse {
+    if payload.api_key.is_none() && app_state.api_key_mandatory {
+        return HttpResponse::Forbidden().json(ErrorResponse {
+            err: String::from("Missing API key"),
+        });
+    }
+
+    let api_key = payload.api_key.as_deref();
+
+    let host_domain = host_domain(req);
+
+    let expires_in = match shorty::expires_in(payload.expires_in, payload.expires_at) {
+        Ok(expires_in) => expires_in,
+        Err(err) => return error_response(err),
+    };
+    let options = LinkOptions {
+        expires_in,
+        permanent: payload.permanent,
+        campaign: payload.campaign.clone(),
+        redirect_status: payload.redirect_status,
+        schedule: payload.schedule.clone(),
+        preconnect: payload.preconnect,
+        creator: creator(req, &app_state.trusted_proxies),
+    };
+
+    let shorten_result = match &payload.custom_id {
+        Some(custom_id) => {
+            app_state
+                .shortener
+                .shorten_with_id(
+                    &api_key,
+                    Some(&host_domain),
+                    custom_id,
+                    &payload.url,
+                    &options,
+                )
+                .await
+        }
+        None => {
+            app_state
+                .shortener
+                .shorten(&api_key, Some(&host_domain), &payload.url, &options)
+                .await
+        }
+    };
 
+    match shorten_result {
+        Ok(shorten_result) => HttpResponse::Ok().json(shorten_result),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Shortens many URLs at once, see `Shortener::shorten_batch`. The response lists the result of
+/// each URL, in order: either the link, or an `err`.
+pub async fn shorten_batch(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    payload: web::Json<BatchRequest>,
+) -> HttpResponse {
+    if payload.api_key.is_none() && app_state.api_key_mandatory {
+        return missing_api_key_response();
+    }
+
+    let api_key = payload.api_key.as_deref();
+    let host_domain = host_domain(&req);
+
+    let expires_in = match shorty::expires_in(payload.expires_in, payload.expires_at) {
+        Ok(expires_in) => expires_in,
+        Err(err) => return error_response(err),
+    };
+    let options = LinkOptions {
+        expires_in,
+        permanent: payload.permanent,
+        campaign: payload.campaign.clone(),
+        redirect_status: payload.redirect_status,
+        schedule: payload.schedule.clone(),
+        preconnect: payload.preconnect,
+        creator: creator(&req, &app_state.trusted_proxies),
+    };
+
+    let urls = payload.urls.iter().map(String::as_str).collect::<Vec<_>>();
     match app_state
         .shortener
-        .shorten(&api_key, Some(host_domain), &payload.url)
+        .shorten_batch(&api_key, Some(&host_domain), &urls, &options)
+        .await
     {
-        Ok(shorten_result) => HttpResponse::Ok().json(shorten_result),
-        Err(err) => HttpResponse::InternalServerError().json(ErrorResponse {
-            err: err.to_string(),
-        }),
+        Ok(results) => HttpResponse::Ok().json(
+            results
+                .into_iter()
+                .map(|result| match result {
+                    Ok(link) => BatchResult::Link(link),
+                    Err(err) => BatchResult::Error(ErrorResponse {
+                        err: err.to_string(),
+                    }),
+                })
+                .collect::<Vec<_>>(),
+        ),
+        Err(err) => error_response(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+    use shorty::memory_storage::MemoryStorage;
+
+    use super::*;
+
+    /// An `AppState` storing in memory, with the API key `my key` and a link `abc` shortened with
+    /// it
+    async fn app_state() -> web::Data<AppState> {
+        let storage = MemoryStorage::new();
+        storage.set("API_KEY_my key", "true").await.unwrap();
+        let app_state = AppState::new(Box::new(storage), &Config::new());
+        app_state
+            .shortener
+            .shorten_with_id(
+                &Some("my key"),
+                None,
+                "abc",
+                "https://example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap();
+        web::Data::new(app_state)
+    }
+
+    fn content_type(response: &actix_web::dev::ServiceResponse) -> &str {
+        response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .unwrap()
+            .to_str()
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_card() {
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state().await)
+                .route("/{shorty_id}/card.png", web::get().to(card)),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/abc/card.png").to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("image/png", content_type(&response));
+
+        let req = TestRequest::get().uri("/missing/card.png").to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+
+    #[actix_web::test]
+    async fn test_badge() {
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state().await)
+                .route("/{shorty_id}/badge.svg", web::get().to(badge)),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/abc/badge.svg").to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("image/svg+xml", content_type(&response));
+
+        let req = TestRequest::get().uri("/missing/badge.svg").to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+
+    #[actix_web::test]
+    async fn test_preview() {
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state().await)
+                .route("/preview", web::post().to(preview)),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/preview")
+            .insert_header((header::HOST, "sho.rt"))
+            .set_json(serde_json::json!({"custom_id": "xyz"}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("application/json", content_type(&response));
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!("http://sho.rt/xyz", body["short_url"]);
+
+        let req = TestRequest::post()
+            .uri("/preview")
+            .set_json(serde_json::json!({"custom_id": "abc"}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::CONFLICT, response.status());
+        assert_eq!("application/json", content_type(&response));
+    }
+
+    #[actix_web::test]
+    async fn test_info() {
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state().await)
+                .route("/{shorty_id}/info", web::get().to(info)),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/abc/info")
+            .insert_header(("X-API-Key", "my key"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("application/json", content_type(&response));
+
+        let req = TestRequest::get().uri("/abc/info").to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+        assert_eq!("application/json", content_type(&response));
+
+        // without an admin key configured, the admin API is disabled
+        let req = TestRequest::get()
+            .uri("/abc/info")
+            .insert_header(("X-Admin-Key", "admin key"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+    }
+
+    #[actix_web::test]
+    async fn test_update() {
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state().await)
+                .route("/{shorty_id}", web::put().to(update)),
+        )
+        .await;
+
+        let req = TestRequest::put()
+            .uri("/abc")
+            .set_json(serde_json::json!({"api_key": "my key", "url": "https://example.org"}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("application/json", content_type(&response));
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!("https://example.org", body["url"]);
+
+        let req = TestRequest::put()
+            .uri("/missing")
+            .set_json(serde_json::json!({"api_key": "my key", "url": "https://example.org"}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        assert_eq!("application/json", content_type(&response));
+    }
+
+    #[actix_web::test]
+    async fn test_latency() {
+        let mut config = Config::new();
+        config.latency_beacon = true;
+        let app_state = web::Data::new(AppState::new(Box::new(MemoryStorage::new()), &config));
+        app_state
+            .shortener
+            .shorten_with_id(
+                &None,
+                None,
+                "abc",
+                "https://example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .route("/{shorty_id}", web::get().to(goto))
+                .route("/{shorty_id}/latency", web::post().to(latency)),
+        )
+        .await;
+
+        // the interstitial runs the beacon, allowed by its policy
+        let req = TestRequest::get().uri("/abc+").to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let policy = response
+            .headers()
+            .get("Content-Security-Policy")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(policy.contains("script-src 'sha256-"));
+
+        let req = TestRequest::post()
+            .uri("/abc/latency")
+            .insert_header((header::CONTENT_TYPE, "text/plain;charset=UTF-8"))
+            .set_payload(r#"{"ms":120}"#)
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        let stats = app_state.shortener.stats("abc").await.unwrap();
+        assert_eq!(Some(120), stats.latency.unwrap().average_ms);
+
+        let req = TestRequest::post()
+            .uri("/abc/latency")
+            .set_payload("slow")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        assert_eq!("application/json", content_type(&response));
+
+        let req = TestRequest::post()
+            .uri("/missing/latency")
+            .set_payload(r#"{"failed":true}"#)
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+
+    #[actix_web::test]
+    async fn test_shorten_query() {
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state().await)
+                .route("/shorten", web::get().to(shorten_query)),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/shorten?url=https%3A%2F%2Fexample.com%2Fpath&custom_id=xyz")
+            .insert_header(("X-API-Key", "my key"))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("application/json", content_type(&response));
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!("xyz", body["id"]);
+        assert_eq!("https://example.com/path", body["url"]);
+
+        let req = TestRequest::get()
+            .uri("/shorten?url=https%3A%2F%2Fexample.com")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+        assert_eq!("application/json", content_type(&response));
+
+        let req = TestRequest::get().uri("/shorten").to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let proxy = "10.0.0.1".parse::<IpAddr>().unwrap();
+        let request = |peer: &str| {
+            TestRequest::default()
+                .peer_addr(format!("{}:4000", peer).parse().unwrap())
+                .insert_header(("X-Forwarded-For", "192.0.2.1, 198.51.100.2, 10.0.0.2"))
+                .to_http_request()
+        };
+
+        let ip = |peer, trusted_proxies: &[IpAddr]| {
+            client_ip(&request(peer), trusted_proxies).map(|ip| ip.to_string())
+        };
+        assert_eq!(Some(String::from("10.0.0.1")), ip("10.0.0.1", &[]));
+        assert_eq!(
+            Some(String::from("203.0.113.9")),
+            ip("203.0.113.9", &[proxy])
+        );
+        assert_eq!(Some(String::from("10.0.0.2")), ip("10.0.0.1", &[proxy]));
+        let proxies = [proxy, "10.0.0.2".parse().unwrap()];
+        assert_eq!(Some(String::from("198.51.100.2")), ip("10.0.0.1", &proxies));
     }
 }
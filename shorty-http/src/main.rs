@@ -13,44 +13,37 @@
 // limitations under the License.
 
 use std::env;
+use std::process;
 
-use actix_web::http::Method;
-use actix_web::middleware::cors::Cors;
-use actix_web::middleware::Logger;
-use actix_web::{server, App};
+use shorty_conf::{Config, StorageBackend};
 
-use shorty_conf::Config;
-use shorty_http::AppState;
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let mut config = match Config::from_env() {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("{}", errors);
+            process::exit(1);
+        }
+    };
+    if let Some(storage) = storage_from_args() {
+        config.storage = storage;
+    }
 
-fn main() {
-    env::set_var(
-        "RUST_LOG",
-        env::var("RUST_LOG").unwrap_or_else(|_| String::from("info")),
-    );
-    env_logger::init();
-
-    let config = Config::new();
-    let host = config.host.clone();
-    let port = config.port.clone();
+    shorty_http::server::run(config).await
+}
 
-    server::new(move || {
-        let app_state = AppState::new(
-            &config.redis_host,
-            &config.redis_port,
-            config.id_length,
-            config.id_generation_max_attempts,
-            config.rate_limit_period,
-            config.rate_limit,
-            config.api_key_mandatory,
-        );
+/// Reads the `--storage <redis|memory|sqlite>` command line argument, which takes precedence over
+/// `SHORTENER_STORAGE`
+fn storage_from_args() -> Option<StorageBackend> {
+    let args = env::args().collect::<Vec<_>>();
 
-        App::with_state(app_state)
-            .middleware(Logger::default())
-            .middleware(Cors::default())
-            .route("/{shorty_id}", Method::GET, shorty_http::goto)
-            .route("/", Method::POST, shorty_http::shorten)
-    })
-    .bind(format!("{}:{}", host, port))
-    .unwrap()
-    .run();
+    args.iter()
+        .position(|arg| arg == "--storage")
+        .map(|position| {
+            args.get(position + 1)
+                .expect("missing value of --storage")
+                .parse::<StorageBackend>()
+                .unwrap()
+        })
 }
@@ -0,0 +1,75 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! openapi holds `ApiDoc`, the OpenAPI 3 document of the links and admin API of shorty-http,
+//! served at `/openapi.json` with a Swagger UI at `/docs`, so that API consumers can generate
+//! their clients.
+
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use crate::{admin, ApiKeyRequest, ErrorResponse, ShortenRequest, UpdateRequest};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "shorty-http"),
+    paths(
+        crate::shorten,
+        crate::shorten_query,
+        crate::goto,
+        crate::stats,
+        crate::latency,
+        crate::info,
+        crate::delete,
+        crate::update,
+        admin::create_key,
+        admin::list_keys,
+        admin::revoke_key,
+        admin::set_features,
+        admin::set_verified,
+        admin::stats,
+        admin::snapshot,
+        admin::export,
+        admin::import,
+        admin::sync,
+        admin::list_quarantined,
+        admin::release_quarantined,
+        admin::reject_quarantined,
+        admin::list_takedowns,
+        admin::get_takedown,
+        admin::list_blocked_domains,
+        admin::block_domain,
+        admin::unblock_domain,
+    ),
+    components(schemas(ShortenRequest, ApiKeyRequest, UpdateRequest, ErrorResponse)),
+    modifiers(&AdminKey),
+    tags(
+        (name = "links", description = "Shortening and following links"),
+        (name = "admin", description = "Operating the instance, with the admin key of the configuration"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Declares the `X-Admin-Key` header the admin routes are protected by
+struct AdminKey;
+
+impl Modify for AdminKey {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "admin_key",
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-Admin-Key"))),
+            );
+        }
+    }
+}
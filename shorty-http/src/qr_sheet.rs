@@ -0,0 +1,222 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! qr_sheet holds the rendering of QR code sheets: A4 PDF pages of QR codes of short URLs, each
+//! labeled with its URL and framed by a dashed line to cut along, such as to print the codes of
+//! the tables of an event at once.
+//!
+//! The PDF is written by hand: codes are drawn as rectangles and labels with the standard Courier
+//! font, which every PDF reader has, so that no font is embedded. Characters outside of ASCII are
+//! drawn as `?`.
+
+use qrcode::{Color, QrCode};
+
+/// The most codes a sheet can have, about 40 pages
+pub const MAX_QR_SHEET_CODES: usize = 500;
+
+/// A4, in points
+const PAGE_WIDTH: f32 = 595.0;
+const PAGE_HEIGHT: f32 = 842.0;
+const COLUMNS: usize = 3;
+const ROWS: usize = 4;
+const MARGIN: f32 = 36.0;
+const QR_CODE_SIZE: f32 = 140.0;
+const LABEL_SIZE: f32 = 11.0;
+const MIN_LABEL_SIZE: f32 = 7.0;
+/// The width of the characters of Courier, relative to their size
+const COURIER_WIDTH: f32 = 0.6;
+
+/// Renders the QR codes of `short_urls` as a PDF, twelve codes per page, in order
+pub fn render(short_urls: &[String]) -> Result<Vec<u8>, String> {
+    if short_urls.is_empty() {
+        return Err(String::from("no QR codes to render"));
+    }
+    let pages = short_urls
+        .chunks(COLUMNS * ROWS)
+        .map(|page| {
+            let mut content = String::new();
+            for (index, short_url) in page.iter().enumerate() {
+                draw_cell(&mut content, short_url, index % COLUMNS, index / COLUMNS)?;
+            }
+            Ok(content)
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    Ok(write_pdf(&pages))
+}
+
+/// Draws the cell of `short_url` at `column`, `row` of the page: its cutting frame, its QR code
+/// and its label centered below
+fn draw_cell(
+    content: &mut String,
+    short_url: &str,
+    column: usize,
+    row: usize,
+) -> Result<(), String> {
+    let cell_width = (PAGE_WIDTH - 2.0 * MARGIN) / COLUMNS as f32;
+    let cell_height = (PAGE_HEIGHT - 2.0 * MARGIN) / ROWS as f32;
+    // PDF pages start at the bottom left corner
+    let left = MARGIN + column as f32 * cell_width;
+    let bottom = PAGE_HEIGHT - MARGIN - (row + 1) as f32 * cell_height;
+
+    content.push_str(&format!(
+        "q 0.7 G 0.5 w [3 3] 0 d {:.2} {:.2} {:.2} {:.2} re S Q\n",
+        left, bottom, cell_width, cell_height
+    ));
+
+    let qr_left = left + (cell_width - QR_CODE_SIZE) / 2.0;
+    let qr_bottom = bottom + cell_height - QR_CODE_SIZE - 8.0;
+    draw_qr_code(content, short_url, qr_left, qr_bottom)?;
+
+    let label = short_url
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let (label, size) = fit_label(label, cell_width - 16.0);
+    let label_width = label.chars().count() as f32 * size * COURIER_WIDTH;
+    content.push_str(&format!(
+        "BT /F1 {:.1} Tf {:.2} {:.2} Td ({}) Tj ET\n",
+        size,
+        left + (cell_width - label_width) / 2.0,
+        qr_bottom - LABEL_SIZE,
+        escape_text(&label)
+    ));
+    Ok(())
+}
+
+/// Draws the QR code of `data` in a square of side `QR_CODE_SIZE` with its bottom left corner at
+/// `x`, `y`, the dark modules of each row merged into runs
+fn draw_qr_code(content: &mut String, data: &str, x: f32, y: f32) -> Result<(), String> {
+    let code = QrCode::new(data.as_bytes()).map_err(|err| err.to_string())?;
+    let modules = code.width();
+    let colors = code.to_colors();
+    // the quiet zone around the code is 4 modules wide
+    let module_size = QR_CODE_SIZE / (modules + 8) as f32;
+    let x = x + 4.0 * module_size;
+    let y = y + 4.0 * module_size;
+
+    for (row, colors) in colors.chunks(modules).enumerate() {
+        let top = y + modules as f32 * module_size - (row + 1) as f32 * module_size;
+        let mut column = 0;
+        while column < modules {
+            if colors[column] == Color::Light {
+                column += 1;
+                continue;
+            }
+            let start = column;
+            while column < modules && colors[column] == Color::Dark {
+                column += 1;
+            }
+            content.push_str(&format!(
+                "{:.2} {:.2} {:.2} {:.2} re\n",
+                x + start as f32 * module_size,
+                top,
+                (column - start) as f32 * module_size,
+                module_size
+            ));
+        }
+    }
+    content.push_str("f\n");
+    Ok(())
+}
+
+/// Returns `label` and the font size to draw it within `max_width`: smaller down to
+/// `MIN_LABEL_SIZE`, and truncated if still too wide
+fn fit_label(label: &str, max_width: f32) -> (String, f32) {
+    let chars = label.chars().count() as f32;
+    let size = (max_width / (chars * COURIER_WIDTH)).clamp(MIN_LABEL_SIZE, LABEL_SIZE);
+
+    let max_chars = (max_width / (size * COURIER_WIDTH)) as usize;
+    if label.chars().count() > max_chars {
+        let truncated = label
+            .chars()
+            .take(max_chars.saturating_sub(3))
+            .collect::<String>();
+        (format!("{}...", truncated), size)
+    } else {
+        (label.to_owned(), size)
+    }
+}
+
+/// Escapes `text` as a PDF string, replacing characters outside of printable ASCII with `?`
+fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '(' | ')' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            ' '..='~' => escaped.push(c),
+            _ => escaped.push('?'),
+        }
+    }
+    escaped
+}
+
+/// Writes a PDF document with a page for each content stream of `pages`
+fn write_pdf(pages: &[String]) -> Vec<u8> {
+    // objects 1 and 2 are the catalog and the page tree, 3 the font, then each page is followed
+    // by its content stream
+    let page_ids = (0..pages.len())
+        .map(|page| 4 + 2 * page)
+        .collect::<Vec<_>>();
+    let mut objects = vec![
+        String::from("<< /Type /Catalog /Pages 2 0 R >>"),
+        format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            page_ids
+                .iter()
+                .map(|id| format!("{} 0 R", id))
+                .collect::<Vec<_>>()
+                .join(" "),
+            pages.len()
+        ),
+        String::from("<< /Type /Font /Subtype /Type1 /BaseFont /Courier >>"),
+    ];
+    for (page_id, content) in page_ids.iter().zip(pages) {
+        objects.push(format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+            PAGE_WIDTH,
+            PAGE_HEIGHT,
+            page_id + 1
+        ));
+        objects.push(format!(
+            "<< /Length {} >>\nstream\n{}endstream",
+            content.len(),
+            content
+        ));
+    }
+
+    let mut pdf = String::from("%PDF-1.4\n");
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (index, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf.push_str(&format!("{} 0 obj\n{}\nendobj\n", index + 1, object));
+    }
+
+    let xref = pdf.len();
+    pdf.push_str(&format!(
+        "xref\n0 {}\n0000000000 65535 f \n",
+        objects.len() + 1
+    ));
+    for offset in offsets {
+        pdf.push_str(&format!("{:010} 00000 n \n", offset));
+    }
+    pdf.push_str(&format!(
+        "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+        objects.len() + 1,
+        xref
+    ));
+    pdf.into_bytes()
+}
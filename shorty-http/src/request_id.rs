@@ -0,0 +1,72 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! request_id holds the correlation IDs of requests, read from the `X-Request-Id` header set by
+//! clients and proxies, or generated, so that a request can be traced across the services it goes
+//! through.
+//!
+//! Each request is served in a `request` span carrying its ID: every log written while serving
+//! it, those of shorty included, carries the ID too. The ID is sent back in the `X-Request-Id`
+//! header of the response.
+
+use std::time::Duration;
+
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use tracing::Span;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// The max length of the IDs read from requests: longer ones are replaced, to keep logs small
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Returns the ID of a request: its `X-Request-Id`, if made of at most 128 visible ASCII
+/// characters, or a new random one
+pub fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LENGTH
+                && id.bytes().all(|byte| byte.is_ascii_graphic())
+        })
+        .map(str::to_owned)
+        .unwrap_or_else(|| nanoid::nanoid!())
+}
+
+/// Returns the span a request is served in. The query string is left out, as it may hold an API
+/// key.
+pub fn request_span(req: &ServiceRequest, request_id: &str) -> Span {
+    tracing::info_span!(
+        "request",
+        request_id,
+        method = %req.method(),
+        path = req.path(),
+    )
+}
+
+/// Sends the ID of a request back with its response, and logs the response, in place of an access
+/// log
+pub fn finish<B>(res: &mut ServiceResponse<B>, request_id: &str, elapsed: Duration) {
+    if let Ok(value) = HeaderValue::from_str(request_id) {
+        res.headers_mut()
+            .insert(HeaderName::from_static("x-request-id"), value);
+    }
+
+    tracing::info!(
+        status = res.status().as_u16(),
+        elapsed_ms = elapsed.as_secs_f64() * 1000.0,
+        "request served"
+    );
+}
@@ -0,0 +1,370 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! server holds `run`, serving shorty-http with a `Config`: the `shorty-http` binary runs it with
+//! the configuration of the environment, other binaries with their own defaults.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::process;
+use std::sync::Arc;
+use std::time::Instant;
+
+use actix_cors::Cors;
+use actix_web::dev::Service;
+use actix_web::middleware::Compress;
+use actix_web::{web, App, HttpServer};
+use rustls::crypto::ring;
+use rustls::ServerConfig;
+use tracing::Instrument;
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
+
+use shorty::content_security_policy::CSP_REPORT_PATH;
+use shorty_conf::Config;
+
+use crate::deadline::DeadlineExceeded;
+use crate::openapi::ApiDoc;
+use crate::supervisor::Supervisor;
+use crate::{
+    admin, api, ascii_json, campaign, compression, deadline, integrations, request_id, AppState,
+};
+
+/// Sets up logging and storage, then serves shorty-http on the host and port of `config` until
+/// the server is stopped by `SIGTERM` or `Ctrl-C`.
+///
+/// On stop, the server stops accepting connections and waits for the requests in flight to
+/// complete, at most for the shutdown timeout of `config`, before stopping the background tasks
+/// and closing the storage.
+pub async fn run(config: Config) -> std::io::Result<()> {
+    shorty_bootstrap::init_logging(&config);
+    log::info!(
+        "Running with profile {:?} and {:?} storage, {} workers of at most {} connections, \
+         {:?} keep-alive and a backlog of {}",
+        config.profile,
+        config.storage,
+        config.workers,
+        config.max_connections,
+        config.keep_alive,
+        config.backlog
+    );
+
+    // read before connecting to the storage, so that an invalid certificate fails fast
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(tls_config(cert, key)?),
+        _ => None,
+    };
+
+    let storage = shorty_bootstrap::storage(&config).await;
+    let app_state = web::Data::new(AppState::new(storage, &config));
+
+    if !app_state.check_eviction_policy().await && config.refuse_unsafe_eviction_policy {
+        log::error!("refusing to start: Redis eviction policy may delete shortened URLs");
+        process::exit(1);
+    }
+
+    // owns the background tasks, restarted when they fail and stopped once the server is
+    let mut supervisor = Supervisor::new(config.task_restart_backoff, config.task_shutdown_timeout);
+    let tasks_health = web::Data::new(supervisor.health());
+
+    // counts redirects off the request path, recording the clicks still queued on exit
+    let clicks_state = app_state.clone();
+    supervisor.spawn("clicks", move |mut shutdown| {
+        let state = clicks_state.clone();
+        async move {
+            state
+                .shortener
+                .record_queued_clicks(shutdown.requested())
+                .await;
+            Ok(())
+        }
+    });
+
+    // delivers the events of the outbox to the events webhook, if any
+    let events_state = app_state.clone();
+    let events_dispatch_interval = config.events_dispatch_interval;
+    supervisor.spawn("events", move |mut shutdown| {
+        let state = events_state.clone();
+        async move {
+            state
+                .shortener
+                .dispatch_events(events_dispatch_interval, shutdown.requested())
+                .await;
+            Ok(())
+        }
+    });
+
+    let compression_min_size = config.compression_min_size.bytes();
+    let request_timeout = config.request_timeout;
+    let ascii_json = config.ascii_json;
+    let cors_origins = config.cors_origins.clone();
+    let cors_methods = config.cors_methods.clone();
+    let cors_headers = config.cors_headers.clone();
+    // kept to close the storage once the server has stopped
+    let stopped_state = app_state.clone();
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(app_state.clone())
+            .app_data(tasks_health.clone())
+            .wrap_fn(move |req, srv| {
+                let deadline = deadline::deadline(req.headers(), request_timeout);
+                let response = srv.call(req);
+                async move {
+                    match deadline {
+                        Some(deadline) => deadline
+                            .run(response)
+                            .await
+                            .unwrap_or_else(|_| Err(DeadlineExceeded::abandoned())),
+                        None => response.await,
+                    }
+                }
+            })
+            .wrap_fn(move |req, srv| {
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    if ascii_json {
+                        ascii_json::rewrite(response).await
+                    } else {
+                        Ok(response.map_into_boxed_body())
+                    }
+                }
+            })
+            .wrap_fn(move |req, srv| {
+                let response = srv.call(req);
+                async move {
+                    let mut response = response.await?;
+                    compression::skip_compression(&mut response, compression_min_size);
+                    Ok(response)
+                }
+            })
+            .wrap(Compress::default())
+            .wrap_fn(|req, srv| {
+                let request_id = request_id::request_id(req.headers());
+                let span = request_id::request_span(&req, &request_id);
+                let start = Instant::now();
+                let response = span.in_scope(|| srv.call(req));
+                async move {
+                    let mut response = response.await?;
+                    request_id::finish(&mut response, &request_id, start.elapsed());
+                    Ok(response)
+                }
+                .instrument(span)
+            })
+            .wrap(cors(&cors_origins, &cors_methods, &cors_headers))
+            .route("/healthz", web::get().to(crate::healthz))
+            .route("/readyz", web::get().to(crate::readyz))
+            .route("/admin/keys", web::get().to(admin::list_keys))
+            .route("/admin/keys", web::post().to(admin::create_key))
+            .route("/admin/keys/{key}", web::delete().to(admin::revoke_key))
+            .route(
+                "/admin/keys/{key}/features",
+                web::put().to(admin::set_features),
+            )
+            .route(
+                "/admin/keys/{key}/verified",
+                web::put().to(admin::set_verified),
+            )
+            .route("/admin/stats", web::get().to(admin::stats))
+            .route("/admin/snapshot", web::get().to(admin::snapshot))
+            .route("/admin/export", web::get().to(admin::export))
+            .service(
+                web::resource("/admin/import")
+                    .app_data(web::PayloadConfig::new(admin::IMPORT_BODY_LIMIT))
+                    .route(web::post().to(admin::import)),
+            )
+            .route("/internal/sync", web::get().to(admin::sync))
+            .route("/admin/quarantine", web::get().to(admin::list_quarantined))
+            .route(
+                "/admin/quarantine/{id}/release",
+                web::post().to(admin::release_quarantined),
+            )
+            .route(
+                "/admin/quarantine/{id}",
+                web::delete().to(admin::reject_quarantined),
+            )
+            .route("/admin/takedowns", web::get().to(admin::list_takedowns))
+            .route(
+                "/admin/takedowns/{ticket}",
+                web::get().to(admin::get_takedown),
+            )
+            .route(
+                "/admin/blocked-domains",
+                web::get().to(admin::list_blocked_domains),
+            )
+            .route(
+                "/admin/blocked-domains/{domain}",
+                web::put().to(admin::block_domain),
+            )
+            .route(
+                "/admin/blocked-domains/{domain}",
+                web::delete().to(admin::unblock_domain),
+            )
+            .route("/api/v1/links", web::get().to(api::list_links))
+            .route("/api/v1/links", web::post().to(api::create_link))
+            .route("/api/v1/links/{id}/events", web::get().to(api::link_events))
+            .route("/api/v1/defaults", web::get().to(api::link_defaults))
+            .route("/api/v1/defaults", web::put().to(api::set_link_defaults))
+            .route("/api/v1/qr/batch", web::post().to(api::qr_sheet))
+            .route("/api/v1/takedowns", web::post().to(api::create_takedown))
+            .route(
+                "/api/v1/domains/{domain}/links",
+                web::get().to(api::list_domain_links),
+            )
+            .route("/integrations/slack", web::post().to(integrations::slack))
+            .route(
+                "/integrations/discord",
+                web::post().to(integrations::discord),
+            )
+            // registered before "/{shorty_id}", which would match "/campaigns" as well
+            .route("/campaigns", web::get().to(campaign::list))
+            .route("/campaigns", web::post().to(campaign::create))
+            .route("/campaigns/{name}/stats", web::get().to(campaign::stats))
+            .route("/campaigns/{name}/pause", web::post().to(campaign::pause))
+            .route("/campaigns/{name}/resume", web::post().to(campaign::resume))
+            .route("/campaigns/{name}/expire", web::post().to(campaign::expire))
+            .route("/campaigns/{name}/rotate", web::post().to(campaign::rotate))
+            .route("/links", web::get().to(crate::list_links))
+            .route("/abuse", web::get().to(crate::abuse))
+            .route(CSP_REPORT_PATH, web::post().to(crate::csp_report))
+            .route("/shorten", web::get().to(crate::shorten_query))
+            .service(web::redirect("/docs", "/docs/"))
+            .service(SwaggerUi::new("/docs/{_:.*}").url("/openapi.json", ApiDoc::openapi()))
+            .service(
+                web::resource("/batch")
+                    .app_data(web::JsonConfig::default().limit(crate::BATCH_BODY_LIMIT))
+                    .route(web::post().to(crate::shorten_batch)),
+            )
+            .route("/{shorty_id}", web::get().to(crate::goto))
+            .route("/{shorty_id}", web::delete().to(crate::delete))
+            .route("/{shorty_id}", web::put().to(crate::update))
+            .route("/{shorty_id}/stats", web::get().to(crate::stats))
+            .route("/{shorty_id}/info", web::get().to(crate::info))
+            .route("/{shorty_id}/latency", web::post().to(crate::latency))
+            .route(
+                "/{shorty_id}/challenge",
+                web::post().to(crate::answer_challenge),
+            )
+            .route("/{shorty_id}/card.png", web::get().to(crate::card))
+            .route("/{shorty_id}/badge.svg", web::get().to(crate::badge))
+            .route("/", web::post().to(crate::shorten))
+            .route("/preview", web::post().to(crate::preview))
+    })
+    .workers(config.workers)
+    .max_connections(config.max_connections)
+    // zero disables keep-alive
+    .keep_alive(config.keep_alive)
+    // how long the requests in flight have to complete once the server is stopped
+    .shutdown_timeout(config.shutdown_timeout.as_secs())
+    // set before binding, which creates the listening sockets
+    .backlog(config.backlog);
+
+    let address = format!("{}:{}", config.host, config.port);
+    let server = match tls {
+        Some(tls) => {
+            log::info!("Serving HTTPS on {}", address);
+            server.bind_rustls_0_23(address, tls)?
+        }
+        None => server.bind(address)?,
+    };
+    server.run().await?;
+
+    log::info!("server stopped, stopping the background tasks and closing the storage");
+    supervisor.shutdown().await;
+    stopped_state.shortener.close().await;
+    Ok(())
+}
+
+/// Builds the TLS configuration serving the certificate chain in the PEM file at `cert_path`, with
+/// the private key in the PEM file at `key_path`. actix offers HTTP/2 to the clients supporting it.
+fn tls_config(cert_path: &str, key_path: &str) -> io::Result<ServerConfig> {
+    let invalid = |path: &str, reason: &dyn std::fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid TLS file {}: {}", path, reason),
+        )
+    };
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(invalid(cert_path, &"no certificate found"));
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| invalid(key_path, &"no private key found"))?;
+
+    ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|err| invalid(key_path, &err))
+}
+
+/// Builds the CORS middleware allowing browsers on `origins` to call shorty-http with `methods`
+/// and `headers`, `*` allowing any. Responses expose all their headers, such as `Retry-After`, but
+/// credentials aren't allowed: API and admin keys are sent in headers, not cookies.
+fn cors(origins: &[String], methods: &[String], headers: &[String]) -> Cors {
+    let any = |values: &[String]| values.iter().any(|value| value == "*");
+
+    let mut cors = Cors::default().expose_any_header().max_age(3600);
+    cors = if any(origins) {
+        cors.allow_any_origin()
+    } else {
+        origins
+            .iter()
+            .fold(cors, |cors, origin| cors.allowed_origin(origin))
+    };
+    cors = if any(methods) {
+        cors.allow_any_method()
+    } else {
+        cors.allowed_methods(methods.iter().map(String::as_str))
+    };
+    if any(headers) {
+        cors.allow_any_header()
+    } else {
+        cors.allowed_headers(headers.iter().map(String::as_str))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shorty::content_security_policy::CSP_REPORT_PATH;
+    use shorty::RESERVED_IDS;
+
+    /// Reads the first segment of every fixed path registered by `run`, such as `admin` of
+    /// `/admin/keys`, from the source itself: a route added without reserving its segment would
+    /// shadow links with the same ID
+    #[test]
+    fn test_routes_reserved() {
+        let source = include_str!("server.rs");
+        let (routes, _) = source.split_once("#[cfg(test)]").unwrap();
+        let mut segments = routes
+            .split('"')
+            .skip(1)
+            .step_by(2)
+            .filter_map(|literal| literal.strip_prefix('/'))
+            .map(|path| path.split('/').next().unwrap())
+            .filter(|segment| !segment.is_empty() && !segment.starts_with('{'))
+            .collect::<Vec<_>>();
+        segments.push(CSP_REPORT_PATH.trim_start_matches('/'));
+
+        assert!(segments.contains(&"admin"));
+        for segment in segments {
+            assert!(
+                RESERVED_IDS.contains(&segment),
+                "/{} is not reserved",
+                segment
+            );
+        }
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! supervisor holds `Supervisor`, owning the background tasks of shorty-http: it restarts the
+//! tasks that fail or panic, tracks their health for `/readyz` and stops them on exit.
+
+use std::cmp;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+/// The longest wait before restarting a task that keeps failing
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+
+/// `Supervisor` runs background tasks until shorty-http exits.
+///
+/// A task failing, returning early or panicking is restarted after a backoff, doubled at each
+/// consecutive failure up to a minute, and reset once the task runs longer than that.
+///
+/// On `shutdown`, tasks are stopped one by one, in the reverse order they were spawned: a task can
+/// rely on the tasks spawned before it until it's stopped.
+pub struct Supervisor {
+    tasks: Vec<Task>,
+    health: TasksHealth,
+    restart_backoff: Duration,
+    shutdown_timeout: Duration,
+}
+
+struct Task {
+    name: String,
+    shutdown: watch::Sender<bool>,
+    handle: JoinHandle<()>,
+}
+
+/// `Shutdown` is given to each supervised task, telling it when to stop
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Completes once the task has to stop, meant to be awaited along with the work of the task,
+    /// such as with `tokio::select!`
+    pub async fn requested(&mut self) {
+        // an error means the supervisor is gone: stop as well
+        let _ = self.0.wait_for(|requested| *requested).await;
+    }
+
+    /// Tells whether the task has to stop
+    pub fn is_requested(&self) -> bool {
+        *self.0.borrow()
+    }
+}
+
+/// The health of a supervised task
+#[derive(Clone, Debug, Serialize)]
+pub struct TaskHealth {
+    pub name: String,
+    /// `false` while the task is waiting to be restarted
+    pub healthy: bool,
+    pub restarts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// `TasksHealth` is a shared view of the health of the supervised tasks, to be given to the
+/// handlers reporting it
+#[derive(Clone, Default)]
+pub struct TasksHealth(Arc<Mutex<Vec<TaskHealth>>>);
+
+impl TasksHealth {
+    /// Returns the health of each task, in the order they were spawned
+    pub fn tasks(&self) -> Vec<TaskHealth> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Tells whether all the tasks are running
+    pub fn is_healthy(&self) -> bool {
+        self.0.lock().unwrap().iter().all(|task| task.healthy)
+    }
+
+    fn update(&self, name: &str, update: impl FnOnce(&mut TaskHealth)) {
+        let mut tasks = self.0.lock().unwrap();
+        if let Some(task) = tasks.iter_mut().find(|task| task.name == name) {
+            update(task);
+        }
+    }
+}
+
+impl Supervisor {
+    /// Creates a new Supervisor
+    ///
+    /// `restart_backoff` is the wait before restarting a task the first time it fails.
+    ///
+    /// `shutdown_timeout` is how long `shutdown` waits for each task to stop, before aborting it.
+    pub fn new(restart_backoff: Duration, shutdown_timeout: Duration) -> Supervisor {
+        Supervisor {
+            tasks: Vec::new(),
+            health: TasksHealth::default(),
+            restart_backoff,
+            shutdown_timeout,
+        }
+    }
+
+    /// Returns the health of the supervised tasks, kept up to date as they fail and restart
+    pub fn health(&self) -> TasksHealth {
+        self.health.clone()
+    }
+
+    /// Spawns a background task named `name`. `task` is called to start it, and called again to
+    /// restart it: the task is expected to run until its `Shutdown` is requested, and then return
+    /// `Ok`.
+    pub fn spawn<F, Fut>(&mut self, name: &str, task: F)
+    where
+        F: Fn(Shutdown) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let (shutdown, receiver) = watch::channel(false);
+        self.health.0.lock().unwrap().push(TaskHealth {
+            name: name.to_owned(),
+            healthy: true,
+            restarts: 0,
+            last_error: None,
+        });
+
+        let handle = tokio::spawn(supervise(
+            name.to_owned(),
+            task,
+            Shutdown(receiver),
+            self.health.clone(),
+            self.restart_backoff,
+        ));
+
+        self.tasks.push(Task {
+            name: name.to_owned(),
+            shutdown,
+            handle,
+        });
+    }
+
+    /// Stops the tasks in the reverse order they were spawned, waiting for each one to return
+    pub async fn shutdown(self) {
+        for task in self.tasks.into_iter().rev() {
+            log::info!("stopping task {}", task.name);
+            let _ = task.shutdown.send(true);
+
+            let abort = task.handle.abort_handle();
+            if timeout(self.shutdown_timeout, task.handle).await.is_err() {
+                log::warn!(
+                    "task {} didn't stop within {:?}, aborting it",
+                    task.name,
+                    self.shutdown_timeout
+                );
+                abort.abort();
+            }
+        }
+    }
+}
+
+/// Runs `task` until shutdown, restarting it when it stops on its own
+async fn supervise<F, Fut>(
+    name: String,
+    task: F,
+    mut shutdown: Shutdown,
+    health: TasksHealth,
+    restart_backoff: Duration,
+) where
+    F: Fn(Shutdown) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), String>> + Send + 'static,
+{
+    let mut backoff = restart_backoff;
+    loop {
+        let started = Instant::now();
+        // spawned on its own, so that a panic is reported here instead of ending the supervision
+        let result = tokio::spawn(task(shutdown.clone())).await;
+        if shutdown.is_requested() {
+            return;
+        }
+
+        let error = match result {
+            Ok(Ok(())) => String::from("stopped before shutdown"),
+            Ok(Err(err)) => err,
+            Err(err) => format!("panicked: {}", err),
+        };
+        if started.elapsed() > MAX_RESTART_BACKOFF {
+            backoff = restart_backoff;
+        }
+        log::error!(
+            "task {} failed, restarting in {:?}: {}",
+            name,
+            backoff,
+            error
+        );
+        health.update(&name, |task| {
+            task.healthy = false;
+            task.restarts += 1;
+            task.last_error = Some(error);
+        });
+
+        tokio::select! {
+            _ = sleep(backoff) => {}
+            _ = shutdown.requested() => return,
+        }
+        backoff = cmp::min(backoff * 2, MAX_RESTART_BACKOFF);
+        health.update(&name, |task| task.healthy = true);
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! shorty-standalone runs shorty-http with the `standalone` profile: links are stored in a local
+//! SQLite file, API keys are optional and there is no rate limit, so that `shorty` alone gives a
+//! working shortener, for evaluation and small personal deployments.
+//!
+//! Every variable of shorty-conf still applies, and a few command line arguments override them:
+//! see `USAGE`. `shorty qr-sheet` prints a PDF sheet of the QR codes of links instead.
+
+use std::env;
+use std::io::{self, Write};
+use std::process;
+
+use shorty_conf::{Config, StorageBackend};
+use shorty_http::qr_sheet;
+
+const USAGE: &str = "Usage: shorty [--port <port>] [--host <host>] [--db <path>] [--storage <sqlite|memory|redis>]
+       shorty qr-sheet [--base-url <url>] <id>... > qr-codes.pdf
+
+Runs a URL shortener on http://127.0.0.1:8088, storing links in shorty.db.
+Shorten a link with:
+
+    curl -X POST http://127.0.0.1:8088/ -H 'Content-Type: application/json' -d '{\"url\":\"https://example.com/\"}'
+
+Every SHORTENER_* environment variable of shorty-http applies as well.
+
+qr-sheet prints a PDF with the QR codes of the short URLs of the IDs, twelve per A4 page,
+such as the table codes of an event. Short URLs start with --base-url, defaulting to
+http://<host>:<port>/. IDs are not looked up.";
+
+fn main() -> std::io::Result<()> {
+    // set before the runtime starts any thread
+    if env::var_os("SHORTENER_ENV").is_none() {
+        env::set_var("SHORTENER_ENV", "standalone");
+    }
+
+    let mut config = match Config::from_env() {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("{}", errors);
+            process::exit(1);
+        }
+    };
+    let mut args = env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("qr-sheet") {
+        args.next();
+        if let Err(err) = print_qr_sheet(&config, args) {
+            eprintln!("{}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
+        return Ok(());
+    }
+    if let Err(err) = apply_args(&mut config, args) {
+        eprintln!("{}\n\n{}", err, USAGE);
+        process::exit(2);
+    }
+
+    match config.storage {
+        StorageBackend::Sqlite => eprintln!(
+            "shorty is running on http://{}:{}, storing links in {}",
+            config.host, config.port, config.sqlite_path
+        ),
+        storage => eprintln!(
+            "shorty is running on http://{}:{}, with {:?} storage",
+            config.host, config.port, storage
+        ),
+    }
+
+    actix_web::rt::System::new().block_on(shorty_http::server::run(config))
+}
+
+/// Overrides `config` with the command line arguments, printing the usage on `--help`
+fn apply_args(config: &mut Config, mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            println!("{}", USAGE);
+            process::exit(0);
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value of {}", arg))?;
+        match arg.as_str() {
+            "--port" => config.port = value,
+            "--host" => config.host = value,
+            "--db" => {
+                config.sqlite_path = value;
+                config.storage = StorageBackend::Sqlite;
+            }
+            "--storage" => config.storage = value.parse::<StorageBackend>()?,
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+    Ok(())
+}
+
+/// Prints the QR code sheet of the IDs listed by the arguments of `qr-sheet` to stdout, see
+/// `shorty_http::qr_sheet`
+fn print_qr_sheet(config: &Config, mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut base_url = format!("http://{}:{}/", config.host, config.port);
+    let mut ids = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            "--base-url" => {
+                base_url = args
+                    .next()
+                    .ok_or_else(|| String::from("missing value of --base-url"))?
+            }
+            _ => ids.push(arg),
+        }
+    }
+    if ids.is_empty() {
+        return Err(String::from("missing IDs of qr-sheet"));
+    }
+    if !base_url.ends_with('/') {
+        base_url.push('/');
+    }
+
+    let short_urls = ids
+        .iter()
+        .map(|id| format!("{}{}", base_url, id))
+        .collect::<Vec<_>>();
+    let pdf = qr_sheet::render(&short_urls)?;
+    io::stdout()
+        .write_all(&pdf)
+        .map_err(|err| format!("unable to write the sheet: {}", err))
+}
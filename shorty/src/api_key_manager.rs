@@ -0,0 +1,480 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! api_key_manager holds `ApiKeyManager`, creating, listing and revoking the API keys verified by
+//! `Shortener::verify_key`, and the `Feature`s each key has access to
+
+use std::sync::Arc;
+
+use crate::link_defaults::defaults_key;
+use crate::rate_limiter::limit_key;
+use crate::storage::Storage;
+use crate::{now, ShortenerError};
+
+/// The set of the API keys created by `ApiKeyManager`
+const API_KEYS: &str = "API_KEYS";
+
+const KEY_LENGTH: usize = 32;
+
+const KEY_ALPHABET: [char; 62] = [
+    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's',
+    't', 'u', 'v', 'w', 'x', 'y', 'z', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L',
+    'M', 'N', 'O', 'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z', '0', '1', '2', '3', '4',
+    '5', '6', '7', '8', '9',
+];
+
+/// A feature API keys may be restricted from, so that a hosted deployment can offer different
+/// tiers: see `ApiKeyManager`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// Shortening links with a custom ID, see `Shortener::shorten_with_id`
+    CustomIds,
+    /// Creating and managing campaigns, and shortening links into them, see `campaign`
+    Campaigns,
+    /// Reading the statistics of campaigns, see `Shortener::campaign_stats`
+    Analytics,
+    /// Shortening many URLs at once, see `Shortener::shorten_batch`
+    Batch,
+}
+
+impl Feature {
+    /// Every feature
+    pub const ALL: [Feature; 4] = [
+        Feature::CustomIds,
+        Feature::Campaigns,
+        Feature::Analytics,
+        Feature::Batch,
+    ];
+
+    /// The name of the feature, as stored and serialized
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::CustomIds => "custom_ids",
+            Feature::Campaigns => "campaigns",
+            Feature::Analytics => "analytics",
+            Feature::Batch => "batch",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Feature> {
+        Feature::ALL
+            .iter()
+            .copied()
+            .find(|feature| feature.name() == name)
+    }
+}
+
+/// The key storing the features of an API key
+pub(crate) fn features_key(api_key: &str) -> String {
+    format!("FEATURES_API_KEY_{}", api_key)
+}
+
+/// The key marking an API key as verified, see `ApiKeyManager::set_verified`
+pub(crate) fn verified_key(api_key: &str) -> String {
+    format!("VERIFIED_API_KEY_{}", api_key)
+}
+
+/// Parses the features of an API key, stored comma separated. Unknown features are skipped.
+pub(crate) fn parse_features(features: &str) -> Vec<Feature> {
+    features.split(',').filter_map(Feature::from_name).collect()
+}
+
+fn format_features(features: &[Feature]) -> String {
+    features
+        .iter()
+        .map(|feature| feature.name())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// A newly created API key, echoing its expiration, rate limit and features, if any
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ApiKey {
+    pub key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub features: Option<Vec<Feature>>,
+}
+
+/// `ApiKeyManager` manages API keys on a `Storage`.
+///
+/// An API key is stored as `API_KEY_<api key>`, assigned `true`, and expires with the key. Keys
+/// created by the manager are also tracked in the `API_KEYS` set, so that they can be listed: keys
+/// inserted by hand are still valid, but not listed.
+///
+/// A key may have its own rate limit, see `RateLimiter`, expiring with the key.
+///
+/// A key may be restricted to some features, stored comma separated in
+/// `FEATURES_API_KEY_<api key>`: calls needing any other feature fail. Keys without features
+/// stored, such as keys created before features existed, have access to all of them.
+///
+/// A key may be verified, stored as `VERIFIED_API_KEY_<api key>` assigned `true`: its links are
+/// marked as coming from a verified publisher, see `Redirect::verified`.
+///
+/// The link defaults of a key, see `link_defaults`, are deleted with the key.
+pub struct ApiKeyManager {
+    storage: Arc<dyn Storage>,
+}
+
+impl ApiKeyManager {
+    /// Creates a new ApiKeyManager
+    pub fn new(storage: Arc<dyn Storage>) -> ApiKeyManager {
+        ApiKeyManager { storage }
+    }
+
+    /// Creates a new API key, generated with a cryptographically secure random number generator.
+    /// If `expires_in` is present, the key stops being valid after that many seconds.
+    ///
+    /// If `rate_limit` is present, it replaces the default rate limit for the key.
+    ///
+    /// If `features` is present, the key has access to those features only.
+    pub async fn create(
+        &self,
+        expires_in: Option<usize>,
+        rate_limit: Option<i64>,
+        features: Option<Vec<Feature>>,
+    ) -> Result<ApiKey, ShortenerError> {
+        if expires_in == Some(0) {
+            return Err(ShortenerError::InvalidInput(
+                "Invalid expiration: expires_in must be greater than zero",
+            ));
+        }
+
+        let key = nanoid::format(nanoid::rngs::default, &KEY_ALPHABET, KEY_LENGTH);
+        let storage_key = format!("API_KEY_{}", key);
+
+        let stored = self
+            .storage
+            .set_if_not_exists(&storage_key, "true")
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if !stored {
+            return Err(ShortenerError::Internal(
+                "Failed to generate a unique API key",
+            ));
+        }
+
+        if let Some(expires_in) = expires_in {
+            self.storage
+                .expire(&storage_key, expires_in)
+                .await
+                .map_err(ShortenerError::Storage)?;
+        }
+
+        if let Some(rate_limit) = rate_limit {
+            let limit_key = limit_key(&key);
+            let rate_limit = rate_limit.to_string();
+            match expires_in {
+                Some(expires_in) => {
+                    self.storage
+                        .set_expiring(&limit_key, &rate_limit, expires_in)
+                        .await
+                }
+                None => self.storage.set(&limit_key, &rate_limit).await,
+            }
+            .map_err(ShortenerError::Storage)?;
+        }
+
+        if let Some(features) = &features {
+            let features_key = features_key(&key);
+            let features = format_features(features);
+            match expires_in {
+                Some(expires_in) => {
+                    self.storage
+                        .set_expiring(&features_key, &features, expires_in)
+                        .await
+                }
+                None => self.storage.set(&features_key, &features).await,
+            }
+            .map_err(ShortenerError::Storage)?;
+        }
+
+        self.storage
+            .add_member(API_KEYS, &key)
+            .await
+            .map_err(ShortenerError::Storage)?;
+
+        Ok(ApiKey {
+            key,
+            expires_in,
+            expires_at: expires_in.map(|expires_in| now() + expires_in as u64),
+            rate_limit,
+            features,
+        })
+    }
+
+    /// Restricts an existing API key to `features`, or gives it access to all of them if `None`,
+    /// such as when its owner changes plan. Features set this way don't expire with the key, and
+    /// are deleted when the expired key is found by `list`.
+    pub async fn set_features(
+        &self,
+        key: &str,
+        features: Option<&[Feature]>,
+    ) -> Result<(), ShortenerError> {
+        let exists = self
+            .storage
+            .exists(&format!("API_KEY_{}", key))
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if !exists {
+            return Err(ShortenerError::NotFound("API key not found"));
+        }
+
+        match features {
+            Some(features) => {
+                self.storage
+                    .set(&features_key(key), &format_features(features))
+                    .await
+            }
+            None => self.storage.delete(&features_key(key)).await.map(|_| ()),
+        }
+        .map_err(ShortenerError::Storage)
+    }
+
+    /// Marks an existing API key as verified, or not, such as the key of a trusted newsletter.
+    /// Links shortened with a verified key, before or after it's verified, are marked as such and
+    /// skip the interstitial required for the others, see `Shortener::requires_interstitial`. The
+    /// mark doesn't expire with the key, and is deleted when the expired key is found by `list`.
+    pub async fn set_verified(&self, key: &str, verified: bool) -> Result<(), ShortenerError> {
+        let exists = self
+            .storage
+            .exists(&format!("API_KEY_{}", key))
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if !exists {
+            return Err(ShortenerError::NotFound("API key not found"));
+        }
+
+        if verified {
+            self.storage.set(&verified_key(key), "true").await
+        } else {
+            self.storage.delete(&verified_key(key)).await.map(|_| ())
+        }
+        .map_err(ShortenerError::Storage)
+    }
+
+    /// Tells whether an API key is verified, see `set_verified`
+    pub async fn is_verified(&self, key: &str) -> bool {
+        self.storage
+            .get_bool(&verified_key(key))
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Revokes an API key: it's no longer valid, but the links and campaigns it created are kept
+    pub async fn revoke(&self, key: &str) -> Result<(), ShortenerError> {
+        self.storage
+            .remove_member(API_KEYS, key)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        self.storage
+            .delete(&limit_key(key))
+            .await
+            .map_err(ShortenerError::Storage)?;
+        self.storage
+            .delete(&features_key(key))
+            .await
+            .map_err(ShortenerError::Storage)?;
+        self.storage
+            .delete(&defaults_key(key))
+            .await
+            .map_err(ShortenerError::Storage)?;
+        self.storage
+            .delete(&verified_key(key))
+            .await
+            .map_err(ShortenerError::Storage)?;
+
+        let deleted = self
+            .storage
+            .delete(&format!("API_KEY_{}", key))
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if !deleted {
+            return Err(ShortenerError::NotFound("API key not found"));
+        }
+
+        Ok(())
+    }
+
+    /// Lists the API keys created by the manager and not expired nor revoked yet, sorted.
+    /// Expired keys are forgotten along the way.
+    pub async fn list(&self) -> Result<Vec<String>, ShortenerError> {
+        let mut keys = Vec::new();
+
+        for key in self
+            .storage
+            .members(API_KEYS)
+            .await
+            .map_err(ShortenerError::Storage)?
+        {
+            let exists = self
+                .storage
+                .exists(&format!("API_KEY_{}", key))
+                .await
+                .map_err(ShortenerError::Storage)?;
+
+            if exists {
+                keys.push(key);
+            } else {
+                self.storage
+                    .remove_member(API_KEYS, &key)
+                    .await
+                    .map_err(ShortenerError::Storage)?;
+                self.storage
+                    .delete(&features_key(&key))
+                    .await
+                    .map_err(ShortenerError::Storage)?;
+                self.storage
+                    .delete(&defaults_key(&key))
+                    .await
+                    .map_err(ShortenerError::Storage)?;
+                self.storage
+                    .delete(&verified_key(&key))
+                    .await
+                    .map_err(ShortenerError::Storage)?;
+            }
+        }
+
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_create_list_revoke() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let manager = ApiKeyManager::new(storage.clone());
+
+        let api_key = manager.create(None, Some(100), None).await.unwrap();
+        assert_eq!(KEY_LENGTH, api_key.key.len());
+        assert_eq!(Some(100), api_key.rate_limit);
+        assert_eq!(
+            "100",
+            storage.get_string(&limit_key(&api_key.key)).await.unwrap()
+        );
+        assert_eq!(None, api_key.expires_at);
+        assert!(storage
+            .get_bool(&format!("API_KEY_{}", api_key.key))
+            .await
+            .unwrap());
+        assert_eq!(vec![api_key.key.clone()], manager.list().await.unwrap());
+
+        manager.revoke(&api_key.key).await.unwrap();
+        assert!(manager.list().await.unwrap().is_empty());
+        assert!(!storage.exists(&limit_key(&api_key.key)).await.unwrap());
+        let err = manager.revoke(&api_key.key).await.err().unwrap();
+        assert_eq!("API key not found", err.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_create_expiring() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let manager = ApiKeyManager::new(storage.clone());
+
+        let err = manager.create(Some(0), None, None).await.err().unwrap();
+        assert_eq!(
+            "Invalid expiration: expires_in must be greater than zero",
+            err.to_string()
+        );
+
+        let api_key = manager.create(Some(600), None, None).await.unwrap();
+        assert_eq!(Some(600), api_key.expires_in);
+        assert!(api_key.expires_at.is_some());
+
+        storage
+            .expire(&format!("API_KEY_{}", api_key.key), 0)
+            .await
+            .unwrap();
+        assert!(manager.list().await.unwrap().is_empty());
+        assert!(storage.members(API_KEYS).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_features() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let manager = ApiKeyManager::new(storage.clone());
+
+        let api_key = manager
+            .create(None, None, Some(vec![Feature::CustomIds, Feature::Batch]))
+            .await
+            .unwrap();
+        let features_key = features_key(&api_key.key);
+        assert_eq!(
+            "custom_ids,batch",
+            storage.get_string(&features_key).await.unwrap()
+        );
+        assert_eq!(
+            vec![Feature::CustomIds, Feature::Batch],
+            parse_features("custom_ids,unknown,batch")
+        );
+
+        manager.set_features(&api_key.key, Some(&[])).await.unwrap();
+        assert_eq!("", storage.get_string(&features_key).await.unwrap());
+        assert!(parse_features("").is_empty());
+        manager.set_features(&api_key.key, None).await.unwrap();
+        assert!(!storage.exists(&features_key).await.unwrap());
+
+        let err = manager
+            .set_features("missing key", None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("API key not found", err.to_string());
+
+        manager
+            .set_features(&api_key.key, Some(&[Feature::Analytics]))
+            .await
+            .unwrap();
+        manager.revoke(&api_key.key).await.unwrap();
+        assert!(!storage.exists(&features_key).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_set_verified() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let manager = ApiKeyManager::new(storage.clone());
+
+        let api_key = manager.create(None, None, None).await.unwrap();
+        assert!(!manager.is_verified(&api_key.key).await);
+        manager.set_verified(&api_key.key, true).await.unwrap();
+        assert!(manager.is_verified(&api_key.key).await);
+        manager.set_verified(&api_key.key, false).await.unwrap();
+        assert!(!storage.exists(&verified_key(&api_key.key)).await.unwrap());
+
+        let err = manager
+            .set_verified("missing key", true)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("API key not found", err.to_string());
+
+        manager.set_verified(&api_key.key, true).await.unwrap();
+        manager.revoke(&api_key.key).await.unwrap();
+        assert!(!manager.is_verified(&api_key.key).await);
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! ascii_json holds `ascii_json`, rewriting JSON bodies for legacy parsers, such as those of
+//! embedded devices, which choke on UTF-8 or on fields they don't expect.
+//!
+//! Frontends apply it to their JSON responses when `SHORTENER_ASCII_JSON` is set.
+
+use std::io;
+
+use serde::Serialize;
+use serde_json::ser::Formatter;
+use serde_json::{Serializer, Value};
+
+/// Rewrites a JSON document: non-ASCII characters are escaped as `\uXXXX`, and fields of objects
+/// which are `null` or empty arrays are omitted, at any depth. Fields are sorted by name and
+/// whitespace is removed. Returns `None` if `json` is not valid JSON.
+pub fn ascii_json(json: &[u8]) -> Option<String> {
+    let mut value = serde_json::from_slice::<Value>(json).ok()?;
+    prune(&mut value);
+
+    let mut output = Vec::with_capacity(json.len());
+    value
+        .serialize(&mut Serializer::with_formatter(&mut output, AsciiFormatter))
+        .ok()?;
+    // only ASCII is written
+    String::from_utf8(output).ok()
+}
+
+/// Removes the `null` and empty array fields of the objects of `value`
+fn prune(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            fields.retain(|_, field| match field {
+                Value::Null => false,
+                Value::Array(items) => !items.is_empty(),
+                _ => true,
+            });
+            fields.values_mut().for_each(prune);
+        }
+        Value::Array(items) => items.iter_mut().for_each(prune),
+        _ => {}
+    }
+}
+
+/// A compact formatter escaping non-ASCII characters, as UTF-16 surrogate pairs beyond the Basic
+/// Multilingual Plane
+struct AsciiFormatter;
+
+impl Formatter for AsciiFormatter {
+    fn write_string_fragment<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        fragment: &str,
+    ) -> io::Result<()> {
+        let mut units = [0; 2];
+        for c in fragment.chars() {
+            if c.is_ascii() {
+                writer.write_all(&[c as u8])?;
+            } else {
+                for unit in c.encode_utf16(&mut units) {
+                    write!(writer, "\\u{:04x}", unit)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ascii_json() {
+        let json = r#"{"id":"abc","url":"https://例え.jp/café?emoji=😀","expires_in":null,"tags":[],"links":[{"next":null,"hits":[1]}]}"#;
+        let ascii = ascii_json(json.as_bytes()).unwrap();
+        assert!(ascii.is_ascii());
+        assert_eq!(
+            r#"{"id":"abc","links":[{"hits":[1]}],"url":"https://\u4f8b\u3048.jp/caf\u00e9?emoji=\ud83d\ude00"}"#,
+            ascii
+        );
+        assert_eq!(
+            "https://例え.jp/café?emoji=😀",
+            serde_json::from_str::<Value>(&ascii).unwrap()["url"]
+        );
+
+        assert_eq!(None, ascii_json(b"not json"));
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! blocking holds a synchronous version of `Shortener`, for frontends not running an async runtime
+//! such as `shorty-aws-lambda`. It's available with the `blocking` feature.
+
+use std::future::Future;
+use std::time::Duration;
+
+use redis::RedisResult;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::challenge::{Challenge, HumanChallenge};
+use crate::interstitial::Interstitial;
+use crate::redis_facade::RedisFacade;
+use crate::{LinkOptions, Redirect, ShortenerError, ShortenerResult, Stats, StorageHealth};
+
+/// `Shortener` wraps a `shorty::Shortener`, running each call on a single threaded runtime it owns
+/// and blocking until the call is done.
+pub struct Shortener {
+    runtime: Runtime,
+    shortener: crate::Shortener,
+}
+
+impl Shortener {
+    /// Creates a new blocking `Shortener`. The `RedisFacade` returned by `connect`, such as
+    /// `|| RedisFacade::connect(&shards, pool_size)`, is given to `new_shortener`, which creates
+    /// the wrapped `shorty::Shortener`: the connections are bound to the runtime of the blocking
+    /// `Shortener`, and can't be created beforehand.
+    pub fn new<C, Fut, F>(connect: C, new_shortener: F) -> RedisResult<Shortener>
+    where
+        C: FnOnce() -> Fut,
+        Fut: Future<Output = RedisResult<RedisFacade>>,
+        F: FnOnce(RedisFacade) -> crate::Shortener,
+    {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let redis = runtime.block_on(connect())?;
+
+        Ok(Shortener {
+            runtime,
+            shortener: new_shortener(redis),
+        })
+    }
+
+    /// See `shorty::Shortener::lookup`
+    pub fn lookup(&self, id: &str) -> Option<String> {
+        self.runtime.block_on(self.shortener.lookup(id))
+    }
+
+    /// See `shorty::Shortener::redirect`
+    pub fn redirect(&self, id: &str) -> Option<Redirect> {
+        self.runtime.block_on(self.shortener.redirect(id))
+    }
+
+    /// See `shorty::Shortener::rotated_to`
+    pub fn rotated_to(&self, id: &str) -> Option<String> {
+        self.runtime.block_on(self.shortener.rotated_to(id))
+    }
+
+    /// See `shorty::Shortener::interstitial`
+    pub fn interstitial(&self, id: &str) -> Option<Interstitial> {
+        self.runtime.block_on(self.shortener.interstitial(id))
+    }
+
+    /// See `shorty::Shortener::requires_interstitial`
+    pub fn requires_interstitial(&self, id: &str) -> bool {
+        self.runtime
+            .block_on(self.shortener.requires_interstitial(id))
+    }
+
+    /// See `shorty::Shortener::requires_challenge`
+    pub fn requires_challenge(&self, id: &str, pass: Option<&str>) -> bool {
+        self.runtime
+            .block_on(self.shortener.requires_challenge(id, pass))
+    }
+
+    /// See `shorty::Shortener::challenge`
+    pub fn challenge(&self, id: &str, failed: bool) -> Option<Challenge> {
+        self.shortener.challenge(id, failed)
+    }
+
+    /// See `shorty::Shortener::pass_challenge`
+    pub fn pass_challenge(&self, id: &str, answer: &str, token: &str) -> Option<String> {
+        self.shortener.pass_challenge(id, answer, token)
+    }
+
+    /// See `shorty::Shortener::human_challenge`
+    pub fn human_challenge(&self) -> Option<&HumanChallenge> {
+        self.shortener.human_challenge()
+    }
+
+    /// See `shorty::Shortener::stats`
+    pub fn stats(&self, id: &str) -> Option<Stats> {
+        self.runtime.block_on(self.shortener.stats(id))
+    }
+
+    /// See `shorty::Shortener::delete`
+    pub fn delete(&self, api_key: &str, id: &str) -> Result<(), ShortenerError> {
+        self.runtime.block_on(self.shortener.delete(api_key, id))
+    }
+
+    /// See `shorty::Shortener::check_storage`
+    pub fn check_storage(&self, timeout: Duration) -> StorageHealth {
+        self.runtime.block_on(self.shortener.check_storage(timeout))
+    }
+
+    /// See `shorty::Shortener::check_eviction_policy`
+    pub fn check_eviction_policy(&self) -> bool {
+        self.runtime
+            .block_on(self.shortener.check_eviction_policy())
+    }
+
+    /// See `shorty::Shortener::shorten`
+    pub fn shorten(
+        &self,
+        api_key: &Option<&str>,
+        host: Option<&str>,
+        url: &str,
+        options: &LinkOptions,
+    ) -> Result<ShortenerResult, ShortenerError> {
+        self.runtime
+            .block_on(self.shortener.shorten(api_key, host, url, options))
+    }
+
+    /// See `shorty::Shortener::shorten_with_id`
+    pub fn shorten_with_id(
+        &self,
+        api_key: &Option<&str>,
+        host: Option<&str>,
+        id: &str,
+        url: &str,
+        options: &LinkOptions,
+    ) -> Result<ShortenerResult, ShortenerError> {
+        self.runtime.block_on(
+            self.shortener
+                .shorten_with_id(api_key, host, id, url, options),
+        )
+    }
+}
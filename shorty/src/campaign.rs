@@ -0,0 +1,877 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! campaign holds campaigns, groups of links sharing their lifecycle.
+//!
+//! A campaign is owned by the API key that created it, and may belong to a parent campaign of the
+//! same owner, forming a hierarchy. Links join a campaign when they are shortened (see
+//! `LinkOptions`). Pausing a campaign pauses all of its links, and those of its sub-campaigns:
+//! paused links are not found until the campaign is resumed.
+//!
+//! Links that must expire together, such as all the assets of an event, can be given a shared
+//! expiry: `expire_campaign` makes every link of a campaign and of its sub-campaigns expire at the
+//! same time, and links shortened into the campaign afterwards expire by then as well.
+//!
+//! `rotate_campaign` re-issues the links of a campaign with new IDs at once, for example when the
+//! old ones leaked: the new links keep their URLs and options, and the old IDs are answered with
+//! `410 Gone` pointing to the new ones, see `rotated_to`.
+//!
+//! Campaigns are stored in these keys:
+//! - `CAMPAIGN_<name>`: the owner API key
+//! - `CAMPAIGN_PARENT_<name>`: the parent campaign, if any
+//! - `CAMPAIGN_PAUSED_<name>`: `true` if the campaign is paused
+//! - `CAMPAIGN_EXPIRES_<name>`: the Unix timestamp the links of the campaign expire at, expiring
+//!   then
+//! - `CAMPAIGN_CHILDREN_<name>`: the set of sub-campaigns
+//! - `CAMPAIGN_LINKS_<name>`: the set of link IDs
+//! - `CAMPAIGNS_<api key>`: the set of campaigns owned by the API key
+//! - `CAMPAIGN_OF_<id>`: the campaign of a link
+//! - `ROTATED_<id>`: the ID a rotated link was re-issued with, expiring with the new link
+
+use std::collections::HashSet;
+
+use crate::api_key_manager::Feature;
+use crate::link_metadata::{metadata_key, Creator};
+use crate::schedule::schedule_key;
+use crate::{deduplication_key, now, LinkOptions, Shortener, ShortenerError};
+
+/// The max number of nested campaigns, bounding the checks made on every lookup
+const MAX_DEPTH: usize = 8;
+
+const MAX_NAME_LENGTH: usize = 64;
+
+/// A campaign, with its optional `parent` campaign and, if its links expire together, the Unix
+/// timestamp they expire at
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Campaign {
+    pub name: String,
+    pub parent: Option<String>,
+    pub paused: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+/// A link re-issued by `Shortener::rotate_campaign`: its old `id`, the ID it was `rotated_to` and
+/// its `url`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RotatedLink {
+    pub id: String,
+    pub rotated_to: String,
+    pub url: String,
+}
+
+/// A struct with the aggregate statistics of a campaign and its sub-campaigns: the number of
+/// `links` and the sum of their `hits`, and the number of sub-campaigns (`campaigns`)
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CampaignStats {
+    pub name: String,
+    pub links: usize,
+    pub hits: i64,
+    pub campaigns: usize,
+}
+
+/// Campaign names are made of ASCII letters, digits and dashes: underscores are reserved to key
+/// prefixes.
+fn validate_name(name: &str) -> Result<(), ShortenerError> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(ShortenerError::InvalidInput(
+            "Invalid campaign name: too short or too long",
+        ));
+    }
+
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(ShortenerError::InvalidInput(
+            "Invalid campaign name: only letters, digits and dashes are allowed",
+        ));
+    }
+
+    Ok(())
+}
+
+impl Shortener {
+    /// Creates a campaign owned by `api_key`, optionally nested in a `parent` campaign of the same
+    /// owner. Campaign names are unique.
+    pub async fn create_campaign(
+        &self,
+        api_key: &str,
+        name: &str,
+        parent: Option<&str>,
+    ) -> Result<Campaign, ShortenerError> {
+        self.verify_api_key(api_key, &[Feature::Campaigns]).await?;
+        validate_name(name)?;
+
+        if let Some(parent) = parent {
+            self.check_campaign_owner(api_key, parent).await?;
+            if self.ancestors(parent).await.len() >= MAX_DEPTH {
+                return Err(ShortenerError::InvalidInput("Campaigns nested too deep"));
+            }
+        }
+
+        let created = self
+            .storage
+            .set_if_not_exists(&format!("CAMPAIGN_{}", name), api_key)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if !created {
+            return Err(ShortenerError::Conflict("Campaign name already taken"));
+        }
+
+        if let Some(parent) = parent {
+            self.storage
+                .set(&format!("CAMPAIGN_PARENT_{}", name), parent)
+                .await
+                .map_err(ShortenerError::Storage)?;
+            self.storage
+                .add_member(&format!("CAMPAIGN_CHILDREN_{}", parent), name)
+                .await
+                .map_err(ShortenerError::Storage)?;
+        }
+
+        self.storage
+            .add_member(&format!("CAMPAIGNS_{}", api_key), name)
+            .await
+            .map_err(ShortenerError::Storage)?;
+
+        Ok(Campaign {
+            name: name.to_owned(),
+            parent: parent.map(str::to_owned),
+            paused: false,
+            expires_at: None,
+        })
+    }
+
+    /// Lists the campaigns owned by `api_key`, sorted by name
+    pub async fn campaigns(&self, api_key: &str) -> Result<Vec<Campaign>, ShortenerError> {
+        self.verify_api_key(api_key, &[Feature::Campaigns]).await?;
+
+        let mut names = self
+            .storage
+            .members(&format!("CAMPAIGNS_{}", api_key))
+            .await
+            .map_err(ShortenerError::Storage)?;
+        names.sort();
+
+        let mut campaigns = Vec::with_capacity(names.len());
+        for name in names {
+            campaigns.push(self.campaign(&name).await?);
+        }
+
+        Ok(campaigns)
+    }
+
+    /// Returns the aggregate statistics of a campaign owned by `api_key`, including its
+    /// sub-campaigns. Expired and deleted links are not counted.
+    pub async fn campaign_stats(
+        &self,
+        api_key: &str,
+        name: &str,
+    ) -> Result<CampaignStats, ShortenerError> {
+        self.verify_api_key(api_key, &[Feature::Analytics]).await?;
+        self.check_campaign_owner(api_key, name).await?;
+
+        let mut campaign_stats = CampaignStats {
+            name: name.to_owned(),
+            links: 0,
+            hits: 0,
+            campaigns: 0,
+        };
+
+        let mut campaigns = vec![name.to_owned()];
+        while let Some(campaign) = campaigns.pop() {
+            let ids = self
+                .storage
+                .members(&format!("CAMPAIGN_LINKS_{}", campaign))
+                .await
+                .map_err(ShortenerError::Storage)?;
+            for id in ids {
+                if let Some(stats) = self.stats(&id).await {
+                    campaign_stats.links += 1;
+                    campaign_stats.hits += stats.hits;
+                }
+            }
+
+            let children = self
+                .storage
+                .members(&format!("CAMPAIGN_CHILDREN_{}", campaign))
+                .await
+                .map_err(ShortenerError::Storage)?;
+            campaign_stats.campaigns += children.len();
+            campaigns.extend(children);
+        }
+
+        Ok(campaign_stats)
+    }
+
+    /// Pauses a campaign owned by `api_key`: its links, and those of its sub-campaigns, are not
+    /// found until the campaign is resumed
+    pub async fn pause_campaign(
+        &self,
+        api_key: &str,
+        name: &str,
+    ) -> Result<Campaign, ShortenerError> {
+        self.verify_api_key(api_key, &[Feature::Campaigns]).await?;
+        self.check_campaign_owner(api_key, name).await?;
+
+        let mut running = vec![];
+        for id in self.campaign_tree_links(name).await? {
+            if !self.is_paused(&id).await {
+                running.push(id);
+            }
+        }
+        self.storage
+            .set(&format!("CAMPAIGN_PAUSED_{}", name), "true")
+            .await
+            .map_err(ShortenerError::Storage)?;
+        for id in running {
+            self.record_toggled(&id, false, name).await;
+        }
+
+        self.campaign(name).await
+    }
+
+    /// Resumes a campaign owned by `api_key`, paused with `pause_campaign`. Links of a resumed
+    /// campaign are still paused if a parent campaign is paused.
+    pub async fn resume_campaign(
+        &self,
+        api_key: &str,
+        name: &str,
+    ) -> Result<Campaign, ShortenerError> {
+        self.verify_api_key(api_key, &[Feature::Campaigns]).await?;
+        self.check_campaign_owner(api_key, name).await?;
+
+        let paused = self
+            .storage
+            .delete(&format!("CAMPAIGN_PAUSED_{}", name))
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if paused {
+            for id in self.campaign_tree_links(name).await? {
+                if !self.is_paused(&id).await {
+                    self.record_toggled(&id, true, name).await;
+                }
+            }
+        }
+
+        self.campaign(name).await
+    }
+
+    /// Makes the links of a campaign owned by `api_key`, and those of its sub-campaigns, expire
+    /// together in `expires_in` seconds, or earlier if they already did. Links shortened into the
+    /// campaign afterwards expire by then too. Campaigns holding permanent links can't expire.
+    pub async fn expire_campaign(
+        &self,
+        api_key: &str,
+        name: &str,
+        expires_in: usize,
+    ) -> Result<Campaign, ShortenerError> {
+        self.verify_api_key(api_key, &[Feature::Campaigns]).await?;
+        self.check_campaign_owner(api_key, name).await?;
+        if expires_in == 0 {
+            return Err(ShortenerError::InvalidInput(
+                "Invalid expiration: expires_in must be greater than zero",
+            ));
+        }
+
+        let ids = self.campaign_tree_links(name).await?;
+        for id in &ids {
+            if self.is_permanent(id).await {
+                return Err(ShortenerError::InvalidInput(
+                    "Invalid expiration: permanent links can't expire",
+                ));
+            }
+        }
+
+        self.storage
+            .set_expiring(
+                &format!("CAMPAIGN_EXPIRES_{}", name),
+                &(now() + expires_in as u64).to_string(),
+                expires_in,
+            )
+            .await
+            .map_err(ShortenerError::Storage)?;
+        for id in ids {
+            let ttl = self
+                .storage
+                .ttl(&id)
+                .await
+                .map_err(ShortenerError::Storage)?;
+            if ttl.is_some_and(|ttl| ttl <= expires_in as u64) {
+                continue;
+            }
+            for key in expiring_keys(&id) {
+                self.storage
+                    .expire(&key, expires_in)
+                    .await
+                    .map_err(ShortenerError::Storage)?;
+            }
+        }
+
+        self.campaign(name).await
+    }
+
+    /// Re-issues the links of a campaign owned by `api_key`, and those of its sub-campaigns, with
+    /// new IDs. The new links keep the URL, options and expiration of the old ones, while their
+    /// statistics start over. The old links are deleted, their IDs pointing to the new ones: see
+    /// `rotated_to`. Quarantined links are left as they are, until reviewed.
+    pub async fn rotate_campaign(
+        &self,
+        api_key: &str,
+        name: &str,
+    ) -> Result<Vec<RotatedLink>, ShortenerError> {
+        self.verify_api_key(api_key, &[Feature::Campaigns]).await?;
+        self.check_campaign_owner(api_key, name).await?;
+
+        let mut ids = self.campaign_tree_links(name).await?;
+        ids.sort();
+        let mut rotated = Vec::with_capacity(ids.len());
+        for id in ids {
+            if self.is_quarantined(&id).await {
+                continue;
+            }
+            if let Some(rotated_link) = self.rotate(api_key, &id).await? {
+                rotated.push(rotated_link);
+            }
+        }
+
+        Ok(rotated)
+    }
+
+    /// Returns the ID the link `id` was re-issued with by `rotate_campaign`, following the links
+    /// rotated more than once, if the link was rotated
+    pub async fn rotated_to(&self, id: &str) -> Option<String> {
+        let mut rotated_to = None;
+        for _ in 0..MAX_DEPTH {
+            let from = rotated_to.as_deref().unwrap_or(id);
+            match self.storage.get_string(&format!("ROTATED_{}", from)).await {
+                Ok(to) => rotated_to = Some(to),
+                Err(_) => break,
+            }
+        }
+
+        rotated_to
+    }
+
+    /// Checks that `id` wasn't rotated: the old IDs of rotated links keep pointing to the new ones,
+    /// and can't be taken as custom IDs
+    pub(crate) async fn check_not_rotated(&self, id: &str) -> Result<(), ShortenerError> {
+        let rotated = self
+            .storage
+            .exists(&format!("ROTATED_{}", id))
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if rotated {
+            return Err(ShortenerError::Conflict("Custom ID already taken"));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the seconds left before the links of `campaign` expire, if it or a parent campaign
+    /// was given an expiry with `expire_campaign`
+    pub(crate) async fn campaign_expires_in(&self, campaign: &str) -> Option<usize> {
+        let mut campaigns = vec![campaign.to_owned()];
+        campaigns.extend(self.ancestors(campaign).await);
+
+        let mut expires_in: Option<usize> = None;
+        for campaign in campaigns {
+            let ttl = self
+                .storage
+                .ttl(&format!("CAMPAIGN_EXPIRES_{}", campaign))
+                .await;
+            if let Ok(Some(ttl)) = ttl {
+                let ttl = ttl as usize;
+                expires_in = Some(expires_in.map_or(ttl, |expires_in| expires_in.min(ttl)));
+            }
+        }
+
+        expires_in
+    }
+
+    /// Re-issues the link `id` of `api_key` with a new ID, returning `None` if it no longer
+    /// exists
+    async fn rotate(&self, api_key: &str, id: &str) -> Result<Option<RotatedLink>, ShortenerError> {
+        let url = match self.storage.get_string(id).await {
+            Ok(url) => url,
+            Err(_) => return Ok(None),
+        };
+        let expires_in = self
+            .storage
+            .ttl(id)
+            .await
+            .map_err(ShortenerError::Storage)?
+            .map(|ttl| ttl as usize);
+        let campaign = self
+            .storage
+            .get_string(&format!("CAMPAIGN_OF_{}", id))
+            .await
+            .ok();
+        // the new link was created by whoever created the old one
+        let creator = match self.metadata(id).await {
+            Some(metadata) => Creator {
+                ip: metadata.ip,
+                user_agent: metadata.user_agent,
+            },
+            None => Creator::default(),
+        };
+        let options = LinkOptions {
+            expires_in,
+            creator,
+            ..self.stored_options(id, campaign).await
+        };
+        let api_key = Some(api_key);
+
+        let new_id = self.generate_id(&HashSet::new(), &url).await?;
+        self.enqueue_created(&new_id, &url, expires_in).await?;
+        self.record_created(&new_id, &api_key, expires_in).await;
+        self.store_metadata(&new_id, &api_key, &options).await?;
+        self.index_domains(&new_id, &url).await?;
+        self.store(&new_id, &url, expires_in)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        self.store_stats(&new_id, expires_in).await;
+
+        self.purge(id, api_key).await?;
+        self.store(&format!("ROTATED_{}", id), &new_id, expires_in)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if self.deduplicate_urls && expires_in.is_none() {
+            let deduplication_key = deduplication_key(&api_key, &url, &options);
+            if let Err(err) = self.storage.set(&deduplication_key, &new_id).await {
+                log::warn!("unable to index '{}' for deduplication: {}", new_id, err);
+            }
+        }
+
+        Ok(Some(RotatedLink {
+            id: id.to_owned(),
+            rotated_to: new_id,
+            url,
+        }))
+    }
+
+    /// Returns the IDs of the links of a campaign and of its sub-campaigns
+    async fn campaign_tree_links(&self, name: &str) -> Result<Vec<String>, ShortenerError> {
+        let mut links = vec![];
+        let mut campaigns = vec![name.to_owned()];
+        while let Some(campaign) = campaigns.pop() {
+            links.extend(
+                self.storage
+                    .members(&format!("CAMPAIGN_LINKS_{}", campaign))
+                    .await
+                    .map_err(ShortenerError::Storage)?,
+            );
+            campaigns.extend(
+                self.storage
+                    .members(&format!("CAMPAIGN_CHILDREN_{}", campaign))
+                    .await
+                    .map_err(ShortenerError::Storage)?,
+            );
+        }
+
+        Ok(links)
+    }
+
+    /// Checks that the campaign exists and is owned by `api_key`
+    pub(crate) async fn check_campaign_owner(
+        &self,
+        api_key: &str,
+        name: &str,
+    ) -> Result<(), ShortenerError> {
+        let owner = self
+            .storage
+            .get_string(&format!("CAMPAIGN_{}", name))
+            .await
+            .ok();
+
+        match owner {
+            Some(owner) if owner == api_key => Ok(()),
+            Some(_) => Err(ShortenerError::Forbidden(
+                "Campaign not owned by the API key",
+            )),
+            None => Err(ShortenerError::NotFound("Campaign not found")),
+        }
+    }
+
+    /// Tells whether the link with the given ID belongs to a paused campaign, or to a sub-campaign
+    /// of a paused campaign
+    pub(crate) async fn is_paused(&self, id: &str) -> bool {
+        let campaign = match self
+            .storage
+            .get_string(&format!("CAMPAIGN_OF_{}", id))
+            .await
+        {
+            Ok(campaign) => campaign,
+            Err(_) => return false,
+        };
+
+        let mut campaigns = vec![campaign.clone()];
+        campaigns.extend(self.ancestors(&campaign).await);
+
+        for campaign in campaigns {
+            let paused = self
+                .storage
+                .get_bool(&format!("CAMPAIGN_PAUSED_{}", campaign))
+                .await
+                .unwrap_or(false);
+            if paused {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    async fn campaign(&self, name: &str) -> Result<Campaign, ShortenerError> {
+        let parent = self
+            .storage
+            .get_string(&format!("CAMPAIGN_PARENT_{}", name))
+            .await
+            .ok();
+        let paused = self
+            .storage
+            .get_bool(&format!("CAMPAIGN_PAUSED_{}", name))
+            .await
+            .map_err(ShortenerError::Storage)?;
+
+        let expires_at = self
+            .storage
+            .get_string(&format!("CAMPAIGN_EXPIRES_{}", name))
+            .await
+            .ok()
+            .and_then(|expires_at| expires_at.parse().ok());
+
+        Ok(Campaign {
+            name: name.to_owned(),
+            parent,
+            paused,
+            expires_at,
+        })
+    }
+
+    /// Returns the parent campaigns of a campaign, from the closest one
+    async fn ancestors(&self, name: &str) -> Vec<String> {
+        let mut ancestors = vec![];
+        let mut name = name.to_owned();
+
+        while ancestors.len() < MAX_DEPTH {
+            match self
+                .storage
+                .get_string(&format!("CAMPAIGN_PARENT_{}", name))
+                .await
+            {
+                Ok(parent) => {
+                    ancestors.push(parent.clone());
+                    name = parent;
+                }
+                Err(_) => break,
+            }
+        }
+
+        ancestors
+    }
+}
+
+/// The keys of a link expiring with it, see `Shortener::store_metadata`
+fn expiring_keys(id: &str) -> Vec<String> {
+    vec![
+        id.to_owned(),
+        format!("OWNER_{}", id),
+        metadata_key(id),
+        format!("CAMPAIGN_OF_{}", id),
+        format!("REDIRECT_STATUS_{}", id),
+        schedule_key(id),
+        format!("CREATED_{}", id),
+        format!("HITS_{}", id),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::storage::Storage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    async fn new_shortener() -> Shortener {
+        let storage = MemoryStorage::new();
+        storage.set("API_KEY_api key", "true").await.unwrap();
+        storage.set("API_KEY_other key", "true").await.unwrap();
+
+        Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, -1)
+    }
+
+    async fn shorten_in(shortener: &Shortener, campaign: &str) -> String {
+        let options = LinkOptions {
+            campaign: Some(campaign.to_owned()),
+            ..LinkOptions::default()
+        };
+
+        shortener
+            .shorten(&Some("api key"), None, "example.com", &options)
+            .await
+            .unwrap()
+            .id
+    }
+
+    #[tokio::test]
+    async fn test_create_and_list() {
+        let shortener = new_shortener().await;
+        shortener
+            .create_campaign("api key", "spring", None)
+            .await
+            .unwrap();
+        shortener
+            .create_campaign("api key", "spring-sale", Some("spring"))
+            .await
+            .unwrap();
+
+        let campaigns = shortener.campaigns("api key").await.unwrap();
+        assert_eq!(2, campaigns.len());
+        assert_eq!("spring", campaigns[0].name);
+        assert_eq!(Some(String::from("spring")), campaigns[1].parent);
+
+        assert!(shortener.campaigns("other key").await.unwrap().is_empty());
+
+        let err = shortener
+            .create_campaign("other key", "spring", None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Campaign name already taken", err.to_string());
+
+        let err = shortener
+            .create_campaign("other key", "autumn", Some("spring"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Campaign not owned by the API key", err.to_string());
+
+        let err = shortener
+            .create_campaign("api key", "spring_sale", None)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("Invalid campaign name"));
+    }
+
+    #[tokio::test]
+    async fn test_shorten_in_campaign() {
+        let shortener = new_shortener().await;
+        shortener
+            .create_campaign("api key", "spring", None)
+            .await
+            .unwrap();
+
+        let options = LinkOptions {
+            campaign: Some(String::from("spring")),
+            ..LinkOptions::default()
+        };
+        let err = shortener
+            .shorten(&Some("other key"), None, "example.com", &options)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Campaign not owned by the API key", err.to_string());
+
+        let err = shortener
+            .shorten(&None, None, "example.com", &options)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Campaigns require an API key", err.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resume() {
+        let shortener = new_shortener().await;
+        shortener
+            .create_campaign("api key", "spring", None)
+            .await
+            .unwrap();
+        shortener
+            .create_campaign("api key", "spring-sale", Some("spring"))
+            .await
+            .unwrap();
+        let id = shorten_in(&shortener, "spring-sale").await;
+
+        assert!(
+            shortener
+                .pause_campaign("api key", "spring")
+                .await
+                .unwrap()
+                .paused
+        );
+        assert!(shortener.lookup(&id).await.is_none());
+
+        let err = shortener
+            .resume_campaign("other key", "spring")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Campaign not owned by the API key", err.to_string());
+
+        assert!(
+            !shortener
+                .resume_campaign("api key", "spring")
+                .await
+                .unwrap()
+                .paused
+        );
+        assert!(shortener.lookup(&id).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_expire_campaign() {
+        let shortener = new_shortener().await;
+        shortener
+            .create_campaign("api key", "event", None)
+            .await
+            .unwrap();
+        shortener
+            .create_campaign("api key", "event-day-1", Some("event"))
+            .await
+            .unwrap();
+        let id = shorten_in(&shortener, "event-day-1").await;
+
+        let campaign = shortener
+            .expire_campaign("api key", "event", 600)
+            .await
+            .unwrap();
+        assert!(campaign.expires_at.is_some());
+        assert_eq!(Some(600), shortener.storage.ttl(&id).await.unwrap());
+        assert_eq!(
+            Some(600),
+            shortener
+                .storage
+                .ttl(&format!("HITS_{}", id))
+                .await
+                .unwrap()
+        );
+
+        let options = LinkOptions {
+            campaign: Some(String::from("event-day-1")),
+            expires_in: Some(3600),
+            ..LinkOptions::default()
+        };
+        let result = shortener
+            .shorten(&Some("api key"), None, "example.com", &options)
+            .await
+            .unwrap();
+        assert_eq!(Some(600), shortener.storage.ttl(result.id()).await.unwrap());
+
+        let options = LinkOptions {
+            campaign: Some(String::from("event")),
+            permanent: true,
+            ..LinkOptions::default()
+        };
+        let err = shortener
+            .shorten(&Some("api key"), None, "example.com", &options)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            "Invalid expiration: permanent links can't expire",
+            err.to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rotate_campaign() {
+        let shortener = new_shortener().await;
+        shortener
+            .create_campaign("api key", "event", None)
+            .await
+            .unwrap();
+        let id = shorten_in(&shortener, "event").await;
+
+        let err = shortener
+            .rotate_campaign("other key", "event")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Campaign not owned by the API key", err.to_string());
+
+        let rotated = shortener.rotate_campaign("api key", "event").await.unwrap();
+        assert_eq!(1, rotated.len());
+        assert_eq!(id, rotated[0].id);
+        let new_id = rotated[0].rotated_to.clone();
+        assert_ne!(id, new_id);
+        assert!(shortener.lookup(&id).await.is_none());
+        assert_eq!(
+            Some(String::from("http://example.com")),
+            shortener.lookup(&new_id).await
+        );
+        assert_eq!(Some(new_id.clone()), shortener.rotated_to(&id).await);
+        assert_eq!(None, shortener.rotated_to(&new_id).await);
+
+        let rotated = shortener.rotate_campaign("api key", "event").await.unwrap();
+        assert_eq!(new_id, rotated[0].id);
+        let newest_id = rotated[0].rotated_to.clone();
+        assert_eq!(Some(newest_id.clone()), shortener.rotated_to(&id).await);
+        assert_eq!(
+            vec![newest_id],
+            shortener
+                .storage
+                .members("CAMPAIGN_LINKS_event")
+                .await
+                .unwrap()
+        );
+
+        let err = shortener
+            .shorten_with_id(
+                &Some("api key"),
+                None,
+                &id,
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Custom ID already taken", err.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_campaign_stats() {
+        let shortener = new_shortener().await;
+        shortener
+            .create_campaign("api key", "spring", None)
+            .await
+            .unwrap();
+        shortener
+            .create_campaign("api key", "spring-sale", Some("spring"))
+            .await
+            .unwrap();
+
+        let id = shorten_in(&shortener, "spring").await;
+        shortener.lookup(&id).await.unwrap();
+        let id = shorten_in(&shortener, "spring-sale").await;
+        shortener.lookup(&id).await.unwrap();
+        shortener.lookup(&id).await.unwrap();
+        let deleted_id = shorten_in(&shortener, "spring-sale").await;
+        shortener.delete("api key", &deleted_id).await.unwrap();
+
+        let stats = shortener.campaign_stats("api key", "spring").await.unwrap();
+        assert_eq!(2, stats.links);
+        assert_eq!(3, stats.hits);
+        assert_eq!(1, stats.campaigns);
+
+        let stats = shortener
+            .campaign_stats("api key", "spring-sale")
+            .await
+            .unwrap();
+        assert_eq!(1, stats.links);
+        assert_eq!(2, stats.hits);
+    }
+}
@@ -0,0 +1,311 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! challenge holds `HumanChallenge`, asking visitors to prove they are human before following
+//! links the phishing screen finds suspicious, but not enough to quarantine them: links whose
+//! destination scores at least the challenge threshold, lower than the quarantine one, or scoring
+//! higher on a domain that isn't young (see `phishing_screen`). Links shortened with a verified
+//! API key are never challenged.
+//!
+//! Instead of redirecting, frontends show a page asking the sum of two digits, answered with a
+//! plain HTML form posted to `/{id}/challenge`: there is no script nor third-party CAPTCHA, and the
+//! page keeps the policy of every other page, but for allowing the form to be posted back. The page
+//! is rendered with the `challenge.html` template, see `templates`.
+//!
+//! Challenges aren't stored: the page carries a token signing the link, the answer and when the
+//! challenge expires. Passing a challenge sets the `HUMAN_COOKIE` cookie, signing when the pass
+//! expires, so that the visitor isn't asked again, for any link, until then.
+
+use url::Url;
+
+use crate::crypto;
+use crate::templates::{Templates, CHALLENGE_TEMPLATE};
+use crate::{now, Shortener};
+
+/// The cookie holding the pass of visitors who answered a challenge
+pub const HUMAN_COOKIE: &str = "shorty_human";
+
+/// How long visitors have to answer a challenge, in seconds
+const CHALLENGE_TTL: u64 = 10 * 60;
+
+/// The built-in `challenge.html` template of `Challenge::html`
+pub const DEFAULT_CHALLENGE_TEMPLATE: &str = r#"{% extends "base.html" %}
+{% block head %}<meta name="robots" content="noindex">{% endblock head %}
+{% block title %}Quick check{% endblock title %}
+{% block style %}
+.error { color: #cf222e; }
+input { font-size: 1em; width: 4em; padding: 0.4em; margin: 0 0.5em; }
+button { font-size: 1em; padding: 0.5em 1.25em; border: 0; border-radius: 6px; background: #0969da; color: #fff; }
+{% endblock style %}
+{% block content %}
+<h1>Quick check</h1>
+<p>This link was flagged for review. Please answer the question below to follow it.</p>
+{% if failed %}<p class="error">That's not the right answer, please try again.</p>{% endif %}
+<form method="post" action="/{{ id }}/challenge">
+<label for="answer">How much is {{ question }}?</label>
+<input id="answer" name="answer" inputmode="numeric" autocomplete="off" required autofocus>
+<input type="hidden" name="token" value="{{ token }}">
+<button type="submit">Continue</button>
+</form>
+{% endblock content %}
+"#;
+
+/// `HumanChallenge` challenges the visitors of links scoring at least `threshold` on the phishing
+/// screen, signing challenges and passes with `key`. Passes last `pass_ttl` seconds.
+#[derive(Clone)]
+pub struct HumanChallenge {
+    key: Vec<u8>,
+    threshold: u32,
+    pass_ttl: u64,
+}
+
+impl HumanChallenge {
+    /// Creates a new HumanChallenge
+    pub fn new(key: &[u8], threshold: u32, pass_ttl: u64) -> HumanChallenge {
+        HumanChallenge {
+            key: key.to_vec(),
+            threshold,
+            pass_ttl,
+        }
+    }
+
+    /// How long passes last, in seconds: the max age of `HUMAN_COOKIE`
+    pub fn pass_ttl(&self) -> u64 {
+        self.pass_ttl
+    }
+
+    fn challenge(&self, id: &str, failed: bool, now: u64) -> Challenge {
+        let digits = nanoid::nanoid!(2, &['1', '2', '3', '4', '5', '6', '7', '8', '9']);
+        let (a, b) = digits.split_at(1);
+        let answer = a.parse::<u32>().unwrap_or_default() + b.parse::<u32>().unwrap_or_default();
+        let expires_at = now + CHALLENGE_TTL;
+
+        Challenge {
+            id: id.to_owned(),
+            question: format!("{} + {}", a, b),
+            token: self.sign_answer(id, &answer.to_string(), expires_at),
+            failed,
+        }
+    }
+
+    fn sign_answer(&self, id: &str, answer: &str, expires_at: u64) -> String {
+        let expires_at = expires_at.to_string();
+        // the separators tell apart the ID, the answer and the expiration
+        let signature = crypto::hmac_sha256(
+            &self.key,
+            &[
+                b"challenge\n",
+                id.as_bytes(),
+                b"\n",
+                answer.as_bytes(),
+                b"\n",
+                expires_at.as_bytes(),
+            ],
+        );
+        format!("{}.{}", expires_at, crypto::encode_hex(&signature))
+    }
+
+    fn sign_pass(&self, expires_at: u64) -> String {
+        let expires_at = expires_at.to_string();
+        let signature = crypto::hmac_sha256(&self.key, &[b"pass\n", expires_at.as_bytes()]);
+        format!("{}.{}", expires_at, crypto::encode_hex(&signature))
+    }
+
+    /// Checks `answer` against the challenge `token` of the link `id`, returning a new pass if
+    /// the answer is right and in time
+    fn pass(&self, id: &str, answer: &str, token: &str, now: u64) -> Option<String> {
+        let expires_at = signed_expiration(token, now)?;
+        let expected = self.sign_answer(id, answer.trim(), expires_at);
+        crypto::constant_time_eq(expected.as_bytes(), token.as_bytes())
+            .then(|| self.sign_pass(now + self.pass_ttl))
+    }
+
+    fn is_pass(&self, pass: &str, now: u64) -> bool {
+        match signed_expiration(pass, now) {
+            Some(expires_at) => {
+                crypto::constant_time_eq(self.sign_pass(expires_at).as_bytes(), pass.as_bytes())
+            }
+            None => false,
+        }
+    }
+}
+
+/// Returns the expiration of a signed token or pass, if it's not expired yet
+fn signed_expiration(signed: &str, now: u64) -> Option<u64> {
+    let (expires_at, _) = signed.split_once('.')?;
+    expires_at
+        .parse::<u64>()
+        .ok()
+        .filter(|expires_at| *expires_at >= now)
+}
+
+/// The challenge of the link `id`: the `question` to answer, the `token` to post back with the
+/// answer, and whether it follows a `failed` answer
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Challenge {
+    pub id: String,
+    pub question: String,
+    pub token: String,
+    pub failed: bool,
+}
+
+impl Challenge {
+    /// Renders the page with the `challenge.html` template
+    pub fn html(&self, templates: &Templates) -> String {
+        templates.render(CHALLENGE_TEMPLATE, self)
+    }
+}
+
+impl Shortener {
+    /// Challenges the visitors of suspicious links, see `challenge`. Links are scored by the
+    /// phishing screen: without one, none is challenged.
+    pub fn with_human_challenge(mut self, human_challenge: HumanChallenge) -> Shortener {
+        self.human_challenge = Some(human_challenge);
+        self
+    }
+
+    /// The human challenge of suspicious links, if enabled
+    pub fn human_challenge(&self) -> Option<&HumanChallenge> {
+        self.human_challenge.as_ref()
+    }
+
+    /// Tells whether frontends must show a challenge instead of redirecting to the link `id`,
+    /// given the `HUMAN_COOKIE` pass of the visitor, if any. Asking doesn't count a hit.
+    pub async fn requires_challenge(&self, id: &str, pass: Option<&str>) -> bool {
+        let (human_challenge, screen) = match (&self.human_challenge, &self.phishing_screen) {
+            (Some(human_challenge), Some(screen)) => (human_challenge, screen),
+            _ => return false,
+        };
+        if pass.is_some_and(|pass| human_challenge.is_pass(pass, now())) {
+            return false;
+        }
+
+        let url = match self.destination(id).await {
+            Some(url) => url,
+            None => return false,
+        };
+        let score = Url::parse(&url).map_or(0, |url| screen.score(&url));
+        score >= human_challenge.threshold && !self.is_verified(id).await
+    }
+
+    /// Returns a new challenge of the link `id`, telling whether it follows a `failed` answer, if
+    /// the human challenge is enabled
+    pub fn challenge(&self, id: &str, failed: bool) -> Option<Challenge> {
+        self.human_challenge
+            .as_ref()
+            .map(|human_challenge| human_challenge.challenge(id, failed, now()))
+    }
+
+    /// Checks the `answer` to the challenge `token` of the link `id`, returning the pass to set
+    /// in the `HUMAN_COOKIE` cookie if the answer is right and not late
+    pub fn pass_challenge(&self, id: &str, answer: &str, token: &str) -> Option<String> {
+        self.human_challenge
+            .as_ref()
+            .and_then(|human_challenge| human_challenge.pass(id, answer, token, now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::phishing_screen::PhishingScreen;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    fn answer(challenge: &Challenge) -> String {
+        let (a, b) = challenge.question.split_once(" + ").unwrap();
+        (a.parse::<u32>().unwrap() + b.parse::<u32>().unwrap()).to_string()
+    }
+
+    #[test]
+    fn test_pass() {
+        let human_challenge = HumanChallenge::new(b"key", 3, 3600);
+        let challenge = human_challenge.challenge("abc", false, 1000);
+        assert_eq!(5, challenge.question.len());
+
+        let right = answer(&challenge);
+        let wrong = (right.parse::<u32>().unwrap() + 1).to_string();
+        let pass = |id, answer: &str, now| human_challenge.pass(id, answer, &challenge.token, now);
+
+        assert_eq!(None, pass("abc", &wrong, 1000));
+        assert_eq!(None, pass("other", &right, 1000));
+        assert_eq!(None, pass("abc", &right, 1000 + CHALLENGE_TTL + 1));
+        let cookie = pass("abc", &format!(" {} ", right), 1000 + CHALLENGE_TTL).unwrap();
+        assert!(cookie.starts_with(&(1000 + CHALLENGE_TTL + 3600).to_string()));
+
+        assert!(human_challenge.is_pass(&cookie, 1000 + CHALLENGE_TTL + 3600));
+        assert!(!human_challenge.is_pass(&cookie, 1000 + CHALLENGE_TTL + 3601));
+        // passes can't be extended
+        assert!(!human_challenge.is_pass(&format!("9{}", cookie), 1000));
+        assert!(!HumanChallenge::new(b"other", 3, 3600).is_pass(&cookie, 1000));
+        assert!(!human_challenge.is_pass("", 1000));
+
+        let html = challenge.html(&Templates::new());
+        assert!(html.contains(r#"action="/abc/challenge""#));
+        assert!(html.contains(&format!(r#"value="{}""#, challenge.token)));
+        assert!(!html.contains("not the right answer"));
+        assert!(!html.contains("{{"));
+    }
+
+    #[tokio::test]
+    async fn test_requires_challenge() {
+        let shortener = Shortener::new(
+            10,
+            vec!['a', 'b', 'c'],
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
+        )
+        .with_phishing_screen(PhishingScreen::new(
+            vec![(String::from("login"), 2), (String::from("wallet"), 5)],
+            5,
+            0,
+        ));
+        let options = LinkOptions::default();
+        let shorten = |url| shortener.shorten(&None, None, url, &options);
+        let safe = shorten("http://example.com/").await.unwrap();
+        let suspicious = shorten("http://example.com/login").await.unwrap();
+        assert!(!suspicious.quarantined);
+
+        // without the challenge, nothing is challenged
+        assert!(!shortener.requires_challenge(suspicious.id(), None).await);
+        assert_eq!(None, shortener.challenge(suspicious.id(), false));
+
+        let shortener = shortener.with_human_challenge(HumanChallenge::new(b"key", 2, 3600));
+        assert!(!shortener.requires_challenge(safe.id(), None).await);
+        assert!(shortener.requires_challenge(suspicious.id(), None).await);
+        assert!(!shortener.requires_challenge("missing", None).await);
+
+        let challenge = shortener.challenge(suspicious.id(), true).unwrap();
+        assert!(challenge
+            .html(&Templates::new())
+            .contains("not the right answer"));
+        let pass = shortener
+            .pass_challenge(suspicious.id(), &answer(&challenge), &challenge.token)
+            .unwrap();
+        assert!(
+            !shortener
+                .requires_challenge(suspicious.id(), Some(&pass))
+                .await
+        );
+        assert!(
+            shortener
+                .requires_challenge(suspicious.id(), Some("1.forged"))
+                .await
+        );
+        // asking is not a hit
+        assert_eq!(0, shortener.stats(suspicious.id()).await.unwrap().hits);
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! click_latency holds the latency beacon of the interstitial, measuring how long the destination
+//! of a link takes to answer the browsers of its visitors, so that owners notice destinations
+//! that became slow or dead.
+//!
+//! With the beacon on, see `Shortener::with_latency_beacon`, the interstitial runs
+//! `BEACON_SCRIPT`: it fetches the destination in the background, without credentials nor
+//! referrer, and posts to `/{id}/latency` how long the destination took to answer, or that it
+//! couldn't be reached. The fetch is opaque: it tells when the answer came, not its status nor
+//! its content. Destinations on `http://` aren't measured from pages on `https://`, as browsers
+//! block them.
+//!
+//! Reports are counted per day in `LATENCY_SAMPLES_<id>_<day>`, `LATENCY_MS_<id>_<day>` and
+//! `LATENCY_FAILURES_<id>_<day>`, days being counted from the Unix epoch in UTC, and the stats of
+//! a link roll up those of the last `LATENCY_WINDOW_DAYS`. Anyone can send a report, so latencies
+//! are capped at `MAX_LATENCY_MS`, and tell a trend rather than a measure.
+
+use crate::crypto::{encode_base64, sha256};
+use crate::{now, Shortener, ShortenerError};
+
+/// How many days of reports the stats of a link roll up
+pub const LATENCY_WINDOW_DAYS: u64 = 7;
+
+/// The longest latency counted, in milliseconds: longer ones are counted as this long
+pub const MAX_LATENCY_MS: u64 = 30_000;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// The script of the beacon, run by the interstitial from the `continue` link having a
+/// `data-beacon` path to post the report to. The `Content-Security-Policy` of the page allows it
+/// by its hash, so it must be rendered as it is.
+pub const BEACON_SCRIPT: &str = r#"(function () {
+  var link = document.querySelector("a.continue[data-beacon]");
+  if (!link || !window.fetch || !navigator.sendBeacon) return;
+  if (location.protocol === "https:" && link.protocol !== "https:") return;
+  var report = function (latency) {
+    navigator.sendBeacon(link.getAttribute("data-beacon"), JSON.stringify(latency));
+  };
+  var start = Date.now();
+  var options = {
+    mode: "no-cors", credentials: "omit", cache: "no-store", referrerPolicy: "no-referrer"
+  };
+  fetch(link.href, options).then(
+    function () { report({ms: Date.now() - start}); },
+    function () { report({failed: true}); }
+  );
+})();"#;
+
+/// Returns the hash `BEACON_SCRIPT` is allowed by in a `Content-Security-Policy`, as
+/// `sha256-<base64>`
+pub fn beacon_script_hash() -> String {
+    format!(
+        "sha256-{}",
+        encode_base64(&sha256(&[BEACON_SCRIPT.as_bytes()]))
+    )
+}
+
+/// A report of the beacon: the destination answered in `ms` milliseconds, or it `failed` to be
+/// reached
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct LatencyReport {
+    #[serde(default)]
+    pub ms: Option<u64>,
+    #[serde(default)]
+    pub failed: bool,
+}
+
+/// The reports of the beacon of a link in the last `LATENCY_WINDOW_DAYS`: the `samples` of the
+/// destination answering, in `average_ms` milliseconds, and the `failures` to reach it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Latency {
+    pub samples: u64,
+    pub failures: u64,
+    pub average_ms: Option<u64>,
+}
+
+impl Shortener {
+    /// Runs the latency beacon on interstitials, see `click_latency`
+    pub fn with_latency_beacon(mut self, latency_beacon: bool) -> Shortener {
+        self.latency_beacon = latency_beacon;
+        self
+    }
+
+    /// Tells whether interstitials run the latency beacon, and need its
+    /// `ContentSecurityPolicy::beacon`
+    pub fn latency_beacon(&self) -> bool {
+        self.latency_beacon
+    }
+
+    /// Counts a report of the beacon for the link `id`. Links not found by `lookup`, and all of
+    /// them with the beacon off, are `NotFound`.
+    pub async fn record_latency(
+        &self,
+        id: &str,
+        report: &LatencyReport,
+    ) -> Result<(), ShortenerError> {
+        if !self.latency_beacon || self.destination(id).await.is_none() {
+            return Err(ShortenerError::NotFound("Link not found"));
+        }
+
+        let day = now() / DAY;
+        let period = ((LATENCY_WINDOW_DAYS + 1) * DAY) as usize;
+        let counts = match (report.failed, report.ms) {
+            (true, _) => vec![(latency_key("FAILURES", id, day), 1)],
+            (false, Some(ms)) => vec![
+                (latency_key("SAMPLES", id, day), 1),
+                (latency_key("MS", id, day), ms.min(MAX_LATENCY_MS) as i64),
+            ],
+            (false, None) => {
+                return Err(ShortenerError::InvalidInput(
+                    "Invalid latency report: either ms or failed is required",
+                ))
+            }
+        };
+        for (key, amount) in counts {
+            self.storage
+                .increment_expiring_by(&key, amount, period)
+                .await
+                .map_err(ShortenerError::Storage)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the reports of the beacon of the link `id` in the last `LATENCY_WINDOW_DAYS`, if
+    /// the beacon is on
+    pub(crate) async fn latency(&self, id: &str) -> Option<Latency> {
+        if !self.latency_beacon {
+            return None;
+        }
+
+        let today = now() / DAY;
+        let mut latency = Latency {
+            samples: 0,
+            failures: 0,
+            average_ms: None,
+        };
+        let mut total_ms = 0;
+        for day in today + 1 - LATENCY_WINDOW_DAYS..=today {
+            latency.samples += self.counter(&latency_key("SAMPLES", id, day)).await;
+            latency.failures += self.counter(&latency_key("FAILURES", id, day)).await;
+            total_ms += self.counter(&latency_key("MS", id, day)).await;
+        }
+        latency.average_ms = total_ms.checked_div(latency.samples);
+        Some(latency)
+    }
+}
+
+fn latency_key(counter: &str, id: &str, day: u64) -> String {
+    format!("LATENCY_{}_{}_{}", counter, id, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_latency() {
+        let shortener = Shortener::new(
+            10,
+            vec!['a', 'b', 'c'],
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
+        );
+        let id = shortener
+            .shorten(&None, None, "http://example.com", &LinkOptions::default())
+            .await
+            .unwrap()
+            .id()
+            .to_owned();
+        let answered = LatencyReport {
+            ms: Some(100),
+            failed: false,
+        };
+
+        // the beacon is off
+        assert!(shortener.record_latency(&id, &answered).await.is_err());
+        assert_eq!(None, shortener.stats(&id).await.unwrap().latency);
+
+        let shortener = shortener.with_latency_beacon(true);
+        assert_eq!(
+            Some(Latency {
+                samples: 0,
+                failures: 0,
+                average_ms: None,
+            }),
+            shortener.stats(&id).await.unwrap().latency
+        );
+
+        shortener.record_latency(&id, &answered).await.unwrap();
+        let slow = LatencyReport {
+            ms: Some(10 * MAX_LATENCY_MS),
+            failed: false,
+        };
+        shortener.record_latency(&id, &slow).await.unwrap();
+        let failed = LatencyReport {
+            ms: None,
+            failed: true,
+        };
+        shortener.record_latency(&id, &failed).await.unwrap();
+        assert!(matches!(
+            shortener
+                .record_latency(&id, &LatencyReport::default())
+                .await,
+            Err(ShortenerError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            shortener.record_latency("missing", &answered).await,
+            Err(ShortenerError::NotFound(_))
+        ));
+
+        assert_eq!(
+            Some(Latency {
+                samples: 2,
+                failures: 1,
+                average_ms: Some((100 + MAX_LATENCY_MS) / 2),
+            }),
+            shortener.stats(&id).await.unwrap().latency
+        );
+    }
+
+    #[test]
+    fn test_beacon_script_hash() {
+        let hash = beacon_script_hash();
+        assert!(hash.starts_with("sha256-"));
+        assert_eq!(7 + 44, hash.len());
+    }
+}
@@ -0,0 +1,216 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! click_queue holds the queue moving the writes counting a redirect off the request path: the
+//! hit of the link, its click of the day and the redirect of `global_stats`.
+//!
+//! Without a queue, `lookup` counts a redirect before returning. With one, it only queues the ID
+//! of the link, and a background task started by the frontend records it with
+//! `record_queued_clicks`. shorty-http and shorty-grpc have a queue, while the lambda, frozen
+//! between invocations, has no task to record it and counts redirects before answering. The
+//! queue is bounded: when full, clicks are dropped with a warning rather than slowing redirects
+//! down, and counted in the `dropped_clicks` of `global_stats`.
+//!
+//! Hits are always counted exactly. Under heavy load, daily clicks and the redirects of
+//! `global_stats` can be sampled instead, see `Shortener::with_click_sampling`.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::Shortener;
+
+/// `ClickQueue` holds the IDs of the links redirected to, until they are counted
+pub(crate) struct ClickQueue {
+    sender: mpsc::Sender<String>,
+    // behind a lock so that a restarted task can take over from the previous one
+    receiver: Mutex<mpsc::Receiver<String>>,
+    dropped: AtomicU64,
+}
+
+impl Shortener {
+    /// Queues the clicks of `lookup`, at most `capacity` at a time, instead of counting them
+    /// before returning: see `click_queue`
+    pub fn with_click_queue(mut self, capacity: usize) -> Shortener {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        self.click_queue = Some(ClickQueue {
+            sender,
+            receiver: Mutex::new(receiver),
+            dropped: AtomicU64::new(0),
+        });
+        self
+    }
+
+    /// Counts a redirect of the link `id`, queueing it if there is a queue
+    pub(crate) async fn count_click(&self, id: &str) {
+        let queue = match &self.click_queue {
+            Some(queue) => queue,
+            None => return self.record_hit(id).await,
+        };
+        match queue.sender.try_send(id.to_owned()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                queue.dropped.fetch_add(1, Ordering::Relaxed);
+                log::warn!("click queue is full, dropping click of '{}'", id);
+            }
+            Err(TrySendError::Closed(_)) => self.record_hit(id).await,
+        }
+    }
+
+    /// Records the queued clicks as they come, until `shutdown` completes. Clicks queued by then
+    /// are recorded before returning. Without a queue, it waits for `shutdown`.
+    ///
+    /// Only one call at a time records clicks, another call waits for it to return.
+    pub async fn record_queued_clicks(&self, shutdown: impl Future<Output = ()>) {
+        let queue = match &self.click_queue {
+            Some(queue) => queue,
+            None => return shutdown.await,
+        };
+        tokio::pin!(shutdown);
+        let mut receiver = queue.receiver.lock().await;
+
+        loop {
+            tokio::select! {
+                id = receiver.recv() => match id {
+                    Some(id) => self.record_hit(&id).await,
+                    None => return,
+                },
+                _ = &mut shutdown => break,
+            }
+        }
+        while let Ok(id) = receiver.try_recv() {
+            self.record_hit(&id).await;
+        }
+    }
+
+    /// Records the daily clicks and the redirects of `global_stats` for one click in `sampling`,
+    /// chosen at random, counting it as `sampling` clicks. Hits are still counted for every click.
+    /// One, the default, records every click.
+    pub fn with_click_sampling(mut self, sampling: u32) -> Shortener {
+        self.click_sampling = sampling.max(1);
+        self
+    }
+
+    /// Records the clicks queued so far, without waiting for more, and returns how many. Meant
+    /// for frontends that can't keep a task running, and record clicks between requests.
+    pub async fn flush_queued_clicks(&self) -> usize {
+        let queue = match &self.click_queue {
+            Some(queue) => queue,
+            None => return 0,
+        };
+        let mut receiver = queue.receiver.lock().await;
+        let mut count = 0;
+        while let Ok(id) = receiver.try_recv() {
+            self.record_hit(&id).await;
+            count += 1;
+        }
+        count
+    }
+
+    /// Returns the number of clicks dropped since the shortener was created, the queue being full
+    pub fn dropped_clicks(&self) -> u64 {
+        self.click_queue
+            .as_ref()
+            .map_or(0, |queue| queue.dropped.load(Ordering::Relaxed))
+    }
+
+    /// Counts a hit of the link `id`, and a click of the day and a redirect of `global_stats` if
+    /// the click is sampled
+    async fn record_hit(&self, id: &str) {
+        if let Err(err) = self.storage.increment(&format!("HITS_{}", id)).await {
+            log::warn!("unable to count hit of '{}': {}", id, err);
+        }
+        if self.is_sampled() {
+            let clicks = i64::from(self.click_sampling);
+            self.record_clicks(id, clicks).await;
+            self.count_redirects(clicks).await;
+        }
+    }
+
+    /// Tells whether a click is one of the `click_sampling` ones to record
+    fn is_sampled(&self) -> bool {
+        if self.click_sampling == 1 {
+            return true;
+        }
+        let random = nanoid::rngs::non_secure(4);
+        let random = u32::from_le_bytes([random[0], random[1], random[2], random[3]]);
+        random.is_multiple_of(self.click_sampling)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::{LinkOptions, Shortener};
+
+    #[tokio::test]
+    async fn test_click_queue() {
+        let storage = Box::new(MemoryStorage::new());
+        let shortener =
+            Shortener::new(10, vec!['a', 'b', 'c'], 10, storage, 600, 10).with_click_queue(2);
+        let id = shortener
+            .shorten(&None, None, "https://example.com", &LinkOptions::default())
+            .await
+            .unwrap()
+            .id;
+
+        for _ in 0..3 {
+            assert!(shortener.lookup(&id).await.is_some());
+        }
+        // queued, not counted yet
+        assert_eq!(shortener.stats(&id).await.unwrap().hits, 0);
+        assert_eq!(shortener.dropped_clicks(), 1);
+
+        // the third click was dropped, the queue being full
+        assert_eq!(shortener.flush_queued_clicks().await, 2);
+        assert_eq!(shortener.stats(&id).await.unwrap().hits, 2);
+
+        shortener.lookup(&id).await.unwrap();
+        shortener.record_queued_clicks(async {}).await;
+        assert_eq!(shortener.stats(&id).await.unwrap().hits, 3);
+    }
+
+    #[tokio::test]
+    async fn test_click_sampling() {
+        let storage = Box::new(MemoryStorage::new());
+        let shortener =
+            Shortener::new(10, vec!['a', 'b', 'c'], 10, storage, 600, 10).with_click_sampling(4);
+        let id = shortener
+            .shorten(&None, None, "https://example.com", &LinkOptions::default())
+            .await
+            .unwrap()
+            .id;
+
+        for _ in 0..100 {
+            shortener.lookup(&id).await.unwrap();
+        }
+        assert_eq!(shortener.stats(&id).await.unwrap().hits, 100);
+
+        assert_eq!(shortener.global_stats().await.unwrap().click_sampling, 4);
+        assert_eq!(shortener.stats(&id).await.unwrap().click_sampling, 4);
+        // counted four at a time
+        for key in shortener.storage.scan("CLICKS_").await.unwrap() {
+            let clicks: i64 = shortener
+                .storage
+                .get_string(&key)
+                .await
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert_eq!(clicks % 4, 0);
+        }
+    }
+}
@@ -0,0 +1,200 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! collision_alert holds `CollisionAlert`, warning operators when generating IDs takes more and
+//! more attempts, long before the keyspace of IDs is so full that shortening fails.
+//!
+//! Counting the links would walk the whole keyspace, so the utilization of the keyspace is
+//! estimated from the outcome of the attempts themselves: a random ID is taken with a probability
+//! equal to the share of IDs in use.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::Shortener;
+
+/// The least time between two alerts: alerts raised meanwhile are counted, and reported with the
+/// next one
+const ALERT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The weight of the last attempt in the estimated utilization, a moving average covering about
+/// the last thousand attempts
+const UTILIZATION_WEIGHT: f64 = 0.001;
+
+/// The report of an ID generation needing more than `threshold` attempts, or failing after
+/// `attempts` attempts if `exhausted`.
+///
+/// `keyspace` is the number of IDs that can be generated, and `utilization` the estimated share of
+/// them in use, between 0 and 1. `suppressed` alerts were raised since the previous one, but not
+/// reported.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CollisionReport {
+    pub attempts: u8,
+    pub threshold: u8,
+    pub exhausted: bool,
+    pub keyspace: f64,
+    pub utilization: f64,
+    pub suppressed: u32,
+}
+
+/// A hook called with each alert
+type Hook = Box<dyn Fn(&CollisionReport) + Send + Sync>;
+
+/// `CollisionAlert` raises an alert when generating an ID needs more than `threshold` attempts.
+/// Alerts are logged as warnings, and passed to the hook, if any, such as to call a webhook. At
+/// most one alert is raised per minute.
+pub struct CollisionAlert {
+    threshold: u8,
+    hook: Option<Hook>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    utilization: f64,
+    last_alert: Option<Instant>,
+    suppressed: u32,
+}
+
+impl CollisionAlert {
+    /// Creates a new CollisionAlert, raised when more than `threshold` attempts are needed
+    pub fn new(threshold: u8) -> CollisionAlert {
+        CollisionAlert {
+            threshold,
+            hook: None,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Sets a hook called with each alert, besides logging it. The hook is called while
+    /// generating an ID, so it must not block: slow work, such as calling a webhook, belongs to
+    /// another task.
+    pub fn with_hook(
+        mut self,
+        hook: impl Fn(&CollisionReport) + Send + Sync + 'static,
+    ) -> CollisionAlert {
+        self.hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Records the outcome of an ID generation: the number of `attempts` it made, the last one
+    /// succeeding unless `exhausted`. Returns the alert raised, if any.
+    pub(crate) fn record(
+        &self,
+        attempts: u8,
+        exhausted: bool,
+        keyspace: f64,
+    ) -> Option<CollisionReport> {
+        let collisions = if exhausted { attempts } else { attempts - 1 };
+        let mut state = self.state.lock().unwrap();
+        for attempt in 0..attempts {
+            let collided = if attempt < collisions { 1.0 } else { 0.0 };
+            state.utilization += (collided - state.utilization) * UTILIZATION_WEIGHT;
+        }
+
+        if attempts <= self.threshold && !exhausted {
+            return None;
+        }
+        let now = Instant::now();
+        if let Some(last_alert) = state.last_alert {
+            if now.duration_since(last_alert) < ALERT_INTERVAL {
+                state.suppressed += 1;
+                return None;
+            }
+        }
+
+        let report = CollisionReport {
+            attempts,
+            threshold: self.threshold,
+            exhausted,
+            keyspace,
+            utilization: state.utilization,
+            suppressed: state.suppressed,
+        };
+        state.last_alert = Some(now);
+        state.suppressed = 0;
+        drop(state);
+
+        log::warn!(
+            "generating an ID took {} attempts (alert threshold {}{}): about {:.1}% of the {} IDs are in use, consider longer IDs",
+            report.attempts,
+            report.threshold,
+            if report.exhausted { ", failed" } else { "" },
+            report.utilization * 100.0,
+            report.keyspace,
+        );
+        if let Some(hook) = &self.hook {
+            hook(&report);
+        }
+        Some(report)
+    }
+}
+
+impl Shortener {
+    /// Enables alerts on ID collisions: see `collision_alert`
+    pub fn with_collision_alert(mut self, collision_alert: CollisionAlert) -> Shortener {
+        self.collision_alert = Some(collision_alert);
+        self
+    }
+
+    /// The number of IDs that can be generated
+    pub(crate) fn keyspace(&self) -> f64 {
+        (self.id_alphabet.len() as f64).powi(self.id_length as i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let hooked = Arc::new(AtomicUsize::new(0));
+        let counter = hooked.clone();
+        let alert = CollisionAlert::new(2).with_hook(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert_eq!(None, alert.record(1, false, 100.0));
+        assert_eq!(None, alert.record(2, false, 100.0));
+
+        let report = alert.record(3, false, 100.0).unwrap();
+        assert_eq!(3, report.attempts);
+        assert!(!report.exhausted);
+        assert!(report.utilization > 0.0);
+        assert_eq!(1, hooked.load(Ordering::SeqCst));
+
+        // alerts are raised at most once per interval
+        assert_eq!(None, alert.record(10, true, 100.0));
+        alert.state.lock().unwrap().last_alert = Some(Instant::now() - ALERT_INTERVAL);
+        let report = alert.record(4, false, 100.0).unwrap();
+        assert_eq!(1, report.suppressed);
+        assert_eq!(2, hooked.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_utilization() {
+        let alert = CollisionAlert::new(u8::MAX);
+        // half of the IDs in use: one collision every two attempts
+        for _ in 0..5000 {
+            alert.record(2, false, 100.0);
+        }
+
+        let utilization = alert.state.lock().unwrap().utilization;
+        assert!((utilization - 0.5).abs() < 0.01);
+    }
+}
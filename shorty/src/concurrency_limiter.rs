@@ -0,0 +1,265 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! concurrency_limiter holds `ConcurrencyLimiter`, capping the calls each API key has in flight
+//! at once, so that the batch job of an API key can't take every worker from interactive users.
+//!
+//! Calls in flight hold a lease on a semaphore shared on the storage: the sorted set
+//! `INFLIGHT_API_KEY_<api key>`, whose members are the leases and whose scores are the Unix
+//! timestamps they expire at. Leases are released when calls end, even when they are dropped
+//! midway, such as past their deadline, and expire otherwise, such as when a frontend crashes.
+
+use std::future::Future;
+use std::sync::Arc;
+
+use crate::storage::Storage;
+use crate::{now, Shortener, ShortenerError};
+
+/// The most expired leases removed when acquiring a lease
+const MAX_EXPIRED_LEASES: usize = 100;
+
+/// `ConcurrencyLimiter` lets each API key hold at most `limit` leases at once, each expiring
+/// after `lease_period` seconds
+#[derive(Clone)]
+pub struct ConcurrencyLimiter {
+    storage: Arc<dyn Storage>,
+    limit: usize,
+    lease_period: usize,
+}
+
+/// A lease acquired with `ConcurrencyLimiter::acquire`, to release once the call ends
+pub struct Lease {
+    key: String,
+    id: String,
+}
+
+impl ConcurrencyLimiter {
+    /// Creates a new ConcurrencyLimiter. Zero `limit` disables it.
+    pub fn new(storage: Arc<dyn Storage>, limit: usize, lease_period: usize) -> ConcurrencyLimiter {
+        ConcurrencyLimiter {
+            storage,
+            limit,
+            lease_period,
+        }
+    }
+
+    /// Acquires a lease for a call made with `api_key`, returning an error if `api_key` holds
+    /// `limit` leases already. The API key itself is not verified: see `Shortener::verify_key`.
+    pub async fn acquire(&self, api_key: &str) -> Result<Option<Lease>, ShortenerError> {
+        if self.limit == 0 {
+            return Ok(None);
+        }
+
+        let key = inflight_key(api_key);
+        let now = now();
+        self.remove_expired(&key, now).await;
+
+        let lease = Lease {
+            key,
+            id: nanoid::nanoid!(),
+        };
+        let expires_at = now + self.lease_period as u64;
+        self.storage
+            .add_scored_member(&lease.key, &lease.id, expires_at)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        // the whole semaphore expires with its last lease
+        self.storage
+            .expire(&lease.key, self.lease_period)
+            .await
+            .map_err(ShortenerError::Storage)?;
+
+        // leases acquired at once may all be refused, but never all be granted past the limit
+        let inflight = self
+            .storage
+            .scored_members(&lease.key, now + 1, u64::MAX, self.limit + 1)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if inflight.len() > self.limit {
+            self.release(lease).await;
+            return Err(ShortenerError::ConcurrencyLimited);
+        }
+
+        Ok(Some(lease))
+    }
+
+    /// Releases `lease`, so that another call can acquire it. A lease that can't be released
+    /// expires anyway.
+    pub async fn release(&self, lease: Lease) {
+        if let Err(err) = self
+            .storage
+            .remove_scored_member(&lease.key, &lease.id)
+            .await
+        {
+            log::warn!("unable to release lease of '{}': {}", lease.key, err);
+        }
+    }
+
+    /// Removes the leases of `key` expired at `now`
+    async fn remove_expired(&self, key: &str, now: u64) {
+        let expired = match self
+            .storage
+            .scored_members_ascending(key, 0, now, MAX_EXPIRED_LEASES)
+            .await
+        {
+            Ok(expired) => expired,
+            Err(err) => {
+                log::warn!("unable to read expired leases of '{}': {}", key, err);
+                return;
+            }
+        };
+
+        for (lease, _) in expired {
+            if let Err(err) = self.storage.remove_scored_member(key, &lease).await {
+                log::warn!("unable to remove expired lease of '{}': {}", key, err);
+            }
+        }
+    }
+}
+
+/// Holds the lease of a call, releasing it in the background if the call is dropped before it
+/// ends
+struct HeldLease {
+    limiter: ConcurrencyLimiter,
+    lease: Option<Lease>,
+}
+
+impl Drop for HeldLease {
+    fn drop(&mut self) {
+        if let Some(lease) = self.lease.take() {
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                let limiter = self.limiter.clone();
+                runtime.spawn(async move { limiter.release(lease).await });
+            }
+        }
+    }
+}
+
+/// The key of the semaphore of `api_key`
+fn inflight_key(api_key: &str) -> String {
+    format!("INFLIGHT_API_KEY_{}", api_key)
+}
+
+impl Shortener {
+    /// Caps the calls each API key has in flight at once to `limit`, each holding a lease for at
+    /// most `lease_period` seconds: see `concurrency_limiter`. Zero `limit` disables the cap.
+    pub fn with_concurrency_limit(mut self, limit: usize, lease_period: usize) -> Shortener {
+        self.concurrency_limiter =
+            ConcurrencyLimiter::new(self.storage.clone(), limit, lease_period);
+        self
+    }
+
+    /// Returns the `ConcurrencyLimiter` capping the calls each API key has in flight
+    pub fn concurrency_limiter(&self) -> &ConcurrencyLimiter {
+        &self.concurrency_limiter
+    }
+
+    /// Runs `call`, made with `api_key`, holding a lease of the concurrency limiter meanwhile.
+    /// Anonymous calls are not limited.
+    pub(crate) async fn leased<T>(
+        &self,
+        api_key: &Option<&str>,
+        call: impl Future<Output = Result<T, ShortenerError>>,
+    ) -> Result<T, ShortenerError> {
+        let lease = match api_key {
+            Some(api_key) => self.concurrency_limiter.acquire(api_key).await?,
+            None => None,
+        };
+        let mut held = HeldLease {
+            limiter: self.concurrency_limiter.clone(),
+            lease,
+        };
+
+        let result = call.await;
+        if let Some(lease) = held.lease.take() {
+            self.concurrency_limiter.release(lease).await;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::deadline::Deadline;
+    use crate::memory_storage::MemoryStorage;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_acquire() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let limiter = ConcurrencyLimiter::new(storage.clone(), 2, 60);
+
+        let first = limiter.acquire("api key").await.unwrap().unwrap();
+        let second = limiter.acquire("api key").await.unwrap().unwrap();
+        let err = limiter.acquire("api key").await.err().unwrap();
+        assert_eq!("Too many concurrent calls", err.to_string());
+        assert_eq!(Some(1), err.retry_after());
+        assert!(limiter.acquire("other api key").await.is_ok());
+
+        limiter.release(first).await;
+        let third = limiter.acquire("api key").await.unwrap().unwrap();
+        limiter.release(second).await;
+        limiter.release(third).await;
+        assert!(storage
+            .scored_members("INFLIGHT_API_KEY_api key", 0, u64::MAX, 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_acquire_expired() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let limiter = ConcurrencyLimiter::new(storage.clone(), 1, 60);
+
+        // a lease left behind by a crashed frontend
+        storage
+            .add_scored_member("INFLIGHT_API_KEY_api key", "stale", now() - 1)
+            .await
+            .unwrap();
+        assert!(limiter.acquire("api key").await.unwrap().is_some());
+        assert!(limiter.acquire("api key").await.is_err());
+
+        let disabled = ConcurrencyLimiter::new(storage, 0, 60);
+        assert!(disabled.acquire("api key").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_call() {
+        let shortener = Shortener::new(
+            10,
+            "abcdefghijklmnopqrstuvwxyz".chars().collect(),
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
+        )
+        .with_concurrency_limit(1, 60);
+
+        let call = shortener.leased(&Some("api key"), async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        });
+        let deadline = Deadline::after(Duration::from_millis(10));
+        assert!(deadline.run(call).await.is_err());
+
+        // the lease of the abandoned call is released in the background
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let limiter = shortener.concurrency_limiter();
+        assert!(limiter.acquire("api key").await.unwrap().is_some());
+    }
+}
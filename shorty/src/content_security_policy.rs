@@ -0,0 +1,123 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! content_security_policy builds the `Content-Security-Policy` of the HTML pages served by shorty
+//! frontends, and the other headers hardening them, so that every page of every frontend gets the
+//! same ones: redirects, interstitials, challenges, error pages and the abuse page.
+//!
+//! Pages load nothing but their own markup and inline styles, run no script and can't be framed.
+//! Only pages posting a form back to shorty, such as the challenge, can submit forms, and only
+//! interstitials with the latency beacon run a script: the beacon, allowed by its hash, see
+//! `click_latency`.
+//!
+//! Browsers report the violations of the policy to `CSP_REPORT_PATH`, where frontends log them:
+//! a violation means that a template, or something injected into a page, tried to load what the
+//! policy forbids.
+
+use std::fmt::{self, Display, Formatter};
+
+use crate::click_latency::beacon_script_hash;
+
+/// The path browsers send the violations of the policy to, with `POST`
+pub const CSP_REPORT_PATH: &str = "/csp-report";
+
+/// The most bytes of a violation report frontends log, so that reports can't flood the logs
+pub const MAX_CSP_REPORT_SIZE: usize = 4096;
+
+/// The `Content-Security-Policy` of an HTML page, written as the value of the header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContentSecurityPolicy {
+    posts_forms: bool,
+    runs_beacon: bool,
+}
+
+impl ContentSecurityPolicy {
+    /// The policy of pages without forms
+    pub fn page() -> ContentSecurityPolicy {
+        ContentSecurityPolicy {
+            posts_forms: false,
+            runs_beacon: false,
+        }
+    }
+
+    /// The policy of pages posting a form back to shorty, such as the challenge
+    pub fn form() -> ContentSecurityPolicy {
+        ContentSecurityPolicy {
+            posts_forms: true,
+            runs_beacon: false,
+        }
+    }
+
+    /// The policy of interstitials running the latency beacon: the beacon script alone can run,
+    /// and fetch the destination of the link
+    pub fn beacon() -> ContentSecurityPolicy {
+        ContentSecurityPolicy {
+            posts_forms: false,
+            runs_beacon: true,
+        }
+    }
+
+    /// Returns the headers of an HTML page with this policy: the policy itself, and the headers
+    /// keeping browsers from guessing the type of the page and from framing it
+    pub fn headers(&self) -> [(&'static str, String); 3] {
+        [
+            ("Content-Security-Policy", self.to_string()),
+            ("X-Content-Type-Options", String::from("nosniff")),
+            ("X-Frame-Options", String::from("DENY")),
+        ]
+    }
+}
+
+impl Display for ContentSecurityPolicy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let form_action = if self.posts_forms { "'self'" } else { "'none'" };
+        write!(
+            f,
+            "default-src 'none'; style-src 'unsafe-inline'; base-uri 'none'; form-action {}; frame-ancestors 'none'; ",
+            form_action
+        )?;
+        if self.runs_beacon {
+            write!(f, "script-src '{}'; connect-src *; ", beacon_script_hash())?;
+        }
+        write!(f, "report-uri {}", CSP_REPORT_PATH)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policies() {
+        assert_eq!(
+            "default-src 'none'; style-src 'unsafe-inline'; base-uri 'none'; form-action 'none'; frame-ancestors 'none'; report-uri /csp-report",
+            ContentSecurityPolicy::page().to_string()
+        );
+        assert!(ContentSecurityPolicy::form()
+            .to_string()
+            .contains("form-action 'self';"));
+
+        let beacon = ContentSecurityPolicy::beacon().to_string();
+        assert!(beacon.contains(&format!("script-src '{}';", beacon_script_hash())));
+        assert!(beacon.contains("connect-src *;"));
+        assert!(beacon.ends_with("report-uri /csp-report"));
+        assert!(!ContentSecurityPolicy::page()
+            .to_string()
+            .contains("script-src"));
+
+        let headers = ContentSecurityPolicy::page().headers();
+        assert_eq!("Content-Security-Policy", headers[0].0);
+        assert_eq!(("X-Frame-Options", String::from("DENY")), headers[2]);
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! crypto holds the signature checks shared by every frontend, so that signed IDs, admin keys and
+//! signed requests, such as those of Slack and Discord, are verified the same way: secrets and
+//! signatures are compared in a time not depending on how much of them is right, and signed
+//! timestamps are accepted within a clock skew.
+//!
+//! `SignatureVerifier` checks the signatures of timestamped messages: either the HMAC-SHA256 of
+//! the message, keyed with a shared secret, or its Ed25519 signature, checked with the public key
+//! of the signer. A signature is valid if it matches, and if the timestamp it signs is no further
+//! than `max_clock_skew` from the clock of shorty, in the past or in the future. The skew bounds
+//! both the clock drift between signer and shorty and how long a captured request can be
+//! replayed.
+
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use sha2::{Digest, Sha256};
+
+/// The default clock skew of `SignatureVerifier`
+pub const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// Compares two byte strings in a time depending only on their length, so that response times
+/// don't tell how much of a secret or signature was guessed right
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Returns the HMAC-SHA256 of the concatenation of `parts`, keyed with `key`
+pub fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    // keys of any length are accepted by HMAC
+    let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+    for part in parts {
+        mac.input(part);
+    }
+    mac.result().code().to_vec()
+}
+
+/// Returns the SHA-256 of the concatenation of `parts`
+pub fn sha256(parts: &[&[u8]]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.input(part);
+    }
+    hasher.result().to_vec()
+}
+
+/// Tells whether `signature` is the HMAC-SHA256 of the concatenation of `parts`, keyed with `key`,
+/// see `constant_time_eq`
+pub fn verify_hmac_sha256(key: &[u8], parts: &[&[u8]], signature: &[u8]) -> bool {
+    constant_time_eq(&hmac_sha256(key, parts), signature)
+}
+
+/// Tells whether `signature` is the Ed25519 signature of the concatenation of `parts`, made with
+/// the private key of `public_key`
+pub fn verify_ed25519(public_key: &[u8], parts: &[&[u8]], signature: &[u8]) -> bool {
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(&parts.concat(), signature)
+        .is_ok()
+}
+
+/// Encodes bytes as a lowercase hex string
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Encodes bytes as standard, padded base64, as in the hashes of a `Content-Security-Policy`
+pub fn encode_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let triple = chunk.iter().enumerate().fold(0u32, |triple, (i, byte)| {
+            triple | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(triple >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decodes a hex string, of either case, returning `None` if it isn't valid hex
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// `SignatureVerifier` verifies signed timestamped messages, see `crypto`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignatureVerifier {
+    max_clock_skew: Duration,
+}
+
+impl Default for SignatureVerifier {
+    fn default() -> Self {
+        SignatureVerifier::new(DEFAULT_MAX_CLOCK_SKEW)
+    }
+}
+
+impl SignatureVerifier {
+    /// Creates a new SignatureVerifier, accepting timestamps up to `max_clock_skew` away from now
+    pub fn new(max_clock_skew: Duration) -> SignatureVerifier {
+        SignatureVerifier { max_clock_skew }
+    }
+
+    /// How far signed timestamps may be from now
+    pub fn max_clock_skew(&self) -> Duration {
+        self.max_clock_skew
+    }
+
+    /// Tells whether `timestamp`, a Unix timestamp in seconds, is within the clock skew of now
+    pub fn is_fresh(&self, timestamp: u64) -> bool {
+        self.is_fresh_at(timestamp, crate::now())
+    }
+
+    /// Tells whether `signature` is the HMAC-SHA256, keyed with `key`, of `parts`, one of which
+    /// holds the signed `timestamp`, and whether `timestamp` is within the clock skew of now
+    pub fn verify(&self, key: &[u8], timestamp: u64, parts: &[&[u8]], signature: &[u8]) -> bool {
+        self.verify_at(key, timestamp, parts, signature, crate::now())
+    }
+
+    /// Tells whether `signature` is the Ed25519 signature of `parts` by the owner of `public_key`,
+    /// one of the parts holding the signed `timestamp`, and whether `timestamp` is within the
+    /// clock skew of now
+    pub fn verify_ed25519(
+        &self,
+        public_key: &[u8],
+        timestamp: u64,
+        parts: &[&[u8]],
+        signature: &[u8],
+    ) -> bool {
+        self.verify_ed25519_at(public_key, timestamp, parts, signature, crate::now())
+    }
+
+    fn is_fresh_at(&self, timestamp: u64, now: u64) -> bool {
+        now.abs_diff(timestamp) <= self.max_clock_skew.as_secs()
+    }
+
+    fn verify_at(
+        &self,
+        key: &[u8],
+        timestamp: u64,
+        parts: &[&[u8]],
+        signature: &[u8],
+        now: u64,
+    ) -> bool {
+        // the signature is checked even for stale timestamps, not to tell them apart by timing
+        let signed = verify_hmac_sha256(key, parts, signature);
+        signed && self.is_fresh_at(timestamp, now)
+    }
+
+    fn verify_ed25519_at(
+        &self,
+        public_key: &[u8],
+        timestamp: u64,
+        parts: &[&[u8]],
+        signature: &[u8],
+        now: u64,
+    ) -> bool {
+        let signed = verify_ed25519(public_key, parts, signature);
+        signed && self.is_fresh_at(timestamp, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    use super::*;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+
+    #[test]
+    fn test_verify_with_clock_skew() {
+        let verifier = SignatureVerifier::new(Duration::from_secs(60));
+        let signature = hmac_sha256(b"key", &[b"v0:1000:", b"body"]);
+        let verify = |timestamp, parts: &[&[u8]], signature: &[u8], now| {
+            verifier.verify_at(b"key", timestamp, parts, signature, now)
+        };
+
+        assert!(verify(1000, &[b"v0:1000:", b"body"], &signature, 1000));
+        assert!(verify(1000, &[b"v0:1000:body"], &signature, 1060));
+        assert!(verify(1000, &[b"v0:1000:body"], &signature, 940));
+        assert!(!verify(1000, &[b"v0:1000:body"], &signature, 1061));
+        assert!(!verify(1000, &[b"v0:1000:body"], &signature, 939));
+        assert!(!verify(1000, &[b"v0:1000:other"], &signature, 1000));
+        assert!(!verify(1000, &[b"v0:1000:body"], &signature[1..], 1000));
+        assert!(!verify(1000, &[b"v0:1000:body"], b"", 1000));
+    }
+
+    #[test]
+    fn test_verify_ed25519() {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap();
+        let public_key = key_pair.public_key().as_ref();
+        let signature = key_pair.sign(b"1000{\"type\":1}");
+        let signature = signature.as_ref();
+        let verifier = SignatureVerifier::new(Duration::from_secs(60));
+        let verify = |parts: &[&[u8]], signature: &[u8], now| {
+            verifier.verify_ed25519_at(public_key, 1000, parts, signature, now)
+        };
+
+        assert!(verify(&[b"1000", b"{\"type\":1}"], signature, 1000));
+        assert!(verify(&[b"1000{\"type\":1}"], signature, 1060));
+        assert!(!verify(&[b"1000{\"type\":1}"], signature, 1061));
+        assert!(!verify(&[b"1000{\"type\":2}"], signature, 1000));
+        assert!(!verify(&[b"1000{\"type\":1}"], &signature[1..], 1000));
+        assert!(!verifier.verify_ed25519_at(&[7; 32], 1000, &[b"1000"], signature, 1000));
+    }
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(Some(vec![0x0f, 0xa0, 0xff]), decode_hex("0fA0ff"));
+        assert_eq!(Some(vec![]), decode_hex(""));
+        assert_eq!(None, decode_hex("abc"));
+        assert_eq!(None, decode_hex("zz"));
+        assert_eq!(None, decode_hex("é1"));
+        assert_eq!("0fa0ff", encode_hex(&decode_hex("0fA0ff").unwrap()));
+    }
+
+    #[test]
+    fn test_encode_base64() {
+        assert_eq!("", encode_base64(b""));
+        assert_eq!("Zg==", encode_base64(b"f"));
+        assert_eq!("Zm8=", encode_base64(b"fo"));
+        assert_eq!("Zm9v", encode_base64(b"foo"));
+        assert_eq!("Zm9vYmE=", encode_base64(b"fooba"));
+        assert_eq!("+/8=", encode_base64(&[0xfb, 0xff]));
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! deadline holds `Deadline`, the time after which the client of a call has given up on it, so
+//! that the call is abandoned instead of keeping storage connections and workers busy for nobody.
+//!
+//! Callers send their deadline either as `X-Request-Deadline`, the Unix time in milliseconds they
+//! give up at, or as `grpc-timeout`, the time they wait for, such as `250m`, in the format of
+//! gRPC. Abandoning a call drops it, with the storage calls and the policy checks it was
+//! awaiting: nothing is left running but the background work it had spawned.
+
+use std::future::Future;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::ShortenerError;
+
+pub const REQUEST_DEADLINE_HEADER: &str = "X-Request-Deadline";
+pub const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+
+/// The most digits of a `grpc-timeout`, as per the gRPC spec
+const MAX_GRPC_TIMEOUT_DIGITS: usize = 8;
+
+/// The time after which a call is abandoned
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    /// Returns the deadline `timeout` from now
+    pub fn after(timeout: Duration) -> Deadline {
+        Deadline(Instant::now() + timeout)
+    }
+
+    /// Returns the deadline at the given Unix time in milliseconds, which may be past already
+    pub fn at_unix_millis(millis: u64) -> Deadline {
+        let at = UNIX_EPOCH + Duration::from_millis(millis);
+        let remaining = at
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO);
+        Deadline::after(remaining)
+    }
+
+    /// Returns the earliest of the deadlines of the given `X-Request-Deadline` and
+    /// `grpc-timeout` headers, and of `default`, the one of calls sending neither. Invalid
+    /// headers are ignored.
+    pub fn from_headers(
+        request_deadline: Option<&str>,
+        grpc_timeout: Option<&str>,
+        default: Option<Duration>,
+    ) -> Option<Deadline> {
+        let request_deadline = request_deadline
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .map(Deadline::at_unix_millis);
+        let grpc_timeout = grpc_timeout
+            .and_then(parse_grpc_timeout)
+            .map(Deadline::after);
+        let default = default.map(Deadline::after);
+
+        [request_deadline, grpc_timeout, default]
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+    }
+
+    /// Returns the time left before the deadline, zero once past
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+
+    /// Tells whether the deadline is past
+    pub fn is_expired(&self) -> bool {
+        self.remaining().is_zero()
+    }
+
+    /// Runs `call` until the deadline, returning `DeadlineExceeded` if it's past before `call`
+    /// ends: `call` is then dropped, midway. `call` isn't started if the deadline is past already.
+    pub async fn run<F: Future>(&self, call: F) -> Result<F::Output, ShortenerError> {
+        if self.is_expired() {
+            return Err(ShortenerError::DeadlineExceeded);
+        }
+        tokio::time::timeout_at(self.0.into(), call)
+            .await
+            .map_err(|_| ShortenerError::DeadlineExceeded)
+    }
+}
+
+/// Parses a `grpc-timeout`: at most 8 digits followed by the unit, `H` for hours, `M` minutes,
+/// `S` seconds, `m` milliseconds, `u` microseconds or `n` nanoseconds
+pub fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let unit = value.chars().last()?;
+    let digits = &value[..value.len() - unit.len_utf8()];
+    if digits.is_empty()
+        || digits.len() > MAX_GRPC_TIMEOUT_DIGITS
+        || !digits.bytes().all(|byte| byte.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let amount = digits.parse::<u64>().ok()?;
+    match unit {
+        'H' => Some(Duration::from_secs(amount * 3600)),
+        'M' => Some(Duration::from_secs(amount * 60)),
+        'S' => Some(Duration::from_secs(amount)),
+        'm' => Some(Duration::from_millis(amount)),
+        'u' => Some(Duration::from_micros(amount)),
+        'n' => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_grpc_timeout() {
+        assert_eq!(Some(Duration::from_secs(7200)), parse_grpc_timeout("2H"));
+        assert_eq!(Some(Duration::from_secs(180)), parse_grpc_timeout("3M"));
+        assert_eq!(Some(Duration::from_secs(1)), parse_grpc_timeout("1S"));
+        assert_eq!(Some(Duration::from_millis(250)), parse_grpc_timeout("250m"));
+        assert_eq!(Some(Duration::from_micros(5)), parse_grpc_timeout("5u"));
+        assert_eq!(
+            Some(Duration::from_nanos(99999999)),
+            parse_grpc_timeout("99999999n")
+        );
+
+        assert_eq!(None, parse_grpc_timeout(""));
+        assert_eq!(None, parse_grpc_timeout("m"));
+        assert_eq!(None, parse_grpc_timeout("250"));
+        assert_eq!(None, parse_grpc_timeout("250ms"));
+        assert_eq!(None, parse_grpc_timeout("-1S"));
+        assert_eq!(None, parse_grpc_timeout("123456789S"));
+        assert_eq!(None, parse_grpc_timeout("1é"));
+    }
+
+    #[tokio::test]
+    async fn test_deadline() {
+        assert_eq!(None, Deadline::from_headers(None, Some("nope"), None));
+
+        let default = Some(Duration::from_secs(60));
+        let deadline = Deadline::from_headers(None, Some("100m"), default).unwrap();
+        assert!(deadline.remaining() <= Duration::from_millis(100));
+
+        let past = Deadline::from_headers(Some("1000"), Some("100m"), default).unwrap();
+        assert!(past.is_expired());
+        assert!(matches!(
+            past.run(async { 1 }).await,
+            Err(ShortenerError::DeadlineExceeded)
+        ));
+
+        assert_eq!(1, deadline.run(async { 1 }).await.unwrap());
+        let late = deadline.run(tokio::time::sleep(Duration::from_secs(5)));
+        assert!(matches!(late.await, Err(ShortenerError::DeadlineExceeded)));
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! domain_blocklist holds the blocklist of destination domains, such as known phishing domains:
+//! URLs on a blocked domain, or on any of its subdomains, can't be shortened.
+//!
+//! Domains come from two sources: the ones given with `Shortener::with_blocked_domains`, usually
+//! read from a file at startup, and the ones of the set `BLOCKED_DOMAINS`, added and removed at
+//! runtime with `block_domain` and `unblock_domain`.
+
+use url::Host;
+
+use crate::domain_index::domains;
+use crate::{Shortener, ShortenerError};
+
+const BLOCKED_DOMAINS: &str = "BLOCKED_DOMAINS";
+
+/// The domains of the blocklist, by source
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct BlockedDomains {
+    pub configured: Vec<String>,
+    pub stored: Vec<String>,
+}
+
+impl Shortener {
+    /// Blocks the given domains, besides the ones stored in `BLOCKED_DOMAINS`. Domains that can't
+    /// be parsed are ignored.
+    pub fn with_blocked_domains(mut self, domains: Vec<String>) -> Shortener {
+        self.blocked_domains = domains
+            .iter()
+            .filter_map(|domain| normalize(domain).ok())
+            .collect();
+        self
+    }
+
+    /// Returns the blocked domains, sorted
+    pub async fn blocked_domains(&self) -> Result<BlockedDomains, ShortenerError> {
+        let mut stored = self
+            .storage
+            .members(BLOCKED_DOMAINS)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        stored.sort();
+
+        Ok(BlockedDomains {
+            configured: self.blocked_domains.iter().cloned().collect(),
+            stored,
+        })
+    }
+
+    /// Blocks a domain and its subdomains, returning whether it wasn't blocked yet
+    pub async fn block_domain(&self, domain: &str) -> Result<bool, ShortenerError> {
+        let domain = normalize(domain)?;
+        if self.blocked_domains.contains(&domain) {
+            return Ok(false);
+        }
+
+        let added = self
+            .storage
+            .add_member(BLOCKED_DOMAINS, &domain)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if added {
+            log::info!("blocked domain '{}'", domain);
+        }
+        Ok(added)
+    }
+
+    /// Unblocks a domain blocked with `block_domain`. Domains given with `with_blocked_domains`
+    /// can't be unblocked at runtime.
+    pub async fn unblock_domain(&self, domain: &str) -> Result<(), ShortenerError> {
+        let domain = normalize(domain)?;
+        if self.blocked_domains.contains(&domain) {
+            return Err(ShortenerError::Conflict("Domain blocked by configuration"));
+        }
+
+        let removed = self
+            .storage
+            .remove_member(BLOCKED_DOMAINS, &domain)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if !removed {
+            return Err(ShortenerError::NotFound("Domain not blocked"));
+        }
+        log::info!("unblocked domain '{}'", domain);
+        Ok(())
+    }
+
+    /// Fails if the destination of a new link is on a blocked domain
+    pub(crate) async fn check_blocklist(&self, url: &str) -> Result<(), ShortenerError> {
+        for domain in domains(url) {
+            let blocked = self.blocked_domains.contains(&domain)
+                || self
+                    .storage
+                    .is_member(BLOCKED_DOMAINS, &domain)
+                    .await
+                    .map_err(ShortenerError::Storage)?;
+            if blocked {
+                log::info!(
+                    "refused to shorten '{}': domain '{}' is blocked",
+                    url,
+                    domain
+                );
+                return Err(ShortenerError::RejectedUrl("Domain blocked"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Normalizes a domain as URLs of that domain are parsed, lowercased and without trailing dot
+fn normalize(domain: &str) -> Result<String, ShortenerError> {
+    let domain = Host::parse(domain.trim().trim_end_matches('.'))
+        .map_err(|_| ShortenerError::InvalidInput("Invalid domain"))?;
+    Ok(domain.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_blocklist() {
+        let shortener = Shortener::new(
+            10,
+            vec!['a', 'b', 'c'],
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
+        )
+        .with_blocked_domains(vec![String::from("Evil.Example.")]);
+        let options = LinkOptions::default();
+        let shorten = |url: &'static str| shortener.shorten(&None, None, url, &options);
+
+        let err = shorten("http://login.evil.example/").await.err().unwrap();
+        assert_eq!("Domain blocked", err.to_string());
+        assert!(shorten("http://example.com/").await.is_ok());
+
+        assert!(shortener.block_domain("example.com").await.unwrap());
+        assert!(!shortener.block_domain("EXAMPLE.com").await.unwrap());
+        assert!(shorten("http://www.example.com/").await.is_err());
+        assert!(shorten("http://example.org/").await.is_ok());
+
+        let blocked = shortener.blocked_domains().await.unwrap();
+        assert_eq!(vec!["evil.example"], blocked.configured);
+        assert_eq!(vec!["example.com"], blocked.stored);
+
+        shortener.unblock_domain("example.com").await.unwrap();
+        assert!(shorten("http://www.example.com/").await.is_ok());
+        let err = shortener.unblock_domain("example.com").await.err().unwrap();
+        assert_eq!("Domain not blocked", err.to_string());
+        let err = shortener
+            .unblock_domain("evil.example")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Domain blocked by configuration", err.to_string());
+    }
+}
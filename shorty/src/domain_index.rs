@@ -0,0 +1,222 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! domain_index holds the index of the links pointing to each destination domain, so that every
+//! link issued to a site can be audited, for example when its owner asks for them.
+//!
+//! The index of a domain is the sorted set `DOMAIN_LINKS_<domain>`, scoring each link ID with its
+//! creation time. A link is indexed under the host of its URL and each parent domain but the top
+//! level one: `https://blog.example.com/` is found under `blog.example.com` and `example.com`.
+//! Expired links are removed from the index when they are found missing, and links shortened
+//! before the index existed are not in it.
+
+use url::{Host, Url};
+
+use crate::link_index::LinkPage;
+use crate::{now, Shortener, ShortenerError};
+
+impl Shortener {
+    /// Returns a page of the links pointing to `domain` or to its subdomains, newest first, paged
+    /// with a `cursor` as in `list_by_api_key`.
+    ///
+    /// With an `api_key`, only the links shortened with it are listed, so that pages may hold
+    /// fewer than `limit` links. Without one, the links of every API key, and those shortened
+    /// without one, are listed: frontends must only allow it to administrators.
+    ///
+    /// Listing links doesn't count against the rate limit of the API key.
+    pub async fn list_by_domain(
+        &self,
+        domain: &str,
+        api_key: Option<&str>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<LinkPage, ShortenerError> {
+        if let Some(api_key) = api_key {
+            if !self.verify_key(api_key).await.valid {
+                return Err(ShortenerError::InvalidApiKey);
+            }
+        }
+        let domain = Host::parse(domain)
+            .map_err(|_| ShortenerError::InvalidInput("Invalid domain"))?
+            .to_string();
+
+        let index = domain_index_key(&domain);
+        let (mut entries, next_cursor) = self.page(&index, cursor, limit).await?;
+        if let Some(api_key) = api_key {
+            let mut owned = Vec::with_capacity(entries.len());
+            for (id, created_at) in entries {
+                let owner = self.storage.get_string(&format!("OWNER_{}", id)).await.ok();
+                if owner.as_deref() == Some(api_key) {
+                    owned.push((id, created_at));
+                }
+            }
+            entries = owned;
+        }
+
+        Ok(LinkPage {
+            links: self.summaries(&index, entries).await?,
+            next_cursor,
+        })
+    }
+
+    /// Adds a new link to the indexes of the domains of its URL
+    pub(crate) async fn index_domains(&self, id: &str, url: &str) -> Result<(), ShortenerError> {
+        let created_at = now();
+        for domain in domains(url) {
+            self.storage
+                .add_scored_member(&domain_index_key(&domain), id, created_at)
+                .await
+                .map_err(ShortenerError::Storage)?;
+        }
+        Ok(())
+    }
+
+    /// Removes a deleted link from the indexes of the domains of its URL
+    pub(crate) async fn unindex_domains(&self, id: &str, url: &str) -> Result<(), ShortenerError> {
+        for domain in domains(url) {
+            self.storage
+                .remove_scored_member(&domain_index_key(&domain), id)
+                .await
+                .map_err(ShortenerError::Storage)?;
+        }
+        Ok(())
+    }
+}
+
+fn domain_index_key(domain: &str) -> String {
+    format!("DOMAIN_LINKS_{}", domain)
+}
+
+/// Returns the domains a URL is indexed under: its host and, for domain names, each parent domain
+/// but the top level one
+pub(crate) fn domains(url: &str) -> Vec<String> {
+    let url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return vec![],
+    };
+    match url.host() {
+        Some(Host::Domain(host)) => {
+            let host = host.trim_end_matches('.');
+            let mut domains = vec![host.to_owned()];
+            let mut parent = host;
+            while let Some((_, rest)) = parent.split_once('.') {
+                if !rest.contains('.') {
+                    break;
+                }
+                domains.push(rest.to_owned());
+                parent = rest;
+            }
+            domains
+        }
+        Some(host) => vec![host.to_string()],
+        None => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::storage::Storage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    #[test]
+    fn test_domains() {
+        assert_eq!(
+            vec!["blog.example.com", "example.com"],
+            domains("https://blog.Example.com./post")
+        );
+        assert_eq!(vec!["localhost"], domains("http://localhost:8080"));
+        assert_eq!(vec!["127.0.0.1"], domains("http://127.0.0.1/"));
+        assert!(domains("not a url").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_list_by_domain() {
+        let storage = MemoryStorage::new();
+        storage.set("API_KEY_api key", "true").await.unwrap();
+        storage.set("API_KEY_other key", "true").await.unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        for (api_key, id, url) in &[
+            ("api key", "a", "example.com/a"),
+            ("other key", "b", "blog.example.com/b"),
+            ("api key", "c", "example.org/c"),
+            ("api key", "ab", "www.example.com/ab"),
+        ] {
+            shortener
+                .shorten_with_id(&Some(api_key), None, id, url, &LinkOptions::default())
+                .await
+                .unwrap();
+        }
+
+        let page = shortener
+            .list_by_domain("EXAMPLE.com", None, None, 10)
+            .await
+            .unwrap();
+        let mut ids = page
+            .links
+            .iter()
+            .map(|link| link.id.as_str())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        assert_eq!(vec!["a", "ab", "b"], ids);
+        assert_eq!(None, page.next_cursor);
+
+        let page = shortener
+            .list_by_domain("blog.example.com", None, None, 10)
+            .await
+            .unwrap();
+        assert_eq!("http://blog.example.com/b", page.links[0].url);
+
+        let page = shortener
+            .list_by_domain("example.com", Some("other key"), None, 10)
+            .await
+            .unwrap();
+        assert_eq!(1, page.links.len());
+        assert_eq!("b", page.links[0].id);
+
+        shortener.delete("api key", "a").await.unwrap();
+        shortener.storage.expire("ab", 0).await.unwrap();
+        let page = shortener
+            .list_by_domain("example.com", Some("api key"), None, 10)
+            .await
+            .unwrap();
+        assert!(page.links.is_empty());
+        assert_eq!(
+            vec![String::from("b")],
+            shortener
+                .storage
+                .scored_members("DOMAIN_LINKS_example.com", 0, u64::MAX, 10)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>()
+        );
+
+        let err = shortener
+            .list_by_domain("exa mple.com", None, None, 10)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Invalid domain", err.to_string());
+        let err = shortener
+            .list_by_domain("example.com", Some("invalid key"), None, 10)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Invalid API key", err.to_string());
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! error_page holds `ErrorPage`, the body of the error responses seen by the people following
+//! short links, so that a mistyped link shows a helpful page instead of a blank one.
+//!
+//! Deployments can replace the page of each status with their own template, for example to
+//! translate it: see `ErrorPage::html`.
+
+use crate::accepts;
+use crate::templates::{Templates, ERROR_TEMPLATE};
+
+/// The built-in `error.html` template of `ErrorPage::html`
+pub const DEFAULT_ERROR_TEMPLATE: &str = r#"{% extends "base.html" %}
+{% block title %}{{ title }}{% endblock title %}
+{% block content %}
+<h1>{{ title }}</h1>
+<p>{{ message }}</p>
+{% if successor %}<p><a href="{{ successor }}">{{ successor }}</a></p>{% endif %}
+<p>Error {{ status }}</p>
+{% endblock content %}
+"#;
+
+/// The error of a response, with the `status` code and a `title` and `message` meant for people,
+/// and, for links re-issued with a new ID, the `successor` URL to follow instead.
+///
+/// Frontends render it with `html` for clients accepting HTML (see `Redirect::accepts_html`), with
+/// `json` for those accepting JSON (see `accepts_json`), and send an empty body otherwise.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ErrorPage {
+    pub status: u16,
+    pub title: &'static str,
+    pub message: &'static str,
+    pub successor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ErrorPageJson<'a> {
+    status: u16,
+    err: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    successor: Option<&'a str>,
+}
+
+impl ErrorPage {
+    /// Returns the page of `status`: `403`, `404` and `410` have their own text, other statuses a
+    /// generic one
+    pub fn new(status: u16) -> ErrorPage {
+        let (title, message) = match status {
+            403 => (
+                "Access denied",
+                "You are not allowed to open this page.",
+            ),
+            404 => (
+                "Link not found",
+                "This short link doesn't exist. Short links are case sensitive: please check it was typed or copied correctly.",
+            ),
+            410 => (
+                "Link no longer available",
+                "This short link existed, but it has expired or has been removed.",
+            ),
+            _ => (
+                "Something went wrong",
+                "This page can't be shown right now. Please try again later.",
+            ),
+        };
+
+        ErrorPage {
+            status,
+            title,
+            message,
+            successor: None,
+        }
+    }
+
+    /// Returns the `410 Gone` page of a link re-issued as `successor`, see
+    /// `Shortener::rotate_campaign`
+    pub fn rotated(successor: String) -> ErrorPage {
+        ErrorPage {
+            message: "This short link has been replaced with a new one.",
+            successor: Some(successor),
+            ..ErrorPage::new(410)
+        }
+    }
+
+    /// Renders the HTML body of the error with the template of its status in `templates`, such as
+    /// `404.html`, or with `error.html` if there is none. Templates get the `status`, the English
+    /// `title` and `message`, and the `successor`, if any: templates of other languages can write
+    /// their own text instead.
+    pub fn html(&self, templates: &Templates) -> String {
+        let template = format!("{}.html", self.status);
+        if templates.has(&template) {
+            templates.render(&template, self)
+        } else {
+            templates.render(ERROR_TEMPLATE, self)
+        }
+    }
+
+    /// Renders the JSON body of the error, with the `err` field of the other API errors
+    pub fn json(&self) -> String {
+        serde_json::to_string(&ErrorPageJson {
+            status: self.status,
+            err: self.title,
+            message: self.message,
+            successor: self.successor.as_deref(),
+        })
+        .unwrap()
+    }
+
+    /// Tells whether a client sending the given `Accept` header wants a JSON body
+    pub fn accepts_json(accept: Option<&str>) -> bool {
+        accepts(accept, "application/json")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_html() {
+        let page = ErrorPage::new(404);
+        let html = page.html(&Templates::new());
+        assert!(html.contains("<title>Link not found</title>"));
+        assert!(html.contains("Short links are case sensitive"));
+        assert!(html.contains("Error 404"));
+
+        let templates = Templates::new()
+            .with_template("404.html", "<h1>Lien introuvable</h1> ({{status}})")
+            .unwrap();
+        assert_eq!(page.html(&templates), "<h1>Lien introuvable</h1> (404)");
+        assert!(ErrorPage::new(410)
+            .html(&templates)
+            .contains("<title>Link no longer available</title>"));
+    }
+
+    #[test]
+    fn test_json() {
+        let json = serde_json::from_str::<serde_json::Value>(&ErrorPage::new(403).json()).unwrap();
+        assert_eq!(json["status"], 403);
+        assert_eq!(json["err"], "Access denied");
+        assert!(json.get("successor").is_none());
+
+        let page = ErrorPage::rotated(String::from("https://sho.rt/new"));
+        let json = serde_json::from_str::<serde_json::Value>(&page.json()).unwrap();
+        assert_eq!(json["status"], 410);
+        assert_eq!(json["successor"], "https://sho.rt/new");
+        assert!(page
+            .html(&Templates::new())
+            .contains("<a href=\"https://sho.rt/new\">"));
+    }
+
+    #[test]
+    fn test_accepts_json() {
+        assert!(ErrorPage::accepts_json(Some("application/json")));
+        assert!(ErrorPage::accepts_json(Some(
+            "text/html;q=0.9, application/json"
+        )));
+        assert!(!ErrorPage::accepts_json(Some("application/json;q=0")));
+        assert!(!ErrorPage::accepts_json(Some("text/html")));
+        assert!(!ErrorPage::accepts_json(None));
+    }
+}
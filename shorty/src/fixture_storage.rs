@@ -0,0 +1,699 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! fixture_storage records the interactions with a `Storage` to a fixture file, and replays them
+//! in tests.
+//!
+//! Wrap the real storage with a `RecordingStorage` to write each call, with its arguments and
+//! result, as a line of JSON. A `ReplayStorage` loaded from that file answers the same calls, in
+//! the same order, with the recorded results: a trace from a production incident becomes a
+//! deterministic regression test.
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use redis::{ErrorKind, RedisError};
+
+use crate::shadow_storage::ShadowStats;
+use crate::storage::{Storage, StorageResult};
+
+/// The value returned by a successful `Storage` call
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Reply {
+    Unit,
+    Bool(bool),
+    Int(i64),
+    String(String),
+    Strings(Vec<String>),
+    ScoredStrings(Vec<(String, u64)>),
+}
+
+/// A `Storage` call, with its arguments and its result. Errors are recorded with their message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Interaction {
+    pub command: String,
+    pub args: Vec<String>,
+    pub result: Result<Reply, String>,
+}
+
+/// `RecordingStorage` is a `Storage` forwarding every call to the wrapped storage, and appending
+/// the call and its result to a fixture file.
+pub struct RecordingStorage {
+    storage: Box<dyn Storage>,
+    fixture: Mutex<File>,
+}
+
+impl RecordingStorage {
+    /// Creates a new `RecordingStorage`, wrapping `storage` and writing to the fixture file at
+    /// `path`, which is truncated if it exists.
+    pub fn new<P: AsRef<Path>>(storage: Box<dyn Storage>, path: P) -> io::Result<RecordingStorage> {
+        Ok(RecordingStorage {
+            storage,
+            fixture: Mutex::new(File::create(path)?),
+        })
+    }
+
+    fn record<T>(
+        &self,
+        command: &str,
+        args: &[&str],
+        result: &StorageResult<T>,
+        reply: impl FnOnce(&T) -> Reply,
+    ) {
+        let interaction = Interaction {
+            command: command.to_owned(),
+            args: args.iter().map(|arg| (*arg).to_owned()).collect(),
+            result: result.as_ref().map(reply).map_err(|err| err.to_string()),
+        };
+
+        let line = serde_json::to_string(&interaction).unwrap();
+        let mut fixture = self.fixture.lock().unwrap();
+        if let Err(err) = writeln!(fixture, "{}", line) {
+            log::error!("unable to record {} call: {}", command, err);
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for RecordingStorage {
+    async fn get_string(&self, key: &str) -> StorageResult<String> {
+        let result = self.storage.get_string(key).await;
+        self.record("get_string", &[key], &result, |value| {
+            Reply::String(value.clone())
+        });
+        result
+    }
+
+    async fn exists(&self, key: &str) -> StorageResult<bool> {
+        let result = self.storage.exists(key).await;
+        self.record("exists", &[key], &result, |value| Reply::Bool(*value));
+        result
+    }
+
+    async fn increment(&self, key: &str) -> StorageResult<i64> {
+        let result = self.storage.increment(key).await;
+        self.record("increment", &[key], &result, |value| Reply::Int(*value));
+        result
+    }
+
+    async fn increment_expiring(&self, key: &str, period: usize) -> StorageResult<i64> {
+        let result = self.storage.increment_expiring(key, period).await;
+        self.record(
+            "increment_expiring",
+            &[key, &period.to_string()],
+            &result,
+            |value| Reply::Int(*value),
+        );
+        result
+    }
+
+    async fn expire(&self, key: &str, period: usize) -> StorageResult<()> {
+        let result = self.storage.expire(key, period).await;
+        self.record("expire", &[key, &period.to_string()], &result, |_| {
+            Reply::Unit
+        });
+        result
+    }
+
+    async fn ttl(&self, key: &str) -> StorageResult<Option<u64>> {
+        let result = self.storage.ttl(key).await;
+        self.record("ttl", &[key], &result, |ttl| {
+            Reply::Int(ttl.map_or(-1, |ttl| ttl as i64))
+        });
+        result
+    }
+
+    async fn set(&self, key: &str, value: &str) -> StorageResult<()> {
+        let result = self.storage.set(key, value).await;
+        self.record("set", &[key, value], &result, |_| Reply::Unit);
+        result
+    }
+
+    async fn set_expiring(&self, key: &str, value: &str, period: usize) -> StorageResult<()> {
+        let result = self.storage.set_expiring(key, value, period).await;
+        self.record(
+            "set_expiring",
+            &[key, value, &period.to_string()],
+            &result,
+            |_| Reply::Unit,
+        );
+        result
+    }
+
+    async fn set_if_not_exists(&self, key: &str, value: &str) -> StorageResult<bool> {
+        let result = self.storage.set_if_not_exists(key, value).await;
+        self.record("set_if_not_exists", &[key, value], &result, |value| {
+            Reply::Bool(*value)
+        });
+        result
+    }
+
+    async fn set_if_not_exists_expiring(
+        &self,
+        key: &str,
+        value: &str,
+        period: usize,
+    ) -> StorageResult<bool> {
+        let result = self
+            .storage
+            .set_if_not_exists_expiring(key, value, period)
+            .await;
+        self.record(
+            "set_if_not_exists_expiring",
+            &[key, value, &period.to_string()],
+            &result,
+            |value| Reply::Bool(*value),
+        );
+        result
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<bool> {
+        let result = self.storage.delete(key).await;
+        self.record("delete", &[key], &result, |value| Reply::Bool(*value));
+        result
+    }
+
+    async fn rename(&self, key: &str, new_key: &str) -> StorageResult<bool> {
+        let result = self.storage.rename(key, new_key).await;
+        self.record("rename", &[key, new_key], &result, |value| {
+            Reply::Bool(*value)
+        });
+        result
+    }
+
+    async fn add_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        let result = self.storage.add_member(key, member).await;
+        self.record("add_member", &[key, member], &result, |value| {
+            Reply::Bool(*value)
+        });
+        result
+    }
+
+    async fn remove_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        let result = self.storage.remove_member(key, member).await;
+        self.record("remove_member", &[key, member], &result, |value| {
+            Reply::Bool(*value)
+        });
+        result
+    }
+
+    async fn members(&self, key: &str) -> StorageResult<Vec<String>> {
+        let result = self.storage.members(key).await;
+        self.record("members", &[key], &result, |values| {
+            Reply::Strings(values.clone())
+        });
+        result
+    }
+
+    async fn is_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        let result = self.storage.is_member(key, member).await;
+        self.record("is_member", &[key, member], &result, |value| {
+            Reply::Bool(*value)
+        });
+        result
+    }
+
+    async fn add_scored_member(&self, key: &str, member: &str, score: u64) -> StorageResult<bool> {
+        let result = self.storage.add_scored_member(key, member, score).await;
+        self.record(
+            "add_scored_member",
+            &[key, member, &score.to_string()],
+            &result,
+            |value| Reply::Bool(*value),
+        );
+        result
+    }
+
+    async fn remove_scored_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        let result = self.storage.remove_scored_member(key, member).await;
+        self.record("remove_scored_member", &[key, member], &result, |value| {
+            Reply::Bool(*value)
+        });
+        result
+    }
+
+    async fn scored_members(
+        &self,
+        key: &str,
+        min_score: u64,
+        max_score: u64,
+        limit: usize,
+    ) -> StorageResult<Vec<(String, u64)>> {
+        let result = self
+            .storage
+            .scored_members(key, min_score, max_score, limit)
+            .await;
+        self.record(
+            "scored_members",
+            &[
+                key,
+                &min_score.to_string(),
+                &max_score.to_string(),
+                &limit.to_string(),
+            ],
+            &result,
+            |values| Reply::ScoredStrings(values.clone()),
+        );
+        result
+    }
+
+    async fn scored_members_ascending(
+        &self,
+        key: &str,
+        min_score: u64,
+        max_score: u64,
+        limit: usize,
+    ) -> StorageResult<Vec<(String, u64)>> {
+        let result = self
+            .storage
+            .scored_members_ascending(key, min_score, max_score, limit)
+            .await;
+        self.record(
+            "scored_members_ascending",
+            &[
+                key,
+                &min_score.to_string(),
+                &max_score.to_string(),
+                &limit.to_string(),
+            ],
+            &result,
+            |values| Reply::ScoredStrings(values.clone()),
+        );
+        result
+    }
+
+    async fn scan(&self, prefix: &str) -> StorageResult<Vec<String>> {
+        let result = self.storage.scan(prefix).await;
+        self.record("scan", &[prefix], &result, |values| {
+            Reply::Strings(values.clone())
+        });
+        result
+    }
+
+    async fn config_get(&self, parameter: &str) -> StorageResult<Vec<String>> {
+        let result = self.storage.config_get(parameter).await;
+        self.record("config_get", &[parameter], &result, |values| {
+            Reply::Strings(values.clone())
+        });
+        result
+    }
+
+    async fn info(&self, section: &str) -> StorageResult<Vec<String>> {
+        let result = self.storage.info(section).await;
+        self.record("info", &[section], &result, |values| {
+            Reply::Strings(values.clone())
+        });
+        result
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        let result = self.storage.ping().await;
+        self.record("ping", &[], &result, |_| Reply::Unit);
+        result
+    }
+
+    fn shadow_stats(&self) -> Option<ShadowStats> {
+        self.storage.shadow_stats()
+    }
+
+    async fn close(&self) {
+        self.storage.close().await
+    }
+}
+
+/// `ReplayStorage` is a `Storage` answering calls with the results recorded in a fixture file,
+/// without touching any real storage.
+///
+/// Calls must come in the recorded order: a call to a different command, or a call past the end
+/// of the fixture, panics. Calls with different arguments are answered anyway, since some of them
+/// are random (such as generated IDs), and are listed by `mismatches`.
+pub struct ReplayStorage {
+    interactions: Mutex<VecDeque<Interaction>>,
+    mismatches: Mutex<Vec<String>>,
+}
+
+impl ReplayStorage {
+    /// Creates a new `ReplayStorage` with the given interactions
+    pub fn new(interactions: Vec<Interaction>) -> ReplayStorage {
+        ReplayStorage {
+            interactions: Mutex::new(interactions.into()),
+            mismatches: Mutex::new(vec![]),
+        }
+    }
+
+    /// Creates a new `ReplayStorage` with the interactions recorded in the fixture file at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<ReplayStorage> {
+        let interactions = fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str::<Interaction>)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ReplayStorage::new(interactions))
+    }
+
+    /// Returns the number of recorded interactions not replayed yet
+    pub fn remaining(&self) -> usize {
+        self.interactions.lock().unwrap().len()
+    }
+
+    /// Returns a description of each call made with arguments different from the recorded ones
+    pub fn mismatches(&self) -> Vec<String> {
+        self.mismatches.lock().unwrap().clone()
+    }
+
+    fn replay<T>(
+        &self,
+        command: &str,
+        args: &[&str],
+        value: impl FnOnce(Reply) -> Option<T>,
+    ) -> StorageResult<T> {
+        let interaction = self
+            .interactions
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| panic!("unexpected {} call: the fixture is over", command));
+
+        if interaction.command != command {
+            panic!(
+                "unexpected {} call: the fixture expects a {} call",
+                command, interaction.command
+            );
+        }
+
+        if interaction.args != args {
+            self.mismatches.lock().unwrap().push(format!(
+                "{} called with {:?} instead of {:?}",
+                command, args, interaction.args
+            ));
+        }
+
+        match interaction.result {
+            Ok(reply) => Ok(value(reply.clone())
+                .unwrap_or_else(|| panic!("unexpected {:?} reply to a {} call", reply, command))),
+            Err(message) => {
+                Err(RedisError::from((ErrorKind::IoError, "replayed error", message)).into())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for ReplayStorage {
+    async fn get_string(&self, key: &str) -> StorageResult<String> {
+        self.replay("get_string", &[key], |reply| match reply {
+            Reply::String(value) => Some(value),
+            _ => None,
+        })
+    }
+
+    async fn exists(&self, key: &str) -> StorageResult<bool> {
+        self.replay("exists", &[key], |reply| match reply {
+            Reply::Bool(value) => Some(value),
+            _ => None,
+        })
+    }
+
+    async fn increment(&self, key: &str) -> StorageResult<i64> {
+        self.replay("increment", &[key], |reply| match reply {
+            Reply::Int(value) => Some(value),
+            _ => None,
+        })
+    }
+
+    async fn increment_expiring(&self, key: &str, period: usize) -> StorageResult<i64> {
+        self.replay(
+            "increment_expiring",
+            &[key, &period.to_string()],
+            |reply| match reply {
+                Reply::Int(value) => Some(value),
+                _ => None,
+            },
+        )
+    }
+
+    async fn expire(&self, key: &str, period: usize) -> StorageResult<()> {
+        self.replay("expire", &[key, &period.to_string()], |reply| match reply {
+            Reply::Unit => Some(()),
+            _ => None,
+        })
+    }
+
+    async fn ttl(&self, key: &str) -> StorageResult<Option<u64>> {
+        self.replay("ttl", &[key], |reply| match reply {
+            Reply::Int(ttl) => Some(u64::try_from(ttl).ok()),
+            _ => None,
+        })
+    }
+
+    async fn set(&self, key: &str, value: &str) -> StorageResult<()> {
+        self.replay("set", &[key, value], |reply| match reply {
+            Reply::Unit => Some(()),
+            _ => None,
+        })
+    }
+
+    async fn set_expiring(&self, key: &str, value: &str, period: usize) -> StorageResult<()> {
+        self.replay(
+            "set_expiring",
+            &[key, value, &period.to_string()],
+            |reply| match reply {
+                Reply::Unit => Some(()),
+                _ => None,
+            },
+        )
+    }
+
+    async fn set_if_not_exists(&self, key: &str, value: &str) -> StorageResult<bool> {
+        self.replay("set_if_not_exists", &[key, value], |reply| match reply {
+            Reply::Bool(value) => Some(value),
+            _ => None,
+        })
+    }
+
+    async fn set_if_not_exists_expiring(
+        &self,
+        key: &str,
+        value: &str,
+        period: usize,
+    ) -> StorageResult<bool> {
+        self.replay(
+            "set_if_not_exists_expiring",
+            &[key, value, &period.to_string()],
+            |reply| match reply {
+                Reply::Bool(value) => Some(value),
+                _ => None,
+            },
+        )
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<bool> {
+        self.replay("delete", &[key], |reply| match reply {
+            Reply::Bool(value) => Some(value),
+            _ => None,
+        })
+    }
+
+    async fn rename(&self, key: &str, new_key: &str) -> StorageResult<bool> {
+        self.replay("rename", &[key, new_key], |reply| match reply {
+            Reply::Bool(value) => Some(value),
+            _ => None,
+        })
+    }
+
+    async fn add_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        self.replay("add_member", &[key, member], |reply| match reply {
+            Reply::Bool(value) => Some(value),
+            _ => None,
+        })
+    }
+
+    async fn remove_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        self.replay("remove_member", &[key, member], |reply| match reply {
+            Reply::Bool(value) => Some(value),
+            _ => None,
+        })
+    }
+
+    async fn members(&self, key: &str) -> StorageResult<Vec<String>> {
+        self.replay("members", &[key], |reply| match reply {
+            Reply::Strings(values) => Some(values),
+            _ => None,
+        })
+    }
+
+    async fn is_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        self.replay("is_member", &[key, member], |reply| match reply {
+            Reply::Bool(value) => Some(value),
+            _ => None,
+        })
+    }
+
+    async fn add_scored_member(&self, key: &str, member: &str, score: u64) -> StorageResult<bool> {
+        self.replay(
+            "add_scored_member",
+            &[key, member, &score.to_string()],
+            |reply| match reply {
+                Reply::Bool(value) => Some(value),
+                _ => None,
+            },
+        )
+    }
+
+    async fn remove_scored_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        self.replay(
+            "remove_scored_member",
+            &[key, member],
+            |reply| match reply {
+                Reply::Bool(value) => Some(value),
+                _ => None,
+            },
+        )
+    }
+
+    async fn scored_members(
+        &self,
+        key: &str,
+        min_score: u64,
+        max_score: u64,
+        limit: usize,
+    ) -> StorageResult<Vec<(String, u64)>> {
+        self.replay(
+            "scored_members",
+            &[
+                key,
+                &min_score.to_string(),
+                &max_score.to_string(),
+                &limit.to_string(),
+            ],
+            |reply| match reply {
+                Reply::ScoredStrings(values) => Some(values),
+                _ => None,
+            },
+        )
+    }
+
+    async fn scored_members_ascending(
+        &self,
+        key: &str,
+        min_score: u64,
+        max_score: u64,
+        limit: usize,
+    ) -> StorageResult<Vec<(String, u64)>> {
+        self.replay(
+            "scored_members_ascending",
+            &[
+                key,
+                &min_score.to_string(),
+                &max_score.to_string(),
+                &limit.to_string(),
+            ],
+            |reply| match reply {
+                Reply::ScoredStrings(values) => Some(values),
+                _ => None,
+            },
+        )
+    }
+
+    async fn scan(&self, prefix: &str) -> StorageResult<Vec<String>> {
+        self.replay("scan", &[prefix], |reply| match reply {
+            Reply::Strings(values) => Some(values),
+            _ => None,
+        })
+    }
+
+    async fn config_get(&self, parameter: &str) -> StorageResult<Vec<String>> {
+        self.replay("config_get", &[parameter], |reply| match reply {
+            Reply::Strings(values) => Some(values),
+            _ => None,
+        })
+    }
+
+    async fn info(&self, section: &str) -> StorageResult<Vec<String>> {
+        self.replay("info", &[section], |reply| match reply {
+            Reply::Strings(values) => Some(values),
+            _ => None,
+        })
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        self.replay("ping", &[], |reply| match reply {
+            Reply::Unit => Some(()),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use crate::memory_storage::MemoryStorage;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = env::temp_dir().join(format!("shorty-fixture-{}.jsonl", std::process::id()));
+
+        let recording = RecordingStorage::new(Box::new(MemoryStorage::new()), &path).unwrap();
+        recording.set("key", "value").await.unwrap();
+        assert_eq!("value", recording.get_string("key").await.unwrap());
+        assert!(recording.get_string("missing").await.is_err());
+        assert_eq!(1, recording.increment("counter").await.unwrap());
+        drop(recording);
+
+        let replay = ReplayStorage::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        replay.set("key", "value").await.unwrap();
+        assert_eq!("value", replay.get_string("key").await.unwrap());
+        assert!(replay.get_string("missing").await.is_err());
+        assert_eq!(1, replay.increment("other counter").await.unwrap());
+
+        assert_eq!(0, replay.remaining());
+        assert_eq!(
+            vec![String::from(
+                "increment called with [\"other counter\"] instead of [\"counter\"]"
+            )],
+            replay.mismatches()
+        );
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "unexpected exists call: the fixture expects a set call")]
+    async fn test_replay_unexpected_command() {
+        let replay = ReplayStorage::new(vec![Interaction {
+            command: String::from("set"),
+            args: vec![String::from("key"), String::from("value")],
+            result: Ok(Reply::Unit),
+        }]);
+
+        replay.exists("key").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "unexpected get_string call: the fixture is over")]
+    async fn test_replay_past_the_end() {
+        let replay = ReplayStorage::new(vec![]);
+
+        replay.get_string("key").await.unwrap();
+    }
+}
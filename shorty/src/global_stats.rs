@@ -0,0 +1,278 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! global_stats holds the roll-up statistics of a whole shorty instance, meant for operators.
+//!
+//! Besides the keys of the links, they are computed from two rolling counters:
+//! - `STATS_CREATED_<day>`: the links created in a day, counted from the Unix epoch in UTC
+//! - `STATS_REDIRECTS_<minute>`: the redirects served in a minute, counted from the Unix epoch
+
+use std::collections::BTreeMap;
+
+use crate::shadow_storage::ShadowStats;
+use crate::{now, Shortener, ShortenerError};
+
+const HOTTEST_LINKS: usize = 10;
+
+const DAY: u64 = 24 * 60 * 60;
+
+const MINUTE: u64 = 60;
+
+/// The kinds of keys counted by `GlobalStats::keys_by_type`, by prefix. The first matching prefix
+/// wins; keys without an underscore are links, and anything else is `other`.
+const KEY_TYPES: [(&str, &str); 28] = [
+    ("RATE_API_KEY_", "rate_limits"),
+    ("RATE_LIMIT_API_KEY_", "rate_limits"),
+    ("INFLIGHT_API_KEY_", "rate_limits"),
+    ("FEATURES_API_KEY_", "features"),
+    ("DEFAULTS_API_KEY_", "link_defaults"),
+    ("VERIFIED_API_KEY_", "verified_keys"),
+    ("API_KEY_", "api_keys"),
+    ("API_KEYS", "api_keys"),
+    ("HITS_", "hits"),
+    ("CREATED_", "created"),
+    ("PERMANENT_", "permanent"),
+    ("PRECONNECT_", "preconnect"),
+    ("REDIRECT_STATUS_", "redirect_statuses"),
+    ("SCHEDULE_", "schedules"),
+    ("OWNER_", "owners"),
+    ("METADATA_", "metadata"),
+    ("URL_", "deduplication"),
+    ("CAMPAIGN", "campaigns"),
+    ("STATS_", "stats"),
+    ("LINKS_", "link_indexes"),
+    ("QUARANTINE", "quarantine"),
+    ("TAKEDOWN", "takedowns"),
+    ("DOMAIN_SEEN_", "domains"),
+    ("BLOCKED_DOMAINS", "blocklist"),
+    ("DOMAIN_LINKS_", "domain_indexes"),
+    ("EVENTS_", "events"),
+    ("CLICKS_", "clicks"),
+    ("LINK_CHANGES", "changes"),
+];
+
+/// The roll-up statistics of a shorty instance, see `Shortener::global_stats`.
+///
+/// `redirects_per_minute` is estimated over the last sixty seconds. `cache_hit_rate` is the ratio
+/// of Redis lookups finding their key, and is missing for storages not backed by Redis.
+/// `dropped_clicks` is the number of redirects this instance didn't count since it started, its
+/// click queue being full: see `click_queue`. `click_sampling` is the one in how many clicks
+/// counted in `redirects_per_minute` and in daily clicks, estimates when above one.
+/// `shadow_storage` compares the storage with the one being migrated to, if any: see
+/// `shadow_storage`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct GlobalStats {
+    pub links: usize,
+    pub links_created_today: u64,
+    pub redirects_per_minute: u64,
+    pub keys_by_type: BTreeMap<String, usize>,
+    pub cache_hit_rate: Option<f64>,
+    pub hottest_links: Vec<LinkHits>,
+    pub dropped_clicks: u64,
+    pub click_sampling: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shadow_storage: Option<ShadowStats>,
+}
+
+/// A link with its number of hits
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct LinkHits {
+    pub id: String,
+    pub hits: u64,
+}
+
+impl Shortener {
+    /// Computes the roll-up statistics of all the links. It walks the whole keyspace, and it's
+    /// meant to be called by operators, not on every request.
+    pub async fn global_stats(&self) -> Result<GlobalStats, ShortenerError> {
+        let keys = self
+            .storage
+            .scan("")
+            .await
+            .map_err(ShortenerError::Storage)?;
+
+        let mut keys_by_type = BTreeMap::new();
+        for key in &keys {
+            *keys_by_type.entry(key_type(key).to_owned()).or_insert(0) += 1;
+        }
+
+        let mut hottest_links = Vec::new();
+        for key in keys.iter().filter(|key| key.starts_with("HITS_")) {
+            hottest_links.push(LinkHits {
+                id: key["HITS_".len()..].to_owned(),
+                hits: self.counter(key).await,
+            });
+        }
+        hottest_links.sort_by(|a, b| b.hits.cmp(&a.hits).then_with(|| a.id.cmp(&b.id)));
+        hottest_links.truncate(HOTTEST_LINKS);
+
+        let now = now();
+        let links_created_today = self.counter(&format!("STATS_CREATED_{}", now / DAY)).await;
+        let redirects_per_minute = {
+            let current = self
+                .counter(&format!("STATS_REDIRECTS_{}", now / MINUTE))
+                .await;
+            let previous = self
+                .counter(&format!("STATS_REDIRECTS_{}", now / MINUTE - 1))
+                .await;
+            // the previous minute weighs as much as it overlaps with the last sixty seconds
+            previous * (MINUTE - now % MINUTE) / MINUTE + current
+        };
+
+        Ok(GlobalStats {
+            links: keys_by_type.get("links").copied().unwrap_or(0),
+            links_created_today,
+            redirects_per_minute,
+            keys_by_type,
+            cache_hit_rate: self.cache_hit_rate().await,
+            hottest_links,
+            dropped_clicks: self.dropped_clicks(),
+            click_sampling: self.click_sampling,
+            shadow_storage: self.storage.shadow_stats(),
+        })
+    }
+
+    /// Counts a new link in the rolling counters. Statistics are not worth failing a shortening:
+    /// errors are just logged.
+    pub(crate) async fn count_creation(&self) {
+        self.count_creations(1).await;
+    }
+
+    /// Counts `links` new links at once, logging errors as `count_creation`
+    pub(crate) async fn count_creations(&self, links: i64) {
+        self.count(&format!("STATS_CREATED_{}", now() / DAY), links, 2 * DAY)
+            .await;
+    }
+
+    /// Counts `redirects` redirects in the rolling counters, logging errors as `count_creation`
+    pub(crate) async fn count_redirects(&self, redirects: i64) {
+        self.count(
+            &format!("STATS_REDIRECTS_{}", now() / MINUTE),
+            redirects,
+            2 * MINUTE,
+        )
+        .await;
+    }
+
+    /// Adds `amount` to the counter `key`, unless built without the `analytics` feature
+    async fn count(&self, key: &str, amount: i64, lifetime: u64) {
+        if !cfg!(feature = "analytics") {
+            return;
+        }
+        if let Err(err) = self
+            .storage
+            .increment_expiring_by(key, amount, lifetime as usize)
+            .await
+        {
+            log::warn!("unable to count '{}': {}", key, err);
+        }
+    }
+
+    /// Reads a counter, a missing or unreadable one being zero
+    pub(crate) async fn counter(&self, key: &str) -> u64 {
+        self.storage
+            .get_string(key)
+            .await
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Sums `keyspace_hits` and `keyspace_misses` of every Redis server, and returns the ratio of
+    /// hits. There's no rate without lookups, nor without Redis.
+    async fn cache_hit_rate(&self) -> Option<f64> {
+        let reports = self.storage.info("stats").await.ok()?;
+
+        let (mut hits, mut misses) = (0_u64, 0_u64);
+        for line in reports.iter().flat_map(|report| report.lines()) {
+            let (name, value) = match line.trim().split_once(':') {
+                Some((name, value)) => (name, value.parse::<u64>().unwrap_or(0)),
+                None => continue,
+            };
+            match name {
+                "keyspace_hits" => hits += value,
+                "keyspace_misses" => misses += value,
+                _ => {}
+            }
+        }
+
+        if hits + misses == 0 {
+            return None;
+        }
+        Some(hits as f64 / (hits + misses) as f64)
+    }
+}
+
+pub(crate) fn key_type(key: &str) -> &'static str {
+    KEY_TYPES
+        .iter()
+        .find(|(prefix, _)| key.starts_with(prefix))
+        .map(|(_, key_type)| *key_type)
+        .unwrap_or(if key.contains('_') { "other" } else { "links" })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_global_stats() {
+        let storage = MemoryStorage::new();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let mut ids = Vec::new();
+        for _ in 0..12 {
+            let result = shortener
+                .shorten(&None, None, "example.com", &LinkOptions::default())
+                .await
+                .unwrap();
+            ids.push(result.id);
+        }
+        for _ in 0..3 {
+            shortener.lookup(&ids[4]).await.unwrap();
+        }
+        shortener.lookup(&ids[7]).await.unwrap();
+
+        let stats = shortener.global_stats().await.unwrap();
+        assert_eq!(12, stats.links);
+        assert_eq!(12, stats.links_created_today);
+        assert_eq!(4, stats.redirects_per_minute);
+        assert_eq!(Some(&12), stats.keys_by_type.get("hits"));
+        assert_eq!(Some(&12), stats.keys_by_type.get("created"));
+        assert_eq!(None, stats.cache_hit_rate);
+        assert_eq!(HOTTEST_LINKS, stats.hottest_links.len());
+        assert_eq!(
+            LinkHits {
+                id: ids[4].clone(),
+                hits: 3
+            },
+            stats.hottest_links[0]
+        );
+        assert_eq!(ids[7], stats.hottest_links[1].id);
+    }
+
+    #[test]
+    fn test_key_type() {
+        assert_eq!("links", key_type("abc"));
+        assert_eq!("api_keys", key_type("API_KEY_test"));
+        assert_eq!("rate_limits", key_type("RATE_API_KEY_test"));
+        assert_eq!("campaigns", key_type("CAMPAIGN_OF_abc"));
+        assert_eq!("other", key_type("SOMETHING_else"));
+    }
+}
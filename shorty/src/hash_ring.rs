@@ -0,0 +1,120 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! hash_ring is a consistent hashing ring, used by `RedisFacade` to spread keys across multiple
+//! Redis instances
+
+const VIRTUAL_NODES: u32 = 160;
+
+/// `HashRing` maps keys to nodes by consistent hashing: adding or removing a node only moves the
+/// keys owned by that node, instead of reshuffling the whole keyspace.
+///
+/// Nodes are identified by name (such as `host:port`), not by position, so reordering the list of
+/// nodes doesn't move any key.
+pub struct HashRing {
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    /// Creates a new `HashRing` with the given node names. Panics if `nodes` is empty.
+    pub fn new<S: AsRef<str>>(nodes: &[S]) -> HashRing {
+        assert!(!nodes.is_empty(), "a hash ring needs at least one node");
+
+        let mut points = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(index, node)| {
+                (0..VIRTUAL_NODES)
+                    .map(move |replica| (hash(&format!("{}#{}", node.as_ref(), replica)), index))
+            })
+            .collect::<Vec<_>>();
+        points.sort();
+
+        HashRing { points }
+    }
+
+    /// Returns the index of the node owning `key`
+    pub fn node_for(&self, key: &str) -> usize {
+        let key_hash = hash(key);
+        let position = match self
+            .points
+            .binary_search_by(|(point, _)| point.cmp(&key_hash))
+        {
+            Ok(position) | Err(position) => position,
+        };
+
+        self.points[position % self.points.len()].1
+    }
+}
+
+/// FNV-1a followed by a splitmix64 finalizer: stable across builds and platforms, which the
+/// standard library hashers don't guarantee, and well spread even for similar keys.
+pub(crate) fn hash(value: &str) -> u64 {
+    let fnv = value.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+
+    let mut mixed = fnv;
+    mixed = (mixed ^ (mixed >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    mixed = (mixed ^ (mixed >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    mixed ^ (mixed >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> Vec<String> {
+        (0..10_000).map(|i| format!("key{}", i)).collect()
+    }
+
+    #[test]
+    fn test_single_node() {
+        let ring = HashRing::new(&["127.0.0.1:6379"]);
+        assert!(keys().iter().all(|key| ring.node_for(key) == 0));
+    }
+
+    #[test]
+    fn test_keys_are_spread() {
+        let ring = HashRing::new(&["redis1:6379", "redis2:6379", "redis3:6379"]);
+
+        let mut counts = [0; 3];
+        keys()
+            .iter()
+            .for_each(|key| counts[ring.node_for(key)] += 1);
+
+        assert!(counts.iter().all(|count| *count > 2_500), "{:?}", counts);
+    }
+
+    #[test]
+    fn test_adding_a_node_only_moves_keys_to_it() {
+        let before = HashRing::new(&["redis1:6379", "redis2:6379"]);
+        let after = HashRing::new(&["redis1:6379", "redis2:6379", "redis3:6379"]);
+
+        for key in keys() {
+            let node_after = after.node_for(&key);
+            assert!(node_after == 2 || node_after == before.node_for(&key));
+        }
+    }
+
+    #[test]
+    fn test_node_order_does_not_matter() {
+        let ring = HashRing::new(&["redis1:6379", "redis2:6379"]);
+        let reversed = HashRing::new(&["redis2:6379", "redis1:6379"]);
+
+        for key in keys() {
+            assert_eq!(ring.node_for(&key), 1 - reversed.node_for(&key));
+        }
+    }
+}
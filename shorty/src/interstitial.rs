@@ -0,0 +1,248 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! interstitial holds `Interstitial`, the page telling where a short link goes before following
+//! it, for people wary of blind redirects. Frontends show it instead of redirecting when the ID
+//! is followed by a `+`, such as `/CGQ6LM8bfj+`, or the query has `preview=1`.
+//!
+//! The page is rendered with the `interstitial.html` template, see `templates`. Showing it doesn't
+//! count a hit: following the link from the page goes straight to the destination.
+//!
+//! Deployments accepting links from anyone may require the page for every link, except those
+//! shortened with a verified API key, see `Shortener::with_interstitial_required`. The page of
+//! those links marks them as coming from a verified publisher.
+//!
+//! With the latency beacon on, the page also measures how long the destination takes to answer
+//! the visitor, see `click_latency`.
+
+use jiff::Timestamp;
+
+use crate::click_latency::BEACON_SCRIPT;
+use crate::templates::{Templates, INTERSTITIAL_TEMPLATE};
+use crate::Shortener;
+
+/// The built-in `interstitial.html` template of `Interstitial::html`
+pub const DEFAULT_INTERSTITIAL_TEMPLATE: &str = r#"{% extends "base.html" %}
+{% block head %}<meta name="robots" content="noindex">{% endblock head %}
+{% block title %}Where this link goes{% endblock title %}
+{% block style %}
+.url { font-family: monospace; font-size: 1.1em; word-break: break-all; padding: 0.75em; background: #f6f8fa; border-radius: 6px; }
+.details { color: #656d76; }
+.verified { color: #1a7f37; font-weight: bold; }
+.continue { display: inline-block; padding: 0.5em 1.25em; border-radius: 6px; background: #0969da; color: #fff; text-decoration: none; }
+{% endblock style %}
+{% block content %}
+<h1>Where this link goes</h1>
+<p>The short link {{ id }} takes you to</p>
+<p class="url">{{ url }}</p>
+<p class="details">Created {% if created_on %}on {{ created_on }}{% else %}at an unknown date{% endif %} &middot; Visits: {{ hits }}</p>
+{% if verified %}<p class="verified">&#10003; Verified publisher</p>{% endif %}
+<p><a class="continue" href="{{ url }}" rel="noreferrer noopener"{% if beacon_script %} data-beacon="/{{ id }}/latency"{% endif %}>Continue</a></p>
+{% if beacon_script %}<script>{{ beacon_script | safe }}</script>{% endif %}
+{% endblock content %}
+"#;
+
+/// The destination `url` of the link `id`, with the day it was `created_on`, if known, its
+/// `hits`, whether it was shortened with a `verified` API key and, with the latency beacon on,
+/// the `beacon_script` to run, see `click_latency`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Interstitial {
+    pub id: String,
+    pub url: String,
+    pub created_on: Option<String>,
+    pub hits: i64,
+    pub verified: bool,
+    pub beacon_script: Option<&'static str>,
+}
+
+impl Interstitial {
+    /// Returns the ID of the link whose interstitial is asked for, if any: the ID requested
+    /// without its trailing `+`, or as it is with `preview=1` in the `query`
+    pub fn requested_id<'a>(id: &'a str, query: &str) -> Option<&'a str> {
+        if let Some(id) = id.strip_suffix('+') {
+            return Some(id);
+        }
+        url::form_urlencoded::parse(query.as_bytes())
+            .any(|(name, value)| name == "preview" && value == "1")
+            .then_some(id)
+    }
+
+    /// Renders the page with the `interstitial.html` template
+    pub fn html(&self, templates: &Templates) -> String {
+        templates.render(INTERSTITIAL_TEMPLATE, self)
+    }
+}
+
+impl Shortener {
+    /// Requires the interstitial for every link not shortened with a verified API key: frontends
+    /// show it instead of redirecting, see `requires_interstitial`
+    pub fn with_interstitial_required(mut self, interstitial_required: bool) -> Shortener {
+        self.interstitial_required = interstitial_required;
+        self
+    }
+
+    /// Tells whether frontends must show the interstitial of the link `id` instead of
+    /// redirecting: when the interstitial is required, and the link wasn't shortened with a
+    /// verified API key. Asking doesn't count a hit.
+    pub async fn requires_interstitial(&self, id: &str) -> bool {
+        self.interstitial_required && !self.is_verified(id).await
+    }
+
+    /// Tells whether the link `id` was shortened with an API key verified now
+    pub(crate) async fn is_verified(&self, id: &str) -> bool {
+        match self.storage.get_string(&format!("OWNER_{}", id)).await {
+            Ok(owner) => self.api_key_manager.is_verified(&owner).await,
+            Err(_) => false,
+        }
+    }
+
+    /// Returns the interstitial of the link with the given ID, going where a redirect would go
+    /// now, without counting a hit. Links not found by `lookup` have none.
+    pub async fn interstitial(&self, id: &str) -> Option<Interstitial> {
+        let url = self.destination(id).await?;
+        let url = self.scheduled_url(id, url).await;
+        let stats = self.stats(id).await?;
+        let created_on = stats
+            .created_at
+            .and_then(|created_at| Timestamp::from_second(created_at as i64).ok())
+            .map(|created_at| {
+                created_at
+                    .to_zoned(self.time_zone.clone())
+                    .strftime("%Y-%m-%d")
+                    .to_string()
+            });
+
+        Some(Interstitial {
+            id: id.to_owned(),
+            url,
+            created_on,
+            hits: stats.hits,
+            verified: self.is_verified(id).await,
+            beacon_script: self.latency_beacon.then_some(BEACON_SCRIPT),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    #[test]
+    fn test_requested_id() {
+        assert_eq!(Some("abc"), Interstitial::requested_id("abc+", ""));
+        assert_eq!(Some("abc"), Interstitial::requested_id("abc", "preview=1"));
+        assert_eq!(
+            Some("abc"),
+            Interstitial::requested_id("abc", "utm_source=x&preview=1")
+        );
+        assert_eq!(None, Interstitial::requested_id("abc", "preview=0"));
+        assert_eq!(None, Interstitial::requested_id("abc", ""));
+    }
+
+    #[tokio::test]
+    async fn test_interstitial() {
+        let shortener = Shortener::new(
+            10,
+            vec!['a', 'b', 'c'],
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
+        );
+        let result = shortener
+            .shorten(
+                &None,
+                None,
+                "http://example.com/?a=1&b=<2>",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap();
+        shortener.lookup(result.id()).await.unwrap();
+
+        let interstitial = shortener.interstitial(result.id()).await.unwrap();
+        assert_eq!("http://example.com/?a=1&b=<2>", interstitial.url);
+        assert_eq!(1, interstitial.hits);
+        assert_eq!(Some(10), interstitial.created_on.as_ref().map(String::len));
+        // showing the page is not a hit
+        assert_eq!(1, shortener.stats(result.id()).await.unwrap().hits);
+
+        let html = interstitial.html(&Templates::new());
+        assert!(html.contains(r#"href="http://example.com/?a=1&amp;b=&lt;2&gt;""#));
+        assert!(html.contains("Visits: 1"));
+        assert!(html.contains("<title>Where this link goes</title>"));
+        assert!(!html.contains("Verified publisher"));
+        assert!(!html.contains("{{"));
+        assert!(!html.contains("<script>"));
+
+        // the beacon script is rendered as it is, to match its hash
+        let shortener = shortener.with_latency_beacon(true);
+        let html = shortener
+            .interstitial(result.id())
+            .await
+            .unwrap()
+            .html(&Templates::new());
+        assert!(html.contains(&format!("<script>{}</script>", BEACON_SCRIPT)));
+        assert!(html.contains(&format!(r#"data-beacon="/{}/latency""#, result.id())));
+
+        assert_eq!(None, shortener.interstitial("missing").await);
+    }
+
+    #[tokio::test]
+    async fn test_requires_interstitial() {
+        let shortener = Shortener::new(
+            10,
+            vec!['a', 'b', 'c'],
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
+        );
+        let api_key = shortener
+            .api_key_manager()
+            .create(None, None, None)
+            .await
+            .unwrap()
+            .key;
+        let result = shortener
+            .shorten(
+                &Some(api_key.as_str()),
+                None,
+                "http://example.com/",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert!(!shortener.requires_interstitial(result.id()).await);
+
+        let shortener = shortener.with_interstitial_required(true);
+        assert!(shortener.requires_interstitial(result.id()).await);
+        assert!(!shortener.redirect(result.id()).await.unwrap().verified);
+
+        shortener
+            .api_key_manager()
+            .set_verified(&api_key, true)
+            .await
+            .unwrap();
+        assert!(!shortener.requires_interstitial(result.id()).await);
+        assert!(shortener.redirect(result.id()).await.unwrap().verified);
+        let interstitial = shortener.interstitial(result.id()).await.unwrap();
+        assert!(interstitial
+            .html(&Templates::new())
+            .contains("Verified publisher"));
+    }
+}
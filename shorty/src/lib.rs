@@ -21,55 +21,202 @@
 extern crate serde_derive;
 
 use core::fmt;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use redis::{ErrorKind, RedisError};
+use jiff::tz::TimeZone;
 use url::Url;
 
-#[cfg(test)]
-use tests::StubRedisFacade as RedisFacade;
-
-#[cfg(not(test))]
-use crate::redis_facade::RedisFacade;
-
-#[cfg(not(test))]
+use crate::api_key_manager::{features_key, parse_features, ApiKeyManager, Feature};
+use crate::challenge::HumanChallenge;
+use crate::click_latency::Latency;
+use crate::click_queue::ClickQueue;
+use crate::collision_alert::CollisionAlert;
+use crate::concurrency_limiter::ConcurrencyLimiter;
+use crate::hash_ring::hash;
+use crate::link_events::events_key;
+use crate::link_loop::RedirectResolver;
+use crate::link_metadata::{metadata_key, Creator, LinkMetadata};
+use crate::link_sync::micros;
+use crate::outbox::EventSink;
+use crate::phishing_screen::PhishingScreen;
+use crate::rate_limiter::RateLimiter;
+use crate::schedule::{schedule_json, schedule_key, Schedule};
+use crate::signed_id::IdSigner;
+use crate::storage::{Storage, StorageError, StorageResult};
+use crate::templates::{Templates, REDIRECT_TEMPLATE};
+use crate::url_checker::{NoUrlChecker, UrlChecker};
+
+pub mod api_key_manager;
+pub mod ascii_json;
+pub mod campaign;
+pub mod challenge;
+pub mod click_latency;
+pub mod click_queue;
+pub mod collision_alert;
+pub mod concurrency_limiter;
+pub mod content_security_policy;
+pub mod crypto;
+pub mod deadline;
+pub mod domain_blocklist;
+pub mod domain_index;
+pub mod error_page;
+pub mod fixture_storage;
+pub mod global_stats;
+pub mod hash_ring;
+pub mod interstitial;
+pub mod link_defaults;
+pub mod link_events;
+pub mod link_health;
+pub mod link_index;
+pub mod link_loop;
+pub mod link_metadata;
+pub mod link_sync;
+pub mod link_transfer;
+pub mod memory_storage;
+pub mod outbox;
+pub mod phishing_screen;
+pub mod private_targets;
+pub mod quarantine;
+pub mod rate_limiter;
 pub mod redis_facade;
-
+pub mod schedule;
+pub mod shadow_storage;
+pub mod signed_id;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_storage;
+pub mod storage;
+pub mod takedown;
+pub mod templates;
+pub mod url_checker;
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
+/// The errors of `Shortener`, each displayed with a message meant for API clients.
+///
+/// Frontends answer each error with the HTTP status of `status_code`, so that every frontend
+/// tells invalid requests from failures the same way.
 #[derive(Debug)]
-pub struct ShortenerError {
-    message: &'static str,
-    cause: Option<Box<dyn Error>>,
+pub enum ShortenerError {
+    /// The API key is unknown or has been revoked
+    InvalidApiKey,
+    /// The API key can't do what it asked, such as deleting a link it doesn't own
+    Forbidden(&'static str),
+    /// The API key made too many calls: see `RateLimiter`. Calls can be made again after
+    /// `retry_after` seconds.
+    RateLimited { retry_after: u64 },
+    /// The API key has too many calls in flight: see `ConcurrencyLimiter`. Calls can be made again
+    /// as soon as one of them ends.
+    ConcurrencyLimited,
+    /// The caller gave up on the call before it ended: see `deadline`
+    DeadlineExceeded,
+    /// The URL to shorten can't be parsed
+    UnparsableUrl(url::ParseError),
+    /// The URL to shorten is valid but not allowed, such as for its scheme
+    InvalidUrl(&'static str),
+    /// The URL to shorten points to the host running shorty
+    LinkLoop,
+    /// The URL to shorten is blocked, or found malicious: see `domain_blocklist` and `url_checker`
+    RejectedUrl(&'static str),
+    /// An argument is invalid, such as the expiration of a link
+    InvalidInput(&'static str),
+    /// What the call is about doesn't exist, such as the link to delete
+    NotFound(&'static str),
+    /// The call conflicts with the stored data, such as a custom ID already taken
+    Conflict(&'static str),
+    /// No free ID was found in `id_generation_max_attempts` attempts
+    IdExhausted,
+    /// The storage failed
+    Storage(StorageError),
+    /// A snapshot can't be parsed: see `snapshot`
+    InvalidSnapshot(serde_json::Error),
+    /// Something else went wrong, such as failing to generate a unique API key
+    Internal(&'static str),
 }
 
 impl ShortenerError {
-    fn new(message: &'static str) -> ShortenerError {
-        ShortenerError {
-            message,
-            cause: None,
+    /// Returns the HTTP status of the error: `400` for invalid requests, `403` for forbidden
+    /// ones, `404`, `409`, `429`, `503` if the storage is unavailable, `504` past the deadline
+    /// of the call and `500` otherwise
+    pub fn status_code(&self) -> u16 {
+        match self {
+            ShortenerError::UnparsableUrl(_)
+            | ShortenerError::InvalidUrl(_)
+            | ShortenerError::LinkLoop
+            | ShortenerError::InvalidInput(_)
+            | ShortenerError::InvalidSnapshot(_) => 400,
+            ShortenerError::InvalidApiKey
+            | ShortenerError::Forbidden(_)
+            | ShortenerError::RejectedUrl(_) => 403,
+            ShortenerError::NotFound(_) => 404,
+            ShortenerError::Conflict(_) => 409,
+            ShortenerError::RateLimited { .. } | ShortenerError::ConcurrencyLimited => 429,
+            ShortenerError::Storage(StorageError::Unavailable { .. }) => 503,
+            ShortenerError::DeadlineExceeded => 504,
+            ShortenerError::IdExhausted
+            | ShortenerError::Storage(_)
+            | ShortenerError::Internal(_) => 500,
         }
     }
-    fn new_with_cause(message: &'static str, error: Box<dyn Error>) -> ShortenerError {
-        ShortenerError {
-            message,
-            cause: Some(error),
+
+    /// Returns the seconds to wait before calling again, for the `Retry-After` header of the
+    /// response, if the error is `RateLimited` or `ConcurrencyLimited`
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            ShortenerError::RateLimited { retry_after } => Some(*retry_after),
+            ShortenerError::ConcurrencyLimited => Some(1),
+            _ => None,
         }
     }
 }
 
 impl Display for ShortenerError {
     fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
-        f.write_str(&self.message)?;
-
-        if let Some(err) = &self.cause {
-            return f.write_str(&format!(" - {}", &err));
+        match self {
+            ShortenerError::InvalidApiKey => f.write_str("Invalid API key"),
+            ShortenerError::RateLimited { .. } => f.write_str("Rate limit exceeded"),
+            ShortenerError::ConcurrencyLimited => f.write_str("Too many concurrent calls"),
+            ShortenerError::DeadlineExceeded => f.write_str("Deadline exceeded"),
+            ShortenerError::LinkLoop => f.write_str("Link loop is not allowed"),
+            ShortenerError::IdExhausted => f.write_str(
+                "Failed to generate an ID: too many attempts. Consider using a longer ID",
+            ),
+            ShortenerError::UnparsableUrl(err) => write!(f, "Unable to parse url - {}", err),
+            ShortenerError::Storage(err) => {
+                let message = match err {
+                    StorageError::Unavailable { .. } => "Storage unavailable",
+                    StorageError::Redis(_) => "Redis error",
+                    StorageError::Backend(_) => "Storage error",
+                };
+                write!(f, "{} - {}", message, err)
+            }
+            ShortenerError::InvalidSnapshot(err) => write!(f, "Invalid snapshot - {}", err),
+            ShortenerError::Forbidden(message)
+            | ShortenerError::InvalidUrl(message)
+            | ShortenerError::RejectedUrl(message)
+            | ShortenerError::InvalidInput(message)
+            | ShortenerError::NotFound(message)
+            | ShortenerError::Conflict(message)
+            | ShortenerError::Internal(message) => f.write_str(message),
         }
-
-        Ok(())
     }
 }
 
-impl Error for ShortenerError {}
+impl Error for ShortenerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ShortenerError::UnparsableUrl(err) => Some(err),
+            ShortenerError::Storage(err) => Some(err),
+            ShortenerError::InvalidSnapshot(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 /// `Shortener` is the struct exposing methods `lookup` and `shorten`.
 ///
@@ -80,23 +227,471 @@ impl Error for ShortenerError {}
 /// validate it and shorten the URL only if validation passes. Otherwise, it will just shorten the
 /// URL.
 ///
-/// `Shortener` interacts with a `RedisFacade`, which makes it easier to work with the `redis` crate
-/// and simplifies testing.
+/// API keys are validated with `verify_key`, and their calls are counted by a `RateLimiter`: both
+/// are public, so that other services sharing the storage can reuse shorty's keys and limits.
+///
+/// `Shortener` stores its data on a `Storage`: `RedisFacade` in production, or `MemoryStorage` for
+/// local development and tests.
 pub struct Shortener {
     id_length: usize,
     id_alphabet: Vec<char>,
     id_generation_max_attempts: u8,
-    redis: RedisFacade,
-    rate_limit_period: usize,
-    rate_limit: i64,
+    storage: Arc<dyn Storage>,
+    rate_limiter: RateLimiter,
+    concurrency_limiter: ConcurrencyLimiter,
+    api_key_manager: ApiKeyManager,
+    deduplicate_urls: bool,
+    phishing_screen: Option<PhishingScreen>,
+    human_challenge: Option<HumanChallenge>,
+    collision_alert: Option<CollisionAlert>,
+    id_signer: Option<IdSigner>,
+    redirect_status: u16,
+    allowed_schemes: Vec<String>,
+    strict_urls: bool,
+    assume_https: bool,
+    interstitial_required: bool,
+    latency_beacon: bool,
+    blocked_domains: BTreeSet<String>,
+    reject_private_targets: bool,
+    url_checker: Box<dyn UrlChecker>,
+    public_hosts: Vec<String>,
+    redirect_resolver: Option<Box<dyn RedirectResolver>>,
+    max_redirect_depth: usize,
+    time_zone: TimeZone,
+    public_base_url: Option<String>,
+    click_queue: Option<ClickQueue>,
+    click_sampling: u32,
+    event_sink: Option<Box<dyn EventSink>>,
+}
+
+/// The options of a link being shortened, besides its URL. The default is a temporary link, never
+/// expiring and not belonging to any campaign.
+///
+/// If `expires_in` is present, the link is deleted after that many seconds.
+///
+/// If `permanent` is true, the link redirects with `301 Moved Permanently` and may be cached
+/// forever: see `Redirect`. Permanent links can't expire.
+///
+/// If `campaign` is present, the link belongs to that campaign, which must be owned by the API key
+/// shortening the link: see `campaign`.
+///
+/// If `redirect_status` is present, the link redirects with that status, one of
+/// `REDIRECT_STATUSES`, instead of the default one (see `Shortener::with_redirect_status`).
+/// Permanent links can only redirect with `301` or `308`.
+///
+/// If `schedule` is present, the link redirects to the closed URL of the schedule outside of its
+/// windows: see `schedule`. Permanent links can't have a schedule.
+///
+/// If `preconnect` is true, the redirects of the link hint browsers to connect to the origin of
+/// the URL while following the redirect: see `Redirect::link_header`. Only permanent links can
+/// preconnect.
+///
+/// `creator` tells who is shortening the link, recorded in its metadata: see `link_metadata`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinkOptions {
+    pub expires_in: Option<usize>,
+    pub permanent: bool,
+    pub campaign: Option<String>,
+    pub redirect_status: Option<u16>,
+    pub schedule: Option<Schedule>,
+    pub preconnect: bool,
+    pub creator: Creator,
 }
 
 /// A struct with the successful result of a URL shortening. It holds the original `url` and the
-/// resulting `id`
+/// resulting `id`, and, if the link expires, the seconds it `expires_in` and the Unix timestamp it
+/// `expires_at`. `permanent` is serialized only for permanent links, `campaign` only for links
+/// belonging to a campaign, `redirect_status` and `schedule` only for links with their own,
+/// `preconnect` only for links preconnecting, and `quarantined` only for links held by the
+/// phishing screen. `short_url` is serialized only if the public base URL of links is known: see
+/// `Shortener::with_public_base_url`.
 #[derive(Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct ShortenerResult {
     id: String,
     url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    short_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_in: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    permanent: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    campaign: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redirect_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schedule: Option<Schedule>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    preconnect: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    quarantined: bool,
+}
+
+impl ShortenerResult {
+    fn new(id: String, url: String, options: &LinkOptions) -> ShortenerResult {
+        let expires_at = options
+            .expires_in
+            .map(|expires_in| now() + expires_in as u64);
+
+        ShortenerResult {
+            id,
+            url,
+            short_url: None,
+            expires_in: options.expires_in,
+            expires_at,
+            permanent: options.permanent,
+            campaign: options.campaign.clone(),
+            redirect_status: options.redirect_status,
+            schedule: options.schedule.clone(),
+            preconnect: options.preconnect,
+            quarantined: false,
+        }
+    }
+
+    fn with_quarantine(mut self, score: Option<u32>) -> ShortenerResult {
+        self.quarantined = score.is_some();
+        self
+    }
+
+    /// The ID of the shortened URL
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The shortened URL
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The link of the shortened URL, such as `https://sho.rt/abc`, if the public base URL of links
+    /// is known
+    pub fn short_url(&self) -> Option<&str> {
+        self.short_url.as_deref()
+    }
+
+    /// The Unix timestamp the link expires at, if it expires
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    /// Whether the link is held by the phishing screen until reviewed
+    pub fn quarantined(&self) -> bool {
+        self.quarantined
+    }
+}
+
+/// The default template of `Redirect::html`: a link to the URL, followed right away by browsers
+pub const DEFAULT_REDIRECT_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="0; url={{url}}">
+<title>Redirecting</title>
+</head>
+<body>
+<p>Redirecting to <a href="{{url}}">{{url}}</a></p>
+</body>
+</html>
+"#;
+
+/// The header frontends add, set to `true`, to the redirects of links shortened with a verified
+/// API key, see `Redirect::verified`
+pub const VERIFIED_PUBLISHER_HEADER: &str = "X-Verified-Publisher";
+
+/// The schemes of the URLs shortened by default, see `Shortener::with_allowed_schemes`
+pub const DEFAULT_ALLOWED_SCHEMES: [&str; 2] = ["http", "https"];
+
+/// The statuses links can redirect with: `301 Moved Permanently`, `302 Found`,
+/// `307 Temporary Redirect` and `308 Permanent Redirect`
+pub const REDIRECT_STATUSES: [u16; 4] = [301, 302, 307, 308];
+
+/// The IDs taken by the routes of shorty frontends, such as `GET /healthz` or `GET /admin/stats`,
+/// which would shadow links with the same ID or their routes: they are never generated, nor
+/// accepted as custom IDs. shorty-http tests that the first segment of each of its routes is here.
+pub const RESERVED_IDS: [&str; 15] = [
+    "abuse",
+    "admin",
+    "api",
+    "batch",
+    "campaigns",
+    "csp-report",
+    "docs",
+    "healthz",
+    "integrations",
+    "internal",
+    "links",
+    "openapi.json",
+    "preview",
+    "readyz",
+    "shorten",
+];
+
+/// Tells whether a URL starts with a scheme, such as `https:` or `javascript:`. A name followed by
+/// a colon and a digit is a host and a port instead, such as `localhost:8080`.
+fn has_scheme(url: &str) -> bool {
+    let (name, rest) = match url.split_once(':') {
+        Some(split) => split,
+        None => return false,
+    };
+
+    name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+        && !rest.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Tells whether `status` is a permanent redirect, `301` or `308`
+fn is_permanent_status(status: u16) -> bool {
+    status == 301 || status == 308
+}
+
+/// A struct with the `url` an ID redirects to, the `status` of the redirect, whether the
+/// redirect is `permanent`, whether it hints browsers to `preconnect` to the URL, and whether the
+/// link was shortened with a `verified` API key, see `ApiKeyManager::set_verified`.
+///
+/// Frontends build their redirect responses with `status_code` and `cache_control`, so that every
+/// frontend handles permanent and temporary links the same way. Clients accepting HTML (see
+/// `accepts_html`) also get the body rendered by `html`, for those ignoring the `Location` header.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Redirect {
+    pub url: String,
+    pub status: u16,
+    pub permanent: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub preconnect: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub verified: bool,
+}
+
+impl Redirect {
+    /// Returns the status of the redirect, one of `REDIRECT_STATUSES`
+    pub fn status_code(&self) -> u16 {
+        self.status
+    }
+
+    /// Returns the `Cache-Control` header of the redirect. Permanent links may be cached by
+    /// browsers and CDNs for a year, while temporary links must not be cached at all, whatever
+    /// their status: they may expire, or be changed.
+    pub fn cache_control(&self) -> &'static str {
+        if self.permanent {
+            "public, max-age=31536000, immutable"
+        } else {
+            "no-store"
+        }
+    }
+
+    /// Returns the `Link` header of the redirect of a link preconnecting, asking browsers to open
+    /// the connection to the origin of the URL while following the redirect. CDNs supporting
+    /// `103 Early Hints` send it before the redirect itself.
+    pub fn link_header(&self) -> Option<String> {
+        if !self.preconnect {
+            return None;
+        }
+
+        let origin = Url::parse(&self.url).ok()?.origin();
+        if origin.is_tuple() {
+            Some(format!(
+                "<{}>; rel=preconnect",
+                origin.ascii_serialization()
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Renders the HTML body of the redirect with the `redirect.html` template, see `templates`
+    pub fn html(&self, templates: &Templates) -> String {
+        templates.render(REDIRECT_TEMPLATE, self)
+    }
+
+    /// Tells whether a client sending the given `Accept` header wants an HTML body: API clients
+    /// sending no header, or asking for something else, get an empty body
+    pub fn accepts_html(accept: Option<&str>) -> bool {
+        accepts(accept, "text/html")
+    }
+}
+
+/// Tells whether the given `Accept` header explicitly lists `media_type`, with a non-zero quality
+fn accepts(accept: Option<&str>, media_type: &str) -> bool {
+    accept.unwrap_or_default().split(',').any(|media_range| {
+        let mut parts = media_range.split(';').map(str::trim);
+        let accepted = parts.next().unwrap_or_default();
+        let quality = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .map(|quality| quality.parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+
+        accepted.eq_ignore_ascii_case(media_type) && quality > 0.0
+    })
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A struct with the statistics of a shortened URL: the original `url`, the number of `hits` (the
+/// successful lookups of its `id`) and the Unix timestamp it was `created_at`. Links shortened
+/// before statistics were introduced have no creation time.
+///
+/// `permanent` links never change, and can be cached by CDNs or exported as static redirects.
+///
+/// `click_sampling` is the one in how many clicks the daily clicks of the link are recorded for,
+/// the daily clicks being estimates when above 1: see `Shortener::with_click_sampling`. Hits are
+/// counted for every click.
+///
+/// With the latency beacon on, `latency` tells how long the destination took to answer visitors
+/// lately: see `click_latency`.
+#[derive(Debug, PartialEq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Stats {
+    pub id: String,
+    pub url: String,
+    pub hits: i64,
+    pub created_at: Option<u64>,
+    pub permanent: bool,
+    pub click_sampling: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency: Option<Latency>,
+}
+
+/// The health of the storage: whether it's `reachable`, how long it took to answer in
+/// milliseconds and, if unreachable, the `error`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StorageHealth {
+    pub reachable: bool,
+    pub latency_ms: f64,
+    pub error: Option<String>,
+}
+
+/// The max number of URLs `Shortener::shorten_batch` shortens at once
+pub const MAX_BATCH_SIZE: usize = 1000;
+
+/// The value `Shortener::shorten_with_id` claims a custom ID with, until the URL of the link is
+/// stored: a claimed ID is taken, but not found by `lookup`
+const CLAIMED_ID: &str = "";
+
+/// The seconds a claimed ID stays taken if its URL is never stored, such as after an error
+const CLAIM_PERIOD: usize = 60;
+
+/// The links of a `Shortener::shorten_batch` waiting to be stored: the `entries` to set, the
+/// `ids` of the new links, and the `deduplicated` IDs of their URLs
+#[derive(Default)]
+struct Batch {
+    entries: Vec<(String, String, Option<usize>)>,
+    ids: HashSet<String>,
+    deduplicated: HashMap<String, String>,
+}
+
+impl Batch {
+    fn add(&mut self, key: String, value: &str, expires_in: Option<usize>) {
+        self.entries.push((key, value.to_owned(), expires_in));
+    }
+}
+
+/// The key of the deduplication index entry of a link: links are deduplicated only when they share
+/// API key, permanence, campaign and redirect status, besides the URL
+fn deduplication_key(api_key: &Option<&str>, url: &str, options: &LinkOptions) -> String {
+    let mut identity = format!(
+        "{}\n{}\n{}\n{}",
+        api_key.unwrap_or_default(),
+        options.permanent,
+        options.campaign.as_deref().unwrap_or_default(),
+        url
+    );
+    // appended only when present, keeping the keys of links without their own status
+    if let Some(status) = options.redirect_status {
+        identity.push_str(&format!("\n{}", status));
+    }
+    if let Some(schedule) = &options.schedule {
+        identity.push_str(&format!("\n{}", schedule_json(schedule)));
+    }
+    if options.preconnect {
+        identity.push_str("\npreconnect");
+    }
+
+    format!("URL_{:016x}", hash(&identity))
+}
+
+/// Fails if the API key of `key_info` has no access to any of `features`
+fn check_features(key_info: &KeyInfo, features: &[Feature]) -> Result<(), ShortenerError> {
+    match features.iter().find(|feature| !key_info.has(**feature)) {
+        Some(_) => Err(ShortenerError::Forbidden(
+            "Feature not available with the API key",
+        )),
+        None => Ok(()),
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+/// Converts the expiration of a shorten request, given either as seconds (`expires_in`) or as a
+/// Unix timestamp (`expires_at`), to the seconds the link will live, as expected by
+/// `Shortener::shorten`. Returns `None` if the link doesn't expire.
+pub fn expires_in(
+    expires_in: Option<usize>,
+    expires_at: Option<u64>,
+) -> Result<Option<usize>, ShortenerError> {
+    match (expires_in, expires_at) {
+        (Some(_), Some(_)) => Err(ShortenerError::InvalidInput(
+            "Invalid expiration: use either expires_in or expires_at",
+        )),
+        (None, Some(expires_at)) => match expires_at.checked_sub(now()) {
+            Some(expires_in) if expires_in > 0 => Ok(Some(expires_in as usize)),
+            _ => Err(ShortenerError::InvalidInput(
+                "Invalid expiration: expires_at is in the past",
+            )),
+        },
+        (expires_in, None) => Ok(expires_in),
+    }
+}
+
+/// A struct with the result of an API key verification. The API key is `valid` if it's stored
+/// with value `true`. Valid keys restricted to some `features` list them, see `ApiKeyManager`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KeyInfo {
+    pub key: String,
+    pub valid: bool,
+    pub features: Option<Vec<Feature>>,
+}
+
+impl KeyInfo {
+    /// Tells whether the key has access to `feature`: keys not restricted to some features have
+    /// access to all of them
+    pub fn has(&self, feature: Feature) -> bool {
+        self.features
+            .as_ref()
+            .is_none_or(|features| features.contains(&feature))
+    }
+}
+
+/// The features needed to shorten a link with `options`
+fn required_features(options: &LinkOptions) -> Vec<Feature> {
+    if options.campaign.is_some() {
+        vec![Feature::Campaigns]
+    } else {
+        vec![]
+    }
 }
 
 impl Shortener {
@@ -110,7 +705,8 @@ impl Shortener {
     /// `id_generation_max_attempts` is the number of attempts to generate an unique ID when a
     /// conflict is detected.
     ///
-    /// `redis` is a `RedisFacade` instance.
+    /// `storage` is where shortened URLs, API keys and rate limits are stored, such as a
+    /// `RedisFacade`.
     ///
     /// `rate_limit_period` is the amount of seconds during which calls to `shorten` will be counted.
     ///
@@ -119,430 +715,2845 @@ impl Shortener {
         id_length: usize,
         id_alphabet: Vec<char>,
         id_generation_max_attempts: u8,
-        redis: RedisFacade,
+        storage: Box<dyn Storage>,
         rate_limit_period: usize,
         rate_limit: i64,
     ) -> Shortener {
+        let storage: Arc<dyn Storage> = Arc::from(storage);
+        let rate_limiter = RateLimiter::new(storage.clone(), rate_limit_period, rate_limit);
+        let concurrency_limiter = ConcurrencyLimiter::new(storage.clone(), 0, 0);
+        let api_key_manager = ApiKeyManager::new(storage.clone());
+
         Shortener {
             id_length,
             id_alphabet,
             id_generation_max_attempts,
-            redis,
-            rate_limit_period,
-            rate_limit,
+            storage,
+            rate_limiter,
+            concurrency_limiter,
+            api_key_manager,
+            deduplicate_urls: false,
+            phishing_screen: None,
+            human_challenge: None,
+            collision_alert: None,
+            id_signer: None,
+            redirect_status: 302,
+            allowed_schemes: DEFAULT_ALLOWED_SCHEMES
+                .iter()
+                .map(|scheme| scheme.to_string())
+                .collect(),
+            strict_urls: false,
+            assume_https: false,
+            interstitial_required: false,
+            latency_beacon: false,
+            blocked_domains: BTreeSet::new(),
+            reject_private_targets: false,
+            url_checker: Box::new(NoUrlChecker),
+            public_hosts: Vec::new(),
+            redirect_resolver: None,
+            max_redirect_depth: 0,
+            time_zone: TimeZone::UTC,
+            public_base_url: None,
+            click_queue: None,
+            click_sampling: 1,
+            event_sink: None,
         }
     }
 
-    /// Looks up a URL by the given ID. If no URL is found or an error occurs, it returns `None`,
-    /// otherwise it returns `Some(url)`.
-    pub fn lookup(&self, id: &str) -> Option<String> {
-        match self.redis.get_string(id) {
-            Ok(url) => Some(url),
-            Err(_) => None,
-        }
+    /// Enables URL deduplication: `shorten` returns the existing link of a URL, if any, instead of
+    /// generating a new ID. See `shorten` for which links are deduplicated.
+    pub fn with_url_deduplication(mut self, deduplicate_urls: bool) -> Shortener {
+        self.deduplicate_urls = deduplicate_urls;
+        self
     }
 
-    fn verify_api_key(&self, api_key: &str) -> Result<(), ShortenerError> {
-        let api_key = format!("API_KEY_{}", api_key);
-        log::trace!("verifying api key '{}'", api_key);
+    /// Sets the schemes of the URLs that can be shortened, `DEFAULT_ALLOWED_SCHEMES` by default.
+    /// URLs with other schemes, such as `javascript:` or `data:`, are rejected with
+    /// "Invalid URL: scheme not allowed".
+    pub fn with_allowed_schemes(mut self, schemes: Vec<String>) -> Shortener {
+        self.allowed_schemes = schemes
+            .into_iter()
+            .map(|scheme| scheme.to_lowercase())
+            .collect();
+        self
+    }
 
-        let verify_and_increment = self.redis.get_bool(&api_key).and_then(|valid| {
-            if !valid {
-                return Err(RedisError::from((
-                    ErrorKind::ExtensionError,
-                    "API key expired",
-                )));
-            }
+    /// Requires URLs to shorten to be absolute, with an explicit scheme and, for URLs with a host,
+    /// `//` before it, such as `https://example.com`. URLs without a scheme are rejected with
+    /// "Invalid URL: missing scheme" instead of being prefixed, and URLs the parser would fix, such
+    /// as `http:example.com`, `http:\\example.com` or with surrounding spaces, with
+    /// "Invalid URL: malformed".
+    pub fn with_strict_urls(mut self, strict_urls: bool) -> Shortener {
+        self.strict_urls = strict_urls;
+        self
+    }
 
-            if self.rate_limit <= 0 {
-                return Ok(-1);
-            }
+    /// Prefixes URLs without a scheme with `https://` instead of `http://`, unless URLs are strict
+    /// (see `with_strict_urls`)
+    pub fn with_assume_https(mut self, assume_https: bool) -> Shortener {
+        self.assume_https = assume_https;
+        self
+    }
 
-            let rate_key = format!("RATE_{}", api_key);
-            log::trace!("verifying rate key '{}'", rate_key);
+    /// Signs generated IDs with `id_signer`, appending a tag of their URL: see `signed_id`
+    pub fn with_id_signer(mut self, id_signer: IdSigner) -> Shortener {
+        self.id_signer = Some(id_signer);
+        self
+    }
 
-            self.redis.exists(&rate_key).and_then(|exists| {
-                log::trace!("rate key exists {}", exists);
+    /// Sets the status links redirect with, unless they have their own (see `LinkOptions`),
+    /// `302 Found` by default. Permanent links redirect with `301 Moved Permanently` unless
+    /// `status` is a permanent redirect too.
+    ///
+    /// Panics if `status` is not one of `REDIRECT_STATUSES`.
+    pub fn with_redirect_status(mut self, status: u16) -> Shortener {
+        assert!(
+            REDIRECT_STATUSES.contains(&status),
+            "invalid redirect status {}",
+            status
+        );
+        self.redirect_status = status;
+        self
+    }
 
-                self.redis.increment(&rate_key).and_then(|number_of_calls| {
-                    log::trace!("rate key {} number of calls {}", rate_key, number_of_calls);
+    /// Sets the URL links are served under, such as `https://sho.rt`, so that the result of
+    /// shortening holds the full link, such as `https://sho.rt/abc`, besides its ID
+    pub fn with_public_base_url(mut self, public_base_url: Option<String>) -> Shortener {
+        self.public_base_url =
+            public_base_url.map(|base_url| base_url.trim_end_matches('/').to_owned());
+        self
+    }
 
-                    if !exists {
-                        self.redis
-                            .expire(&rate_key, self.rate_limit_period)
-                            .unwrap();
-                    }
+    /// Returns the URL links are served under, if set: see `with_public_base_url`
+    pub fn public_base_url(&self) -> Option<&str> {
+        self.public_base_url.as_deref()
+    }
 
-                    Ok(number_of_calls)
-                })
-            })
-        });
+    /// Returns the link of `id` under the public base URL, if set: see `with_public_base_url`
+    pub fn short_url(&self, id: &str) -> Option<String> {
+        self.public_base_url
+            .as_ref()
+            .map(|base_url| format!("{}/{}", base_url, id))
+    }
 
-        match verify_and_increment {
-            Ok(call_rate) if self.rate_limit > 0 && call_rate > self.rate_limit => {
-                Err(ShortenerError::new("Rate limit exceeded"))
-            }
-            Ok(_) => Ok(()),
-            Err(err) => Err(ShortenerError::new_with_cause(
-                "Invalid API key",
-                Box::new(err),
-            )),
-        }
+    /// Returns the `RateLimiter` counting the calls made with each API key
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    /// Returns the `ApiKeyManager` creating, listing and revoking API keys
+    pub fn api_key_manager(&self) -> &ApiKeyManager {
+        &self.api_key_manager
     }
 
-    fn generate_id(&self) -> Result<String, ShortenerError> {
-        for _ in 1..=self.id_generation_max_attempts {
-            let id = nanoid::custom(self.id_length, &self.id_alphabet);
+    /// Looks up a URL by the given ID. If no URL is found or an error occurs, it returns `None`,
+    /// otherwise it returns `Some(url)`.
+    ///
+    /// Every successful lookup is counted as a hit, stored in `HITS_<id>`: see `stats`. It is also
+    /// counted in the redirects of `global_stats`. With a click queue, they are counted in the
+    /// background instead: see `click_queue`.
+    ///
+    /// Links of a paused campaign are not found: see `campaign`. Neither are quarantined links,
+    /// until they are released: see `quarantine`.
+    pub async fn lookup(&self, id: &str) -> Option<String> {
+        let url = self.destination(id).await?;
+        self.count_click(id).await;
+        Some(url)
+    }
 
-            let exists = self.redis.exists(&id).unwrap_or(false);
+    /// Looks up a URL by the given ID as `lookup` does, without counting a hit. Frontends use it to
+    /// describe a link without following it, such as in social cards.
+    pub async fn destination(&self, id: &str) -> Option<String> {
+        let url = self
+            .storage
+            .get_string(id)
+            .await
+            .ok()
+            .filter(|url| url != CLAIMED_ID)?;
+
+        let (paused, quarantined) = tokio::join!(self.is_paused(id), self.is_quarantined(id));
+        if paused {
+            log::debug!("link '{}' belongs to a paused campaign", id);
+            return None;
+        }
 
-            if !exists {
-                return Ok(id);
-            }
+        if quarantined {
+            log::debug!("link '{}' is quarantined", id);
+            return None;
         }
 
-        Err(ShortenerError::new(
-            "Failed to generate an ID: too many attempts. Consider using a longer ID",
-        ))
+        Some(url)
     }
 
-    /// Shortens an URL, returning a `ShortenerResult` holding the provided URL and the generated ID.
-    ///
-    /// If the optional API key is present, it will validate it and shorten the URL only if
-    /// validation passes.
-    ///
-    /// If the optional host is present, it will ensure that the url to shorten is not a url from
-    /// the same host that's running shorty (which would create a link loop)
-    ///
-    /// Otherwise, it will just shorten the URL.
-    pub fn shorten(
-        &self,
-        api_key: &Option<&str>,
-        host: Option<&str>,
-        url: &str,
-    ) -> Result<ShortenerResult, ShortenerError> {
-        let verify_result = api_key
-            .as_ref()
-            .map(|api_key| self.verify_api_key(api_key))
-            .unwrap_or(Ok(()));
-
-        verify_result
-            .and_then(|_| self.generate_id())
-            .and_then(|id| {
-                let mut url = url.to_owned();
-                if !url.to_lowercase().starts_with("http") {
-                    url = format!("http://{}", url);
-                }
-                Url::parse(&url)
-                    .and_then(|parsed_url| Ok((id, url, parsed_url)))
-                    .map_err(|parse_err| {
-                        ShortenerError::new_with_cause("Unable to parse url", Box::new(parse_err))
-                    })
-            })
-            .and_then(|(id, url, parsed_url)| {
-                if host.is_none() {
-                    return Ok((id, url));
-                }
+    /// Looks up a URL by the given ID as `lookup` does, also telling the status of the redirect
+    /// and whether the link is permanent. Frontends use it to build redirect responses.
+    pub async fn redirect(&self, id: &str) -> Option<Redirect> {
+        let url = self.lookup(id).await?;
+        Some(self.redirect_to(id, url).await)
+    }
 
-                if parsed_url.host_str().unwrap().eq(host.unwrap()) {
-                    return Err(ShortenerError::new("Link loop is not allowed"));
-                }
+    /// Returns the redirect of the link `id` to its `url`, with the status of the link. Outside of
+    /// its schedule, if any, the link redirects to the closed URL of the schedule instead.
+    pub(crate) async fn redirect_to(&self, id: &str, url: String) -> Redirect {
+        // read concurrently, sparing redirects a round trip to the storage for each
+        let (url, permanent, preconnecting, link_status, verified) = tokio::join!(
+            self.scheduled_url(id, url),
+            self.is_permanent(id),
+            self.is_preconnecting(id),
+            self.link_redirect_status(id),
+            self.is_verified(id)
+        );
+        // only permanent links preconnect
+        let preconnect = permanent && preconnecting;
+        let status = match link_status {
+            Some(status) => status,
+            None if permanent && !is_permanent_status(self.redirect_status) => 301,
+            None => self.redirect_status,
+        };
+
+        Redirect {
+            url,
+            status,
+            permanent,
+            preconnect,
+            verified,
+        }
+    }
 
-                Ok((id, url))
-            })
-            .and_then(|(id, url)| {
-                self.redis
-                    .set(&id, url.as_str())
-                    .map(|_| ShortenerResult { id, url })
-                    .map_err(|err| ShortenerError::new_with_cause("Redis error", Box::new(err)))
-            })
+    /// Returns the status the link redirects with, if it has its own
+    async fn link_redirect_status(&self, id: &str) -> Option<u16> {
+        self.storage
+            .get_string(&format!("REDIRECT_STATUS_{}", id))
+            .await
+            .ok()
+            .and_then(|status| status.parse().ok())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::cell::RefCell;
+    async fn is_permanent(&self, id: &str) -> bool {
+        self.storage
+            .get_bool(&format!("PERMANENT_{}", id))
+            .await
+            .unwrap_or(false)
+    }
 
-    use redis::RedisResult;
+    async fn is_preconnecting(&self, id: &str) -> bool {
+        self.storage
+            .get_bool(&format!("PRECONNECT_{}", id))
+            .await
+            .unwrap_or(false)
+    }
 
-    use super::*;
+    /// Returns the statistics of the URL with the given ID, without counting a hit. If no URL is
+    /// found or an error occurs, it returns `None`, as it does for the links `destination` hides:
+    /// IDs just claimed, quarantined links and links of paused campaigns.
+    pub async fn stats(&self, id: &str) -> Option<Stats> {
+        let url = self.destination(id).await?;
+        let hits = self.hits(id).await;
+        let created_at = self
+            .storage
+            .get_string(&format!("CREATED_{}", id))
+            .await
+            .ok()
+            .and_then(|created_at| created_at.parse().ok());
+        let permanent = self.is_permanent(id).await;
+
+        Some(Stats {
+            id: id.to_owned(),
+            url,
+            hits,
+            created_at,
+            permanent,
+            click_sampling: self.click_sampling,
+            latency: self.latency(id).await,
+        })
+    }
 
-    pub struct StubRedisFacade {
-        get_string_answers: RefCell<Vec<RedisResult<String>>>,
-        get_bool_answers: RefCell<Vec<RedisResult<bool>>>,
-        exists_answers: RefCell<Vec<RedisResult<bool>>>,
-        set_answers: RefCell<Vec<RedisResult<()>>>,
-        incr_answers: RefCell<Vec<RedisResult<i64>>>,
-        expire_answers: RefCell<Vec<RedisResult<()>>>,
-    }
-
-    impl StubRedisFacade {
-        fn new() -> Self {
-            StubRedisFacade {
-                get_string_answers: RefCell::new(vec![]),
-                get_bool_answers: RefCell::new(vec![]),
-                exists_answers: RefCell::new(vec![]),
-                set_answers: RefCell::new(vec![]),
-                incr_answers: RefCell::new(vec![]),
-                expire_answers: RefCell::new(vec![]),
-            }
-        }
+    /// Returns the hits of the link with the given ID, 0 if it has none or an error occurs
+    async fn hits(&self, id: &str) -> i64 {
+        self.storage
+            .get_string(&format!("HITS_{}", id))
+            .await
+            .ok()
+            .and_then(|hits| hits.parse().ok())
+            .unwrap_or(0)
+    }
 
-        pub fn get_string(&self, _key: &str) -> RedisResult<String> {
-            if self.get_string_answers.borrow().len() > 0 {
-                return self.get_string_answers.borrow_mut().remove(0);
-            }
-            panic!("unexpected get_string call");
-        }
+    /// Closes the storage, once the shortener is no longer used: see `Storage::close`
+    pub async fn close(&self) {
+        self.storage.close().await
+    }
 
-        pub fn get_bool(&self, _key: &str) -> RedisResult<bool> {
-            if self.get_bool_answers.borrow().len() > 0 {
-                return self.get_bool_answers.borrow_mut().remove(0);
-            }
-            panic!("unexpected get_bool call");
+    /// Checks that the storage can be reached within `timeout`, and how long it took to answer
+    pub async fn check_storage(&self, timeout: Duration) -> StorageHealth {
+        let start = Instant::now();
+        let error = match tokio::time::timeout(timeout, self.storage.ping()).await {
+            Ok(Ok(())) => None,
+            Ok(Err(err)) => Some(err.to_string()),
+            Err(_) => Some(format!("no answer within {}ms", timeout.as_millis())),
+        };
+        if let Some(error) = &error {
+            log::warn!("storage is unreachable: {}", error);
         }
 
-        pub fn exists(&self, _key: &str) -> RedisResult<bool> {
-            if self.exists_answers.borrow().len() > 0 {
-                return self.exists_answers.borrow_mut().remove(0);
-            }
-            panic!("unexpected exists call");
+        StorageHealth {
+            reachable: error.is_none(),
+            latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+            error,
         }
+    }
 
-        pub fn set(&self, _key: &str, _value: &str) -> RedisResult<()> {
-            if self.set_answers.borrow().len() > 0 {
-                return self.set_answers.borrow_mut().remove(0);
+    /// Checks the `maxmemory-policy` of Redis. When Redis runs out of memory, `allkeys-*` policies
+    /// evict any key, shortened URLs included: in that case a prominent error is logged and `false`
+    /// is returned. If the policy can't be read (some managed Redis disable `CONFIG`), a warning is
+    /// logged and `true` is returned.
+    ///
+    /// When keys are sharded across multiple Redis instances, every instance is checked.
+    pub async fn check_eviction_policy(&self) -> bool {
+        let policies = match self.storage.config_get("maxmemory-policy").await {
+            Ok(policies) => policies,
+            Err(err) => {
+                log::warn!("unable to verify Redis maxmemory-policy: {}", err);
+                return true;
             }
-            panic!("unexpected set call");
-        }
+        };
 
-        pub fn increment(&self, _key: &str) -> RedisResult<i64> {
-            if self.incr_answers.borrow().len() > 0 {
-                return self.incr_answers.borrow_mut().remove(0);
-            }
-            panic!("unexpected increment call");
-        }
+        let unsafe_policies = policies
+            .iter()
+            .filter(|policy| !Self::check_policy(policy))
+            .count();
 
-        pub fn expire(&self, _key: &str, _seconds: usize) -> RedisResult<()> {
-            if self.expire_answers.borrow().len() > 0 {
-                return self.expire_answers.borrow_mut().remove(0);
-            }
-            panic!("unexpected expire call");
-        }
+        unsafe_policies == 0
     }
 
-    #[test]
-    fn test_lookup() {
-        let redis = StubRedisFacade::new();
-        &redis
-            .get_string_answers
-            .borrow_mut()
-            .push(Ok(String::from("test url")));
+    fn check_policy(policy: &str) -> bool {
+        if policy.starts_with("allkeys-") {
+            log::error!(
+                "Redis maxmemory-policy is '{}': shortened URLs may be silently deleted when \
+                 Redis runs out of memory. Use 'noeviction' or a 'volatile-*' policy",
+                policy
+            );
+            return false;
+        }
 
-        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, redis, 600, 10);
-        assert_eq!(shortener.lookup("id").unwrap(), "test url");
+        log::info!("Redis maxmemory-policy is '{}'", policy);
+        true
     }
 
-    #[test]
-    fn test_shorten_happy_path_first_call() {
-        let redis = StubRedisFacade::new();
-        // api key verification
-        &redis.get_bool_answers.borrow_mut().push(Ok(true));
-        &redis.exists_answers.borrow_mut().push(Ok(false));
-        &redis.incr_answers.borrow_mut().push(Ok(1));
-        &redis.expire_answers.borrow_mut().push(Ok(()));
-
-        // id generation
-        &redis.exists_answers.borrow_mut().push(Ok(false));
+    /// Verifies `api_key`, looking for an `API_KEY_<api key>` key with value `true`, and reads
+    /// the features of valid keys. The call is not counted by the rate limiter: see
+    /// `RateLimiter::check`.
+    pub async fn verify_key(&self, api_key: &str) -> KeyInfo {
+        let key = format!("API_KEY_{}", api_key);
+        log::trace!("verifying api key '{}'", key);
+
+        let valid = self.storage.get_bool(&key).await.unwrap_or(false);
+        let features = if valid {
+            self.storage
+                .get_string(&features_key(api_key))
+                .await
+                .ok()
+                .map(|features| parse_features(&features))
+        } else {
+            None
+        };
+
+        KeyInfo {
+            key: api_key.to_owned(),
+            valid,
+            features,
+        }
+    }
 
-        // shortened url storage
-        &redis.set_answers.borrow_mut().push(Ok(()));
+    /// Verifies `api_key` and its access to `features`, then counts the call with the rate
+    /// limiter: calls rejected for lack of a feature are not counted
+    async fn verify_api_key(
+        &self,
+        api_key: &str,
+        features: &[Feature],
+    ) -> Result<(), ShortenerError> {
+        let key_info = self.verify_key(api_key).await;
+        if !key_info.valid {
+            return Err(ShortenerError::InvalidApiKey);
+        }
+        check_features(&key_info, features)?;
 
-        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, redis, 600, 10);
-        let shorten_result = shortener
-            .shorten(&Some("api key"), Some("with.lv"), "example.com")
-            .unwrap();
-        assert_eq!(10, shorten_result.id.len());
-        assert_eq!("http://example.com", shorten_result.url);
+        self.rate_limiter.check(api_key).await
     }
 
-    #[test]
-    fn test_shorten_happy_path_no_rate_limit() {
-        let redis = StubRedisFacade::new();
-        // api key verification
-        &redis.get_bool_answers.borrow_mut().push(Ok(true));
+    /// Generates an ID for `url` not in use yet, nor in `reserved`: the IDs given to links not
+    /// stored yet. IDs are signed if an `IdSigner` is set. Attempts are reported to the collision
+    /// alert, if any: see `with_collision_alert`.
+    async fn generate_id(
+        &self,
+        reserved: &HashSet<String>,
+        url: &str,
+    ) -> Result<String, ShortenerError> {
+        for attempt in 1..=self.id_generation_max_attempts {
+            let mut id = nanoid::format(nanoid::rngs::default, &self.id_alphabet, self.id_length);
+            if let Some(id_signer) = &self.id_signer {
+                id = id_signer.sign(&id, url);
+            }
+            if reserved.contains(&id) || RESERVED_IDS.contains(&id.as_str()) {
+                continue;
+            }
 
-        // id generation
-        &redis.exists_answers.borrow_mut().push(Ok(false));
+            let exists = self.storage.exists(&id).await.unwrap_or(false);
 
-        // shortened url storage
-        &redis.set_answers.borrow_mut().push(Ok(()));
+            if !exists {
+                if let Some(collision_alert) = &self.collision_alert {
+                    collision_alert.record(attempt, false, self.keyspace());
+                }
+                return Ok(id);
+            }
+        }
 
-        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, redis, 600, -1);
-        let shorten_result = shortener
-            .shorten(&Some("api key"), Some("with.lv"), "example.com")
-            .unwrap();
-        assert_eq!(10, shorten_result.id.len());
-        assert_eq!("http://example.com", shorten_result.url);
+        if let Some(collision_alert) = &self.collision_alert {
+            collision_alert.record(self.id_generation_max_attempts, true, self.keyspace());
+        }
+        Err(ShortenerError::IdExhausted)
     }
 
-    #[test]
-    fn test_shorten_happy_path_second_call() {
-        let redis = StubRedisFacade::new();
-        // api key verification
-        &redis.get_bool_answers.borrow_mut().push(Ok(true));
-        &redis.exists_answers.borrow_mut().push(Ok(true));
-        &redis.incr_answers.borrow_mut().push(Ok(2));
+    fn validate_id(&self, id: &str) -> Result<(), ShortenerError> {
+        if id.is_empty() || id.chars().count() > self.id_length {
+            return Err(ShortenerError::InvalidInput(
+                "Invalid custom ID: too short or longer than generated IDs",
+            ));
+        }
 
-        // id generation
-        &redis.exists_answers.borrow_mut().push(Ok(false));
+        if !id.chars().all(|c| self.id_alphabet.contains(&c)) {
+            return Err(ShortenerError::InvalidInput(
+                "Invalid custom ID: it contains characters outside the ID alphabet",
+            ));
+        }
 
-        // shortened url storage
-        &redis.set_answers.borrow_mut().push(Ok(()));
+        if RESERVED_IDS.contains(&id) {
+            return Err(ShortenerError::InvalidInput(
+                "Invalid custom ID: it's reserved for a route of shorty",
+            ));
+        }
 
-        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, redis, 600, 10);
-        let shorten_result = shortener
-            .shorten(&Some("api key"), Some("with.lv"), "example.com")
-            .unwrap();
-        assert_eq!(10, shorten_result.id.len());
-        assert_eq!("http://example.com", shorten_result.url);
+        Ok(())
     }
 
-    #[test]
-    fn test_shorten_happy_path_no_api_key() {
-        let redis = StubRedisFacade::new();
-        // id generation
-        &redis.exists_answers.borrow_mut().push(Ok(false));
+    async fn validate_options(
+        &self,
+        api_key: &Option<&str>,
+        options: &LinkOptions,
+    ) -> Result<(), ShortenerError> {
+        let expires_in = options.expires_in;
+        if expires_in == Some(0) {
+            return Err(ShortenerError::InvalidInput(
+                "Invalid expiration: expires_in must be greater than zero",
+            ));
+        }
+
+        if expires_in.is_some() && options.permanent {
+            return Err(ShortenerError::InvalidInput(
+                "Invalid expiration: permanent links can't expire",
+            ));
+        }
 
-        // shortened url storage
-        &redis.set_answers.borrow_mut().push(Ok(()));
+        if let Some(status) = options.redirect_status {
+            if !REDIRECT_STATUSES.contains(&status) {
+                return Err(ShortenerError::InvalidInput(
+                    "Invalid redirect status: it must be 301, 302, 307 or 308",
+                ));
+            }
+            if options.permanent && !is_permanent_status(status) {
+                return Err(ShortenerError::InvalidInput(
+                    "Invalid redirect status: permanent links redirect with 301 or 308",
+                ));
+            }
+        }
 
-        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, redis, 600, 10);
-        let shorten_result = shortener
-            .shorten(&None, Some("with.lv"), "example.com")
-            .unwrap();
-        assert_eq!(10, shorten_result.id.len());
-        assert_eq!("http://example.com", shorten_result.url);
-    }
+        if options.preconnect && !options.permanent {
+            return Err(ShortenerError::InvalidInput(
+                "Invalid preconnect: only permanent links preconnect",
+            ));
+        }
 
-    #[test]
-    fn test_shorten_unhappy_path_rate_limit_exceeded() {
-        let rate_limit = 10;
-        let redis = StubRedisFacade::new();
-        // api key verification
-        &redis.get_bool_answers.borrow_mut().push(Ok(true));
-        &redis.exists_answers.borrow_mut().push(Ok(true));
-        &redis.incr_answers.borrow_mut().push(Ok(rate_limit + 1));
+        if let Some(campaign) = &options.campaign {
+            let api_key =
+                api_key.ok_or_else(|| ShortenerError::Forbidden("Campaigns require an API key"))?;
+            self.check_campaign_owner(api_key, campaign).await?;
+        }
 
-        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, redis, 600, rate_limit);
-        let shorten_result_err = shortener
-            .shorten(&Some("api key"), Some("with.lv"), "example.com")
-            .err()
-            .unwrap();
-        assert_eq!("Rate limit exceeded", shorten_result_err.message);
+        Ok(())
     }
 
-    #[test]
-    fn test_shorten_unhappy_path_bad_url() {
-        let redis = StubRedisFacade::new();
-        // id generation
-        &redis.exists_answers.borrow_mut().push(Ok(false));
+    /// Parses a URL to shorten, prefixed with `http://` or `https://` if it has no scheme and URLs
+    /// aren't strict, and checks that its scheme is allowed and that it doesn't point to `host`
+    fn parse_url(&self, host: Option<&str>, url: &str) -> Result<String, ShortenerError> {
+        let mut url = url.to_owned();
+        if !has_scheme(&url) {
+            if self.strict_urls {
+                return Err(ShortenerError::InvalidUrl("Invalid URL: missing scheme"));
+            }
+            let scheme = if self.assume_https { "https" } else { "http" };
+            url = format!("{}://{}", scheme, url);
+        }
+        let parsed_url = Url::parse(&url).map_err(ShortenerError::UnparsableUrl)?;
+
+        if self.strict_urls {
+            let after_scheme = &url[parsed_url.scheme().len() + 1..];
+            let malformed =
+                url.trim() != url || (parsed_url.has_host() && !after_scheme.starts_with("//"));
+            if malformed {
+                return Err(ShortenerError::InvalidUrl("Invalid URL: malformed"));
+            }
+        }
 
-        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, redis, 600, -1);
-        let shorten_result_err = shortener
-            .shorten(&None, Some("with.lv"), "wrong domain.com")
-            .err()
-            .unwrap();
-        assert_eq!("Unable to parse url", shorten_result_err.message);
-    }
+        if !self
+            .allowed_schemes
+            .iter()
+            .any(|scheme| scheme == parsed_url.scheme())
+        {
+            return Err(ShortenerError::InvalidUrl(
+                "Invalid URL: scheme not allowed",
+            ));
+        }
 
-    #[test]
-    fn test_shorten_unhappy_path_same_domain() {
-        let redis = StubRedisFacade::new();
-        // id generation
-        &redis.exists_answers.borrow_mut().push(Ok(false));
+        if self.is_own_url(host, &parsed_url) {
+            return Err(ShortenerError::LinkLoop);
+        }
 
-        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, redis, 600, -1);
-        let shorten_result_err = shortener
-            .shorten(&None, Some("example.com"), "example.com")
-            .err()
-            .unwrap();
-        assert_eq!("Link loop is not allowed", shorten_result_err.message);
+        Ok(url)
     }
 
-    #[test]
-    fn test_shorten_happy_path_rate_limit_expired() {
-        let redis = StubRedisFacade::new();
+    /// Builds the result of shortening `url` to `id`, with the link of `id` if known
+    fn shortener_result(&self, id: String, url: String, options: &LinkOptions) -> ShortenerResult {
+        let short_url = self.short_url(&id);
+        ShortenerResult {
+            short_url,
+            ..ShortenerResult::new(id, url, options)
+        }
+    }
+
+    /// Parses a URL that new links redirect to as `parse_url` does, and checks that it's neither
+    /// blocked, private if rejected, malicious, nor redirecting to shorty (see `link_loop`)
+    async fn check_destination(
+        &self,
+        host: Option<&str>,
+        url: &str,
+    ) -> Result<String, ShortenerError> {
+        let url = self.parse_url(host, url)?;
+        self.check_blocklist(&url).await?;
+        self.check_target(&url).await?;
+        self.check_url(&url).await?;
+        self.check_redirects(host, &url).await?;
+        Ok(url)
+    }
+
+    /// Shortens an URL, returning a `ShortenerResult` holding the provided URL and the generated ID.
+    ///
+    /// If the optional API key is present, it will validate it and shorten the URL only if
+    /// validation passes.
+    ///
+    /// If the optional host is present, it will ensure that the url to shorten is not a url from
+    /// the same host that's running shorty (which would create a link loop)
+    ///
+    /// Otherwise, it will just shorten the URL.
+    ///
+    /// Expiration, permanence and campaign of the link are set with `options`: see `LinkOptions`.
+    /// Options the API key has defaults for, and `options` doesn't set, take the defaults: see
+    /// `link_defaults`.
+    ///
+    /// With URL deduplication enabled (see `with_url_deduplication`), shortening again a URL with
+    /// the same API key, permanence and campaign returns the existing link. The reverse index is
+    /// stored in `URL_<hash>`. Expiring links are neither deduplicated nor reused.
+    ///
+    /// With a phishing screen (see `with_phishing_screen`), links flagged by the screen are stored
+    /// but quarantined, until an operator releases them: see `quarantine`.
+    ///
+    /// URLs on a blocked domain, to a private host if rejected, or found malicious by the
+    /// `UrlChecker`, are rejected: see `domain_blocklist`, `private_targets` and `url_checker`. So
+    /// are the closed URLs of schedules.
+    ///
+    /// Links with a schedule (see `LinkOptions`) redirect to its closed URL outside of its windows,
+    /// stored in `SCHEDULE_<id>`.
+    ///
+    /// Calls with an API key hold a lease of the concurrency limiter: see `with_concurrency_limit`.
+    pub async fn shorten(
+        &self,
+        api_key: &Option<&str>,
+        host: Option<&str>,
+        url: &str,
+        options: &LinkOptions,
+    ) -> Result<ShortenerResult, ShortenerError> {
+        self.leased(api_key, self.shorten_leased(api_key, host, url, options))
+            .await
+    }
+
+    /// Shortens `url` as `shorten` does, once its lease is acquired
+    async fn shorten_leased(
+        &self,
+        api_key: &Option<&str>,
+        host: Option<&str>,
+        url: &str,
+        options: &LinkOptions,
+    ) -> Result<ShortenerResult, ShortenerError> {
+        let options = &self.apply_defaults(api_key, options).await;
+        if let Some(api_key) = api_key {
+            self.verify_api_key(api_key, &required_features(options))
+                .await?;
+        }
+
+        self.validate_options(api_key, options).await?;
+        let options = &self.check_schedule(host, options).await?;
+        let url = self.check_destination(host, url).await?;
+
+        let deduplication_key = if self.deduplicate_urls && options.expires_in.is_none() {
+            Some(deduplication_key(api_key, &url, options))
+        } else {
+            None
+        };
+
+        if let Some(deduplication_key) = &deduplication_key {
+            if let Some(id) = self.deduplicated_id(deduplication_key, &url).await {
+                return Ok(self.shortener_result(id, url, options));
+            }
+        }
+
+        let score = self.screen(&url).await?;
+        let id = self.generate_id(&HashSet::new(), &url).await?;
+        self.enqueue_created(&id, &url, options.expires_in).await?;
+        self.record_created(&id, api_key, options.expires_in).await;
+
+        if let Some(score) = score {
+            self.quarantine(&id, score, options.expires_in, "quarantine")
+                .await?;
+        }
+        self.store_metadata(&id, api_key, options).await?;
+        self.index_domains(&id, &url).await?;
+        self.store(&id, &url, options.expires_in)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        self.store_stats(&id, options.expires_in).await;
+
+        if let Some(deduplication_key) = &deduplication_key {
+            if let Err(err) = self.storage.set(deduplication_key, &id).await {
+                log::warn!("unable to index '{}' for deduplication: {}", id, err);
+            }
+        }
+
+        Ok(self
+            .shortener_result(id, url, options)
+            .with_quarantine(score))
+    }
+
+    /// Shortens many URLs at once, as `shorten` does with the same API key, host and `options`.
+    ///
+    /// The API key and `options` are checked once, while each URL is counted by the rate limiter.
+    /// Each URL gets its own result, in the order of `urls`: a URL that can't be shortened, such
+    /// as an invalid one, doesn't fail the others. Links are then stored together, in as few
+    /// round trips as the storage allows (see `Storage::set_many`): a storage error fails the
+    /// whole batch, possibly leaving some of its links stored.
+    ///
+    /// At most `MAX_BATCH_SIZE` URLs can be shortened at once, and API keys restricted to some
+    /// features need `Feature::Batch`. A batch holds a single lease of the concurrency limiter.
+    pub async fn shorten_batch(
+        &self,
+        api_key: &Option<&str>,
+        host: Option<&str>,
+        urls: &[&str],
+        options: &LinkOptions,
+    ) -> Result<Vec<Result<ShortenerResult, ShortenerError>>, ShortenerError> {
+        self.leased(
+            api_key,
+            self.shorten_batch_leased(api_key, host, urls, options),
+        )
+        .await
+    }
+
+    /// Shortens `urls` as `shorten_batch` does, once its lease is acquired
+    async fn shorten_batch_leased(
+        &self,
+        api_key: &Option<&str>,
+        host: Option<&str>,
+        urls: &[&str],
+        options: &LinkOptions,
+    ) -> Result<Vec<Result<ShortenerResult, ShortenerError>>, ShortenerError> {
+        if urls.len() > MAX_BATCH_SIZE {
+            return Err(ShortenerError::InvalidInput(
+                "Too many URLs: at most 1000 URLs can be shortened at once",
+            ));
+        }
+        let options = &self.apply_defaults(api_key, options).await;
+
+        if let Some(api_key) = api_key {
+            let key_info = self.verify_key(api_key).await;
+            if !key_info.valid {
+                return Err(ShortenerError::InvalidApiKey);
+            }
+            let mut features = required_features(options);
+            features.push(Feature::Batch);
+            check_features(&key_info, &features)?;
+            self.rate_limiter
+                .check_calls(api_key, urls.len() as i64)
+                .await?;
+        }
+        self.validate_options(api_key, options).await?;
+        let options = &self.check_schedule(host, options).await?;
+
+        let mut batch = Batch::default();
+        let mut results = Vec::with_capacity(urls.len());
+        for url in urls {
+            results.push(
+                self.prepare_batch_link(api_key, host, url, options, &mut batch)
+                    .await,
+            );
+        }
+
+        self.storage
+            .set_many(&batch.entries)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        for result in results.iter().flatten() {
+            if batch.ids.contains(result.id()) {
+                self.index_domains(result.id(), &result.url).await?;
+            }
+        }
+        for id in &batch.ids {
+            if let Some(api_key) = api_key {
+                self.index_link(api_key, id).await?;
+            }
+            if let Some(campaign) = &options.campaign {
+                self.storage
+                    .add_member(&format!("CAMPAIGN_LINKS_{}", campaign), id)
+                    .await
+                    .map_err(ShortenerError::Storage)?;
+            }
+        }
+        self.count_creations(batch.ids.len() as i64).await;
+
+        Ok(results)
+    }
+
+    /// Prepares the link of a URL of `shorten_batch`, adding the keys `shorten` would store to
+    /// `batch`. Quarantines are stored right away, so that links are never served before being
+    /// quarantined.
+    async fn prepare_batch_link(
+        &self,
+        api_key: &Option<&str>,
+        host: Option<&str>,
+        url: &str,
+        options: &LinkOptions,
+        batch: &mut Batch,
+    ) -> Result<ShortenerResult, ShortenerError> {
+        let url = self.check_destination(host, url).await?;
+
+        let deduplication_key = if self.deduplicate_urls && options.expires_in.is_none() {
+            Some(deduplication_key(api_key, &url, options))
+        } else {
+            None
+        };
+        if let Some(deduplication_key) = &deduplication_key {
+            let id = match batch.deduplicated.get(deduplication_key) {
+                Some(id) => Some(id.clone()),
+                None => self.deduplicated_id(deduplication_key, &url).await,
+            };
+            if let Some(id) = id {
+                return Ok(self.shortener_result(id, url, options));
+            }
+        }
+
+        let score = self.screen(&url).await?;
+        let id = self.generate_id(&batch.ids, &url).await?;
+        self.enqueue_created(&id, &url, options.expires_in).await?;
+        self.record_created(&id, api_key, options.expires_in).await;
+        if let Some(score) = score {
+            self.quarantine(&id, score, options.expires_in, "quarantine")
+                .await?;
+        }
+
+        let expires_in = options.expires_in;
+        let metadata = LinkMetadata::new(api_key, &options.creator);
+        batch.add(metadata_key(&id), &metadata.json(), expires_in);
+        if let Some(api_key) = api_key {
+            batch.add(format!("OWNER_{}", id), api_key, expires_in);
+        }
+        if options.permanent {
+            batch.add(format!("PERMANENT_{}", id), "true", None);
+        }
+        if options.preconnect {
+            batch.add(format!("PRECONNECT_{}", id), "true", None);
+        }
+        if let Some(campaign) = &options.campaign {
+            batch.add(format!("CAMPAIGN_OF_{}", id), campaign, expires_in);
+        }
+        if let Some(status) = options.redirect_status {
+            batch.add(
+                format!("REDIRECT_STATUS_{}", id),
+                &status.to_string(),
+                expires_in,
+            );
+        }
+        if let Some(schedule) = &options.schedule {
+            batch.add(schedule_key(&id), &schedule_json(schedule), expires_in);
+        }
+        batch.add(id.clone(), &url, expires_in);
+        batch.add(format!("CREATED_{}", id), &now().to_string(), expires_in);
+        batch.add(format!("HITS_{}", id), "0", expires_in);
+        if let Some(deduplication_key) = deduplication_key {
+            batch.add(deduplication_key.clone(), &id, None);
+            batch.deduplicated.insert(deduplication_key, id.clone());
+        }
+        batch.ids.insert(id.clone());
+
+        Ok(self
+            .shortener_result(id, url, options)
+            .with_quarantine(score))
+    }
+
+    /// Returns the ID of the existing link indexed by `deduplication_key`, if it still points to
+    /// `url`: hashes may collide, and links may have been deleted
+    async fn deduplicated_id(&self, deduplication_key: &str, url: &str) -> Option<String> {
+        let id = self.storage.get_string(deduplication_key).await.ok()?;
+        let existing_url = self.storage.get_string(&id).await.ok()?;
+
+        if existing_url == url {
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    /// Shortens an URL using the given custom ID (an alias such as `promo2024`) instead of a
+    /// generated one. API key and host are handled as in `shorten`.
+    ///
+    /// The custom ID must be made of characters of the ID alphabet, and can't be longer than
+    /// generated IDs. If the custom ID is already taken, an error is returned.
+    ///
+    /// API keys restricted to some features need `Feature::CustomIds`.
+    ///
+    /// `options` are handled as in `shorten`, and so is the concurrency limiter.
+    pub async fn shorten_with_id(
+        &self,
+        api_key: &Option<&str>,
+        host: Option<&str>,
+        id: &str,
+        url: &str,
+        options: &LinkOptions,
+    ) -> Result<ShortenerResult, ShortenerError> {
+        self.leased(
+            api_key,
+            self.shorten_with_id_leased(api_key, host, id, url, options),
+        )
+        .await
+    }
+
+    /// Shortens `url` to `id` as `shorten_with_id` does, once its lease is acquired
+    async fn shorten_with_id_leased(
+        &self,
+        api_key: &Option<&str>,
+        host: Option<&str>,
+        id: &str,
+        url: &str,
+        options: &LinkOptions,
+    ) -> Result<ShortenerResult, ShortenerError> {
+        let options = &self.apply_defaults(api_key, options).await;
+        if let Some(api_key) = api_key {
+            let mut features = required_features(options);
+            features.push(Feature::CustomIds);
+            self.verify_api_key(api_key, &features).await?;
+        }
+
+        self.validate_options(api_key, options).await?;
+        let options = &self.check_schedule(host, options).await?;
+        self.check_custom_id(id).await?;
+        let url = self.check_destination(host, url).await?;
+        let score = self.screen(&url).await?;
+
+        // the ID is claimed before anything else is stored, so that a caller losing the race for
+        // it leaves the link of the winner untouched
+        let claimed = self
+            .storage
+            .set_if_not_exists_expiring(id, CLAIMED_ID, CLAIM_PERIOD)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if !claimed {
+            return Err(ShortenerError::Conflict("Custom ID already taken"));
+        }
+
+        // quarantined before its URL is stored, so that the link is never served meanwhile
+        self.enqueue_created(id, &url, options.expires_in).await?;
+        self.record_created(id, api_key, options.expires_in).await;
+        if let Some(score) = score {
+            self.quarantine(id, score, options.expires_in, "quarantine")
+                .await?;
+        }
+        self.store(id, &url, options.expires_in)
+            .await
+            .map_err(ShortenerError::Storage)?;
+
+        self.store_metadata(id, api_key, options).await?;
+        self.index_domains(id, &url).await?;
+        self.store_stats(id, options.expires_in).await;
+
+        Ok(self
+            .shortener_result(id.to_owned(), url, options)
+            .with_quarantine(score))
+    }
+
+    /// Checks that `id` can be used as a custom ID, as `shorten_with_id` would: it must be valid,
+    /// and not taken yet. Nothing is stored, so the ID may be taken before it's used.
+    pub async fn check_custom_id(&self, id: &str) -> Result<(), ShortenerError> {
+        self.validate_id(id)?;
+        self.check_not_rotated(id).await?;
+
+        let exists = self
+            .storage
+            .exists(id)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if exists {
+            return Err(ShortenerError::Conflict("Custom ID already taken"));
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the link with the given ID, together with its statistics. Only the API key that
+    /// shortened the link can delete it: links shortened without an API key can't be deleted.
+    pub async fn delete(&self, api_key: &str, id: &str) -> Result<(), ShortenerError> {
+        self.verify_api_key(api_key, &[]).await?;
+
+        let exists = self
+            .storage
+            .exists(id)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if !exists {
+            return Err(ShortenerError::NotFound("Link not found"));
+        }
+
+        let owner = self.storage.get_string(&format!("OWNER_{}", id)).await.ok();
+        if owner.as_deref() != Some(api_key) {
+            return Err(ShortenerError::Forbidden("Link not owned by the API key"));
+        }
+
+        self.purge(id, Some(api_key)).await
+    }
+
+    /// Deletes a link together with its statistics, removing it from the indexes of its `owner`,
+    /// if any, and of its campaign, without any check
+    pub(crate) async fn purge(&self, id: &str, owner: Option<&str>) -> Result<(), ShortenerError> {
+        let campaign = self
+            .storage
+            .get_string(&format!("CAMPAIGN_OF_{}", id))
+            .await
+            .ok();
+        if let Some(campaign) = &campaign {
+            self.storage
+                .remove_member(&format!("CAMPAIGN_LINKS_{}", campaign), id)
+                .await
+                .map_err(ShortenerError::Storage)?;
+        }
+
+        if self.deduplicate_urls {
+            self.delete_deduplication_key(owner, id, campaign).await?;
+        }
+        if let Some(owner) = owner {
+            self.unindex_link(owner, id).await?;
+        }
+        if let Ok(url) = self.storage.get_string(id).await {
+            self.unindex_domains(id, &url).await?;
+        }
+        self.unquarantine(id).await?;
+
+        for key in &[
+            format!("CAMPAIGN_OF_{}", id),
+            format!("HITS_{}", id),
+            format!("CREATED_{}", id),
+            format!("PERMANENT_{}", id),
+            format!("PRECONNECT_{}", id),
+            format!("REDIRECT_STATUS_{}", id),
+            schedule_key(id),
+            format!("OWNER_{}", id),
+            metadata_key(id),
+            events_key(id),
+            id.to_owned(),
+        ] {
+            self.storage
+                .delete(key)
+                .await
+                .map_err(ShortenerError::Storage)?;
+        }
+        self.forget_clicks(id)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        self.record_change(id, micros()).await;
+
+        Ok(())
+    }
+
+    /// Changes the URL the link with the given ID redirects to, such as when its destination
+    /// moves. Only the API key that shortened the link can update it, as with `delete`, and the
+    /// new URL is checked as `shorten` checks URLs, the phishing screen quarantining the link if
+    /// it flags the URL. The link keeps its ID, options, statistics and expiration.
+    ///
+    /// Links with signed IDs can't be updated, their ID being tied to their URL, and neither can
+    /// quarantined links, until reviewed.
+    pub async fn update(
+        &self,
+        api_key: &str,
+        host: Option<&str>,
+        id: &str,
+        url: &str,
+    ) -> Result<ShortenerResult, ShortenerError> {
+        self.verify_api_key(api_key, &[]).await?;
+
+        let old_url = match self.storage.get_string(id).await {
+            Ok(url) => url,
+            Err(_) => return Err(ShortenerError::NotFound("Link not found")),
+        };
+        let owner = self.storage.get_string(&format!("OWNER_{}", id)).await.ok();
+        if owner.as_deref() != Some(api_key) {
+            return Err(ShortenerError::Forbidden("Link not owned by the API key"));
+        }
+        if let Some(id_signer) = &self.id_signer {
+            if id_signer.verify(id, &old_url) {
+                return Err(ShortenerError::InvalidInput(
+                    "Invalid update: signed IDs can't change URL",
+                ));
+            }
+        }
+        if self.is_quarantined(id).await {
+            return Err(ShortenerError::Conflict("Link quarantined"));
+        }
+
+        let url = self.check_destination(host, url).await?;
+        let score = self.screen(&url).await?;
+
+        let expires_in = self
+            .storage
+            .ttl(id)
+            .await
+            .map_err(ShortenerError::Storage)?
+            .map(|ttl| ttl as usize);
+        let campaign = self
+            .storage
+            .get_string(&format!("CAMPAIGN_OF_{}", id))
+            .await
+            .ok();
+        let options = LinkOptions {
+            expires_in,
+            ..self.stored_options(id, campaign).await
+        };
+        let api_key = Some(api_key);
+        if self.deduplicate_urls {
+            self.delete_deduplication_key(api_key, id, options.campaign.clone())
+                .await?;
+        }
+        self.unindex_domains(id, &old_url).await?;
+
+        // quarantined before the new URL is stored, so that it's never served meanwhile
+        if let Some(score) = score {
+            self.quarantine(id, score, expires_in, "quarantine").await?;
+        }
+        self.store(id, &url, expires_in)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        self.index_domains(id, &url).await?;
+        if self.deduplicate_urls && expires_in.is_none() {
+            let deduplication_key = deduplication_key(&api_key, &url, &options);
+            if let Err(err) = self.storage.set(&deduplication_key, id).await {
+                log::warn!("unable to index '{}' for deduplication: {}", id, err);
+            }
+        }
+        self.record_updated(id, &url).await;
+
+        Ok(self
+            .shortener_result(id.to_owned(), url, &options)
+            .with_quarantine(score))
+    }
+
+    /// Returns the options a link was shortened with into `campaign`, as stored, but its
+    /// expiration
+    async fn stored_options(&self, id: &str, campaign: Option<String>) -> LinkOptions {
+        LinkOptions {
+            expires_in: None,
+            permanent: self.is_permanent(id).await,
+            campaign,
+            redirect_status: self.link_redirect_status(id).await,
+            schedule: self.link_schedule(id).await,
+            preconnect: self.is_preconnecting(id).await,
+            creator: Creator::default(),
+        }
+    }
+
+    /// Deletes the deduplication index entry of a link being deleted, if it points to the link
+    async fn delete_deduplication_key(
+        &self,
+        api_key: Option<&str>,
+        id: &str,
+        campaign: Option<String>,
+    ) -> Result<(), ShortenerError> {
+        let url = self
+            .storage
+            .get_string(id)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        let options = self.stored_options(id, campaign).await;
+        let deduplication_key = deduplication_key(&api_key, &url, &options);
+
+        if self
+            .storage
+            .get_string(&deduplication_key)
+            .await
+            .ok()
+            .as_deref()
+            == Some(id)
+        {
+            self.storage
+                .delete(&deduplication_key)
+                .await
+                .map_err(ShortenerError::Storage)?;
+        }
+
+        Ok(())
+    }
+
+    /// Stores the API key owning a new link, indexing the link (see `link_index`), its campaign,
+    /// its redirect status and its schedule, expiring with the link, and whether the link is
+    /// permanent and preconnects
+    async fn store_metadata(
+        &self,
+        id: &str,
+        api_key: &Option<&str>,
+        options: &LinkOptions,
+    ) -> Result<(), ShortenerError> {
+        let metadata = LinkMetadata::new(api_key, &options.creator);
+        self.store(&metadata_key(id), &metadata.json(), options.expires_in)
+            .await
+            .map_err(ShortenerError::Storage)?;
+
+        if let Some(api_key) = api_key {
+            self.store(&format!("OWNER_{}", id), api_key, options.expires_in)
+                .await
+                .map_err(ShortenerError::Storage)?;
+            self.index_link(api_key, id).await?;
+        }
+
+        if options.permanent {
+            self.storage
+                .set(&format!("PERMANENT_{}", id), "true")
+                .await
+                .map_err(ShortenerError::Storage)?;
+        }
+
+        if options.preconnect {
+            self.storage
+                .set(&format!("PRECONNECT_{}", id), "true")
+                .await
+                .map_err(ShortenerError::Storage)?;
+        }
+
+        if let Some(campaign) = &options.campaign {
+            self.store(&format!("CAMPAIGN_OF_{}", id), campaign, options.expires_in)
+                .await
+                .map_err(ShortenerError::Storage)?;
+            self.storage
+                .add_member(&format!("CAMPAIGN_LINKS_{}", campaign), id)
+                .await
+                .map_err(ShortenerError::Storage)?;
+        }
+
+        if let Some(status) = options.redirect_status {
+            self.store(
+                &format!("REDIRECT_STATUS_{}", id),
+                &status.to_string(),
+                options.expires_in,
+            )
+            .await
+            .map_err(ShortenerError::Storage)?;
+        }
+
+        if let Some(schedule) = &options.schedule {
+            self.store_schedule(id, schedule, options.expires_in)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn store(&self, key: &str, value: &str, expires_in: Option<usize>) -> StorageResult<()> {
+        match expires_in {
+            Some(expires_in) => self.storage.set_expiring(key, value, expires_in).await,
+            None => self.storage.set(key, value).await,
+        }
+    }
+
+    /// Stores the creation time and a zero hits counter of a new link, expiring with the link.
+    /// Statistics are not worth failing a shortening: errors are just logged.
+    async fn store_stats(&self, id: &str, expires_in: Option<usize>) {
+        let stored = match self
+            .store(&format!("CREATED_{}", id), &now().to_string(), expires_in)
+            .await
+        {
+            Ok(_) => self.store(&format!("HITS_{}", id), "0", expires_in).await,
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = stored {
+            log::warn!("unable to store stats of '{}': {}", id, err);
+        }
+        self.count_creation().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use redis::{ErrorKind, RedisError};
+
+    use crate::link_events::LinkEventKind;
+    use crate::memory_storage::MemoryStorage;
+
+    use super::*;
+
+    /// A `Storage` answering `config_get` with the given values, or with an error if there are
+    /// none, and failing any other call
+    struct ConfigStorage(Option<Vec<String>>);
+
+    impl ConfigStorage {
+        fn unavailable<T>() -> StorageResult<T> {
+            Err(RedisError::from((ErrorKind::IoError, "storage unavailable")).into())
+        }
+    }
+
+    #[async_trait]
+    impl Storage for ConfigStorage {
+        async fn get_string(&self, _key: &str) -> StorageResult<String> {
+            Self::unavailable()
+        }
+
+        async fn exists(&self, _key: &str) -> StorageResult<bool> {
+            Self::unavailable()
+        }
+
+        async fn increment(&self, _key: &str) -> StorageResult<i64> {
+            Self::unavailable()
+        }
+
+        async fn increment_expiring(&self, _key: &str, _period: usize) -> StorageResult<i64> {
+            Self::unavailable()
+        }
+
+        async fn expire(&self, _key: &str, _period: usize) -> StorageResult<()> {
+            Self::unavailable()
+        }
+
+        async fn ttl(&self, _key: &str) -> StorageResult<Option<u64>> {
+            Self::unavailable()
+        }
+
+        async fn set(&self, _key: &str, _value: &str) -> StorageResult<()> {
+            Self::unavailable()
+        }
+
+        async fn set_expiring(
+            &self,
+            _key: &str,
+            _value: &str,
+            _period: usize,
+        ) -> StorageResult<()> {
+            Self::unavailable()
+        }
+
+        async fn set_if_not_exists(&self, _key: &str, _value: &str) -> StorageResult<bool> {
+            Self::unavailable()
+        }
+
+        async fn set_if_not_exists_expiring(
+            &self,
+            _key: &str,
+            _value: &str,
+            _period: usize,
+        ) -> StorageResult<bool> {
+            Self::unavailable()
+        }
+
+        async fn delete(&self, _key: &str) -> StorageResult<bool> {
+            Self::unavailable()
+        }
+
+        async fn rename(&self, _key: &str, _new_key: &str) -> StorageResult<bool> {
+            Self::unavailable()
+        }
+
+        async fn add_member(&self, _key: &str, _member: &str) -> StorageResult<bool> {
+            Self::unavailable()
+        }
+
+        async fn remove_member(&self, _key: &str, _member: &str) -> StorageResult<bool> {
+            Self::unavailable()
+        }
+
+        async fn members(&self, _key: &str) -> StorageResult<Vec<String>> {
+            Self::unavailable()
+        }
+
+        async fn is_member(&self, _key: &str, _member: &str) -> StorageResult<bool> {
+            Self::unavailable()
+        }
+
+        async fn add_scored_member(
+            &self,
+            _key: &str,
+            _member: &str,
+            _score: u64,
+        ) -> StorageResult<bool> {
+            Self::unavailable()
+        }
+
+        async fn remove_scored_member(&self, _key: &str, _member: &str) -> StorageResult<bool> {
+            Self::unavailable()
+        }
+
+        async fn scored_members(
+            &self,
+            _key: &str,
+            _min_score: u64,
+            _max_score: u64,
+            _limit: usize,
+        ) -> StorageResult<Vec<(String, u64)>> {
+            Self::unavailable()
+        }
+
+        async fn scored_members_ascending(
+            &self,
+            _key: &str,
+            _min_score: u64,
+            _max_score: u64,
+            _limit: usize,
+        ) -> StorageResult<Vec<(String, u64)>> {
+            Self::unavailable()
+        }
+
+        async fn scan(&self, _prefix: &str) -> StorageResult<Vec<String>> {
+            Self::unavailable()
+        }
+
+        async fn config_get(&self, _parameter: &str) -> StorageResult<Vec<String>> {
+            self.0.clone().map_or_else(Self::unavailable, Ok)
+        }
+
+        async fn ping(&self) -> StorageResult<()> {
+            Self::unavailable()
+        }
+    }
+
+    /// A `MemoryStorage` telling that `hidden` doesn't exist, as if it was stored right after
+    /// being checked
+    struct RacingStorage {
+        storage: MemoryStorage,
+        hidden: &'static str,
+    }
+
+    #[async_trait]
+    impl Storage for RacingStorage {
+        async fn get_string(&self, key: &str) -> StorageResult<String> {
+            self.storage.get_string(key).await
+        }
+
+        async fn exists(&self, key: &str) -> StorageResult<bool> {
+            if key == self.hidden {
+                return Ok(false);
+            }
+            self.storage.exists(key).await
+        }
+
+        async fn increment(&self, key: &str) -> StorageResult<i64> {
+            self.storage.increment(key).await
+        }
+
+        async fn increment_expiring(&self, key: &str, period: usize) -> StorageResult<i64> {
+            self.storage.increment_expiring(key, period).await
+        }
+
+        async fn expire(&self, key: &str, period: usize) -> StorageResult<()> {
+            self.storage.expire(key, period).await
+        }
+
+        async fn ttl(&self, key: &str) -> StorageResult<Option<u64>> {
+            self.storage.ttl(key).await
+        }
+
+        async fn set(&self, key: &str, value: &str) -> StorageResult<()> {
+            self.storage.set(key, value).await
+        }
+
+        async fn set_expiring(&self, key: &str, value: &str, period: usize) -> StorageResult<()> {
+            self.storage.set_expiring(key, value, period).await
+        }
+
+        async fn set_if_not_exists(&self, key: &str, value: &str) -> StorageResult<bool> {
+            self.storage.set_if_not_exists(key, value).await
+        }
+
+        async fn set_if_not_exists_expiring(
+            &self,
+            key: &str,
+            value: &str,
+            period: usize,
+        ) -> StorageResult<bool> {
+            self.storage
+                .set_if_not_exists_expiring(key, value, period)
+                .await
+        }
+
+        async fn delete(&self, key: &str) -> StorageResult<bool> {
+            self.storage.delete(key).await
+        }
+
+        async fn rename(&self, key: &str, new_key: &str) -> StorageResult<bool> {
+            self.storage.rename(key, new_key).await
+        }
+
+        async fn add_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+            self.storage.add_member(key, member).await
+        }
+
+        async fn remove_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+            self.storage.remove_member(key, member).await
+        }
+
+        async fn members(&self, key: &str) -> StorageResult<Vec<String>> {
+            self.storage.members(key).await
+        }
+
+        async fn is_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+            self.storage.is_member(key, member).await
+        }
+
+        async fn add_scored_member(
+            &self,
+            key: &str,
+            member: &str,
+            score: u64,
+        ) -> StorageResult<bool> {
+            self.storage.add_scored_member(key, member, score).await
+        }
+
+        async fn remove_scored_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+            self.storage.remove_scored_member(key, member).await
+        }
+
+        async fn scored_members(
+            &self,
+            key: &str,
+            min_score: u64,
+            max_score: u64,
+            limit: usize,
+        ) -> StorageResult<Vec<(String, u64)>> {
+            self.storage
+                .scored_members(key, min_score, max_score, limit)
+                .await
+        }
+
+        async fn scored_members_ascending(
+            &self,
+            key: &str,
+            min_score: u64,
+            max_score: u64,
+            limit: usize,
+        ) -> StorageResult<Vec<(String, u64)>> {
+            self.storage
+                .scored_members_ascending(key, min_score, max_score, limit)
+                .await
+        }
+
+        async fn scan(&self, prefix: &str) -> StorageResult<Vec<String>> {
+            self.storage.scan(prefix).await
+        }
+    }
+
+    async fn storage_with_api_key() -> MemoryStorage {
+        let storage = MemoryStorage::new();
+        storage.set("API_KEY_api key", "true").await.unwrap();
+        storage
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let storage = MemoryStorage::new();
+        storage.set("id", "test url").await.unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        assert_eq!(shortener.lookup("id").await.unwrap(), "test url");
+        assert!(shortener.lookup("missing").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_counts_hits() {
+        let storage = MemoryStorage::new();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let id = shortener
+            .shorten(
+                &None,
+                Some("with.lv"),
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap()
+            .id;
+
+        let stats = shortener.stats(&id).await.unwrap();
+        assert_eq!(0, stats.hits);
+        assert_eq!("http://example.com", stats.url);
+        assert!(stats.created_at.unwrap() <= now());
+
+        shortener.lookup(&id).await.unwrap();
+        shortener.lookup(&id).await.unwrap();
+        assert_eq!(2, shortener.stats(&id).await.unwrap().hits);
+
+        assert!(shortener.stats("missing").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_destination() {
+        let storage = MemoryStorage::new();
+        storage.set("id", "test url").await.unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        assert_eq!("test url", shortener.destination("id").await.unwrap());
+        assert!(shortener.destination("missing").await.is_none());
+        assert_eq!(0, shortener.stats("id").await.unwrap().hits);
+
+        shortener
+            .quarantine("id", 5, None, "quarantine")
+            .await
+            .unwrap();
+        assert!(shortener.destination("id").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stats_of_link_without_stats() {
+        let storage = MemoryStorage::new();
+        storage.set("id", "test url").await.unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        shortener.lookup("id").await.unwrap();
+
+        let stats = shortener.stats("id").await.unwrap();
+        assert_eq!(1, stats.hits);
+        assert!(stats.created_at.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stats_of_hidden_link() {
+        let storage = MemoryStorage::new();
+        storage.set("id", "test url").await.unwrap();
+        storage.set("claimed", CLAIMED_ID).await.unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        assert!(shortener.stats("claimed").await.is_none());
+
+        shortener
+            .quarantine("id", 5, None, "quarantine")
+            .await
+            .unwrap();
+        assert!(shortener.stats("id").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_shorten_happy_path_first_call() {
+        let storage = storage_with_api_key().await;
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let shorten_result = shortener
+            .shorten(
+                &Some("api key"),
+                Some("with.lv"),
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(10, shorten_result.id.len());
+        assert_eq!("http://example.com", shorten_result.url);
+        assert_eq!(None, shorten_result.short_url());
+
+        assert_eq!(
+            "http://example.com",
+            shortener.lookup(&shorten_result.id).await.unwrap()
+        );
+        assert_eq!(
+            "1",
+            shortener
+                .storage
+                .get_string("RATE_API_KEY_api key")
+                .await
+                .unwrap()
+        );
+    }
 
-        // api key verification
-        &redis.get_bool_answers.borrow_mut().push(Ok(true));
-        &redis.exists_answers.borrow_mut().push(Ok(true));
-        &redis.incr_answers.borrow_mut().push(Ok(1));
+    #[tokio::test]
+    async fn test_shorten_happy_path_short_url() {
+        let storage = storage_with_api_key().await;
 
-        // id generation
-        &redis.exists_answers.borrow_mut().push(Ok(false));
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10)
+            .with_public_base_url(Some(String::from("https://sho.rt/")));
+        let shorten_result = shortener
+            .shorten(
+                &Some("api key"),
+                Some("with.lv"),
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(format!("https://sho.rt/{}", shorten_result.id)),
+            shorten_result.short_url
+        );
 
-        // shortened url storage
-        &redis.set_answers.borrow_mut().push(Ok(()));
+        let json = serde_json::to_value(&shorten_result).unwrap();
+        assert_eq!(
+            format!("https://sho.rt/{}", shorten_result.id),
+            json["short_url"]
+        );
+    }
 
-        // api key verification
-        &redis.get_bool_answers.borrow_mut().push(Ok(true));
-        &redis.exists_answers.borrow_mut().push(Ok(false));
-        &redis.incr_answers.borrow_mut().push(Ok(1));
-        &redis.expire_answers.borrow_mut().push(Ok(()));
+    #[tokio::test]
+    async fn test_shorten_batch() {
+        let storage = storage_with_api_key().await;
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10)
+            .with_url_deduplication(true);
+        let results = shortener
+            .shorten_batch(
+                &Some("api key"),
+                Some("with.lv"),
+                &["example.com", "with.lv/loop", "example.com", "example.org"],
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(4, results.len());
+        assert!(results[1].is_err());
 
-        // id generation
-        &redis.exists_answers.borrow_mut().push(Ok(false));
+        let first = results[0].as_ref().unwrap();
+        assert_eq!(first.id, results[2].as_ref().unwrap().id);
+        assert_eq!(
+            "http://example.org",
+            shortener
+                .lookup(&results[3].as_ref().unwrap().id)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            "api key",
+            shortener
+                .storage
+                .get_string(&format!("OWNER_{}", first.id))
+                .await
+                .unwrap()
+        );
+        assert_eq!(2, shortener.links("api key", 0, 10).await.unwrap().len());
+        assert_eq!(
+            "4",
+            shortener
+                .storage
+                .get_string("RATE_API_KEY_api key")
+                .await
+                .unwrap()
+        );
 
-        // shortened url storage
-        &redis.set_answers.borrow_mut().push(Ok(()));
+        let err = shortener
+            .shorten_batch(
+                &Some("api key"),
+                None,
+                &["example.com"; 7],
+                &LinkOptions::default(),
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Rate limit exceeded", err.to_string());
+    }
 
-        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, redis, 600, 10);
+    #[tokio::test]
+    async fn test_shorten_happy_path_no_rate_limit() {
+        let storage = storage_with_api_key().await;
 
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, -1);
         let shorten_result = shortener
-            .shorten(&Some("api key"), Some("with.lv"), "example.com")
+            .shorten(
+                &Some("api key"),
+                Some("with.lv"),
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
             .unwrap();
         assert_eq!(10, shorten_result.id.len());
         assert_eq!("http://example.com", shorten_result.url);
 
+        assert!(!shortener
+            .storage
+            .exists("RATE_API_KEY_api key")
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_shorten_happy_path_second_call() {
+        let storage = storage_with_api_key().await;
+        storage.set("RATE_API_KEY_api key", "1").await.unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
         let shorten_result = shortener
-            .shorten(&Some("api key"), Some("with.lv"), "www.wikipedia.org")
+            .shorten(
+                &Some("api key"),
+                Some("with.lv"),
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
             .unwrap();
         assert_eq!(10, shorten_result.id.len());
-        assert_eq!("http://www.wikipedia.org", shorten_result.url);
+        assert_eq!("http://example.com", shorten_result.url);
+
+        assert_eq!(
+            "2",
+            shortener
+                .storage
+                .get_string("RATE_API_KEY_api key")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shorten_happy_path_no_api_key() {
+        let storage = MemoryStorage::new();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let shorten_result = shortener
+            .shorten(
+                &None,
+                Some("with.lv"),
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(10, shorten_result.id.len());
+        assert_eq!("http://example.com", shorten_result.url);
+    }
+
+    #[tokio::test]
+    async fn test_shorten_unhappy_path_rate_limit_exceeded() {
+        let rate_limit = 10;
+        let storage = storage_with_api_key().await;
+        storage
+            .set("RATE_API_KEY_api key", &rate_limit.to_string())
+            .await
+            .unwrap();
+
+        let shortener = Shortener::new(
+            10,
+            vec!['a', 'b', 'c'],
+            10,
+            Box::new(storage),
+            600,
+            rate_limit,
+        );
+        let shorten_result_err = shortener
+            .shorten(
+                &Some("api key"),
+                Some("with.lv"),
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Rate limit exceeded", shorten_result_err.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_shorten_happy_path_expiring() {
+        let storage = MemoryStorage::new();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let shorten_result = shortener
+            .shorten(
+                &None,
+                Some("with.lv"),
+                "example.com",
+                &LinkOptions {
+                    expires_in: Some(600),
+                    ..LinkOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(Some(600), shorten_result.expires_in);
+        assert!(shorten_result.expires_at.unwrap() >= now() + 599);
+
+        let id = shorten_result.id;
+        assert_eq!("http://example.com", shortener.lookup(&id).await.unwrap());
+
+        // the link is expired
+        shortener.storage.expire(&id, 0).await.unwrap();
+        assert!(shortener.lookup(&id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_shorten_happy_path_permanent() {
+        let storage = MemoryStorage::new();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let permanent_id = shortener
+            .shorten(
+                &None,
+                Some("with.lv"),
+                "example.com",
+                &LinkOptions {
+                    permanent: true,
+                    ..LinkOptions::default()
+                },
+            )
+            .await
+            .unwrap()
+            .id;
+        let temporary_id = shortener
+            .shorten(
+                &None,
+                Some("with.lv"),
+                "example.org",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap()
+            .id;
+
+        let redirect = shortener.redirect(&permanent_id).await.unwrap();
+        assert_eq!("http://example.com", redirect.url);
+        assert_eq!(301, redirect.status_code());
+        assert!(redirect.cache_control().contains("max-age"));
+        assert!(shortener.stats(&permanent_id).await.unwrap().permanent);
+
+        let redirect = shortener.redirect(&temporary_id).await.unwrap();
+        assert_eq!(302, redirect.status_code());
+        assert_eq!("no-store", redirect.cache_control());
+        assert!(!shortener.stats(&temporary_id).await.unwrap().permanent);
+
+        assert!(shortener.redirect("missing").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_shorten_happy_path_signed_id() {
+        let alphabet = vec!['a', 'b', 'c'];
+        let id_signer = IdSigner::new(b"secret", alphabet.clone(), 4);
+        let shortener = Shortener::new(10, alphabet, 10, Box::new(MemoryStorage::new()), 600, 10)
+            .with_id_signer(id_signer.clone());
+
+        let result = shortener
+            .shorten(&None, None, "example.com", &LinkOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(14, result.id().len());
+        assert!(id_signer.verify(result.id(), result.url()));
+        assert!(!id_signer.verify(result.id(), "http://example.org"));
+        assert_eq!(
+            "http://example.com",
+            shortener.lookup(result.id()).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shorten_happy_path_redirect_status() {
+        let storage = MemoryStorage::new();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10)
+            .with_redirect_status(307);
+        let mut ids = vec![];
+        for options in &[
+            LinkOptions::default(),
+            LinkOptions {
+                permanent: true,
+                ..LinkOptions::default()
+            },
+            LinkOptions {
+                permanent: true,
+                redirect_status: Some(308),
+                ..LinkOptions::default()
+            },
+            LinkOptions {
+                expires_in: Some(600),
+                redirect_status: Some(301),
+                ..LinkOptions::default()
+            },
+        ] {
+            let result = shortener
+                .shorten(&None, None, "example.com", options)
+                .await
+                .unwrap();
+            ids.push(result.id);
+        }
+
+        let mut statuses = vec![];
+        for id in &ids {
+            statuses.push(shortener.redirect(id).await.unwrap().status_code());
+        }
+        assert_eq!(vec![307, 301, 308, 301], statuses);
+        let redirect = shortener.redirect(&ids[3]).await.unwrap();
+        assert_eq!("no-store", redirect.cache_control());
+        assert!(shortener
+            .storage
+            .exists(&format!("REDIRECT_STATUS_{}", ids[3]))
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_shorten_unhappy_path_invalid_redirect_status() {
+        let storage = MemoryStorage::new();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let err = shortener
+            .shorten(
+                &None,
+                None,
+                "example.com",
+                &LinkOptions {
+                    redirect_status: Some(303),
+                    ..LinkOptions::default()
+                },
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            "Invalid redirect status: it must be 301, 302, 307 or 308",
+            err.to_string()
+        );
+
+        let err = shortener
+            .shorten(
+                &None,
+                None,
+                "example.com",
+                &LinkOptions {
+                    permanent: true,
+                    redirect_status: Some(307),
+                    ..LinkOptions::default()
+                },
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            "Invalid redirect status: permanent links redirect with 301 or 308",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_redirect_html() {
+        let redirect = Redirect {
+            url: String::from("http://example.com/?a=1&b=\"<script>"),
+            status: 302,
+            permanent: false,
+            preconnect: false,
+            verified: false,
+        };
+
+        let html = redirect.html(&Templates::new());
+        assert!(html.contains(r#"<a href="http://example.com/?a=1&amp;b=&quot;&lt;script&gt;">"#));
+        assert!(html.contains("url=http://example.com/?a=1&amp;b="));
+        assert!(!html.contains("{{url}}"));
+
+        assert!(Redirect::accepts_html(Some(
+            "text/html,application/xhtml+xml;q=0.9,*/*;q=0.8"
+        )));
+        assert!(Redirect::accepts_html(Some(
+            "application/json, TEXT/HTML;q=0.5"
+        )));
+        assert!(!Redirect::accepts_html(Some(
+            "text/html;q=0, application/json"
+        )));
+        assert!(!Redirect::accepts_html(Some("*/*")));
+        assert!(!Redirect::accepts_html(None));
+    }
+
+    #[tokio::test]
+    async fn test_shorten_happy_path_preconnect() {
+        let shortener = Shortener::new(
+            10,
+            vec!['a', 'b', 'c'],
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
+        );
+        let options = LinkOptions {
+            permanent: true,
+            preconnect: true,
+            ..LinkOptions::default()
+        };
+        let id = shortener
+            .shorten(&None, None, "https://example.com:8443/path?a=1", &options)
+            .await
+            .unwrap()
+            .id;
+
+        let redirect = shortener.redirect(&id).await.unwrap();
+        assert_eq!(
+            Some(String::from("<https://example.com:8443>; rel=preconnect")),
+            redirect.link_header()
+        );
+
+        let err = shortener
+            .shorten(
+                &None,
+                None,
+                "https://example.com/",
+                &LinkOptions {
+                    preconnect: true,
+                    ..LinkOptions::default()
+                },
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            "Invalid preconnect: only permanent links preconnect",
+            err.to_string()
+        );
+
+        shortener.purge(&id, None).await.unwrap();
+        assert!(!shortener.is_preconnecting(&id).await);
+    }
+
+    #[tokio::test]
+    async fn test_shorten_unhappy_path_expiring_permanent() {
+        let storage = MemoryStorage::new();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let shorten_result_err = shortener
+            .shorten(
+                &None,
+                Some("with.lv"),
+                "example.com",
+                &LinkOptions {
+                    expires_in: Some(600),
+                    permanent: true,
+                    ..LinkOptions::default()
+                },
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            "Invalid expiration: permanent links can't expire",
+            shorten_result_err.to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shorten_unhappy_path_invalid_expiration() {
+        let storage = MemoryStorage::new();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let shorten_result_err = shortener
+            .shorten(
+                &None,
+                Some("with.lv"),
+                "example.com",
+                &LinkOptions {
+                    expires_in: Some(0),
+                    ..LinkOptions::default()
+                },
+            )
+            .await
+            .err()
+            .unwrap();
+        assert!(shorten_result_err
+            .to_string()
+            .starts_with("Invalid expiration"));
     }
 
     #[test]
-    fn test_shorten_unhappy_path_invalid_api_key() {
-        let redis = StubRedisFacade::new();
+    fn test_expires_in() {
+        assert_eq!(None, expires_in(None, None).unwrap());
+        assert_eq!(Some(600), expires_in(Some(600), None).unwrap());
+
+        let expires_in_600 = expires_in(None, Some(now() + 600)).unwrap().unwrap();
+        assert!((599..=600).contains(&expires_in_600));
+
+        assert!(expires_in(None, Some(now() - 1)).is_err());
+        assert!(expires_in(Some(600), Some(now() + 600)).is_err());
+    }
 
-        // api key verification
-        &redis.get_bool_answers.borrow_mut().push(Ok(false));
+    #[tokio::test]
+    async fn test_shorten_unhappy_path_bad_url() {
+        let storage = MemoryStorage::new();
 
-        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, redis, 600, 10);
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, -1);
         let shorten_result_err = shortener
-            .shorten(&Some("api key"), Some("with.lv"), "example.com")
+            .shorten(
+                &None,
+                Some("with.lv"),
+                "wrong domain.com",
+                &LinkOptions::default(),
+            )
+            .await
             .err()
             .unwrap();
-        assert_eq!("Invalid API key", shorten_result_err.message);
+        assert!(matches!(
+            shorten_result_err,
+            ShortenerError::UnparsableUrl(_)
+        ));
+        assert_eq!(400, shorten_result_err.status_code());
     }
 
     #[test]
-    fn test_shorten_unhappy_path_too_many_attempts_generating_id() {
-        let redis = StubRedisFacade::new();
+    fn test_error_status_code() {
+        assert_eq!(403, ShortenerError::InvalidApiKey.status_code());
+        let rate_limited = ShortenerError::RateLimited { retry_after: 30 };
+        assert_eq!(429, rate_limited.status_code());
+        assert_eq!(Some(30), rate_limited.retry_after());
+        assert_eq!(None, ShortenerError::LinkLoop.retry_after());
+        assert_eq!(
+            409,
+            ShortenerError::Conflict("Custom ID already taken").status_code()
+        );
+        assert_eq!(500, ShortenerError::IdExhausted.status_code());
+
+        let cause = RedisError::from((ErrorKind::IoError, "connection refused"));
+        let err = ShortenerError::Storage(StorageError::Unavailable { attempts: 3, cause });
+        assert_eq!(503, err.status_code());
+        assert!(err
+            .to_string()
+            .starts_with("Storage unavailable - storage unavailable after 3 attempts"));
+    }
+
+    #[tokio::test]
+    async fn test_shorten_unhappy_path_scheme_not_allowed() {
+        let storage = MemoryStorage::new();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, -1);
+        for url in &[
+            "javascript:alert(document.cookie)",
+            "JavaScript://%0Aalert(1)",
+            "data:text/html,<script>alert(1)</script>",
+            "httpx://example.com",
+            "ftp://example.com",
+        ] {
+            let err = shortener
+                .shorten(&None, None, url, &LinkOptions::default())
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(
+                "Invalid URL: scheme not allowed",
+                err.to_string(),
+                "{}",
+                url
+            );
+        }
+
+        for (url, expected) in &[
+            ("HTTPS://example.com", "HTTPS://example.com"),
+            ("example.com:8080/path", "http://example.com:8080/path"),
+            ("localhost:8080", "http://localhost:8080"),
+        ] {
+            let result = shortener
+                .shorten(&None, None, url, &LinkOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(expected, &result.url);
+        }
+
+        let shortener = shortener.with_allowed_schemes(vec![String::from("FTP")]);
+        assert!(shortener
+            .shorten(&None, None, "ftp://example.com", &LinkOptions::default())
+            .await
+            .is_ok());
+        assert!(shortener
+            .shorten(&None, None, "https://example.com", &LinkOptions::default())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shorten_strict_urls() {
+        let storage = MemoryStorage::new();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, -1)
+            .with_assume_https(true);
+        let result = shortener
+            .shorten(&None, None, "example.com/path", &LinkOptions::default())
+            .await
+            .unwrap();
+        assert_eq!("https://example.com/path", result.url);
+
+        let shortener = shortener.with_strict_urls(true);
+        for (url, expected) in &[
+            ("example.com", "Invalid URL: missing scheme"),
+            ("localhost:8080", "Invalid URL: missing scheme"),
+            (" https://example.com", "Invalid URL: missing scheme"),
+            ("https://example.com ", "Invalid URL: malformed"),
+            ("http:example.com", "Invalid URL: malformed"),
+            ("https:/example.com", "Invalid URL: malformed"),
+            ("http:\\\\example.com", "Invalid URL: malformed"),
+            ("ftp://example.com", "Invalid URL: scheme not allowed"),
+        ] {
+            let err = shortener
+                .shorten(&None, None, url, &LinkOptions::default())
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(expected, &err.to_string(), "{}", url);
+        }
+
+        let result = shortener
+            .shorten(
+                &None,
+                None,
+                "HTTPS://example.com/a",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!("HTTPS://example.com/a", result.url);
+        let shortener = shortener.with_allowed_schemes(vec![String::from("mailto")]);
+        assert!(shortener
+            .shorten(
+                &None,
+                None,
+                "mailto:me@example.com",
+                &LinkOptions::default()
+            )
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_shorten_unhappy_path_same_domain() {
+        let storage = MemoryStorage::new();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, -1);
+        let shorten_result_err = shortener
+            .shorten(
+                &None,
+                Some("example.com"),
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Link loop is not allowed", shorten_result_err.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_shorten_happy_path_rate_limit_expired() {
+        let storage = storage_with_api_key().await;
+        storage.set("RATE_API_KEY_api key", "10").await.unwrap();
+        storage.expire("RATE_API_KEY_api key", 600).await.unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+
+        // rate limit period is over
+        shortener
+            .storage
+            .expire("RATE_API_KEY_api key", 0)
+            .await
+            .unwrap();
+
+        let shorten_result = shortener
+            .shorten(
+                &Some("api key"),
+                Some("with.lv"),
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(10, shorten_result.id.len());
+        assert_eq!("http://example.com", shorten_result.url);
+
+        let shorten_result = shortener
+            .shorten(
+                &Some("api key"),
+                Some("with.lv"),
+                "www.wikipedia.org",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(10, shorten_result.id.len());
+        assert_eq!("http://www.wikipedia.org", shorten_result.url);
+
+        assert_eq!(
+            "2",
+            shortener
+                .storage
+                .get_string("RATE_API_KEY_api key")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shorten_unhappy_path_invalid_api_key() {
+        let storage = MemoryStorage::new();
+        storage.set("API_KEY_api key", "false").await.unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let shorten_result_err = shortener
+            .shorten(
+                &Some("api key"),
+                Some("with.lv"),
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Invalid API key", shorten_result_err.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_verify_key() {
+        let storage = storage_with_api_key().await;
+        storage.set("API_KEY_expired key", "false").await.unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        for (api_key, valid) in &[
+            ("api key", true),
+            ("expired key", false),
+            ("missing key", false),
+        ] {
+            let key_info = shortener.verify_key(api_key).await;
+            assert_eq!(*api_key, key_info.key);
+            assert_eq!(*valid, key_info.valid);
+            assert_eq!(None, key_info.features);
+        }
+
+        assert!(!shortener
+            .storage
+            .exists("RATE_API_KEY_api key")
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_shorten_unhappy_path_missing_feature() {
+        let storage = storage_with_api_key().await;
+        storage
+            .set("FEATURES_API_KEY_api key", "batch")
+            .await
+            .unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let key_info = shortener.verify_key("api key").await;
+        assert_eq!(Some(vec![Feature::Batch]), key_info.features);
+        assert!(key_info.has(Feature::Batch));
+        assert!(!key_info.has(Feature::CustomIds));
+
+        shortener
+            .shorten(
+                &Some("api key"),
+                None,
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap();
+        let err = shortener
+            .shorten_with_id(
+                &Some("api key"),
+                None,
+                "abc",
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Feature not available with the API key", err.to_string());
+        let err = shortener
+            .create_campaign("api key", "spring", None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Feature not available with the API key", err.to_string());
+
+        // rejected calls are not counted
+        assert_eq!(
+            "1",
+            shortener
+                .storage
+                .get_string("RATE_API_KEY_api key")
+                .await
+                .unwrap()
+        );
+    }
 
-        // id generation attempts
-        &redis.exists_answers.borrow_mut().push(Ok(true));
-        &redis.exists_answers.borrow_mut().push(Ok(true));
+    #[tokio::test]
+    async fn test_shorten_unhappy_path_too_many_attempts_generating_id() {
+        // every possible ID is taken
+        let storage = MemoryStorage::new();
+        storage.set("a", "http://example.com").await.unwrap();
+        storage.set("b", "http://example.com").await.unwrap();
 
-        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 2, redis, 600, 10);
+        let shortener = Shortener::new(1, vec!['a', 'b'], 2, Box::new(storage), 600, 10);
         let shorten_result_err = shortener
-            .shorten(&None, Some("with.lv"), "example.com")
+            .shorten(
+                &None,
+                Some("with.lv"),
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
             .err()
             .unwrap();
         assert_eq!(
             "Failed to generate an ID: too many attempts. Consider using a longer ID",
-            shorten_result_err.message
+            shorten_result_err.to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shorten_with_id_happy_path() {
+        let storage = storage_with_api_key().await;
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let shorten_result = shortener
+            .shorten_with_id(
+                &Some("api key"),
+                Some("with.lv"),
+                "abc",
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!("abc", shorten_result.id);
+        assert_eq!("http://example.com", shorten_result.url);
+
+        assert_eq!("http://example.com", shortener.lookup("abc").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_shorten_with_id_happy_path_expiring() {
+        let storage = MemoryStorage::new();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let shorten_result = shortener
+            .shorten_with_id(
+                &None,
+                Some("with.lv"),
+                "abc",
+                "example.com",
+                &LinkOptions {
+                    expires_in: Some(600),
+                    ..LinkOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(Some(600), shorten_result.expires_in);
+
+        shortener.storage.expire("abc", 0).await.unwrap();
+        assert!(shortener.lookup("abc").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_shorten_with_id_unhappy_path_invalid_id() {
+        let storage = MemoryStorage::new();
+
+        let shortener = Shortener::new(4, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+
+        for id in &["", "abcab", "abd", "ab/c"] {
+            let shorten_result_err = shortener
+                .shorten_with_id(
+                    &None,
+                    Some("with.lv"),
+                    id,
+                    "example.com",
+                    &LinkOptions::default(),
+                )
+                .await
+                .err()
+                .unwrap();
+            assert!(shorten_result_err
+                .to_string()
+                .starts_with("Invalid custom ID"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shorten_with_id_unhappy_path_reserved_id() {
+        let alphabet = ('a'..='z').collect();
+        let shortener = Shortener::new(10, alphabet, 10, Box::new(MemoryStorage::new()), 600, 10);
+
+        for id in &["links", "healthz", "shorten", "admin"] {
+            let shorten_result_err = shortener
+                .shorten_with_id(&None, None, id, "example.com", &LinkOptions::default())
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(
+                "Invalid custom ID: it's reserved for a route of shorty",
+                shorten_result_err.to_string()
+            );
+        }
+        assert!(shortener.check_custom_id("linked").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_shorten_with_id_unhappy_path_taken() {
+        let storage = MemoryStorage::new();
+        storage.set("abc", "http://example.org").await.unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let shorten_result_err = shortener
+            .shorten_with_id(
+                &None,
+                Some("with.lv"),
+                "abc",
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Custom ID already taken", shorten_result_err.to_string());
+
+        assert_eq!("http://example.org", shortener.lookup("abc").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_shorten_with_id_unhappy_path_taken_since_checked() {
+        let storage = storage_with_api_key().await;
+        storage.set("API_KEY_other key", "true").await.unwrap();
+        let storage = RacingStorage {
+            storage,
+            hidden: "abc",
+        };
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10)
+            .with_phishing_screen(PhishingScreen::new(vec![(String::from("wallet"), 5)], 5, 0));
+        shortener
+            .shorten_with_id(
+                &Some("api key"),
+                None,
+                "abc",
+                "http://example.org/blog",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap();
+        shortener.lookup("abc").await.unwrap();
+        shortener.lookup("abc").await.unwrap();
+
+        let shorten_result_err = shortener
+            .shorten_with_id(
+                &Some("other key"),
+                None,
+                "abc",
+                "http://example.com/wallet",
+                &LinkOptions::default(),
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Custom ID already taken", shorten_result_err.to_string());
+
+        assert_eq!(
+            "http://example.org/blog",
+            shortener.lookup("abc").await.unwrap()
+        );
+        assert!(shortener.quarantined_links(10).await.unwrap().is_empty());
+        let events = shortener.link_events("api key", "abc").await.unwrap();
+        assert_eq!(
+            vec![LinkEventKind::Created, LinkEventKind::Clicks],
+            events.iter().map(|event| event.kind).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(3), events[1].clicks);
+    }
+
+    #[tokio::test]
+    async fn test_check_custom_id() {
+        let storage = MemoryStorage::new();
+        storage.set("abc", "http://example.org").await.unwrap();
+
+        let shortener = Shortener::new(4, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        assert!(shortener.check_custom_id("cba").await.is_ok());
+
+        let check_err = shortener.check_custom_id("abc").await.err().unwrap();
+        assert_eq!("Custom ID already taken", check_err.to_string());
+
+        let check_err = shortener.check_custom_id("abcab").await.err().unwrap();
+        assert!(check_err.to_string().starts_with("Invalid custom ID"));
+    }
+
+    #[tokio::test]
+    async fn test_shorten_deduplicated() {
+        let storage = storage_with_api_key().await;
+        storage.set("API_KEY_other key", "true").await.unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10)
+            .with_url_deduplication(true);
+        let shorten = |api_key, options| {
+            let shortener = &shortener;
+            async move {
+                shortener
+                    .shorten(&api_key, Some("with.lv"), "example.com", &options)
+                    .await
+                    .unwrap()
+                    .id
+            }
+        };
+
+        let id = shorten(Some("api key"), LinkOptions::default()).await;
+        assert_eq!(id, shorten(Some("api key"), LinkOptions::default()).await);
+
+        let permanent = LinkOptions {
+            permanent: true,
+            ..LinkOptions::default()
+        };
+        let expiring = LinkOptions {
+            expires_in: Some(600),
+            ..LinkOptions::default()
+        };
+        assert_ne!(id, shorten(Some("other key"), LinkOptions::default()).await);
+        assert_ne!(id, shorten(None, LinkOptions::default()).await);
+        assert_ne!(id, shorten(Some("api key"), permanent).await);
+        let expiring_id = shorten(Some("api key"), expiring.clone()).await;
+        assert_ne!(expiring_id, shorten(Some("api key"), expiring).await);
+
+        shortener.delete("api key", &id).await.unwrap();
+        assert_ne!(id, shorten(Some("api key"), LinkOptions::default()).await);
+    }
+
+    #[tokio::test]
+    async fn test_shorten_not_deduplicated_by_default() {
+        let storage = MemoryStorage::new();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let first = shortener
+            .shorten(&None, None, "example.com", &LinkOptions::default())
+            .await
+            .unwrap();
+        let second = shortener
+            .shorten(&None, None, "example.com", &LinkOptions::default())
+            .await
+            .unwrap();
+
+        assert_ne!(first.id, second.id);
+    }
+
+    #[tokio::test]
+    async fn test_delete_happy_path() {
+        let storage = storage_with_api_key().await;
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let id = shortener
+            .shorten(
+                &Some("api key"),
+                Some("with.lv"),
+                "example.com",
+                &LinkOptions {
+                    permanent: true,
+                    ..LinkOptions::default()
+                },
+            )
+            .await
+            .unwrap()
+            .id;
+
+        shortener.delete("api key", &id).await.unwrap();
+
+        assert!(shortener.lookup(&id).await.is_none());
+        for key in &["HITS", "CREATED", "PERMANENT", "OWNER"] {
+            let key = format!("{}_{}", key, id);
+            assert!(!shortener.storage.exists(&key).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update() {
+        let storage = storage_with_api_key().await;
+        storage.set("API_KEY_other key", "true").await.unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10)
+            .with_url_deduplication(true);
+        let options = LinkOptions {
+            expires_in: Some(600),
+            redirect_status: Some(307),
+            ..LinkOptions::default()
+        };
+        let id = shortener
+            .shorten(&Some("api key"), None, "example.com", &options)
+            .await
+            .unwrap()
+            .id;
+
+        let result = shortener
+            .update("api key", Some("with.lv"), &id, "https://example.org/moved")
+            .await
+            .unwrap();
+        assert_eq!("https://example.org/moved", result.url);
+        assert_eq!(Some(307), result.redirect_status);
+        assert!(result.expires_in.unwrap() <= 600);
+        let redirect = shortener.redirect(&id).await.unwrap();
+        assert_eq!("https://example.org/moved", redirect.url);
+        assert_eq!(307, redirect.status);
+        assert_eq!(1, shortener.stats(&id).await.unwrap().hits);
+
+        let events = shortener.link_events("api key", &id).await.unwrap();
+        assert_eq!(LinkEventKind::Updated, events[1].kind);
+        assert_eq!(Some("https://example.org/moved"), events[1].url.as_deref());
+
+        for (api_key, id, url, expected) in &[
+            (
+                "other key",
+                id.as_str(),
+                "example.net",
+                "Link not owned by the API key",
+            ),
+            ("api key", "missing", "example.net", "Link not found"),
+            (
+                "api key",
+                id.as_str(),
+                "javascript:alert(1)",
+                "Invalid URL: scheme not allowed",
+            ),
+            (
+                "api key",
+                id.as_str(),
+                "with.lv/abc",
+                "Link loop is not allowed",
+            ),
+        ] {
+            let err = shortener
+                .update(api_key, Some("with.lv"), id, url)
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(*expected, err.to_string());
+        }
+        assert_eq!(
+            "https://example.org/moved",
+            shortener.destination(&id).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_unhappy_path_not_owner() {
+        let storage = storage_with_api_key().await;
+        storage.set("API_KEY_other key", "true").await.unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let owned_id = shortener
+            .shorten(
+                &Some("api key"),
+                Some("with.lv"),
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap()
+            .id;
+        let anonymous_id = shortener
+            .shorten(
+                &None,
+                Some("with.lv"),
+                "example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap()
+            .id;
+
+        for id in &[owned_id, anonymous_id] {
+            let delete_err = shortener.delete("other key", id).await.err().unwrap();
+            assert_eq!("Link not owned by the API key", delete_err.to_string());
+            assert!(shortener.lookup(id).await.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_unhappy_path_not_found() {
+        let storage = storage_with_api_key().await;
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let delete_err = shortener.delete("api key", "missing").await.err().unwrap();
+        assert_eq!("Link not found", delete_err.to_string());
+
+        let delete_err = shortener
+            .delete("invalid key", "missing")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Invalid API key", delete_err.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_check_storage() {
+        let timeout = Duration::from_secs(1);
+        let shortener = Shortener::new(
+            10,
+            vec!['a', 'b', 'c'],
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
         );
+        let health = shortener.check_storage(timeout).await;
+        assert!(health.reachable);
+        assert_eq!(None, health.error);
+
+        let storage = ConfigStorage(None);
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let health = shortener.check_storage(timeout).await;
+        assert!(!health.reachable);
+        assert!(health.error.unwrap().contains("storage unavailable"));
+    }
+
+    #[tokio::test]
+    async fn test_check_eviction_policy_safe() {
+        let storage = ConfigStorage(Some(vec![String::from("noeviction")]));
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        assert!(shortener.check_eviction_policy().await);
+    }
+
+    #[tokio::test]
+    async fn test_check_eviction_policy_unsafe() {
+        let storage = ConfigStorage(Some(vec![
+            String::from("noeviction"),
+            String::from("allkeys-lru"),
+        ]));
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        assert!(!shortener.check_eviction_policy().await);
+    }
+
+    #[tokio::test]
+    async fn test_check_eviction_policy_unknown() {
+        let storage = ConfigStorage(None);
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        assert!(shortener.check_eviction_policy().await);
+    }
+
+    #[tokio::test]
+    async fn test_check_eviction_policy_memory_storage() {
+        let storage = MemoryStorage::new();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        assert!(shortener.check_eviction_policy().await);
     }
 }
@@ -0,0 +1,257 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! link_defaults holds `LinkDefaults`, the options an API key applies to every link it shortens
+//! unless the request sets its own, so that clients don't repeat them on every call.
+//!
+//! The defaults of an API key are stored as JSON in `DEFAULTS_API_KEY_<api key>`, expiring with
+//! the key.
+
+use crate::{is_permanent_status, required_features, LinkOptions, Shortener, ShortenerError};
+
+/// The default options of the links of an API key: see `LinkOptions` for their meaning.
+///
+/// Defaults never make a request invalid: permanent links don't get the default `expires_in`, as
+/// they can't expire, nor a default `redirect_status` that isn't permanent.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct LinkDefaults {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect_status: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub campaign: Option<String>,
+}
+
+impl LinkDefaults {
+    /// Returns `options`, with the defaults applied to the options it doesn't set
+    pub fn apply(&self, options: &LinkOptions) -> LinkOptions {
+        let mut options = options.clone();
+        if options.expires_in.is_none() && !options.permanent {
+            options.expires_in = self.expires_in;
+        }
+        if options.redirect_status.is_none() {
+            options.redirect_status = self
+                .redirect_status
+                .filter(|status| !options.permanent || is_permanent_status(*status));
+        }
+        if options.campaign.is_none() {
+            options.campaign = self.campaign.clone();
+        }
+        options
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == LinkDefaults::default()
+    }
+
+    /// The options of a link using the defaults only
+    fn options(&self) -> LinkOptions {
+        self.apply(&LinkOptions::default())
+    }
+}
+
+impl Shortener {
+    /// Returns the defaults of the links of an API key, empty if it has none
+    pub async fn link_defaults(&self, api_key: &str) -> Result<LinkDefaults, ShortenerError> {
+        self.verify_api_key(api_key, &[]).await?;
+        Ok(self.stored_defaults(api_key).await)
+    }
+
+    /// Replaces the defaults of the links of an API key, removing them if `defaults` is empty. The
+    /// defaults are checked as the options of a link would be, so a default campaign must be
+    /// owned by the API key, and API keys restricted to some features need `Feature::Campaigns` for
+    /// it.
+    pub async fn set_link_defaults(
+        &self,
+        api_key: &str,
+        defaults: &LinkDefaults,
+    ) -> Result<(), ShortenerError> {
+        let options = defaults.options();
+        self.verify_api_key(api_key, &required_features(&options))
+            .await?;
+
+        let key = defaults_key(api_key);
+        if defaults.is_empty() {
+            return self
+                .storage
+                .delete(&key)
+                .await
+                .map(|_| ())
+                .map_err(ShortenerError::Storage);
+        }
+        self.validate_options(&Some(api_key), &options).await?;
+
+        let json = serde_json::to_string(defaults).unwrap();
+        let ttl = self
+            .storage
+            .ttl(&format!("API_KEY_{}", api_key))
+            .await
+            .map_err(ShortenerError::Storage)?;
+        match ttl {
+            Some(ttl) => self.storage.set_expiring(&key, &json, ttl as usize).await,
+            None => self.storage.set(&key, &json).await,
+        }
+        .map_err(ShortenerError::Storage)
+    }
+
+    /// Returns `options` with the defaults of the API key, if any, applied, expiring no later than
+    /// their campaign, if it was given an expiry: see `Shortener::expire_campaign`
+    pub(crate) async fn apply_defaults(
+        &self,
+        api_key: &Option<&str>,
+        options: &LinkOptions,
+    ) -> LinkOptions {
+        let mut options = match api_key {
+            Some(api_key) => self.stored_defaults(api_key).await.apply(options),
+            None => options.clone(),
+        };
+
+        if let Some(campaign) = &options.campaign {
+            if let Some(campaign_expires_in) = self.campaign_expires_in(campaign).await {
+                options.expires_in = Some(
+                    options
+                        .expires_in
+                        .map_or(campaign_expires_in, |expires_in| {
+                            expires_in.min(campaign_expires_in)
+                        }),
+                );
+            }
+        }
+
+        options
+    }
+
+    /// Reads the defaults of an API key. Unreadable defaults are logged and ignored, so that they
+    /// don't fail shortening.
+    async fn stored_defaults(&self, api_key: &str) -> LinkDefaults {
+        let json = match self.storage.get_string(&defaults_key(api_key)).await {
+            Ok(json) => json,
+            Err(_) => return LinkDefaults::default(),
+        };
+        serde_json::from_str(&json).unwrap_or_else(|err| {
+            log::warn!("skipping unreadable link defaults: {}", err);
+            LinkDefaults::default()
+        })
+    }
+}
+
+/// The key storing the link defaults of an API key
+pub(crate) fn defaults_key(api_key: &str) -> String {
+    format!("DEFAULTS_API_KEY_{}", api_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::storage::Storage;
+
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let defaults = LinkDefaults {
+            expires_in: Some(600),
+            redirect_status: Some(307),
+            campaign: Some(String::from("spring")),
+        };
+
+        let options = defaults.apply(&LinkOptions::default());
+        assert_eq!(Some(600), options.expires_in);
+        assert_eq!(Some(307), options.redirect_status);
+        assert_eq!(Some(String::from("spring")), options.campaign);
+
+        let requested = LinkOptions {
+            expires_in: Some(60),
+            redirect_status: Some(302),
+            ..LinkOptions::default()
+        };
+        let options = defaults.apply(&requested);
+        assert_eq!(Some(60), options.expires_in);
+        assert_eq!(Some(302), options.redirect_status);
+
+        // permanent links neither expire nor redirect temporarily
+        let permanent = LinkOptions {
+            permanent: true,
+            ..LinkOptions::default()
+        };
+        let options = defaults.apply(&permanent);
+        assert_eq!(None, options.expires_in);
+        assert_eq!(None, options.redirect_status);
+    }
+
+    #[tokio::test]
+    async fn test_set_link_defaults() {
+        let storage = MemoryStorage::new();
+        storage.set("API_KEY_api key", "true").await.unwrap();
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, -1);
+
+        let defaults = LinkDefaults {
+            expires_in: Some(600),
+            redirect_status: Some(307),
+            campaign: None,
+        };
+        shortener
+            .set_link_defaults("api key", &defaults)
+            .await
+            .unwrap();
+        assert_eq!(defaults, shortener.link_defaults("api key").await.unwrap());
+
+        let result = shortener
+            .shorten(
+                &Some("api key"),
+                None,
+                "http://example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(Some(600), result.expires_in);
+        assert_eq!(Some(307), result.redirect_status);
+        // links shortened without the API key don't get its defaults
+        let result = shortener
+            .shorten(&None, None, "http://example.com", &LinkOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(None, result.expires_in);
+
+        let invalid = LinkDefaults {
+            redirect_status: Some(200),
+            ..LinkDefaults::default()
+        };
+        assert!(shortener
+            .set_link_defaults("api key", &invalid)
+            .await
+            .is_err());
+        let unowned = LinkDefaults {
+            campaign: Some(String::from("spring")),
+            ..LinkDefaults::default()
+        };
+        assert!(shortener
+            .set_link_defaults("api key", &unowned)
+            .await
+            .is_err());
+        assert!(shortener
+            .set_link_defaults("wrong key", &defaults)
+            .await
+            .is_err());
+
+        shortener
+            .set_link_defaults("api key", &LinkDefaults::default())
+            .await
+            .unwrap();
+        assert!(shortener.link_defaults("api key").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,489 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! link_events holds the change log of each link, an append-only feed of its lifecycle events, so
+//! that owners get a timeline of their links without separate analytics tooling.
+//!
+//! Events are appended to the sorted set `EVENTS_<id>`, scored by the microsecond they were
+//! recorded at. Two kinds of events are not stored as such:
+//! - clicks are counted per day in `CLICKS_<id>_<day>`, days being counted from the Unix epoch in
+//!   the time zone of the shortener (see `Shortener::with_time_zone`), and rolled up into one
+//!   event per day when the feed is read
+//! - expiration is derived from the expiration time of the `created` event, as expired keys just
+//!   vanish
+//!
+//! Daily clicks are kept for `RETENTION_DAYS`, and so are the events of a link once it expired.
+//! Events of links shortened before the feed existed are missing, but their clicks are not. A new
+//! link reusing the ID of an expired or deleted one starts with an empty feed.
+
+use jiff::civil::Date;
+use jiff::{Span, Timestamp};
+
+use crate::api_key_manager::Feature;
+use crate::link_sync::micros;
+use crate::storage::StorageResult;
+use crate::{now, Shortener, ShortenerError};
+
+/// How many days clicks are rolled up for, and events are kept once their link expired
+pub const RETENTION_DAYS: u64 = 30;
+
+const DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkEventKind {
+    Created,
+    Updated,
+    Disabled,
+    Enabled,
+    Expired,
+    Clicks,
+}
+
+/// An event of the lifecycle of a link, happened `at` a Unix timestamp.
+///
+/// `created` events tell when the link expires, if ever, and `updated` events the new `url` of the
+/// link, see `Shortener::update`. `disabled` and `enabled` events tell the
+/// `reason`: `quarantine`, `takedown`, or the name of a paused or resumed campaign. `clicks`
+/// events count the `clicks` of a day in the time zone of the shortener, happening at its last
+/// second, or now for the current day, with the `click_sampling` of the shortener: clicks are
+/// estimates when it's above 1, see `Shortener::with_click_sampling`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LinkEvent {
+    pub kind: LinkEventKind,
+    pub at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clicks: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub click_sampling: Option<u32>,
+}
+
+impl LinkEvent {
+    fn new(kind: LinkEventKind, at: u64) -> LinkEvent {
+        LinkEvent {
+            kind,
+            at,
+            expires_at: None,
+            url: None,
+            reason: None,
+            clicks: None,
+            click_sampling: None,
+        }
+    }
+}
+
+/// An event as stored, with the microsecond it was `recorded_at`, which also tells apart events
+/// of the same second: identical members would be stored once. `created` events also store the
+/// `owner` of the link, so that the feed can still be read once the link expired.
+#[derive(Serialize, Deserialize)]
+struct StoredEvent {
+    #[serde(flatten)]
+    event: LinkEvent,
+    recorded_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    owner: Option<String>,
+}
+
+impl Shortener {
+    /// Returns the lifecycle events of the link with the given ID, oldest first. Only the API key
+    /// that shortened the link can read them, also once it expired, and API keys restricted to
+    /// some features need `Feature::Analytics`.
+    pub async fn link_events(
+        &self,
+        api_key: &str,
+        id: &str,
+    ) -> Result<Vec<LinkEvent>, ShortenerError> {
+        self.verify_api_key(api_key, &[Feature::Analytics]).await?;
+
+        let stored = self.stored_events(id).await?;
+        let owner = match self.storage.get_string(&format!("OWNER_{}", id)).await {
+            Ok(owner) => Some(owner),
+            Err(_) => stored.iter().rev().find_map(|stored| stored.owner.clone()),
+        };
+        match owner {
+            Some(owner) if owner == api_key => {}
+            Some(_) => return Err(ShortenerError::Forbidden("Link not owned by the API key")),
+            None => return Err(ShortenerError::NotFound("Link not found")),
+        }
+
+        let now = now();
+        let mut events = stored
+            .into_iter()
+            .map(|stored| stored.event)
+            .collect::<Vec<_>>();
+        let expires_at = events
+            .iter()
+            .find(|event| event.kind == LinkEventKind::Created)
+            .and_then(|event| event.expires_at);
+        if let Some(expires_at) = expires_at.filter(|expires_at| *expires_at <= now) {
+            events.push(LinkEvent::new(LinkEventKind::Expired, expires_at));
+        }
+
+        let today = self.day_of(now);
+        for day in today + 1 - RETENTION_DAYS..=today {
+            let clicks = self
+                .storage
+                .get_string(&clicks_key(id, day))
+                .await
+                .ok()
+                .and_then(|clicks| clicks.parse().ok())
+                .unwrap_or(0);
+            if clicks > 0 {
+                events.push(LinkEvent {
+                    clicks: Some(clicks),
+                    click_sampling: Some(self.click_sampling),
+                    ..LinkEvent::new(LinkEventKind::Clicks, self.last_second_of(day).min(now))
+                });
+            }
+        }
+        // stored events come first within a second, in the order they were recorded
+        events.sort_by_key(|event| event.at);
+
+        Ok(events)
+    }
+
+    /// Returns when the link with the given ID expired, if it did less than `RETENTION_DAYS` ago.
+    /// Deleted links, and links that never expire, have no expiration time.
+    pub(crate) async fn expired_at(&self, id: &str) -> Option<u64> {
+        let expires_at = self
+            .stored_events(id)
+            .await
+            .ok()?
+            .into_iter()
+            .find(|stored| stored.event.kind == LinkEventKind::Created)?
+            .event
+            .expires_at?;
+        Some(expires_at).filter(|expires_at| *expires_at <= now())
+    }
+
+    /// Returns the stored events of a link, oldest first
+    async fn stored_events(&self, id: &str) -> Result<Vec<StoredEvent>, ShortenerError> {
+        let mut stored = self
+            .storage
+            .scored_members(&events_key(id), 0, u64::MAX, usize::MAX)
+            .await
+            .map_err(ShortenerError::Storage)?
+            .into_iter()
+            .filter_map(
+                |(member, _)| match serde_json::from_str::<StoredEvent>(&member) {
+                    Ok(stored) => Some(stored),
+                    Err(err) => {
+                        log::warn!("skipping unreadable event of '{}': {}", id, err);
+                        None
+                    }
+                },
+            )
+            .collect::<Vec<_>>();
+        stored.sort_by_key(|stored| stored.recorded_at);
+        Ok(stored)
+    }
+
+    /// Records the creation of a link, owned by `api_key` if any, and makes its events expire
+    /// `RETENTION_DAYS` after the link. The events and clicks of a previous link with the same ID,
+    /// expired less than `RETENTION_DAYS` ago, are forgotten first.
+    pub(crate) async fn record_created(
+        &self,
+        id: &str,
+        api_key: &Option<&str>,
+        expires_in: Option<usize>,
+    ) {
+        // clicks expire before the events of their link, sparing a scan for new IDs
+        match self.storage.delete(&events_key(id)).await {
+            Ok(true) => {
+                if let Err(err) = self.forget_clicks(id).await {
+                    log::warn!("unable to forget clicks of the previous '{}': {}", id, err);
+                }
+            }
+            Ok(false) => {}
+            Err(err) => log::warn!("unable to forget events of the previous '{}': {}", id, err),
+        }
+
+        let now = now();
+        let event = LinkEvent {
+            expires_at: expires_in.map(|expires_in| now + expires_in as u64),
+            ..LinkEvent::new(LinkEventKind::Created, now)
+        };
+        self.record(id, event, api_key.map(str::to_owned)).await;
+
+        if let Some(expires_in) = expires_in {
+            let period = expires_in + (RETENTION_DAYS * DAY) as usize;
+            if let Err(err) = self.storage.expire(&events_key(id), period).await {
+                log::warn!("unable to expire events of '{}': {}", id, err);
+            }
+        }
+    }
+
+    /// Records that the URL of a link was changed to `url`
+    pub(crate) async fn record_updated(&self, id: &str, url: &str) {
+        let event = LinkEvent {
+            url: Some(url.to_owned()),
+            ..LinkEvent::new(LinkEventKind::Updated, now())
+        };
+        self.record(id, event, None).await;
+    }
+
+    /// Records that a link was disabled or enabled for the given `reason`
+    pub(crate) async fn record_toggled(&self, id: &str, enabled: bool, reason: &str) {
+        let kind = if enabled {
+            LinkEventKind::Enabled
+        } else {
+            LinkEventKind::Disabled
+        };
+        let event = LinkEvent {
+            reason: Some(reason.to_owned()),
+            ..LinkEvent::new(kind, now())
+        };
+        self.record(id, event, None).await;
+    }
+
+    /// Counts `clicks` clicks of a link in the clicks of the day, unless built without the
+    /// `analytics` feature
+    pub(crate) async fn record_clicks(&self, id: &str, clicks: i64) {
+        if !cfg!(feature = "analytics") {
+            return;
+        }
+        let key = clicks_key(id, self.day_of(now()));
+        let period = (RETENTION_DAYS * DAY) as usize;
+        if let Err(err) = self
+            .storage
+            .increment_expiring_by(&key, clicks, period)
+            .await
+        {
+            log::warn!("unable to count click of '{}': {}", id, err);
+        }
+    }
+
+    /// Deletes the daily clicks of a link. Keys are found with a scan, so that clicks of days
+    /// before the retention period, not expired yet, go as well.
+    pub(crate) async fn forget_clicks(&self, id: &str) -> StorageResult<()> {
+        let prefix = format!("CLICKS_{}_", id);
+        for key in self.storage.scan(&prefix).await? {
+            // the clicks of `<id>_<suffix>` share the prefix
+            if key[prefix.len()..]
+                .bytes()
+                .all(|byte| byte.is_ascii_digit())
+            {
+                self.storage.delete(&key).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the day of the Unix timestamp `at` in the time zone of the shortener, counted from
+    /// the Unix epoch
+    fn day_of(&self, at: u64) -> u64 {
+        let offset = Timestamp::from_second(at as i64)
+            .map_or(0, |timestamp| self.time_zone.to_offset(timestamp).seconds());
+        (at as i64 + offset as i64).div_euclid(DAY as i64).max(0) as u64
+    }
+
+    /// Returns the Unix timestamp of the last second of `day`, as counted by `day_of`
+    fn last_second_of(&self, day: u64) -> u64 {
+        Date::constant(1970, 1, 2)
+            .checked_add(Span::new().days(day as i64))
+            .and_then(|next_day| next_day.to_zoned(self.time_zone.clone()))
+            .map_or((day + 1) * DAY - 1, |next_day| {
+                next_day.timestamp().as_second() as u64 - 1
+            })
+    }
+
+    /// Appends an event to the feed of a link. Failing to do so is logged, without failing the
+    /// change the event tells about.
+    async fn record(&self, id: &str, event: LinkEvent, owner: Option<String>) {
+        let recorded_at = micros();
+        let stored = StoredEvent {
+            event,
+            recorded_at,
+            owner,
+        };
+        let member = serde_json::to_string(&stored).unwrap();
+
+        if let Err(err) = self
+            .storage
+            .add_scored_member(&events_key(id), &member, recorded_at)
+            .await
+        {
+            log::warn!("unable to record event of '{}': {}", id, err);
+        }
+        self.record_change(id, recorded_at).await;
+    }
+}
+
+pub(crate) fn events_key(id: &str) -> String {
+    format!("EVENTS_{}", id)
+}
+
+fn clicks_key(id: &str, day: u64) -> String {
+    format!("CLICKS_{}_{}", id, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use jiff::tz::{offset, TimeZone};
+
+    use crate::memory_storage::MemoryStorage;
+    use crate::storage::Storage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    fn kinds(events: &[LinkEvent]) -> Vec<LinkEventKind> {
+        events.iter().map(|event| event.kind).collect()
+    }
+
+    #[tokio::test]
+    async fn test_link_events() {
+        let storage = MemoryStorage::new();
+        storage.set("API_KEY_api key", "true").await.unwrap();
+        storage.set("API_KEY_other key", "true").await.unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        shortener
+            .shorten_with_id(
+                &Some("api key"),
+                None,
+                "abc",
+                "example.com",
+                &LinkOptions {
+                    expires_in: Some(3600),
+                    ..LinkOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+        shortener.lookup("abc").await.unwrap();
+        shortener.lookup("abc").await.unwrap();
+        shortener.record_toggled("abc", false, "quarantine").await;
+        shortener.record_toggled("abc", true, "quarantine").await;
+
+        let events = shortener.link_events("api key", "abc").await.unwrap();
+        assert_eq!(
+            vec![
+                LinkEventKind::Created,
+                LinkEventKind::Disabled,
+                LinkEventKind::Enabled,
+                LinkEventKind::Clicks,
+            ],
+            kinds(&events)
+        );
+        assert_eq!(Some(events[0].at + 3600), events[0].expires_at);
+        assert_eq!(Some(String::from("quarantine")), events[1].reason);
+        assert_eq!(Some(2), events[3].clicks);
+        assert_eq!(Some(1), events[3].click_sampling);
+        assert_eq!(now() / DAY, events[3].at / DAY);
+
+        // the feed outlives the link, telling when it expired
+        shortener
+            .storage
+            .add_scored_member(
+                "EVENTS_gone",
+                r#"{"kind":"created","at":1000,"expires_at":2000,"recorded_at":1000000000,"owner":"api key"}"#,
+                1000000000,
+            )
+            .await
+            .unwrap();
+        let events = shortener.link_events("api key", "gone").await.unwrap();
+        assert_eq!(
+            vec![LinkEventKind::Created, LinkEventKind::Expired],
+            kinds(&events)
+        );
+        assert_eq!(2000, events[1].at);
+
+        let err = shortener
+            .link_events("other key", "abc")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Link not owned by the API key", err.to_string());
+        let err = shortener
+            .link_events("api key", "missing")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Link not found", err.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_reused_id() {
+        let storage = MemoryStorage::new();
+        storage.set("API_KEY_api key", "true").await.unwrap();
+        storage.set("API_KEY_other key", "true").await.unwrap();
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        let options = LinkOptions {
+            expires_in: Some(3600),
+            ..LinkOptions::default()
+        };
+
+        for api_key in &["api key", "other key"] {
+            shortener
+                .shorten_with_id(&Some(api_key), None, "abc", "example.com", &options)
+                .await
+                .unwrap();
+            shortener.lookup("abc").await.unwrap();
+            shortener.record_toggled("abc", false, "quarantine").await;
+            // as if the link expired
+            shortener.storage.delete("abc").await.unwrap();
+            shortener.storage.delete("OWNER_abc").await.unwrap();
+        }
+        shortener.storage.set("CLICKS_abc_x_1", "1").await.unwrap();
+
+        let events = shortener.link_events("other key", "abc").await.unwrap();
+        assert_eq!(
+            vec![
+                LinkEventKind::Created,
+                LinkEventKind::Disabled,
+                LinkEventKind::Clicks,
+            ],
+            kinds(&events)
+        );
+        assert_eq!(Some(1), events[2].clicks);
+        let err = shortener.link_events("api key", "abc").await.err().unwrap();
+        assert_eq!("Link not owned by the API key", err.to_string());
+
+        shortener.purge("abc", None).await.unwrap();
+        assert_eq!(
+            vec![String::from("CLICKS_abc_x_1")],
+            shortener.storage.scan("CLICKS_").await.unwrap()
+        );
+    }
+
+    #[test]
+    fn test_days_in_time_zone() {
+        let shortener = Shortener::new(10, vec!['a'], 10, Box::new(MemoryStorage::new()), 600, 10);
+        assert_eq!(0, shortener.day_of(DAY - 1));
+        assert_eq!(1, shortener.day_of(DAY));
+        assert_eq!(DAY - 1, shortener.last_second_of(0));
+
+        let shortener = shortener.with_time_zone(TimeZone::fixed(offset(14)));
+        // 1970-01-02 at 00:00 in UTC+14 is 1970-01-01 at 10:00 in UTC
+        assert_eq!(0, shortener.day_of(10 * 60 * 60 - 1));
+        assert_eq!(1, shortener.day_of(10 * 60 * 60));
+        assert_eq!(10 * 60 * 60 - 1, shortener.last_second_of(0));
+
+        let shortener = shortener.with_time_zone(TimeZone::get("Europe/Rome").unwrap());
+        // 2024-03-31 is 23 hours long in Rome, from 2024-03-30 at 23:00 in UTC
+        let day = 19_813;
+        assert_eq!(day, shortener.day_of(1_711_839_600));
+        assert_eq!(day - 1, shortener.day_of(1_711_839_599));
+        assert_eq!(
+            1_711_839_600 + 23 * 60 * 60 - 1,
+            shortener.last_second_of(day)
+        );
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! link_health holds `LinkHealth`, the state of a link as shown to anyone it's shared with, such
+//! as on the badges frontends render for READMEs and wikis. Unlike `Shortener::stats`, it tells
+//! expired links apart from missing ones, for as long as their events are kept: see
+//! `link_events`, and disabled links apart from missing ones.
+
+use crate::{Shortener, CLAIMED_ID};
+
+/// Whether a link redirects: `Disabled` links exist but are not found, being quarantined or in a
+/// paused campaign
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkStatus {
+    Active,
+    Disabled,
+    Expired,
+}
+
+/// The `status` of a link, and its `clicks`, unknown once the link expired
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LinkHealth {
+    pub status: LinkStatus,
+    pub clicks: Option<i64>,
+}
+
+impl Shortener {
+    /// Returns the health of the link with the given ID, or `None` if it doesn't exist, has been
+    /// deleted, or expired more than `RETENTION_DAYS` ago
+    pub async fn link_health(&self, id: &str) -> Option<LinkHealth> {
+        let exists = self
+            .storage
+            .get_string(id)
+            .await
+            .is_ok_and(|url| url != CLAIMED_ID);
+        if !exists {
+            self.expired_at(id).await?;
+            return Some(LinkHealth {
+                status: LinkStatus::Expired,
+                clicks: None,
+            });
+        }
+
+        let status = if self.destination(id).await.is_some() {
+            LinkStatus::Active
+        } else {
+            LinkStatus::Disabled
+        };
+        Some(LinkHealth {
+            status,
+            clicks: Some(self.hits(id).await),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::storage::Storage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_link_health() {
+        let storage = MemoryStorage::new();
+        storage.set("API_KEY_api key", "true").await.unwrap();
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+
+        let id = shortener
+            .shorten(
+                &Some("api key"),
+                None,
+                "http://example.com",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap()
+            .id;
+        shortener.lookup(&id).await.unwrap();
+        assert_eq!(
+            Some(LinkHealth {
+                status: LinkStatus::Active,
+                clicks: Some(1),
+            }),
+            shortener.link_health(&id).await
+        );
+
+        shortener
+            .quarantine(&id, 0, None, "quarantine")
+            .await
+            .unwrap();
+        assert_eq!(
+            LinkStatus::Disabled,
+            shortener.link_health(&id).await.unwrap().status
+        );
+
+        // the link vanished, but its events tell it expired
+        shortener
+            .storage
+            .add_scored_member(
+                "EVENTS_gone",
+                r#"{"kind":"created","at":1000,"expires_at":2000,"recorded_at":1000000000}"#,
+                1000000000,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(LinkHealth {
+                status: LinkStatus::Expired,
+                clicks: None,
+            }),
+            shortener.link_health("gone").await
+        );
+
+        assert_eq!(None, shortener.link_health("missing").await);
+    }
+}
@@ -0,0 +1,350 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! link_index holds the index of the links shortened with each API key, so that clients such as
+//! no-code platforms can poll them, newest first, and users can page through all their links.
+//!
+//! The index of an API key is the sorted set `LINKS_<api key>`, scoring each link ID with its
+//! creation time. Expired links are removed from the index when they are found missing.
+
+use crate::{now, Shortener, ShortenerError};
+
+/// The max number of links returned by `Shortener::links`
+pub const MAX_LINKS: usize = 100;
+
+/// A link shortened with an API key, with its creation time
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LinkSummary {
+    pub id: String,
+    pub url: String,
+    pub created_at: u64,
+}
+
+/// A page of the links shortened with an API key, see `Shortener::list_by_api_key`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LinkPage {
+    pub links: Vec<LinkSummary>,
+    pub next_cursor: Option<String>,
+}
+
+impl Shortener {
+    /// Returns the links shortened with `api_key` and created since `since` (a Unix timestamp,
+    /// included), at most `limit` and never more than `MAX_LINKS`.
+    ///
+    /// Links are sorted newest first. Links created in the same second are sorted by descending
+    /// ID, so that repeated calls return the same order. Expired links are skipped, so fewer than
+    /// `limit` links may be returned even if more exist.
+    ///
+    /// Listing links doesn't count against the rate limit of the API key.
+    pub async fn links(
+        &self,
+        api_key: &str,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<LinkSummary>, ShortenerError> {
+        if !self.verify_key(api_key).await.valid {
+            return Err(ShortenerError::InvalidApiKey);
+        }
+
+        let index = index_key(api_key);
+        let entries = self
+            .storage
+            .scored_members(&index, since, u64::MAX, limit.min(MAX_LINKS))
+            .await
+            .map_err(ShortenerError::Storage)?;
+
+        self.summaries(&index, entries).await
+    }
+
+    /// Returns a page of the links shortened with `api_key`, newest first as in `links`, with at
+    /// most `limit` links and never more than `MAX_LINKS`.
+    ///
+    /// The first page is read without a `cursor`, the following ones with the `next_cursor` of
+    /// the previous page, until it is `None`. Cursors point to the last link of a page, so that
+    /// links created meanwhile don't shift the pages: they are found on the first page again.
+    /// Expired links are skipped, so a page may hold fewer than `limit` links, or none, and still
+    /// have a next one.
+    ///
+    /// Listing links doesn't count against the rate limit of the API key.
+    pub async fn list_by_api_key(
+        &self,
+        api_key: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<LinkPage, ShortenerError> {
+        if !self.verify_key(api_key).await.valid {
+            return Err(ShortenerError::InvalidApiKey);
+        }
+
+        let index = index_key(api_key);
+        let (entries, next_cursor) = self.page(&index, cursor, limit).await?;
+        Ok(LinkPage {
+            links: self.summaries(&index, entries).await?,
+            next_cursor,
+        })
+    }
+
+    /// Reads a page of at most `limit` entries of an index, never more than `MAX_LINKS`, newest
+    /// first, following `cursor` as in `list_by_api_key`. Returns the entries with the cursor of
+    /// the next page, if any.
+    pub(crate) async fn page(
+        &self,
+        index: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<(String, u64)>, Option<String>), ShortenerError> {
+        let cursor = match cursor {
+            Some(cursor) => Some(parse_cursor(cursor)?),
+            None => None,
+        };
+
+        let limit = limit.min(MAX_LINKS);
+        let mut entries = Vec::with_capacity(limit);
+        let mut max_score = Some(u64::MAX);
+        if let Some((created_at, id)) = cursor {
+            // links created in the same second as the last one of the previous page, such as
+            // those of a batch, are told apart by ID
+            let ties = self
+                .storage
+                .scored_members(index, created_at, created_at, usize::MAX)
+                .await
+                .map_err(ShortenerError::Storage)?;
+            entries.extend(
+                ties.into_iter()
+                    .filter(|(tie, _)| tie.as_str() < id)
+                    .take(limit),
+            );
+            max_score = created_at.checked_sub(1);
+        }
+        if let Some(max_score) = max_score.filter(|_| entries.len() < limit) {
+            let older = self
+                .storage
+                .scored_members(index, 0, max_score, limit - entries.len())
+                .await
+                .map_err(ShortenerError::Storage)?;
+            entries.extend(older);
+        }
+
+        let next_cursor = match entries.last() {
+            Some((id, created_at)) if entries.len() == limit => {
+                Some(format!("{}-{}", created_at, id))
+            }
+            _ => None,
+        };
+        Ok((entries, next_cursor))
+    }
+
+    /// Reads the URLs of the links of an index, removing the expired ones from it
+    pub(crate) async fn summaries(
+        &self,
+        index: &str,
+        entries: Vec<(String, u64)>,
+    ) -> Result<Vec<LinkSummary>, ShortenerError> {
+        let mut links = Vec::with_capacity(entries.len());
+        for (id, created_at) in entries {
+            match self.storage.get_string(&id).await {
+                Ok(url) => links.push(LinkSummary {
+                    id,
+                    url,
+                    created_at,
+                }),
+                Err(_) => {
+                    self.storage
+                        .remove_scored_member(index, &id)
+                        .await
+                        .map_err(ShortenerError::Storage)?;
+                }
+            }
+        }
+
+        Ok(links)
+    }
+
+    /// Adds a new link to the index of the API key it was shortened with
+    pub(crate) async fn index_link(&self, api_key: &str, id: &str) -> Result<(), ShortenerError> {
+        self.storage
+            .add_scored_member(&index_key(api_key), id, now())
+            .await
+            .map_err(ShortenerError::Storage)?;
+        Ok(())
+    }
+
+    /// Removes a deleted link from the index of its API key
+    pub(crate) async fn unindex_link(&self, api_key: &str, id: &str) -> Result<(), ShortenerError> {
+        self.storage
+            .remove_scored_member(&index_key(api_key), id)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        Ok(())
+    }
+}
+
+fn index_key(api_key: &str) -> String {
+    format!("LINKS_{}", api_key)
+}
+
+/// Parses a cursor of `Shortener::list_by_api_key`: the creation time and ID of a link, split by
+/// the first `-`
+fn parse_cursor(cursor: &str) -> Result<(u64, &str), ShortenerError> {
+    cursor
+        .split_once('-')
+        .and_then(|(created_at, id)| Some((created_at.parse().ok()?, id)))
+        .ok_or_else(|| ShortenerError::InvalidInput("Invalid cursor"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::storage::Storage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_links() {
+        let storage = MemoryStorage::new();
+        storage.set("API_KEY_api key", "true").await.unwrap();
+        storage.set("API_KEY_other key", "true").await.unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        for id in &["a", "b", "c"] {
+            shortener
+                .shorten_with_id(
+                    &Some("api key"),
+                    None,
+                    id,
+                    "example.com",
+                    &LinkOptions::default(),
+                )
+                .await
+                .unwrap();
+        }
+        shortener
+            .storage
+            .add_scored_member("LINKS_api key", "a", 1000)
+            .await
+            .unwrap();
+
+        let links = shortener.links("api key", 0, 10).await.unwrap();
+        let ids = links
+            .iter()
+            .map(|link| link.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["c", "b", "a"], ids);
+        assert_eq!("http://example.com", links[0].url);
+        assert_eq!(1000, links[2].created_at);
+
+        let links = shortener.links("api key", 2000, 1).await.unwrap();
+        assert_eq!(1, links.len());
+        assert_eq!("c", links[0].id);
+
+        shortener.delete("api key", "c").await.unwrap();
+        shortener.storage.expire("b", 0).await.unwrap();
+        let links = shortener.links("api key", 0, 10).await.unwrap();
+        assert_eq!(1, links.len());
+        assert_eq!(
+            vec![(String::from("a"), 1000)],
+            shortener
+                .storage
+                .scored_members("LINKS_api key", 0, u64::MAX, 10)
+                .await
+                .unwrap()
+        );
+
+        assert!(shortener
+            .links("other key", 0, 10)
+            .await
+            .unwrap()
+            .is_empty());
+        let err = shortener.links("invalid key", 0, 10).await.err().unwrap();
+        assert_eq!("Invalid API key", err.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_list_by_api_key() {
+        let storage = MemoryStorage::new();
+        storage.set("API_KEY_api key", "true").await.unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        for id in &["a", "b", "c", "ab", "ac"] {
+            shortener
+                .shorten_with_id(
+                    &Some("api key"),
+                    None,
+                    id,
+                    "example.com",
+                    &LinkOptions::default(),
+                )
+                .await
+                .unwrap();
+        }
+        // "c", "b" and "ac" were created in the same second
+        let created = [
+            ("a", 1000),
+            ("ab", 1500),
+            ("b", 2000),
+            ("c", 2000),
+            ("ac", 2000),
+        ];
+        for (id, created_at) in &created {
+            shortener
+                .storage
+                .add_scored_member("LINKS_api key", id, *created_at)
+                .await
+                .unwrap();
+        }
+
+        let page = shortener.list_by_api_key("api key", None, 2).await.unwrap();
+        let ids = page
+            .links
+            .iter()
+            .map(|link| link.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["c", "b"], ids);
+        assert_eq!(Some(String::from("2000-b")), page.next_cursor);
+
+        shortener.storage.expire("ab", 0).await.unwrap();
+        let page = shortener
+            .list_by_api_key("api key", page.next_cursor.as_deref(), 2)
+            .await
+            .unwrap();
+        let ids = page
+            .links
+            .iter()
+            .map(|link| link.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["ac"], ids);
+        assert_eq!(Some(String::from("1500-ab")), page.next_cursor);
+
+        let page = shortener
+            .list_by_api_key("api key", page.next_cursor.as_deref(), 2)
+            .await
+            .unwrap();
+        assert_eq!("a", page.links[0].id);
+        assert_eq!(None, page.next_cursor);
+
+        let err = shortener
+            .list_by_api_key("api key", Some("2000"), 2)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Invalid cursor", err.to_string());
+        let err = shortener
+            .list_by_api_key("invalid key", None, 2)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Invalid API key", err.to_string());
+    }
+}
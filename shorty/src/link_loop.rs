@@ -0,0 +1,196 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! link_loop holds the protection against link loops: links redirecting to shorty itself, which
+//! would redirect from link to link forever, or hide where a link goes.
+//!
+//! A URL loops if its host is the one the request to shorten it was sent to, or one of the public
+//! hosts of the deployment (see `Shortener::with_public_hosts`), for deployments behind a proxy
+//! or serving many domains. With a `RedirectResolver`, the redirects of the URL are followed as
+//! well, up to a maximum depth, so that a URL reaching shorty through another shortener loops too.
+//!
+//! shorty doesn't make HTTP calls itself: the resolver following redirects with HTTP lives with
+//! the frontends, see shorty-bootstrap.
+
+use std::error::Error;
+
+use async_trait::async_trait;
+use url::Url;
+
+use crate::{Shortener, ShortenerError};
+
+/// `RedirectResolver` tells where a URL redirects to, one redirect at a time.
+///
+/// A resolver failing, such as a destination being unreachable, doesn't fail shortening: the
+/// error is logged and the URL accepted.
+#[async_trait]
+pub trait RedirectResolver: Send + Sync {
+    /// Returns the `Location` the `http` or `https` URL redirects to, possibly relative, or
+    /// `None` if it doesn't redirect
+    async fn resolve(&self, url: &str) -> Result<Option<String>, Box<dyn Error + Send + Sync>>;
+}
+
+impl Shortener {
+    /// Sets the hosts shorty serves its links on, such as `sho.rt`: URLs to any of them loop,
+    /// whatever host the request to shorten them was sent to
+    pub fn with_public_hosts(mut self, public_hosts: Vec<String>) -> Shortener {
+        self.public_hosts = public_hosts
+            .into_iter()
+            .map(|host| host.to_lowercase())
+            .collect();
+        self
+    }
+
+    /// Follows the redirects of the destination of new links with `redirect_resolver`, up to
+    /// `max_depth` redirects, rejecting those reaching shorty: see `link_loop`
+    pub fn with_redirect_resolver(
+        mut self,
+        redirect_resolver: impl RedirectResolver + 'static,
+        max_depth: usize,
+    ) -> Shortener {
+        self.redirect_resolver = Some(Box::new(redirect_resolver));
+        self.max_redirect_depth = max_depth;
+        self
+    }
+
+    /// Tells whether `url` goes to shorty: to the `host` of the request, if any, or to one of the
+    /// public hosts
+    pub(crate) fn is_own_url(&self, host: Option<&str>, url: &Url) -> bool {
+        match url.host_str() {
+            Some(url_host) => {
+                host.is_some_and(|host| host.eq_ignore_ascii_case(url_host))
+                    || self.public_hosts.iter().any(|host| host == url_host)
+            }
+            None => false,
+        }
+    }
+
+    /// Follows the redirects of `url`, the destination of a new link, failing if one of them goes
+    /// to shorty, or to a private target if those are rejected. Redirects deeper than the maximum
+    /// depth aren't followed.
+    pub(crate) async fn check_redirects(
+        &self,
+        host: Option<&str>,
+        url: &str,
+    ) -> Result<(), ShortenerError> {
+        let redirect_resolver = match &self.redirect_resolver {
+            Some(redirect_resolver) => redirect_resolver,
+            None => return Ok(()),
+        };
+        let mut current = match Url::parse(url) {
+            Ok(current) => current,
+            Err(_) => return Ok(()),
+        };
+
+        for _ in 0..self.max_redirect_depth {
+            if !matches!(current.scheme(), "http" | "https") {
+                return Ok(());
+            }
+            let location = match redirect_resolver.resolve(current.as_str()).await {
+                Ok(Some(location)) => location,
+                Ok(None) => return Ok(()),
+                Err(err) => {
+                    log::warn!(
+                        "unable to follow '{}', accepting '{}': {}",
+                        current,
+                        url,
+                        err
+                    );
+                    return Ok(());
+                }
+            };
+            current = match current.join(&location) {
+                Ok(next) => next,
+                Err(_) => return Ok(()),
+            };
+
+            if self.is_own_url(host, &current) {
+                log::info!("refused to shorten '{}': redirects to '{}'", url, current);
+                return Err(ShortenerError::LinkLoop);
+            }
+            self.check_target(current.as_str()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    /// Resolves `other.example/a` to shorty in two redirects, and `other.example/c` elsewhere
+    struct FakeResolver;
+
+    #[async_trait]
+    impl RedirectResolver for FakeResolver {
+        async fn resolve(&self, url: &str) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+            let location = match url {
+                "http://other.example/a" => Some("https://third.example/b"),
+                "https://third.example/b" => Some("http://sho.rt/xyz"),
+                "http://other.example/c" => Some("/d"),
+                _ => None,
+            };
+            Ok(location.map(String::from))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_link_loop() {
+        let shortener = Shortener::new(
+            10,
+            vec!['a', 'b', 'c'],
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
+        )
+        .with_public_hosts(vec![String::from("Sho.rt")]);
+        let options = LinkOptions::default();
+
+        for url in &["http://sho.rt/abc", "http://SHORT.example/abc"] {
+            let err = shortener
+                .shorten(&None, Some("short.example"), url, &options)
+                .await
+                .err()
+                .unwrap();
+            assert_eq!("Link loop is not allowed", err.to_string());
+        }
+
+        // chains are only followed with a resolver, up to its depth
+        let chained = "http://other.example/a";
+        assert!(shortener
+            .shorten(&None, None, chained, &options)
+            .await
+            .is_ok());
+        let shortener = shortener.with_redirect_resolver(FakeResolver, 1);
+        assert!(shortener
+            .shorten(&None, None, chained, &options)
+            .await
+            .is_ok());
+        let shortener = shortener.with_redirect_resolver(FakeResolver, 2);
+        let err = shortener
+            .shorten(&None, None, chained, &options)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("Link loop is not allowed", err.to_string());
+        assert!(shortener
+            .shorten(&None, None, "http://other.example/c", &options)
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! link_metadata holds `LinkMetadata`, the record of how each link was created: when, with which
+//! API key, and from which client IP address and user agent, as far as the frontend knows them
+//! (see `Creator`). The record is stored as JSON in `METADATA_<id>`, expiring with the link, while
+//! the link itself stays the bare URL it redirects to: redirects read a single string, and the
+//! record is read only when asked for.
+//!
+//! Links shortened before records were stored have their metadata rebuilt from `CREATED_<id>` and
+//! `OWNER_<id>`, without client IP address and user agent.
+
+use crate::{now, Shortener, ShortenerError};
+
+/// Who is shortening a link: the IP address and the user agent of the client, if known
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Creator {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// How a link was created, see `link_metadata`: when (`created_at`), with which `api_key`, and
+/// the `ip` and `user_agent` of the client. Unknown fields are not serialized.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct LinkMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+impl LinkMetadata {
+    /// The metadata of a link shortened now with `api_key` by `creator`
+    pub(crate) fn new(api_key: &Option<&str>, creator: &Creator) -> LinkMetadata {
+        LinkMetadata {
+            created_at: Some(now()),
+            api_key: api_key.map(String::from),
+            ip: creator.ip.clone(),
+            user_agent: creator.user_agent.clone(),
+        }
+    }
+
+    pub(crate) fn json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+impl Shortener {
+    /// Returns the metadata of the link `id`, if it exists, whether it's found by `lookup` or not,
+    /// such as when quarantined
+    pub async fn metadata(&self, id: &str) -> Option<LinkMetadata> {
+        if !self.storage.exists(id).await.unwrap_or(false) {
+            return None;
+        }
+
+        let stored = self.storage.get_string(&metadata_key(id)).await.ok();
+        if let Some(metadata) = stored.and_then(|json| serde_json::from_str(&json).ok()) {
+            return Some(metadata);
+        }
+        let created_at = self.storage.get_string(&format!("CREATED_{}", id)).await;
+        Some(LinkMetadata {
+            created_at: created_at
+                .ok()
+                .and_then(|created_at| created_at.parse().ok()),
+            api_key: self.storage.get_string(&format!("OWNER_{}", id)).await.ok(),
+            ip: None,
+            user_agent: None,
+        })
+    }
+
+    /// Returns the metadata of the link `id` to the API key it was shortened with
+    pub async fn owned_metadata(
+        &self,
+        api_key: &str,
+        id: &str,
+    ) -> Result<LinkMetadata, ShortenerError> {
+        self.verify_api_key(api_key, &[]).await?;
+
+        let metadata = self
+            .metadata(id)
+            .await
+            .ok_or(ShortenerError::NotFound("Link not found"))?;
+        match &metadata.api_key {
+            Some(owner) if owner == api_key => Ok(metadata),
+            _ => Err(ShortenerError::Forbidden("Link not owned by the API key")),
+        }
+    }
+}
+
+pub(crate) fn metadata_key(id: &str) -> String {
+    format!("METADATA_{}", id)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::storage::Storage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_metadata() {
+        let storage = MemoryStorage::new();
+        storage.set("API_KEY_api key", "true").await.unwrap();
+        storage.set("API_KEY_other key", "true").await.unwrap();
+        storage.set("legacy", "http://example.com/").await.unwrap();
+        storage.set("CREATED_legacy", "1000").await.unwrap();
+        storage.set("OWNER_legacy", "api key").await.unwrap();
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+
+        let options = LinkOptions {
+            creator: Creator {
+                ip: Some(String::from("192.0.2.1")),
+                user_agent: Some(String::from("curl/8.0")),
+            },
+            ..LinkOptions::default()
+        };
+        let owned = shortener
+            .shorten(&Some("api key"), None, "http://example.com/", &options)
+            .await
+            .unwrap();
+        let anonymous = shortener
+            .shorten(&None, None, "http://example.com/", &LinkOptions::default())
+            .await
+            .unwrap();
+
+        let metadata = shortener.metadata(owned.id()).await.unwrap();
+        assert!(metadata.created_at.unwrap() >= now() - 1);
+        assert_eq!(Some("api key"), metadata.api_key.as_deref());
+        assert_eq!(Some("192.0.2.1"), metadata.ip.as_deref());
+        assert_eq!(Some("curl/8.0"), metadata.user_agent.as_deref());
+
+        let metadata = shortener.metadata(anonymous.id()).await.unwrap();
+        assert_eq!(None, metadata.api_key);
+        assert_eq!(
+            format!(r#"{{"created_at":{}}}"#, metadata.created_at.unwrap()),
+            metadata.json()
+        );
+
+        // links created before metadata was stored
+        assert_eq!(
+            Some(LinkMetadata {
+                created_at: Some(1000),
+                api_key: Some(String::from("api key")),
+                ip: None,
+                user_agent: None,
+            }),
+            shortener.metadata("legacy").await
+        );
+        assert_eq!(None, shortener.metadata("missing").await);
+
+        assert!(shortener
+            .owned_metadata("api key", owned.id())
+            .await
+            .is_ok());
+        assert!(matches!(
+            shortener.owned_metadata("other key", owned.id()).await,
+            Err(ShortenerError::Forbidden(_))
+        ));
+        assert!(matches!(
+            shortener.owned_metadata("api key", anonymous.id()).await,
+            Err(ShortenerError::Forbidden(_))
+        ));
+        assert!(matches!(
+            shortener.owned_metadata("api key", "missing").await,
+            Err(ShortenerError::NotFound(_))
+        ));
+
+        shortener.delete("api key", owned.id()).await.unwrap();
+        assert_eq!(None, shortener.metadata(owned.id()).await);
+    }
+}
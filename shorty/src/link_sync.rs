@@ -0,0 +1,237 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! link_sync holds the changes of the links, so that edge resolvers and static exporters can sync
+//! incrementally, fetching what changed since their last sync instead of a whole snapshot.
+//!
+//! Changes are kept in the sorted set `LINK_CHANGES`, scoring each link ID with the time of its
+//! last change, in microseconds: the time of its last event, see `link_events`, or of its
+//! deletion. The scores are the cursors of the sync. Each link appears once, however many times
+//! it changed, so the set grows with the number of links and deleted links stay as tombstones.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{now, Redirect, Shortener, ShortenerError};
+
+const LINK_CHANGES: &str = "LINK_CHANGES";
+
+/// How long changes are held back, in microseconds, so that a change recorded late by an instance
+/// with a slower clock, or slower to write, isn't skipped by a client already past its time
+pub const SETTLE_TIME: u64 = 1_000_000;
+
+/// The most changes returned at once
+pub const MAX_CHANGES: usize = 1000;
+
+/// The current state of a link changed after some cursor: either its redirect, as in a snapshot,
+/// or its deletion. Links that are quarantined or belong to paused campaigns count as deleted,
+/// since `lookup` doesn't find them.
+///
+/// `expires_at` is when an upserted link expires, so that clients drop it by themselves: links
+/// expire without a change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct LinkChange {
+    pub cursor: u64,
+    pub id: String,
+    #[serde(flatten)]
+    pub op: ChangeOp,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ChangeOp {
+    Upsert {
+        #[serde(flatten)]
+        redirect: Redirect,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    Delete,
+}
+
+impl Shortener {
+    /// Returns at most `limit` changes after the `since` cursor, oldest first, and never more than
+    /// `MAX_CHANGES`. Syncing from cursor `0` returns every link changed since changes are
+    /// recorded.
+    ///
+    /// Clients keep the cursor of the last change, and call again with it until fewer than `limit`
+    /// changes are returned. Changes of the last `SETTLE_TIME` are held back.
+    pub async fn changes_since(
+        &self,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<(String, u64)>, ShortenerError> {
+        let limit = limit.min(MAX_CHANGES);
+        let until = micros().saturating_sub(SETTLE_TIME);
+        if since >= until || limit == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut changed = self
+            .storage
+            .scored_members_ascending(LINK_CHANGES, since + 1, until, limit)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        // a full page may cut changes sharing a cursor: leave them all to the next page
+        if changed.len() == limit {
+            let last = changed[limit - 1].1;
+            if changed[0].1 != last {
+                changed.retain(|(_, cursor)| *cursor != last);
+            }
+        }
+
+        Ok(changed)
+    }
+
+    /// Returns the changes of `changes_since`, with the current state of each link
+    pub async fn sync(&self, since: u64, limit: usize) -> Result<Vec<LinkChange>, ShortenerError> {
+        let mut changes = vec![];
+        for (id, cursor) in self.changes_since(since, limit).await? {
+            let op = match self.destination(&id).await {
+                Some(url) => ChangeOp::Upsert {
+                    redirect: self.redirect_to(&id, url).await,
+                    expires_at: self
+                        .storage
+                        .ttl(&id)
+                        .await
+                        .ok()
+                        .flatten()
+                        .map(|ttl| now() + ttl),
+                },
+                None => ChangeOp::Delete,
+            };
+            changes.push(LinkChange { cursor, id, op });
+        }
+
+        Ok(changes)
+    }
+
+    /// Records that the link with the given ID changed at `at`, in microseconds. Failing to do so
+    /// is logged, without failing the change.
+    pub(crate) async fn record_change(&self, id: &str, at: u64) {
+        if let Err(err) = self.storage.add_scored_member(LINK_CHANGES, id, at).await {
+            log::warn!("unable to record change of '{}': {}", id, err);
+        }
+    }
+}
+
+/// The current time, in microseconds
+pub(crate) fn micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::memory_storage::MemoryStorage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sync() {
+        let shortener = Shortener::new(
+            10,
+            vec!['a', 'b', 'c'],
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
+        );
+        let expiring_options = LinkOptions {
+            expires_in: Some(600),
+            ..LinkOptions::default()
+        };
+        let temporary = shortener
+            .shorten(&None, None, "http://example.com/", &expiring_options)
+            .await
+            .unwrap();
+        let permanent_options = LinkOptions {
+            permanent: true,
+            ..LinkOptions::default()
+        };
+        let permanent = shortener
+            .shorten(&None, None, "http://example.org/", &permanent_options)
+            .await
+            .unwrap();
+        let deleted = shortener
+            .shorten(&None, None, "http://example.net/", &LinkOptions::default())
+            .await
+            .unwrap();
+        shortener.purge(deleted.id(), None).await.unwrap();
+
+        // recent changes are held back
+        assert!(shortener.sync(0, 10).await.unwrap().is_empty());
+        tokio::time::sleep(Duration::from_micros(SETTLE_TIME)).await;
+
+        let changes = shortener.sync(0, 10).await.unwrap();
+        assert_eq!(3, changes.len());
+        assert_eq!(temporary.id(), changes[0].id);
+        match &changes[1].op {
+            ChangeOp::Upsert {
+                redirect,
+                expires_at,
+            } => {
+                assert_eq!(301, redirect.status);
+                assert_eq!(None, *expires_at);
+            }
+            ChangeOp::Delete => panic!("permanent link deleted"),
+        }
+        assert_eq!(permanent.id(), changes[1].id);
+        assert_eq!(deleted.id(), changes[2].id);
+        assert_eq!(ChangeOp::Delete, changes[2].op);
+
+        let line = serde_json::to_value(&changes[2]).unwrap();
+        assert_eq!("delete", line["op"]);
+        let line = serde_json::to_value(&changes[0]).unwrap();
+        assert_eq!("upsert", line["op"]);
+        assert_eq!("http://example.com/", line["url"]);
+        assert!(line["expires_at"].as_u64().unwrap() > now());
+
+        let changes = shortener.sync(changes[0].cursor, 10).await.unwrap();
+        assert_eq!(2, changes.len());
+        assert_eq!(1, shortener.sync(0, 1).await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_changes_since() {
+        let shortener = Shortener::new(
+            10,
+            vec!['a', 'b', 'c'],
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
+        );
+        shortener.record_change("x", 5).await;
+        shortener.record_change("y", 5).await;
+        shortener.record_change("z", 4).await;
+
+        // changes sharing the last cursor of a full page are left to the next page
+        assert_eq!(
+            vec![(String::from("z"), 4)],
+            shortener.changes_since(0, 2).await.unwrap()
+        );
+        assert_eq!(
+            vec![(String::from("x"), 5), (String::from("y"), 5)],
+            shortener.changes_since(4, 2).await.unwrap()
+        );
+        assert!(shortener.changes_since(5, 2).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,340 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! link_transfer holds the bulk export and import of links, to migrate them between instances:
+//! `Shortener::export` writes the ID and URL of every link, and `Shortener::import` creates links
+//! from them.
+//!
+//! Links are transferred as CSV, with an `id,url` header, or as JSON lines, one `{"id", "url"}`
+//! object per link. Only their redirects are: owners, options and statistics stay behind.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::str::FromStr;
+
+use crate::global_stats::key_type;
+use crate::{Shortener, ShortenerError};
+
+/// The keys scanned for each page of `Shortener::export_page`
+pub const EXPORT_PAGE_SIZE: usize = 1000;
+
+/// The most errors `ImportSummary` reports, the others are only counted
+pub const MAX_IMPORT_ERRORS: usize = 100;
+
+/// The format links are exported and imported in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferFormat {
+    Csv,
+    Json,
+}
+
+impl FromStr for TransferFormat {
+    type Err = ShortenerError;
+
+    fn from_str(format: &str) -> Result<TransferFormat, ShortenerError> {
+        match format {
+            "csv" => Ok(TransferFormat::Csv),
+            "json" => Ok(TransferFormat::Json),
+            _ => Err(ShortenerError::InvalidInput(
+                "Invalid format: it must be csv or json",
+            )),
+        }
+    }
+}
+
+impl TransferFormat {
+    /// The name of the format, as parsed by `from_str`
+    pub fn name(self) -> &'static str {
+        match self {
+            TransferFormat::Csv => "csv",
+            TransferFormat::Json => "json",
+        }
+    }
+
+    /// The content type of the format, for frontends serving exports
+    pub fn content_type(self) -> &'static str {
+        match self {
+            TransferFormat::Csv => "text/csv",
+            TransferFormat::Json => "application/x-ndjson",
+        }
+    }
+}
+
+/// A link as exported: its ID and the URL it redirects to
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct LinkRecord {
+    id: String,
+    url: String,
+}
+
+/// A page of an export: its `data`, possibly empty, and the `cursor` of the next page, `None`
+/// after the last one
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportPage {
+    pub data: String,
+    pub cursor: Option<String>,
+}
+
+/// What `Shortener::import` did: the links `imported`, those `skipped` because their ID is taken,
+/// and the lines that couldn't be imported, with the first `MAX_IMPORT_ERRORS` `errors`
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub errors: Vec<String>,
+}
+
+impl ImportSummary {
+    fn fail(&mut self, line: usize, err: impl ToString) {
+        self.failed += 1;
+        if self.errors.len() < MAX_IMPORT_ERRORS {
+            self.errors
+                .push(format!("line {}: {}", line, err.to_string()));
+        }
+    }
+}
+
+impl Shortener {
+    /// Writes the ID and URL of every link to `writer` in `format`, returning the number of links
+    /// written. Links `lookup` doesn't find, such as quarantined ones, are left out, as in
+    /// `export_snapshot`.
+    ///
+    /// It walks the whole keyspace a page at a time, see `export_page`, and is meant for
+    /// migrations, out of the request path.
+    pub async fn export(
+        &self,
+        mut writer: impl Write,
+        format: TransferFormat,
+    ) -> Result<usize, ShortenerError> {
+        let mut exported = 0;
+        let mut cursor = String::new();
+        loop {
+            let page = self.export_page(&cursor, format).await?;
+            exported += page.data.lines().count();
+            writer.write_all(page.data.as_bytes()).map_err(|err| {
+                log::error!("unable to write the export: {}", err);
+                ShortenerError::Internal("Unable to write the export")
+            })?;
+            match page.cursor {
+                Some(next) => cursor = next,
+                None => break,
+            }
+        }
+
+        // the CSV header is not a link
+        if format == TransferFormat::Csv {
+            exported -= 1;
+        }
+        Ok(exported)
+    }
+
+    /// Exports the page of the links starting at `cursor`, empty for the first page, scanning about
+    /// `EXPORT_PAGE_SIZE` keys. The first page of a CSV export starts with its header. Cursors are
+    /// those of `Storage::scan_page`, as in `export_snapshot_page`.
+    pub async fn export_page(
+        &self,
+        cursor: &str,
+        format: TransferFormat,
+    ) -> Result<ExportPage, ShortenerError> {
+        let (keys, next) = self
+            .storage
+            .scan_page("", cursor, EXPORT_PAGE_SIZE)
+            .await
+            .map_err(ShortenerError::Storage)?;
+
+        let mut records = Vec::new();
+        for id in keys.into_iter().filter(|key| key_type(key) == "links") {
+            if let Some(url) = self.destination(&id).await {
+                records.push(LinkRecord { id, url });
+            }
+        }
+
+        let data = match format {
+            TransferFormat::Csv => {
+                let mut csv = csv::WriterBuilder::new()
+                    .has_headers(cursor.is_empty())
+                    .from_writer(Vec::new());
+                if cursor.is_empty() && records.is_empty() {
+                    csv.write_record(["id", "url"]).unwrap();
+                }
+                for record in &records {
+                    csv.serialize(record).unwrap();
+                }
+                String::from_utf8(csv.into_inner().unwrap()).unwrap()
+            }
+            TransferFormat::Json => records
+                .iter()
+                .map(|record| serde_json::to_string(record).unwrap() + "\n")
+                .collect(),
+        };
+
+        Ok(ExportPage { data, cursor: next })
+    }
+
+    /// Creates a link for each ID and URL read from `reader` in `format`, as written by `export`.
+    /// IDs already taken are skipped, so that an import cut short can be run again. IDs must be
+    /// valid custom IDs, see `shorten_with_id`, and URLs must parse: other lines are reported in
+    /// the summary, without failing the import. Imported links are anonymous and never expire.
+    pub async fn import(
+        &self,
+        reader: impl Read,
+        format: TransferFormat,
+    ) -> Result<ImportSummary, ShortenerError> {
+        let mut summary = ImportSummary::default();
+        match format {
+            TransferFormat::Csv => {
+                let mut csv = csv::Reader::from_reader(reader);
+                for (index, record) in csv.deserialize::<LinkRecord>().enumerate() {
+                    // the header is the first line
+                    let line = index + 2;
+                    match record {
+                        Ok(record) => self.import_link(record, line, &mut summary).await?,
+                        Err(err) => summary.fail(line, err),
+                    }
+                }
+            }
+            TransferFormat::Json => {
+                for (index, line) in BufReader::new(reader).lines().enumerate() {
+                    let line_number = index + 1;
+                    let line = line.map_err(|err| {
+                        log::error!("unable to read the import: {}", err);
+                        ShortenerError::Internal("Unable to read the import")
+                    })?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str::<LinkRecord>(&line) {
+                        Ok(record) => self.import_link(record, line_number, &mut summary).await?,
+                        Err(err) => summary.fail(line_number, err),
+                    }
+                }
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Creates the link of `record`, read at `line`, counting it in `summary`
+    async fn import_link(
+        &self,
+        record: LinkRecord,
+        line: usize,
+        summary: &mut ImportSummary,
+    ) -> Result<(), ShortenerError> {
+        if let Err(err) = self.validate_id(&record.id) {
+            summary.fail(line, err);
+            return Ok(());
+        }
+        if let Err(err) = url::Url::parse(&record.url) {
+            summary.fail(line, ShortenerError::UnparsableUrl(err));
+            return Ok(());
+        }
+
+        let stored = self
+            .storage
+            .set_if_not_exists(&record.id, &record.url)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if !stored {
+            summary.skipped += 1;
+            return Ok(());
+        }
+
+        self.record_created(&record.id, &None, None).await;
+        self.index_domains(&record.id, &record.url).await?;
+        self.store_stats(&record.id, None).await;
+        summary.imported += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    fn new_shortener() -> Shortener {
+        Shortener::new(
+            10,
+            "abcdefghijklmnopqrstuvwxyz".chars().collect(),
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let source = new_shortener();
+        for url in ["http://example.com/a,b", "http://example.org/\"quoted\""] {
+            source
+                .shorten(&None, None, url, &LinkOptions::default())
+                .await
+                .unwrap();
+        }
+
+        for format in [TransferFormat::Csv, TransferFormat::Json] {
+            let mut export = Vec::new();
+            assert_eq!(2, source.export(&mut export, format).await.unwrap());
+
+            let target = new_shortener();
+            let summary = target.import(export.as_slice(), format).await.unwrap();
+            assert_eq!(2, summary.imported);
+            assert!(summary.errors.is_empty());
+
+            let mut reexport = Vec::new();
+            target.export(&mut reexport, format).await.unwrap();
+            assert_eq!(sorted_lines(&export), sorted_lines(&reexport));
+
+            // importing again skips the links imported already
+            let summary = target.import(export.as_slice(), format).await.unwrap();
+            assert_eq!(0, summary.imported);
+            assert_eq!(2, summary.skipped);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_errors() {
+        let shortener = new_shortener();
+        let csv =
+            "id,url\nabc,http://example.com\nAPI_KEY_x,http://example.com\nabd,not a url\nabe\n";
+
+        let summary = shortener
+            .import(csv.as_bytes(), TransferFormat::Csv)
+            .await
+            .unwrap();
+        assert_eq!(1, summary.imported);
+        assert_eq!(3, summary.failed);
+        assert!(summary.errors[0].starts_with("line 3: Invalid custom ID"));
+        assert!(summary.errors[1].starts_with("line 4: Unable to parse url"));
+        assert!(summary.errors[2].starts_with("line 5: "));
+        assert_eq!("http://example.com", shortener.lookup("abc").await.unwrap());
+        assert!(!shortener.storage.exists("API_KEY_x").await.unwrap());
+
+        let empty = new_shortener().export_page("", TransferFormat::Csv).await;
+        assert_eq!("id,url\n", empty.unwrap().data);
+    }
+
+    fn sorted_lines(export: &[u8]) -> Vec<&str> {
+        let mut lines = std::str::from_utf8(export)
+            .unwrap()
+            .lines()
+            .collect::<Vec<_>>();
+        lines.sort_unstable();
+        lines
+    }
+}
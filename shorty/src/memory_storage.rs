@@ -0,0 +1,630 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! memory_storage holds `MemoryStorage`, a `Storage` keeping its data in memory
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use dashmap::mapref::entry::Entry as MapEntry;
+use dashmap::DashMap;
+use redis::{ErrorKind, RedisError};
+
+use crate::storage::{Storage, StorageError, StorageResult};
+
+enum Value {
+    String(String),
+    Set(BTreeSet<String>),
+    SortedSet(BTreeMap<String, u64>),
+}
+
+struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn new(value: &str) -> Entry {
+        Entry {
+            value: Value::String(value.to_owned()),
+            expires_at: None,
+        }
+    }
+
+    fn new_set() -> Entry {
+        Entry {
+            value: Value::Set(BTreeSet::new()),
+            expires_at: None,
+        }
+    }
+
+    fn new_sorted_set() -> Entry {
+        Entry {
+            value: Value::SortedSet(BTreeMap::new()),
+            expires_at: None,
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+fn wrong_type() -> StorageError {
+    RedisError::from((
+        ErrorKind::TypeError,
+        "operation against a key holding the wrong kind of value",
+    ))
+    .into()
+}
+
+/// `MemoryStorage` is a `Storage` keeping keys in a concurrent map, and expiring them the way
+/// Redis does.
+///
+/// It's meant for local development and tests: nothing is persisted, and data isn't shared among
+/// processes.
+#[derive(Default)]
+pub struct MemoryStorage {
+    entries: DashMap<String, Entry>,
+}
+
+impl MemoryStorage {
+    /// Creates a new, empty, `MemoryStorage`
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    /// Applies `read` to the entry of `key`, if it exists and it's not expired. Expired entries
+    /// are removed.
+    fn live_entry<T>(&self, key: &str, read: impl FnOnce(&Entry) -> T) -> Option<T> {
+        let now = Instant::now();
+        let value = self
+            .entries
+            .get(key)
+            .map(|entry| (entry.is_expired(now), read(&entry)));
+
+        match value {
+            Some((false, value)) => Some(value),
+            Some((true, _)) => {
+                self.entries
+                    .remove_if(key, |_, entry| entry.is_expired(now));
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Removes the set stored at `key` if it's empty, like Redis does
+    fn remove_if_empty(&self, key: &str) {
+        self.entries.remove_if(key, |_, entry| match &entry.value {
+            Value::Set(members) => members.is_empty(),
+            Value::SortedSet(members) => members.is_empty(),
+            Value::String(_) => false,
+        });
+    }
+
+    /// Increments the integer value of `key` by `amount`, holding the lock of its entry while
+    /// setting the expiration, if `period` is present and the entry has none
+    fn increment_entry(&self, key: &str, amount: i64, period: Option<usize>) -> StorageResult<i64> {
+        let now = Instant::now();
+        let mut entry = self
+            .entries
+            .entry(key.to_owned())
+            .or_insert_with(|| Entry::new("0"));
+        if entry.is_expired(now) {
+            *entry = Entry::new("0");
+        }
+
+        let value = match &entry.value {
+            Value::String(value) => {
+                value.parse::<i64>().map_err(|_| {
+                    StorageError::from(RedisError::from((
+                        ErrorKind::TypeError,
+                        "value is not an integer",
+                    )))
+                })? + amount
+            }
+            Value::Set(_) | Value::SortedSet(_) => return Err(wrong_type()),
+        };
+        entry.value = Value::String(value.to_string());
+        if let (Some(period), None) = (period, entry.expires_at) {
+            entry.expires_at = Some(now + Duration::from_secs(period as u64));
+        }
+
+        Ok(value)
+    }
+
+    /// Returns at most `limit` members of the sorted set stored at `key` whose score is between
+    /// `min_score` and `max_score`, sorted by `order`
+    fn sorted_members(
+        &self,
+        key: &str,
+        min_score: u64,
+        max_score: u64,
+        limit: usize,
+        order: impl Fn(&(String, u64), &(String, u64)) -> std::cmp::Ordering,
+    ) -> StorageResult<Vec<(String, u64)>> {
+        self.live_entry(key, |entry| match &entry.value {
+            Value::SortedSet(members) => {
+                let mut members = members
+                    .iter()
+                    .filter(|(_, score)| (min_score..=max_score).contains(*score))
+                    .map(|(member, score)| (member.clone(), *score))
+                    .collect::<Vec<_>>();
+                members.sort_by(order);
+                members.truncate(limit);
+                Ok(members)
+            }
+            Value::String(_) | Value::Set(_) => Err(wrong_type()),
+        })
+        .unwrap_or_else(|| Ok(vec![]))
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn get_string(&self, key: &str) -> StorageResult<String> {
+        self.live_entry(key, |entry| match &entry.value {
+            Value::String(value) => Ok(value.clone()),
+            Value::Set(_) | Value::SortedSet(_) => Err(wrong_type()),
+        })
+        .unwrap_or_else(|| Err(RedisError::from((ErrorKind::TypeError, "key not found")).into()))
+    }
+
+    async fn exists(&self, key: &str) -> StorageResult<bool> {
+        Ok(self.live_entry(key, |_| ()).is_some())
+    }
+
+    async fn increment(&self, key: &str) -> StorageResult<i64> {
+        self.increment_entry(key, 1, None)
+    }
+
+    async fn increment_expiring(&self, key: &str, period: usize) -> StorageResult<i64> {
+        self.increment_entry(key, 1, Some(period))
+    }
+
+    async fn increment_expiring_by(
+        &self,
+        key: &str,
+        amount: i64,
+        period: usize,
+    ) -> StorageResult<i64> {
+        self.increment_entry(key, amount, Some(period))
+    }
+
+    async fn expire(&self, key: &str, period: usize) -> StorageResult<()> {
+        if self.live_entry(key, |_| ()).is_none() {
+            return Ok(());
+        }
+
+        if period == 0 {
+            self.entries.remove(key);
+        } else if let Some(mut entry) = self.entries.get_mut(key) {
+            entry.expires_at = Some(Instant::now() + Duration::from_secs(period as u64));
+        }
+
+        Ok(())
+    }
+
+    async fn ttl(&self, key: &str) -> StorageResult<Option<u64>> {
+        let expires_at = self.live_entry(key, |entry| entry.expires_at).flatten();
+        Ok(expires_at.map(|expires_at| {
+            let left = expires_at.saturating_duration_since(Instant::now());
+            left.as_secs() + u64::from(left.subsec_nanos() > 0)
+        }))
+    }
+
+    async fn set(&self, key: &str, value: &str) -> StorageResult<()> {
+        self.entries.insert(key.to_owned(), Entry::new(value));
+        Ok(())
+    }
+
+    async fn set_expiring(&self, key: &str, value: &str, period: usize) -> StorageResult<()> {
+        let mut entry = Entry::new(value);
+        entry.expires_at = Some(Instant::now() + Duration::from_secs(period as u64));
+        self.entries.insert(key.to_owned(), entry);
+        Ok(())
+    }
+
+    async fn set_if_not_exists(&self, key: &str, value: &str) -> StorageResult<bool> {
+        match self.entries.entry(key.to_owned()) {
+            MapEntry::Occupied(mut occupied) => {
+                if !occupied.get().is_expired(Instant::now()) {
+                    return Ok(false);
+                }
+                occupied.insert(Entry::new(value));
+            }
+            MapEntry::Vacant(vacant) => {
+                vacant.insert(Entry::new(value));
+            }
+        }
+
+        Ok(true)
+    }
+
+    async fn set_if_not_exists_expiring(
+        &self,
+        key: &str,
+        value: &str,
+        period: usize,
+    ) -> StorageResult<bool> {
+        let now = Instant::now();
+        let mut entry = Entry::new(value);
+        entry.expires_at = Some(now + Duration::from_secs(period as u64));
+
+        match self.entries.entry(key.to_owned()) {
+            MapEntry::Occupied(mut occupied) => {
+                if !occupied.get().is_expired(now) {
+                    return Ok(false);
+                }
+                occupied.insert(entry);
+            }
+            MapEntry::Vacant(vacant) => {
+                vacant.insert(entry);
+            }
+        }
+
+        Ok(true)
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<bool> {
+        let existed = self.live_entry(key, |_| ()).is_some();
+        self.entries.remove(key);
+        Ok(existed)
+    }
+
+    async fn rename(&self, key: &str, new_key: &str) -> StorageResult<bool> {
+        if key == new_key || self.live_entry(new_key, |_| ()).is_some() {
+            return Ok(false);
+        }
+
+        match self.entries.remove(key) {
+            Some((_, entry)) if !entry.is_expired(Instant::now()) => {
+                self.entries.insert(new_key.to_owned(), entry);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn add_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        let mut entry = self
+            .entries
+            .entry(key.to_owned())
+            .or_insert_with(Entry::new_set);
+        if entry.is_expired(Instant::now()) {
+            *entry = Entry::new_set();
+        }
+
+        match &mut entry.value {
+            Value::Set(members) => Ok(members.insert(member.to_owned())),
+            Value::String(_) | Value::SortedSet(_) => Err(wrong_type()),
+        }
+    }
+
+    async fn remove_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        let removed = match self.entries.get_mut(key) {
+            Some(entry) if entry.is_expired(Instant::now()) => Ok(false),
+            Some(mut entry) => match &mut entry.value {
+                Value::Set(members) => Ok(members.remove(member)),
+                Value::String(_) | Value::SortedSet(_) => Err(wrong_type()),
+            },
+            None => Ok(false),
+        };
+        self.remove_if_empty(key);
+
+        removed
+    }
+
+    async fn members(&self, key: &str) -> StorageResult<Vec<String>> {
+        self.live_entry(key, |entry| match &entry.value {
+            Value::Set(members) => Ok(members.iter().cloned().collect()),
+            Value::String(_) | Value::SortedSet(_) => Err(wrong_type()),
+        })
+        .unwrap_or_else(|| Ok(vec![]))
+    }
+
+    async fn is_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        self.live_entry(key, |entry| match &entry.value {
+            Value::Set(members) => Ok(members.contains(member)),
+            Value::String(_) | Value::SortedSet(_) => Err(wrong_type()),
+        })
+        .unwrap_or(Ok(false))
+    }
+
+    async fn add_scored_member(&self, key: &str, member: &str, score: u64) -> StorageResult<bool> {
+        let mut entry = self
+            .entries
+            .entry(key.to_owned())
+            .or_insert_with(Entry::new_sorted_set);
+        if entry.is_expired(Instant::now()) {
+            *entry = Entry::new_sorted_set();
+        }
+
+        match &mut entry.value {
+            Value::SortedSet(members) => Ok(members.insert(member.to_owned(), score).is_none()),
+            Value::String(_) | Value::Set(_) => Err(wrong_type()),
+        }
+    }
+
+    async fn remove_scored_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        let removed = match self.entries.get_mut(key) {
+            Some(entry) if entry.is_expired(Instant::now()) => Ok(false),
+            Some(mut entry) => match &mut entry.value {
+                Value::SortedSet(members) => Ok(members.remove(member).is_some()),
+                Value::String(_) | Value::Set(_) => Err(wrong_type()),
+            },
+            None => Ok(false),
+        };
+        self.remove_if_empty(key);
+
+        removed
+    }
+
+    async fn scored_members(
+        &self,
+        key: &str,
+        min_score: u64,
+        max_score: u64,
+        limit: usize,
+    ) -> StorageResult<Vec<(String, u64)>> {
+        self.sorted_members(key, min_score, max_score, limit, |a, b| {
+            b.1.cmp(&a.1).then_with(|| b.0.cmp(&a.0))
+        })
+    }
+
+    async fn scored_members_ascending(
+        &self,
+        key: &str,
+        min_score: u64,
+        max_score: u64,
+        limit: usize,
+    ) -> StorageResult<Vec<(String, u64)>> {
+        self.sorted_members(key, min_score, max_score, limit, |a, b| {
+            a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0))
+        })
+    }
+
+    async fn scan(&self, prefix: &str) -> StorageResult<Vec<String>> {
+        let now = Instant::now();
+        Ok(self
+            .entries
+            .iter()
+            .filter(|entry| entry.key().starts_with(prefix) && !entry.value().is_expired(now))
+            .map(|entry| entry.key().clone())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_scan() {
+        let storage = MemoryStorage::new();
+        storage.set("HITS_a", "1").await.unwrap();
+        storage.set("HITS_b", "2").await.unwrap();
+        storage.set("a", "url").await.unwrap();
+        storage.set("HITS_c", "3").await.unwrap();
+        storage.expire("HITS_c", 0).await.unwrap();
+
+        let mut keys = storage.scan("HITS_").await.unwrap();
+        keys.sort();
+        assert_eq!(vec!["HITS_a", "HITS_b"], keys);
+        assert_eq!(3, storage.scan("").await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_set_and_get() {
+        let storage = MemoryStorage::new();
+        storage.set("key", "value").await.unwrap();
+
+        assert_eq!("value", storage.get_string("key").await.unwrap());
+        assert!(storage.exists("key").await.unwrap());
+        assert!(storage.get_string("missing").await.is_err());
+        assert!(!storage.exists("missing").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_increment() {
+        let storage = MemoryStorage::new();
+
+        assert_eq!(1, storage.increment("counter").await.unwrap());
+        assert_eq!(2, storage.increment("counter").await.unwrap());
+
+        storage.set("text", "value").await.unwrap();
+        assert!(storage.increment("text").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_increment_expiring() {
+        let storage = MemoryStorage::new();
+
+        assert_eq!(1, storage.increment_expiring("counter", 600).await.unwrap());
+        assert_eq!(2, storage.increment_expiring("counter", 600).await.unwrap());
+        storage.expire("counter", 0).await.unwrap();
+        assert_eq!(1, storage.increment_expiring("counter", 600).await.unwrap());
+
+        storage.set("immortal", "5").await.unwrap();
+        assert_eq!(
+            6,
+            storage.increment_expiring("immortal", 600).await.unwrap()
+        );
+        assert!(storage
+            .entries
+            .get("immortal")
+            .unwrap()
+            .expires_at
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_expire() {
+        let storage = MemoryStorage::new();
+        storage.set("expiring", "value").await.unwrap();
+        storage.set("expired", "value").await.unwrap();
+
+        storage.expire("expiring", 600).await.unwrap();
+        storage.expire("expired", 0).await.unwrap();
+
+        assert!(storage.exists("expiring").await.unwrap());
+        assert!(!storage.exists("expired").await.unwrap());
+
+        assert_eq!(Some(600), storage.ttl("expiring").await.unwrap());
+        assert_eq!(None, storage.ttl("expired").await.unwrap());
+        storage.set("persistent", "value").await.unwrap();
+        assert_eq!(None, storage.ttl("persistent").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_set_if_not_exists() {
+        let storage = MemoryStorage::new();
+
+        assert!(storage.set_if_not_exists("key", "value").await.unwrap());
+        assert!(!storage.set_if_not_exists("key", "other").await.unwrap());
+        assert_eq!("value", storage.get_string("key").await.unwrap());
+
+        assert!(storage
+            .set_if_not_exists_expiring("expiring", "value", 600)
+            .await
+            .unwrap());
+        assert!(!storage
+            .set_if_not_exists_expiring("expiring", "other", 600)
+            .await
+            .unwrap());
+        assert_eq!(Some(600), storage.ttl("expiring").await.unwrap());
+        storage.set_expiring("expired", "value", 0).await.unwrap();
+        assert!(storage
+            .set_if_not_exists_expiring("expired", "other", 600)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_set_expiring() {
+        let storage = MemoryStorage::new();
+        storage
+            .set_expiring("expiring", "value", 600)
+            .await
+            .unwrap();
+        storage.set_expiring("expired", "value", 0).await.unwrap();
+
+        assert_eq!("value", storage.get_string("expiring").await.unwrap());
+        assert!(storage
+            .entries
+            .get("expiring")
+            .unwrap()
+            .expires_at
+            .is_some());
+        assert!(!storage.exists("expired").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let storage = MemoryStorage::new();
+        storage.set("key", "value").await.unwrap();
+
+        assert!(storage.delete("key").await.unwrap());
+        assert!(!storage.exists("key").await.unwrap());
+        assert!(!storage.delete("key").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_rename() {
+        let storage = MemoryStorage::new();
+        storage.set_expiring("key", "value", 600).await.unwrap();
+        storage.set("taken", "other").await.unwrap();
+
+        assert!(!storage.rename("key", "taken").await.unwrap());
+        assert!(storage.rename("key", "shorty:key").await.unwrap());
+        assert!(!storage.exists("key").await.unwrap());
+        assert_eq!("value", storage.get_string("shorty:key").await.unwrap());
+        assert!(storage.ttl("shorty:key").await.unwrap().is_some());
+        assert!(!storage.rename("missing", "shorty:missing").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_sets() {
+        let storage = MemoryStorage::new();
+
+        assert!(storage.add_member("set", "b").await.unwrap());
+        assert!(storage.add_member("set", "a").await.unwrap());
+        assert!(!storage.add_member("set", "a").await.unwrap());
+        assert_eq!(vec!["a", "b"], storage.members("set").await.unwrap());
+        assert!(storage.is_member("set", "a").await.unwrap());
+        assert!(!storage.is_member("set", "c").await.unwrap());
+        assert!(!storage.is_member("missing", "a").await.unwrap());
+        assert!(storage.get_string("set").await.is_err());
+
+        assert!(storage.remove_member("set", "a").await.unwrap());
+        assert!(!storage.remove_member("set", "a").await.unwrap());
+        assert!(storage.remove_member("set", "b").await.unwrap());
+        assert!(!storage.exists("set").await.unwrap());
+        assert!(storage.members("set").await.unwrap().is_empty());
+
+        storage.set("key", "value").await.unwrap();
+        assert!(storage.add_member("key", "a").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sorted_sets() {
+        let storage = MemoryStorage::new();
+
+        assert!(storage.add_scored_member("zset", "a", 1).await.unwrap());
+        assert!(storage.add_scored_member("zset", "b", 2).await.unwrap());
+        assert!(storage.add_scored_member("zset", "c", 2).await.unwrap());
+        assert!(!storage.add_scored_member("zset", "a", 3).await.unwrap());
+
+        let members = storage
+            .scored_members("zset", 2, u64::MAX, 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![(String::from("a"), 3), (String::from("c"), 2)],
+            members
+        );
+        let members = storage.scored_members("zset", 0, 2, 10).await.unwrap();
+        assert_eq!(
+            vec![(String::from("c"), 2), (String::from("b"), 2)],
+            members
+        );
+        let members = storage
+            .scored_members_ascending("zset", 0, u64::MAX, 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![(String::from("b"), 2), (String::from("c"), 2)],
+            members
+        );
+        assert!(storage.add_member("zset", "d").await.is_err());
+
+        for member in &["a", "b", "c"] {
+            assert!(storage.remove_scored_member("zset", member).await.unwrap());
+        }
+        assert!(!storage.exists("zset").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_set_removes_expiration() {
+        let storage = MemoryStorage::new();
+        storage.set("key", "value").await.unwrap();
+        storage.expire("key", 600).await.unwrap();
+
+        storage.set("key", "new value").await.unwrap();
+
+        assert!(storage.entries.get("key").unwrap().expires_at.is_none());
+    }
+}
@@ -0,0 +1,327 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! outbox holds the outbox of the `link.created` events delivered to an `EventSink`, such as the
+//! webhook of shorty-bootstrap, so that no new link goes without its event.
+//!
+//! Storages can't write two keys atomically, sharded Redis included, so the event is written to
+//! the outbox before the link: shortening fails if it can't be. A dispatcher run by the frontend
+//! then delivers the events of the outbox, oldest first, and removes them once delivered, keeping
+//! them for the next round if the sink fails. Events whose link doesn't exist yet are kept too, as
+//! the link may still be being written, until `OUTBOX_GRACE_PERIOD` passes: the link was never
+//! written, or was deleted or expired meanwhile, and the event is dropped.
+//!
+//! Events are delivered at least once: a dispatcher stopping between delivering and removing an
+//! event delivers it again, so sinks tell duplicates apart by the `id` of the event. One dispatcher
+//! at a time delivers events, holding the lease `OUTBOX_DISPATCHER`, so that events are delivered
+//! in order even with many instances.
+//!
+//! The outbox is the sorted set `OUTBOX`, scoring each event with the microsecond it was written.
+
+use std::error::Error;
+use std::future::Future;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::link_sync::micros;
+use crate::{now, Shortener, ShortenerError, CLAIMED_ID, CLAIM_PERIOD};
+
+/// The type of the events of new links
+pub const LINK_CREATED: &str = "link.created";
+
+/// How long an event whose link doesn't exist is kept, in seconds: longer than shortening can
+/// take after writing the event, claiming a custom ID included
+pub const OUTBOX_GRACE_PERIOD: u64 = 2 * CLAIM_PERIOD as u64;
+
+const OUTBOX: &str = "OUTBOX";
+
+const OUTBOX_DISPATCHER: &str = "OUTBOX_DISPATCHER";
+
+/// How long the lease of a dispatcher lasts, in seconds, if it stops without releasing it
+const DISPATCHER_LEASE_PERIOD: usize = 60;
+
+/// The most events delivered in a round
+const DISPATCH_BATCH: usize = 100;
+
+/// An event of the outbox: the `link.created` event of the link `link_id`, shortening `url` `at` a
+/// Unix timestamp, expiring at `expires_at` if ever. `id` is unique to the event.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutboxEvent {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub link_id: String,
+    pub url: String,
+    pub at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+/// `EventSink` delivers the events of the outbox, such as to a webhook or to a message broker.
+///
+/// shorty doesn't make HTTP calls itself: implementations live with the frontends, such as the
+/// webhook of shorty-bootstrap. A sink failing keeps the event in the outbox, delivered again in
+/// the next round.
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    async fn deliver(&self, event: &OutboxEvent) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+impl Shortener {
+    /// Writes the events of new links to the outbox, delivered to `event_sink` by
+    /// `dispatch_events`: see `outbox`
+    pub fn with_event_sink(mut self, event_sink: impl EventSink + 'static) -> Shortener {
+        self.event_sink = Some(Box::new(event_sink));
+        self
+    }
+
+    /// Writes the `link.created` event of the link `id` to the outbox, if there is a sink. Called
+    /// before writing the link, failing its creation if the event can't be written.
+    pub(crate) async fn enqueue_created(
+        &self,
+        id: &str,
+        url: &str,
+        expires_in: Option<usize>,
+    ) -> Result<(), ShortenerError> {
+        if self.event_sink.is_none() {
+            return Ok(());
+        }
+
+        let recorded_at = micros();
+        let now = now();
+        let event = OutboxEvent {
+            id: format!("{}-{}", id, recorded_at),
+            event_type: LINK_CREATED.to_owned(),
+            link_id: id.to_owned(),
+            url: url.to_owned(),
+            at: now,
+            expires_at: expires_in.map(|expires_in| now + expires_in as u64),
+        };
+        let member = serde_json::to_string(&event).unwrap();
+        self.storage
+            .add_scored_member(OUTBOX, &member, recorded_at)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        Ok(())
+    }
+
+    /// Delivers the events of the outbox, a round every `interval`, until `shutdown` completes.
+    /// Without a sink, it waits for `shutdown`.
+    pub async fn dispatch_events(&self, interval: Duration, shutdown: impl Future<Output = ()>) {
+        if self.event_sink.is_none() {
+            return shutdown.await;
+        }
+        tokio::pin!(shutdown);
+
+        loop {
+            if let Err(err) = self.dispatch_outbox().await {
+                log::warn!("unable to dispatch the events of the outbox: {}", err);
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = &mut shutdown => return,
+            }
+        }
+    }
+
+    /// Delivers a round of events of the outbox, oldest first, returning how many were delivered.
+    /// Nothing is delivered while another dispatcher holds the lease, or without a sink.
+    pub async fn dispatch_outbox(&self) -> Result<usize, ShortenerError> {
+        let event_sink = match &self.event_sink {
+            Some(event_sink) => event_sink,
+            None => return Ok(0),
+        };
+        let leased = self
+            .storage
+            .set_if_not_exists_expiring(OUTBOX_DISPATCHER, "true", DISPATCHER_LEASE_PERIOD)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if !leased {
+            return Ok(0);
+        }
+
+        let delivered = self.deliver_events(event_sink.as_ref()).await;
+        if let Err(err) = self.storage.delete(OUTBOX_DISPATCHER).await {
+            log::warn!("unable to release the outbox dispatcher lease: {}", err);
+        }
+        delivered
+    }
+
+    async fn deliver_events(&self, event_sink: &dyn EventSink) -> Result<usize, ShortenerError> {
+        let events = self
+            .storage
+            .scored_members_ascending(OUTBOX, 0, u64::MAX, DISPATCH_BATCH)
+            .await
+            .map_err(ShortenerError::Storage)?;
+
+        let mut delivered = 0;
+        for (member, recorded_at) in events {
+            let event = match serde_json::from_str::<OutboxEvent>(&member) {
+                Ok(event) => event,
+                Err(err) => {
+                    log::warn!("dropping invalid event '{}' of the outbox: {}", member, err);
+                    self.remove_event(&member).await?;
+                    continue;
+                }
+            };
+
+            let exists = self
+                .storage
+                .get_string(&event.link_id)
+                .await
+                .is_ok_and(|url| url != CLAIMED_ID);
+            if !exists {
+                if micros().saturating_sub(recorded_at) > OUTBOX_GRACE_PERIOD * 1_000_000 {
+                    log::debug!("dropping event {}: its link doesn't exist", event.id);
+                    self.remove_event(&member).await?;
+                }
+                continue;
+            }
+
+            // events are delivered in order: a failure stops the round, retried in the next one
+            if let Err(err) = event_sink.deliver(&event).await {
+                log::warn!("unable to deliver event {}: {}", event.id, err);
+                break;
+            }
+            self.remove_event(&member).await?;
+            delivered += 1;
+        }
+        Ok(delivered)
+    }
+
+    async fn remove_event(&self, member: &str) -> Result<(), ShortenerError> {
+        self.storage
+            .remove_scored_member(OUTBOX, member)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::memory_storage::MemoryStorage;
+    use crate::storage::Storage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    /// Records the events delivered, failing while `failing` is set
+    #[derive(Clone, Default)]
+    struct TestSink {
+        delivered: Arc<Mutex<Vec<OutboxEvent>>>,
+        failing: Arc<Mutex<bool>>,
+    }
+
+    #[async_trait]
+    impl EventSink for TestSink {
+        async fn deliver(&self, event: &OutboxEvent) -> Result<(), Box<dyn Error + Send + Sync>> {
+            if *self.failing.lock().unwrap() {
+                return Err("sink unreachable".into());
+            }
+            self.delivered.lock().unwrap().push(event.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_outbox() {
+        let storage = MemoryStorage::new();
+        storage.set("API_KEY_api key", "true").await.unwrap();
+        let sink = TestSink::default();
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10)
+            .with_event_sink(sink.clone());
+
+        let api_key = Some("api key");
+        let options = LinkOptions::default();
+        let first = shortener
+            .shorten(&api_key, None, "http://example.com", &options)
+            .await
+            .unwrap();
+        shortener
+            .shorten_with_id(&api_key, None, "abc", "http://example.org", &options)
+            .await
+            .unwrap();
+
+        // a failing sink keeps the events
+        *sink.failing.lock().unwrap() = true;
+        assert_eq!(0, shortener.dispatch_outbox().await.unwrap());
+        *sink.failing.lock().unwrap() = false;
+
+        // the event of a link still being written waits for it
+        shortener
+            .enqueue_created("pending", "http://example.net", None)
+            .await
+            .unwrap();
+        // while the event of a link never written is dropped after the grace period
+        let stale = OutboxEvent {
+            id: "stale-1".to_owned(),
+            event_type: LINK_CREATED.to_owned(),
+            link_id: "stale".to_owned(),
+            url: "http://example.net".to_owned(),
+            at: 0,
+            expires_at: None,
+        };
+        shortener
+            .storage
+            .add_scored_member(OUTBOX, &serde_json::to_string(&stale).unwrap(), 1)
+            .await
+            .unwrap();
+
+        assert_eq!(2, shortener.dispatch_outbox().await.unwrap());
+        let delivered = sink.delivered.lock().unwrap().clone();
+        assert_eq!(2, delivered.len());
+        assert_eq!(LINK_CREATED, delivered[0].event_type);
+        assert_eq!(first.id(), delivered[0].link_id);
+        assert_eq!("http://example.com", delivered[0].url);
+        assert_eq!("abc", delivered[1].link_id);
+        assert_eq!(0, shortener.dispatch_outbox().await.unwrap());
+
+        let pending = shortener
+            .storage
+            .scored_members(OUTBOX, 0, u64::MAX, 10)
+            .await
+            .unwrap();
+        assert_eq!(1, pending.len());
+        assert!(pending[0].0.contains("pending"));
+
+        // another dispatcher holds the lease
+        shortener
+            .storage
+            .set(OUTBOX_DISPATCHER, "true")
+            .await
+            .unwrap();
+        shortener
+            .shorten(&api_key, None, "http://example.com/other", &options)
+            .await
+            .unwrap();
+        assert_eq!(0, shortener.dispatch_outbox().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_outbox_without_sink() {
+        let storage = MemoryStorage::new();
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+
+        shortener
+            .shorten(&None, None, "http://example.com", &LinkOptions::default())
+            .await
+            .unwrap();
+        assert!(!shortener.storage.exists(OUTBOX).await.unwrap());
+        assert_eq!(0, shortener.dispatch_outbox().await.unwrap());
+    }
+}
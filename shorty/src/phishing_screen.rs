@@ -0,0 +1,186 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! phishing_screen holds `PhishingScreen`, flagging new links whose destination looks like a
+//! phishing page: high-risk keywords (such as `login`, `verify` or `wallet`) in the path or query
+//! of a URL on a young domain. Flagged links are not rejected, but quarantined: see `quarantine`.
+//!
+//! shorty can't tell when a domain was registered, so a domain is young when shorty first saw it
+//! recently: the first time a domain is screened is stored in `DOMAIN_SEEN_<domain>`.
+
+use url::Url;
+
+use crate::{now, Shortener, ShortenerError};
+
+/// `PhishingScreen` scores the path and query of a URL, summing the score of each keyword they
+/// contain, case insensitively. A URL scoring at least `threshold` is flagged if its domain was
+/// first seen less than `young_domain_age` seconds ago: with an age of zero, every domain is young.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhishingScreen {
+    keywords: Vec<(String, u32)>,
+    threshold: u32,
+    young_domain_age: u64,
+}
+
+impl PhishingScreen {
+    /// Creates a new PhishingScreen from a list of keywords and their scores
+    pub fn new(keywords: Vec<(String, u32)>, threshold: u32, young_domain_age: u64) -> Self {
+        PhishingScreen {
+            keywords: keywords
+                .into_iter()
+                .map(|(keyword, score)| (keyword.to_lowercase(), score))
+                .collect(),
+            threshold,
+            young_domain_age,
+        }
+    }
+
+    /// Returns the score of a URL: the sum of the scores of the keywords found in its path and
+    /// query, each keyword counting once
+    pub fn score(&self, url: &Url) -> u32 {
+        let target = format!("{}?{}", url.path(), url.query().unwrap_or_default()).to_lowercase();
+
+        self.keywords
+            .iter()
+            .filter(|(keyword, _)| target.contains(keyword.as_str()))
+            .map(|(_, score)| score)
+            .sum()
+    }
+}
+
+impl Shortener {
+    /// Enables the phishing screen of new links: see `phishing_screen`
+    pub fn with_phishing_screen(mut self, phishing_screen: PhishingScreen) -> Shortener {
+        self.phishing_screen = Some(phishing_screen);
+        self
+    }
+
+    /// Screens the destination of a new link, returning its score if the link must be
+    /// quarantined. Without a phishing screen, nothing is flagged.
+    pub(crate) async fn screen(&self, url: &str) -> Result<Option<u32>, ShortenerError> {
+        let screen = match &self.phishing_screen {
+            Some(screen) => screen,
+            None => return Ok(None),
+        };
+        let url = match Url::parse(url) {
+            Ok(url) => url,
+            Err(_) => return Ok(None),
+        };
+
+        let domain = url.host_str().unwrap_or_default().to_lowercase();
+        let first_seen = self.first_seen(&domain).await?;
+
+        let score = screen.score(&url);
+        let young = screen.young_domain_age == 0
+            || now().saturating_sub(first_seen) < screen.young_domain_age;
+        if score >= screen.threshold && young {
+            log::info!("'{}' scored {} on the phishing screen", url, score);
+            Ok(Some(score))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns when a domain was first seen, recording now if it's a new one
+    async fn first_seen(&self, domain: &str) -> Result<u64, ShortenerError> {
+        let key = format!("DOMAIN_SEEN_{}", domain);
+        let now = now();
+
+        let new_domain = self
+            .storage
+            .set_if_not_exists(&key, &now.to_string())
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if new_domain {
+            return Ok(now);
+        }
+
+        let first_seen = self
+            .storage
+            .get_string(&key)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        Ok(first_seen.parse().unwrap_or(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::storage::Storage;
+
+    use super::*;
+
+    fn screen(young_domain_age: u64) -> PhishingScreen {
+        PhishingScreen::new(
+            vec![
+                (String::from("Login"), 2),
+                (String::from("verify"), 2),
+                (String::from("wallet"), 3),
+            ],
+            4,
+            young_domain_age,
+        )
+    }
+
+    #[test]
+    fn test_score() {
+        let screen = screen(0);
+        let score = |url| screen.score(&Url::parse(url).unwrap());
+
+        assert_eq!(0, score("http://login.example.com/"));
+        assert_eq!(2, score("http://example.com/LOGIN"));
+        assert_eq!(2, score("http://example.com/login/login"));
+        assert_eq!(4, score("http://example.com/login?next=verify"));
+        assert_eq!(5, score("http://example.com/wallet/verify"));
+    }
+
+    #[tokio::test]
+    async fn test_screen() {
+        let storage = MemoryStorage::new();
+        storage
+            .set("DOMAIN_SEEN_old.example.com", "0")
+            .await
+            .unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+        assert_eq!(
+            None,
+            shortener
+                .screen("http://example.com/verify-wallet")
+                .await
+                .unwrap()
+        );
+
+        let shortener = shortener.with_phishing_screen(screen(24 * 60 * 60));
+        assert_eq!(
+            Some(5),
+            shortener
+                .screen("http://example.com/verify-wallet")
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            None,
+            shortener.screen("http://example.com/login").await.unwrap()
+        );
+        assert_eq!(
+            None,
+            shortener
+                .screen("http://old.example.com/verify-wallet")
+                .await
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! private_targets holds the check rejecting links to private, loopback and link-local hosts,
+//! such as `localhost`, `10.0.0.1` or the cloud metadata service at `169.254.169.254`, so that
+//! services following short links, like link previews, can't be pointed at internal addresses.
+//!
+//! Domains are resolved when the link is shortened: a domain resolving to a public address when
+//! shortened and to a private one later is not caught, services following links must still not
+//! trust them.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use url::{Host, Url};
+
+use crate::{Shortener, ShortenerError};
+
+impl Shortener {
+    /// Rejects links to private, loopback and link-local hosts with
+    /// "Invalid URL: private target": see `private_targets`
+    pub fn with_private_targets_rejected(mut self, reject_private_targets: bool) -> Shortener {
+        self.reject_private_targets = reject_private_targets;
+        self
+    }
+
+    /// Fails if private targets are rejected and the host of `url` is, or resolves to, a private
+    /// address. Domains that don't resolve are accepted, as nothing can be reached through them.
+    pub(crate) async fn check_target(&self, url: &str) -> Result<(), ShortenerError> {
+        if !self.reject_private_targets {
+            return Ok(());
+        }
+        let parsed_url = match Url::parse(url) {
+            Ok(parsed_url) => parsed_url,
+            Err(_) => return Ok(()),
+        };
+
+        let private = match parsed_url.host() {
+            None => false,
+            Some(Host::Ipv4(ip)) => is_private(IpAddr::V4(ip)),
+            Some(Host::Ipv6(ip)) => is_private(IpAddr::V6(ip)),
+            Some(Host::Domain(domain)) if is_localhost(domain) => true,
+            Some(Host::Domain(domain)) => {
+                let port = parsed_url.port_or_known_default().unwrap_or(80);
+                match tokio::net::lookup_host((domain, port)).await {
+                    Ok(mut addresses) => addresses.any(|address| is_private(address.ip())),
+                    Err(err) => {
+                        log::debug!("unable to resolve '{}': {}", domain, err);
+                        false
+                    }
+                }
+            }
+        };
+
+        if private {
+            log::info!("refused to shorten '{}': private target", url);
+            return Err(ShortenerError::InvalidUrl("Invalid URL: private target"));
+        }
+        Ok(())
+    }
+}
+
+/// Tells whether `domain` is `localhost` or one of its subdomains, always loopback (RFC 6761)
+fn is_localhost(domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.');
+    domain == "localhost" || domain.ends_with(".localhost")
+}
+
+/// Tells whether `ip` is not a public address: private, loopback, link-local, shared (RFC 6598)
+/// or unspecified, including IPv4 addresses mapped to IPv6
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private_v4(ip),
+            None => is_private_v6(ip),
+        },
+    }
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || (a == 100 && (64..128).contains(&b))
+}
+
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_check_target() {
+        let shortener = Shortener::new(
+            10,
+            vec!['a', 'b', 'c'],
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
+        );
+        let options = LinkOptions::default();
+        assert!(shortener
+            .shorten(&None, None, "http://127.0.0.1/", &options)
+            .await
+            .is_ok());
+
+        let shortener = shortener.with_private_targets_rejected(true);
+        let shorten = |url: &'static str| shortener.shorten(&None, None, url, &options);
+        for url in [
+            "http://localhost:8080/",
+            "http://api.localhost/",
+            "http://127.0.0.1/",
+            "http://169.254.169.254/latest/meta-data/",
+            "10.1.2.3",
+            "https://192.168.0.1/admin",
+            "http://100.64.0.1/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[fe80::1]/",
+            "http://[::ffff:10.0.0.1]/",
+        ] {
+            let err = shorten(url).await.err().unwrap();
+            assert_eq!("Invalid URL: private target", err.to_string(), "{}", url);
+        }
+
+        assert!(shorten("http://93.184.215.14/").await.is_ok());
+        assert!(shorten("http://[2606:2800:21f:cb07:6820:80da:af6b:8b2c]/")
+            .await
+            .is_ok());
+        assert!(shorten("http://172.32.0.1/").await.is_ok());
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! quarantine holds the queue of links flagged by the phishing screen (see `phishing_screen`),
+//! waiting for an operator to review them. Quarantined links are stored, but not found by
+//! `Shortener::lookup` until they are released; rejected links are deleted. Links named by a
+//! takedown request are quarantined as well, with a zero score: see `takedown`.
+//!
+//! The queue is the sorted set `QUARANTINE`, scoring each link ID with the time it was
+//! quarantined. The score given by the phishing screen is stored in `QUARANTINED_<id>`, expiring
+//! with the link: expired links are removed from the queue when they are found missing.
+
+use crate::link_index::MAX_LINKS;
+use crate::takedown::takedown_of_key;
+use crate::{now, Shortener, ShortenerError};
+
+const QUARANTINE: &str = "QUARANTINE";
+
+/// A quarantined link, with its phishing `score`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct QuarantinedLink {
+    pub id: String,
+    pub url: String,
+    pub score: u32,
+    pub quarantined_at: u64,
+}
+
+impl Shortener {
+    /// Returns the quarantined links, newest first, at most `limit` and never more than
+    /// `MAX_LINKS`
+    pub async fn quarantined_links(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<QuarantinedLink>, ShortenerError> {
+        let entries = self
+            .storage
+            .scored_members(QUARANTINE, 0, u64::MAX, limit.min(MAX_LINKS))
+            .await
+            .map_err(ShortenerError::Storage)?;
+
+        let mut links = Vec::with_capacity(entries.len());
+        for (id, quarantined_at) in entries {
+            let url = self.storage.get_string(&id).await;
+            let score = self.storage.get_string(&quarantined_key(&id)).await;
+
+            match (url, score) {
+                (Ok(url), Ok(score)) => links.push(QuarantinedLink {
+                    id,
+                    url,
+                    score: score.parse().unwrap_or(0),
+                    quarantined_at,
+                }),
+                _ => {
+                    self.storage
+                        .remove_scored_member(QUARANTINE, &id)
+                        .await
+                        .map_err(ShortenerError::Storage)?;
+                }
+            }
+        }
+
+        Ok(links)
+    }
+
+    /// Releases a quarantined link, which is found again by `lookup`
+    pub async fn release_quarantined(&self, id: &str) -> Result<(), ShortenerError> {
+        let ticket = self.takedown_of(id).await;
+        if !self.unquarantine(id).await? {
+            return Err(ShortenerError::NotFound("Link not quarantined"));
+        }
+        let reason = if ticket.is_some() {
+            "takedown"
+        } else {
+            "quarantine"
+        };
+        self.record_toggled(id, true, reason).await;
+        self.record_review(id, ticket, true).await;
+
+        Ok(())
+    }
+
+    /// Rejects a quarantined link, deleting it as its owner would
+    pub async fn reject_quarantined(&self, id: &str) -> Result<(), ShortenerError> {
+        if !self.is_quarantined(id).await {
+            return Err(ShortenerError::NotFound("Link not quarantined"));
+        }
+
+        let ticket = self.takedown_of(id).await;
+        let owner = self.storage.get_string(&format!("OWNER_{}", id)).await.ok();
+        self.purge(id, owner.as_deref()).await?;
+        self.record_review(id, ticket, false).await;
+
+        Ok(())
+    }
+
+    /// Adds a new link to the quarantine queue, recording that it was disabled for `reason`
+    pub(crate) async fn quarantine(
+        &self,
+        id: &str,
+        score: u32,
+        expires_in: Option<usize>,
+        reason: &str,
+    ) -> Result<(), ShortenerError> {
+        let key = quarantined_key(id);
+        let score = score.to_string();
+        match expires_in {
+            Some(expires_in) => self.storage.set_expiring(&key, &score, expires_in).await,
+            None => self.storage.set(&key, &score).await,
+        }
+        .map_err(ShortenerError::Storage)?;
+
+        self.storage
+            .add_scored_member(QUARANTINE, id, now())
+            .await
+            .map_err(ShortenerError::Storage)?;
+        self.record_toggled(id, false, reason).await;
+        Ok(())
+    }
+
+    /// Removes a link from the quarantine queue, returning whether it was quarantined
+    pub(crate) async fn unquarantine(&self, id: &str) -> Result<bool, ShortenerError> {
+        self.storage
+            .remove_scored_member(QUARANTINE, id)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        self.storage
+            .delete(&takedown_of_key(id))
+            .await
+            .map_err(ShortenerError::Storage)?;
+        self.storage
+            .delete(&quarantined_key(id))
+            .await
+            .map_err(ShortenerError::Storage)
+    }
+
+    pub(crate) async fn is_quarantined(&self, id: &str) -> bool {
+        self.storage
+            .exists(&quarantined_key(id))
+            .await
+            .unwrap_or(false)
+    }
+}
+
+fn quarantined_key(id: &str) -> String {
+    format!("QUARANTINED_{}", id)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::phishing_screen::PhishingScreen;
+    use crate::storage::Storage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_quarantine() {
+        let storage = MemoryStorage::new();
+        storage.set("API_KEY_api key", "true").await.unwrap();
+
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10)
+            .with_phishing_screen(PhishingScreen::new(vec![(String::from("wallet"), 5)], 5, 0));
+
+        let safe = shortener
+            .shorten(
+                &Some("api key"),
+                None,
+                "http://example.com/blog",
+                &LinkOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert!(!safe.quarantined);
+
+        let mut flagged = Vec::new();
+        for _ in 0..2 {
+            let result = shortener
+                .shorten(
+                    &Some("api key"),
+                    None,
+                    "http://example.com/wallet",
+                    &LinkOptions::default(),
+                )
+                .await
+                .unwrap();
+            assert!(result.quarantined);
+            assert_eq!(None, shortener.lookup(&result.id).await);
+            flagged.push(result.id);
+        }
+
+        let links = shortener.quarantined_links(10).await.unwrap();
+        assert_eq!(2, links.len());
+        assert_eq!("http://example.com/wallet", links[0].url);
+        assert_eq!(5, links[0].score);
+
+        shortener.release_quarantined(&flagged[0]).await.unwrap();
+        assert_eq!(
+            Some(String::from("http://example.com/wallet")),
+            shortener.lookup(&flagged[0]).await
+        );
+
+        shortener.reject_quarantined(&flagged[1]).await.unwrap();
+        assert_eq!(None, shortener.stats(&flagged[1]).await);
+        assert!(shortener.quarantined_links(10).await.unwrap().is_empty());
+
+        let err = shortener.release_quarantined(&safe.id).await.err().unwrap();
+        assert_eq!("Link not quarantined", err.to_string());
+        let err = shortener.reject_quarantined(&safe.id).await.err().unwrap();
+        assert_eq!("Link not quarantined", err.to_string());
+
+        let links = shortener.links("api key", 0, 10).await.unwrap();
+        assert_eq!(2, links.len());
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! rate_limiter holds `RateLimiter`, counting the calls made with each API key and refusing them
+//! once the limit is exceeded
+
+use std::sync::Arc;
+
+use crate::storage::Storage;
+use crate::ShortenerError;
+
+/// `RateLimiter` counts the calls made with an API key during a period of time, on a `Storage`.
+///
+/// Calls are counted on the `RATE_API_KEY_<api key>` key, which expires at the end of the period:
+/// other services sharing the storage with shorty share its limits as well.
+///
+/// Each API key may have its own limit, stored in `RATE_LIMIT_API_KEY_<api key>`, overriding the
+/// default limit of the `RateLimiter`. The period is the same for all API keys.
+pub struct RateLimiter {
+    storage: Arc<dyn Storage>,
+    period: usize,
+    limit: i64,
+}
+
+impl RateLimiter {
+    /// Creates a new RateLimiter
+    ///
+    /// `period` is the amount of seconds during which calls will be counted.
+    ///
+    /// `limit` is the default max number of calls that can be made in a period. Zero or a negative
+    /// limit disables rate limiting.
+    pub fn new(storage: Arc<dyn Storage>, period: usize, limit: i64) -> RateLimiter {
+        RateLimiter {
+            storage,
+            period,
+            limit,
+        }
+    }
+
+    /// Counts a call made with `api_key`, returning an error if the limit of the current period
+    /// has been exceeded. The API key itself is not verified: see `Shortener::verify_key`.
+    pub async fn check(&self, api_key: &str) -> Result<(), ShortenerError> {
+        self.check_calls(api_key, 1).await
+    }
+
+    /// Counts `calls` calls made at once with `api_key`, such as the URLs of a batch, as `check`
+    /// does. The calls are counted even if they exceed the limit.
+    pub async fn check_calls(&self, api_key: &str, calls: i64) -> Result<(), ShortenerError> {
+        let limit = self.limit_of(api_key).await;
+        if limit <= 0 {
+            return Ok(());
+        }
+
+        let rate_key = format!("RATE_API_KEY_{}", api_key);
+        log::trace!("verifying rate key '{}'", rate_key);
+
+        let number_of_calls = self
+            .storage
+            .increment_expiring_by(&rate_key, calls, self.period)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        log::trace!("rate key {} number of calls {}", rate_key, number_of_calls);
+
+        if number_of_calls > limit {
+            // the period ends when the rate key expires: if its TTL is unknown, wait a whole period
+            let retry_after = match self.storage.ttl(&rate_key).await {
+                Ok(Some(ttl)) if ttl > 0 => ttl,
+                _ => self.period as u64,
+            };
+            return Err(ShortenerError::RateLimited { retry_after });
+        }
+
+        Ok(())
+    }
+
+    /// Returns the limit of `api_key`: its own, if any, otherwise the default one
+    pub async fn limit_of(&self, api_key: &str) -> i64 {
+        let limit = match self.storage.get_string(&limit_key(api_key)).await {
+            Ok(limit) => limit,
+            Err(_) => return self.limit,
+        };
+
+        limit.parse().unwrap_or_else(|_| {
+            log::warn!("invalid rate limit '{}' of an API key", limit);
+            self.limit
+        })
+    }
+}
+
+/// The key holding the rate limit of `api_key`
+pub(crate) fn limit_key(api_key: &str) -> String {
+    format!("RATE_LIMIT_API_KEY_{}", api_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_check() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let rate_limiter = RateLimiter::new(storage.clone(), 600, 2);
+
+        assert!(rate_limiter.check("api key").await.is_ok());
+        assert!(rate_limiter.check("api key").await.is_ok());
+        let err = rate_limiter.check("api key").await.err().unwrap();
+        assert_eq!("Rate limit exceeded", err.to_string());
+        let retry_after = err.retry_after().unwrap();
+        assert!(retry_after > 0 && retry_after <= 600);
+
+        assert!(rate_limiter.check("other api key").await.is_ok());
+        assert_eq!(
+            "3",
+            storage.get_string("RATE_API_KEY_api key").await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_calls() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let rate_limiter = RateLimiter::new(storage.clone(), 600, 10);
+
+        assert!(rate_limiter.check_calls("api key", 8).await.is_ok());
+        assert!(rate_limiter.check_calls("api key", 3).await.is_err());
+        assert_eq!(
+            "11",
+            storage.get_string("RATE_API_KEY_api key").await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_per_key_limit() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        storage
+            .set("RATE_LIMIT_API_KEY_api key", "1")
+            .await
+            .unwrap();
+        storage
+            .set("RATE_LIMIT_API_KEY_unlimited key", "0")
+            .await
+            .unwrap();
+        let rate_limiter = RateLimiter::new(storage.clone(), 600, 2);
+
+        assert_eq!(1, rate_limiter.limit_of("api key").await);
+        assert_eq!(2, rate_limiter.limit_of("other api key").await);
+
+        assert!(rate_limiter.check("api key").await.is_ok());
+        assert!(rate_limiter.check("api key").await.is_err());
+        for _ in 0..3 {
+            assert!(rate_limiter.check("unlimited key").await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_disabled() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let rate_limiter = RateLimiter::new(storage.clone(), 600, 0);
+
+        assert!(rate_limiter.check("api key").await.is_ok());
+        assert!(!storage.exists("RATE_API_KEY_api key").await.unwrap());
+    }
+}
@@ -14,45 +14,1075 @@
 
 //! redis_facade is a convenience module holding `RedisFacade`
 
-use redis::Commands;
-use redis::{Connection, RedisResult};
+use std::convert::TryFrom;
+use std::future::Future;
+use std::time::Duration;
 
-use std::str::FromStr;
+use async_trait::async_trait;
+use deadpool_redis::{Connection, Manager, Pool, PoolError};
+use redis::aio::{ConnectionLike, MultiplexedConnection};
+use redis::cluster::ClusterClient;
+use redis::cluster_async::ClusterConnection;
+use redis::sentinel::{SentinelClient, SentinelNodeConnectionInfo, SentinelServerType};
+use redis::{
+    AsyncCommands, Client, Cmd, ConnectionAddr, ConnectionInfo, ErrorKind, FromRedisValue,
+    IntoConnectionInfo, Pipeline, RedisConnectionInfo, RedisError, RedisFuture, RedisResult,
+    Script, TlsMode, Value,
+};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
 
-/// `RedisFacade` is a wrapper around a `redis` `Connection`. It provides convenience methods such
-/// as `get_string` and `get_bool` which otherwise would be coded as `get::<_, String>` and
-/// `get::<_, bool>`, making it harder to stub the struct and properly test `shorty`.
-pub struct RedisFacade(Connection);
+use crate::hash_ring::HashRing;
+use crate::storage::{Storage, StorageError, StorageResult};
+
+/// Increments `KEYS[1]` by `ARGV[2]` and, if it has no expiration, makes it expire after `ARGV[1]`
+/// seconds.
+/// Checking the TTL instead of the incremented value also fixes counters left without an
+/// expiration.
+const INCREMENT_EXPIRING: &str = r"
+local value = redis.call('INCRBY', KEYS[1], ARGV[2])
+if redis.call('TTL', KEYS[1]) == -1 then
+    redis.call('EXPIRE', KEYS[1], ARGV[1])
+end
+return value
+";
+
+/// `RedisFacade` is the Redis `Storage`: a wrapper around `redis` async connections, providing
+/// methods such as `get_string` which otherwise would be coded as `get::<_, String>`.
+///
+/// A `RedisFacade` talks to one of:
+/// - one or more standalone Redis instances, using consistent hashing to pick the instance owning
+///   each key (see `connect`)
+/// - the master of a set of instances monitored by Redis Sentinel, asking the sentinels for its
+///   address again whenever the connection is lost, such as after a failover (see
+///   `connect_sentinel`)
+/// - a Redis Cluster, sending each command to the node owning the slot of its key (see
+///   `connect_cluster`)
+///
+/// Standalone instances are reached through pools of connections, reused across calls: a
+/// `RedisFacade` can be shared by any number of concurrent tasks, each command taking a connection
+/// from the pool and giving it back when done. The master of Sentinel and the nodes of a cluster
+/// are reached through a single multiplexed connection each, shared by all tasks.
+///
+/// When a connection drops, the pool replaces it with a new one, and commands failing for a
+/// connection error are retried (see `with_retries`). Once retries are exhausted, the command
+/// fails with `StorageError::Unavailable`. A command is retried even if Redis ran it before the
+/// connection dropped: retried increments may be counted twice.
+pub struct RedisFacade {
+    backend: Backend,
+    retries: u32,
+    backoff: Duration,
+}
+
+/// The Redis deployment a `RedisFacade` talks to
+enum Backend {
+    Shards {
+        pools: Vec<Pool>,
+        ring: HashRing,
+    },
+    Sentinel(Box<SentinelMaster>),
+    Cluster {
+        connection: ClusterConnection,
+        options: ConnectionOptions,
+    },
+}
+
+/// What `RedisFacade::rebalance_page` did on a page: the keys `scanned`, those `moved` to their
+/// server, those `conflicting` with a key of their server, and the `cursor` of the next page, if
+/// any
+#[derive(Debug, Default, PartialEq)]
+pub struct RebalancedPage {
+    pub scanned: usize,
+    pub moved: usize,
+    pub conflicting: Vec<String>,
+    pub cursor: Option<String>,
+}
+
+/// How to connect to each Redis server, besides its `host:port`
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionOptions {
+    /// The password sent with `AUTH`, if any
+    pub password: Option<String>,
+    /// Connects with TLS, as `rediss://` URLs do, verifying the certificate of the server
+    pub tls: bool,
+    /// The database to use: Redis Cluster only has database 0
+    pub db: i64,
+}
+
+impl ConnectionOptions {
+    fn connection_info(&self, host_and_port: &str) -> RedisResult<ConnectionInfo> {
+        let addr = match format!("redis://{}/", host_and_port)
+            .into_connection_info()?
+            .addr
+        {
+            ConnectionAddr::Tcp(host, port) if self.tls => ConnectionAddr::TcpTls {
+                host,
+                port,
+                insecure: false,
+            },
+            addr => addr,
+        };
+
+        Ok(ConnectionInfo {
+            addr,
+            redis: self.redis_connection_info(),
+        })
+    }
+
+    fn redis_connection_info(&self) -> RedisConnectionInfo {
+        RedisConnectionInfo {
+            db: self.db,
+            username: None,
+            password: self.password.clone(),
+        }
+    }
+}
 
 impl RedisFacade {
-    /// Creates a new `RedisFacade`, owning an active `redis` `Connection`
-    pub fn new(redis: Connection) -> RedisFacade {
-        RedisFacade(redis)
+    /// Creates a new `RedisFacade`, taking connections from a `deadpool_redis` pool
+    pub fn new(redis: Pool) -> RedisFacade {
+        RedisFacade::with_backend(Backend::Shards {
+            pools: vec![redis],
+            ring: HashRing::new(&["default"]),
+        })
+    }
+
+    /// Creates a new `RedisFacade` sharding keys across multiple Redis instances. Each shard is
+    /// given as a pair of a stable name (such as `host:port`) and a pool of connections to it.
+    pub fn new_sharded(shards: Vec<(String, Pool)>) -> RedisFacade {
+        let (names, pools): (Vec<String>, Vec<Pool>) = shards.into_iter().unzip();
+
+        RedisFacade::with_backend(Backend::Shards {
+            ring: HashRing::new(&names),
+            pools,
+        })
+    }
+
+    fn with_backend(backend: Backend) -> RedisFacade {
+        RedisFacade {
+            backend,
+            retries: 0,
+            backoff: Duration::from_millis(0),
+        }
+    }
+
+    /// Creates a pool of at most `pool_size` connections to each of the given `host:port` Redis
+    /// instances, and a new `RedisFacade` sharding keys across them. A first connection to each
+    /// instance is opened right away, so that unreachable instances are reported here.
+    pub async fn connect(
+        shards: &[String],
+        pool_size: usize,
+        options: &ConnectionOptions,
+    ) -> RedisResult<RedisFacade> {
+        let mut pools = Vec::with_capacity(shards.len());
+        for shard in shards {
+            let manager = Manager::new(options.connection_info(shard)?)?;
+            let pool = Pool::builder(manager)
+                .max_size(pool_size)
+                .build()
+                .map_err(|err| {
+                    RedisError::from((
+                        ErrorKind::ClientError,
+                        "Unable to create the connection pool",
+                        err.to_string(),
+                    ))
+                })?;
+            pool.get().await.map_err(pool_error)?;
+            pools.push((shard.clone(), pool));
+        }
+
+        Ok(RedisFacade::new_sharded(pools))
+    }
+
+    /// Creates a new `RedisFacade` talking to the master named `master_name`, whose address is
+    /// asked to the given `host:port` sentinels. The master is connected to right away, so that a
+    /// missing master is reported here.
+    ///
+    /// The sentinels are connected to with the TLS setting of `options`, but without password
+    /// nor database, which only apply to the master.
+    pub async fn connect_sentinel(
+        sentinels: &[String],
+        master_name: &str,
+        options: &ConnectionOptions,
+    ) -> RedisResult<RedisFacade> {
+        let sentinel_options = ConnectionOptions {
+            tls: options.tls,
+            ..ConnectionOptions::default()
+        };
+        let master_info = SentinelNodeConnectionInfo {
+            tls_mode: if options.tls {
+                Some(TlsMode::Secure)
+            } else {
+                None
+            },
+            redis_connection_info: Some(options.redis_connection_info()),
+        };
+        let client = SentinelClient::build(
+            sentinels
+                .iter()
+                .map(|sentinel| sentinel_options.connection_info(sentinel))
+                .collect::<RedisResult<Vec<_>>>()?,
+            master_name.to_owned(),
+            Some(master_info),
+            SentinelServerType::Master,
+        )?;
+        let master = SentinelMaster {
+            client: Mutex::new(client),
+            connection: Mutex::new(None),
+        };
+        master.connection().await?;
+
+        Ok(RedisFacade::with_backend(Backend::Sentinel(Box::new(
+            master,
+        ))))
+    }
+
+    /// Creates a new `RedisFacade` talking to the Redis Cluster the given `host:port` nodes belong
+    /// to. The other nodes are discovered from them, and connected to with the same `options`.
+    pub async fn connect_cluster(
+        nodes: &[String],
+        options: &ConnectionOptions,
+    ) -> RedisResult<RedisFacade> {
+        if options.db != 0 {
+            return Err(RedisError::from((
+                ErrorKind::InvalidClientConfig,
+                "Redis Cluster only has database 0",
+            )));
+        }
+
+        let client = ClusterClient::new(
+            nodes
+                .iter()
+                .map(|node| options.connection_info(node))
+                .collect::<RedisResult<Vec<_>>>()?,
+        )?;
+        let connection = client.get_async_connection().await?;
+
+        Ok(RedisFacade::with_backend(Backend::Cluster {
+            connection,
+            options: options.clone(),
+        }))
+    }
+
+    /// Retries commands failing for a connection error up to `retries` times, waiting `backoff`
+    /// before the first retry and doubling the wait before each of the next ones. By default,
+    /// commands are not retried.
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> RedisFacade {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Moves the keys of a page of `scan_page` stored on another server than the one the hash ring
+    /// gives them, such as after adding a server to the shards, to their server: see `move_key`.
+    /// Keys found on their server too are left where they are, and returned as conflicting.
+    ///
+    /// Only sharded standalone servers can hold keys on the wrong server: on Sentinel and Cluster,
+    /// pages are scanned with nothing to move.
+    pub async fn rebalance_page(
+        &self,
+        cursor: &str,
+        count: usize,
+    ) -> StorageResult<RebalancedPage> {
+        let (keys, next) = self.scan_page("", cursor, count).await?;
+        let mut page = RebalancedPage {
+            scanned: keys.len(),
+            moved: 0,
+            conflicting: Vec::new(),
+            cursor: next,
+        };
+
+        let (server, _) = parse_scan_cursor(cursor)?;
+        let (pool, ring) = match &self.backend {
+            Backend::Shards { pools, ring } if server < pools.len() => (&pools[server], ring),
+            _ => return Ok(page),
+        };
+        for key in keys.iter().filter(|key| ring.node_for(key) != server) {
+            if self.move_key(|| pooled(pool), key, key).await? {
+                page.moved += 1;
+                continue;
+            }
+            let left = self
+                .run_with(
+                    || pooled(pool),
+                    |mut redis| async move { redis.exists::<_, bool>(key).await },
+                )
+                .await?;
+            if left {
+                page.conflicting.push(key.clone());
+            }
+        }
+        Ok(page)
+    }
+
+    /// Runs a Lua `script` on the server holding `key`, passed as its only key, with `args`.
+    /// Scripts run atomically, and must only touch `key`: other keys may be on other servers.
+    pub async fn eval<T: FromRedisValue + Send>(
+        &self,
+        script: &Script,
+        key: &str,
+        args: &[&str],
+    ) -> StorageResult<T> {
+        let mut invocation = script.key(key);
+        for arg in args {
+            invocation.arg(*arg);
+        }
+        let invocation = &invocation;
+
+        self.run(key, |mut redis| async move {
+            invocation.invoke_async(&mut redis).await
+        })
+        .await
     }
 
-    pub fn get_string(&self, key: &str) -> RedisResult<String> {
-        self.0.get::<_, String>(key)
+    /// Runs a command on a connection to the server holding `key`, see `run_with`
+    async fn run<T, F, Fut>(&self, key: &str, command: F) -> StorageResult<T>
+    where
+        F: Fn(RedisConnection) -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        self.run_with(|| self.connection_for(key), command).await
     }
 
-    pub fn get_bool(&self, key: &str) -> RedisResult<bool> {
-        self.get_string(key)
-            .map(|value| FromStr::from_str(&value).unwrap_or(false))
-            .or(Ok(false))
+    /// Runs a command on every server: each standalone instance, the master of Sentinel or each
+    /// master of the cluster
+    async fn run_on_each<T, F, Fut>(&self, command: F) -> StorageResult<Vec<T>>
+    where
+        F: Fn(RedisConnection) -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        let mut results = Vec::new();
+        match &self.backend {
+            Backend::Shards { pools, .. } => {
+                for pool in pools {
+                    results.push(self.run_with(|| pooled(pool), &command).await?);
+                }
+            }
+            Backend::Sentinel(_) => {
+                results.push(self.run_with(|| self.connection_for(""), &command).await?);
+            }
+            Backend::Cluster { options, .. } => {
+                let nodes = self
+                    .run_with(
+                        || self.connection_for(""),
+                        |mut redis| async move {
+                            redis::cmd("CLUSTER")
+                                .arg("NODES")
+                                .query_async::<_, String>(&mut redis)
+                                .await
+                        },
+                    )
+                    .await?;
+                for master in cluster_masters(&nodes) {
+                    results.push(self.run_with(|| direct(master, options), &command).await?);
+                }
+            }
+        }
+        Ok(results)
     }
 
-    pub fn exists(&self, key: &str) -> RedisResult<bool> {
-        self.0.exists::<_, bool>(key)
+    /// Runs a command on the server at `index` among those of `run_on_each`, if any. The masters
+    /// of a cluster are sorted by address, so that indexes are stable while the cluster is.
+    async fn run_on_nth<T, F, Fut>(&self, index: usize, command: F) -> StorageResult<Option<T>>
+    where
+        F: Fn(RedisConnection) -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        match &self.backend {
+            Backend::Shards { pools, .. } => match pools.get(index) {
+                Some(pool) => self.run_with(|| pooled(pool), command).await.map(Some),
+                None => Ok(None),
+            },
+            Backend::Sentinel(_) if index == 0 => self
+                .run_with(|| self.connection_for(""), command)
+                .await
+                .map(Some),
+            Backend::Sentinel(_) => Ok(None),
+            Backend::Cluster { options, .. } => {
+                let nodes = self
+                    .run_with(
+                        || self.connection_for(""),
+                        |mut redis| async move {
+                            redis::cmd("CLUSTER")
+                                .arg("NODES")
+                                .query_async::<_, String>(&mut redis)
+                                .await
+                        },
+                    )
+                    .await?;
+                let mut masters = cluster_masters(&nodes);
+                masters.sort_unstable();
+                match masters.get(index) {
+                    Some(master) => self
+                        .run_with(|| direct(master, options), command)
+                        .await
+                        .map(Some),
+                    None => Ok(None),
+                }
+            }
+        }
     }
 
-    pub fn increment(&self, key: &str) -> RedisResult<i64> {
-        self.0.incr::<_, _, i64>(key, 1)
+    /// Runs a command on a connection opened by `connect`, retrying it on connection errors
+    async fn run_with<T, C, CFut, F, Fut>(&self, connect: C, command: F) -> StorageResult<T>
+    where
+        C: Fn() -> CFut,
+        CFut: Future<Output = RedisResult<RedisConnection>>,
+        F: Fn(RedisConnection) -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        let mut attempts = 0;
+        loop {
+            let result = match connect().await {
+                Ok(connection) => command(connection).await,
+                Err(err) => Err(err),
+            };
+            attempts += 1;
+
+            match result {
+                Err(err) if is_connection_error(&err) => {
+                    if let Backend::Sentinel(master) = &self.backend {
+                        master.reset().await;
+                    }
+                    if attempts > self.retries {
+                        return Err(StorageError::Unavailable {
+                            attempts,
+                            cause: err,
+                        });
+                    }
+
+                    let backoff = self.backoff * 2_u32.saturating_pow(attempts - 1);
+                    log::warn!("Redis command failed, retrying in {:?}: {}", backoff, err);
+                    sleep(backoff).await;
+                }
+                result => return result.map_err(StorageError::from),
+            }
+        }
+    }
+
+    /// Returns a connection to the server holding `key`
+    async fn connection_for(&self, key: &str) -> RedisResult<RedisConnection> {
+        match &self.backend {
+            Backend::Shards { pools, ring } => pooled(&pools[ring.node_for(key)]).await,
+            Backend::Sentinel(master) => {
+                master.connection().await.map(RedisConnection::Multiplexed)
+            }
+            Backend::Cluster { connection, .. } => Ok(RedisConnection::Cluster(connection.clone())),
+        }
+    }
+
+    /// Moves `key`, read on the server `source` connects to, to `new_key` on the server holding
+    /// it, with `DUMP` and `RESTORE`, then deletes it: a key written meanwhile loses the write.
+    /// Returns whether it was moved, `false` meaning that `key` doesn't exist or that `new_key`
+    /// does.
+    async fn move_key<C, CFut>(&self, source: C, key: &str, new_key: &str) -> StorageResult<bool>
+    where
+        C: Fn() -> CFut,
+        CFut: Future<Output = RedisResult<RedisConnection>>,
+    {
+        let (payload, ttl) = self
+            .run_with(&source, |mut redis| async move {
+                redis::pipe()
+                    .cmd("DUMP")
+                    .arg(key)
+                    .cmd("PTTL")
+                    .arg(key)
+                    .query_async::<_, (Option<Vec<u8>>, i64)>(&mut redis)
+                    .await
+            })
+            .await?;
+        let payload = match payload {
+            Some(payload) => payload,
+            None => return Ok(false),
+        };
+
+        let payload = &payload;
+        let restored = self
+            .run(new_key, |mut redis| async move {
+                // a TTL of 0 restores a key without expiration
+                redis::cmd("RESTORE")
+                    .arg(new_key)
+                    .arg(ttl.max(0))
+                    .arg(payload)
+                    .query_async::<_, ()>(&mut redis)
+                    .await
+            })
+            .await;
+        match restored {
+            Ok(()) => self
+                .run_with(&source, |mut redis| async move {
+                    redis.del::<_, bool>(key).await
+                })
+                .await
+                .map(|_| true),
+            Err(StorageError::Redis(err)) if err.code() == Some("BUSYKEY") => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// `SentinelMaster` holds the connection to the master of Sentinel, asking the sentinels for the
+/// address of the master when there's no connection yet
+struct SentinelMaster {
+    client: Mutex<SentinelClient>,
+    connection: Mutex<Option<MultiplexedConnection>>,
+}
+
+impl SentinelMaster {
+    async fn connection(&self) -> RedisResult<MultiplexedConnection> {
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = connection.as_ref() {
+            return Ok(connection.clone());
+        }
+
+        let master = self.client.lock().await.get_async_connection().await?;
+        *connection = Some(master.clone());
+        Ok(master)
+    }
+
+    /// Drops the connection to the master, which may have been demoted: the next command asks the
+    /// sentinels for the current one
+    async fn reset(&self) {
+        *self.connection.lock().await = None;
+    }
+}
+
+/// A connection to Redis, whichever the `Backend` it comes from
+enum RedisConnection {
+    Pooled(Connection),
+    Multiplexed(MultiplexedConnection),
+    Cluster(ClusterConnection),
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            RedisConnection::Pooled(redis) => redis.req_packed_command(cmd),
+            RedisConnection::Multiplexed(redis) => redis.req_packed_command(cmd),
+            RedisConnection::Cluster(redis) => redis.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipeline: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            RedisConnection::Pooled(redis) => redis.req_packed_commands(pipeline, offset, count),
+            RedisConnection::Multiplexed(redis) => {
+                redis.req_packed_commands(pipeline, offset, count)
+            }
+            RedisConnection::Cluster(redis) => redis.req_packed_commands(pipeline, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConnection::Pooled(redis) => redis.get_db(),
+            RedisConnection::Multiplexed(redis) => redis.get_db(),
+            RedisConnection::Cluster(redis) => redis.get_db(),
+        }
     }
+}
 
-    pub fn expire(&self, key: &str, period: usize) -> RedisResult<()> {
-        self.0.expire::<_, ()>(key, period)
+/// Builds the pipeline setting `entries`, see `Storage::set_many`
+fn set_pipeline(entries: &[&(String, String, Option<usize>)]) -> Pipeline {
+    let mut pipeline = redis::pipe();
+    for (key, value, period) in entries {
+        match period {
+            Some(period) => pipeline.set_ex(key, value, *period),
+            None => pipeline.set(key, value),
+        }
+        .ignore();
     }
+    pipeline
+}
+
+/// Takes a connection from `pool`
+async fn pooled(pool: &Pool) -> RedisResult<RedisConnection> {
+    pool.get()
+        .await
+        .map(RedisConnection::Pooled)
+        .map_err(pool_error)
+}
+
+/// Parses a cursor of `scan_page` into the index of the server and the cursor of `SCAN` on it
+fn parse_scan_cursor(cursor: &str) -> StorageResult<(usize, u64)> {
+    match cursor {
+        "" => Ok((0, 0)),
+        cursor => cursor
+            .split_once('-')
+            .and_then(|(server, position)| Some((server.parse().ok()?, position.parse().ok()?)))
+            .ok_or_else(|| {
+                StorageError::Redis(RedisError::from((
+                    ErrorKind::ClientError,
+                    "invalid scan cursor",
+                )))
+            }),
+    }
+}
+
+/// Opens a connection to the `host:port` node of a cluster, bypassing the routing of the cluster
+async fn direct(node: &str, options: &ConnectionOptions) -> RedisResult<RedisConnection> {
+    Client::open(options.connection_info(node)?)?
+        .get_multiplexed_tokio_connection()
+        .await
+        .map(RedisConnection::Multiplexed)
+}
+
+/// Returns the `host:port` of the reachable masters listed in the output of `CLUSTER NODES`
+fn cluster_masters(nodes: &str) -> Vec<&str> {
+    nodes
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let flags = fields.get(2)?.split(',').collect::<Vec<_>>();
+            if !flags.contains(&"master") || flags.iter().any(|flag| flag.starts_with("fail")) {
+                return None;
+            }
+            // ip:port@cport, optionally followed by ,hostname
+            let address = fields.get(1)?.split('@').next()?;
+            Some(address).filter(|address| !address.starts_with(':'))
+        })
+        .collect()
+}
+
+#[async_trait]
+impl Storage for RedisFacade {
+    async fn get_string(&self, key: &str) -> StorageResult<String> {
+        self.run(key, |mut redis| async move { redis.get(key).await })
+            .await
+    }
+
+    async fn exists(&self, key: &str) -> StorageResult<bool> {
+        self.run(key, |mut redis| async move { redis.exists(key).await })
+            .await
+    }
+
+    async fn increment(&self, key: &str) -> StorageResult<i64> {
+        self.run(key, |mut redis| async move { redis.incr(key, 1).await })
+            .await
+    }
+
+    async fn increment_expiring(&self, key: &str, period: usize) -> StorageResult<i64> {
+        self.eval(
+            &Script::new(INCREMENT_EXPIRING),
+            key,
+            &[&period.to_string(), "1"],
+        )
+        .await
+    }
+
+    async fn increment_expiring_by(
+        &self,
+        key: &str,
+        amount: i64,
+        period: usize,
+    ) -> StorageResult<i64> {
+        self.eval(
+            &Script::new(INCREMENT_EXPIRING),
+            key,
+            &[&period.to_string(), &amount.to_string()],
+        )
+        .await
+    }
+
+    async fn expire(&self, key: &str, period: usize) -> StorageResult<()> {
+        self.run(
+            key,
+            |mut redis| async move { redis.expire(key, period).await },
+        )
+        .await
+    }
+
+    async fn ttl(&self, key: &str) -> StorageResult<Option<u64>> {
+        // TTL answers -2 for missing keys, and -1 for keys without expiration
+        let ttl: i64 = self
+            .run(key, |mut redis| async move { redis.ttl(key).await })
+            .await?;
+        Ok(u64::try_from(ttl).ok())
+    }
+
+    async fn set(&self, key: &str, value: &str) -> StorageResult<()> {
+        self.run(key, |mut redis| async move { redis.set(key, value).await })
+            .await
+    }
+
+    async fn set_expiring(&self, key: &str, value: &str, period: usize) -> StorageResult<()> {
+        self.run(key, |mut redis| async move {
+            redis.set_ex(key, value, period).await
+        })
+        .await
+    }
+
+    /// Sends the keys of each standalone instance, or of the master of Sentinel, in one pipeline.
+    /// Keys of a cluster are set one by one, as they may belong to different nodes.
+    async fn set_many(&self, entries: &[(String, String, Option<usize>)]) -> StorageResult<()> {
+        match &self.backend {
+            Backend::Shards { pools, ring } => {
+                for (node, pool) in pools.iter().enumerate() {
+                    let entries = entries
+                        .iter()
+                        .filter(|(key, _, _)| ring.node_for(key) == node)
+                        .collect::<Vec<_>>();
+                    if !entries.is_empty() {
+                        let pipeline = &set_pipeline(&entries);
+                        self.run_with(
+                            || pooled(pool),
+                            |mut redis| async move { pipeline.query_async::<_, ()>(&mut redis).await },
+                        )
+                        .await?;
+                    }
+                }
+            }
+            Backend::Sentinel(_) => {
+                let pipeline = &set_pipeline(&entries.iter().collect::<Vec<_>>());
+                self.run("", |mut redis| async move {
+                    pipeline.query_async::<_, ()>(&mut redis).await
+                })
+                .await?;
+            }
+            Backend::Cluster { .. } => {
+                for (key, value, period) in entries {
+                    match period {
+                        Some(period) => self.set_expiring(key, value, *period).await?,
+                        None => self.set(key, value).await?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_if_not_exists(&self, key: &str, value: &str) -> StorageResult<bool> {
+        self.run(
+            key,
+            |mut redis| async move { redis.set_nx(key, value).await },
+        )
+        .await
+    }
+
+    async fn set_if_not_exists_expiring(
+        &self,
+        key: &str,
+        value: &str,
+        period: usize,
+    ) -> StorageResult<bool> {
+        let reply = self
+            .run(key, |mut redis| async move {
+                redis::cmd("SET")
+                    .arg(key)
+                    .arg(value)
+                    .arg("NX")
+                    .arg("EX")
+                    .arg(period)
+                    .query_async::<_, Option<String>>(&mut redis)
+                    .await
+            })
+            .await?;
+        Ok(reply.is_some())
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<bool> {
+        self.run(key, |mut redis| async move { redis.del(key).await })
+            .await
+    }
+
+    /// Renames with `RENAMENX` when both keys are on the same server, and otherwise moves the
+    /// key with `DUMP` and `RESTORE`, then deletes it: a key written meanwhile loses the write
+    async fn rename(&self, key: &str, new_key: &str) -> StorageResult<bool> {
+        let same_server = match &self.backend {
+            Backend::Shards { ring, .. } => ring.node_for(key) == ring.node_for(new_key),
+            Backend::Sentinel(_) => true,
+            // keys of different slots can't be renamed, even on the same node
+            Backend::Cluster { .. } => false,
+        };
+        if same_server {
+            return self
+                .run(key, |mut redis| async move {
+                    redis.rename_nx(key, new_key).await
+                })
+                .await;
+        }
+
+        self.move_key(|| self.connection_for(key), key, new_key)
+            .await
+    }
+
+    async fn add_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        self.run(
+            key,
+            |mut redis| async move { redis.sadd(key, member).await },
+        )
+        .await
+    }
+
+    async fn remove_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        self.run(
+            key,
+            |mut redis| async move { redis.srem(key, member).await },
+        )
+        .await
+    }
+
+    async fn members(&self, key: &str) -> StorageResult<Vec<String>> {
+        self.run(key, |mut redis| async move { redis.smembers(key).await })
+            .await
+    }
+
+    async fn is_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        self.run(key, |mut redis| async move {
+            redis.sismember(key, member).await
+        })
+        .await
+    }
+
+    async fn add_scored_member(&self, key: &str, member: &str, score: u64) -> StorageResult<bool> {
+        self.run(key, |mut redis| async move {
+            redis.zadd(key, member, score).await
+        })
+        .await
+    }
+
+    async fn remove_scored_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        self.run(
+            key,
+            |mut redis| async move { redis.zrem(key, member).await },
+        )
+        .await
+    }
+
+    async fn scored_members(
+        &self,
+        key: &str,
+        min_score: u64,
+        max_score: u64,
+        limit: usize,
+    ) -> StorageResult<Vec<(String, u64)>> {
+        self.run(key, |mut redis| async move {
+            redis
+                .zrevrangebyscore_limit_withscores(key, max_score, min_score, 0, limit as isize)
+                .await
+        })
+        .await
+    }
+
+    async fn scored_members_ascending(
+        &self,
+        key: &str,
+        min_score: u64,
+        max_score: u64,
+        limit: usize,
+    ) -> StorageResult<Vec<(String, u64)>> {
+        self.run(key, |mut redis| async move {
+            redis
+                .zrangebyscore_limit_withscores(key, min_score, max_score, 0, limit as isize)
+                .await
+        })
+        .await
+    }
+
+    async fn scan(&self, prefix: &str) -> StorageResult<Vec<String>> {
+        let pattern = &format!("{}*", escape_pattern(prefix));
+        let keys = self
+            .run_on_each(|mut redis| async move {
+                let mut iter = redis.scan_match::<_, String>(pattern).await?;
+                let mut keys = Vec::new();
+                while let Some(key) = iter.next_item().await {
+                    keys.push(key);
+                }
+                Ok(keys)
+            })
+            .await?;
+        Ok(keys.into_iter().flatten().collect())
+    }
+
+    /// Pages with `SCAN` each server in turn: cursors are the index of the server and the cursor of
+    /// `SCAN` on it, such as `0-1234`
+    async fn scan_page(
+        &self,
+        prefix: &str,
+        cursor: &str,
+        count: usize,
+    ) -> StorageResult<(Vec<String>, Option<String>)> {
+        let (server, position) = parse_scan_cursor(cursor)?;
+        let pattern = &format!("{}*", escape_pattern(prefix));
+
+        let page = self
+            .run_on_nth(server, |mut redis| async move {
+                redis::cmd("SCAN")
+                    .arg(position)
+                    .arg("MATCH")
+                    .arg(pattern)
+                    .arg("COUNT")
+                    .arg(count.max(1))
+                    .query_async::<_, (u64, Vec<String>)>(&mut redis)
+                    .await
+            })
+            .await?;
+        Ok(match page {
+            // the last server is only known to be the last when the next one is missing
+            None => (vec![], None),
+            Some((0, keys)) => (keys, Some(format!("{}-0", server + 1))),
+            Some((position, keys)) => (keys, Some(format!("{}-{}", server, position))),
+        })
+    }
+
+    async fn config_get(&self, parameter: &str) -> StorageResult<Vec<String>> {
+        let values = self
+            .run_on_each(|mut redis| async move {
+                redis::cmd("CONFIG")
+                    .arg("GET")
+                    .arg(parameter)
+                    .query_async::<_, (String, String)>(&mut redis)
+                    .await
+            })
+            .await?;
+        Ok(values.into_iter().map(|(_, value)| value).collect())
+    }
+
+    async fn info(&self, section: &str) -> StorageResult<Vec<String>> {
+        self.run_on_each(|mut redis| async move {
+            redis::cmd("INFO")
+                .arg(section)
+                .query_async::<_, String>(&mut redis)
+                .await
+        })
+        .await
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        self.run_on_each(|mut redis| async move {
+            redis::cmd("PING")
+                .query_async::<_, String>(&mut redis)
+                .await
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Closes the pools of the shards, dropping their idle connections, and the connection to the
+    /// Sentinel master. Connections to a cluster are shared, and closed once the facade is dropped.
+    async fn close(&self) {
+        match &self.backend {
+            Backend::Shards { pools, .. } => pools.iter().for_each(Pool::close),
+            Backend::Sentinel(master) => *master.connection.lock().await = None,
+            Backend::Cluster { .. } => {}
+        }
+    }
+}
+
+/// Tells whether a command failed because Redis couldn't be reached, rather than because of the
+/// command itself. A read-only replica, such as a master demoted by a failover, counts as
+/// unreachable as well.
+fn is_connection_error(err: &RedisError) -> bool {
+    err.kind() == ErrorKind::ReadOnly
+        || err.is_io_error()
+        || err.is_connection_dropped()
+        || err.is_connection_refusal()
+        || err.is_timeout()
+}
+
+/// Unwraps the Redis error of a failed connection, or describes the failure of the pool itself,
+/// such as a timeout
+fn pool_error(err: PoolError) -> RedisError {
+    match err {
+        PoolError::Backend(err) => err,
+        err => RedisError::from((
+            ErrorKind::IoError,
+            "Unable to get a connection from the pool",
+            err.to_string(),
+        )),
+    }
+}
+
+/// Escapes the characters having a special meaning in the patterns of `SCAN MATCH`
+fn escape_pattern(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_retries_exhausted() {
+        // nothing listens on port 1: every attempt fails to connect
+        let pool = Pool::builder(Manager::new("redis://127.0.0.1:1/").unwrap())
+            .build()
+            .unwrap();
+        let redis = RedisFacade::new(pool).with_retries(2, Duration::from_millis(1));
+
+        match redis.get_string("key").await {
+            Err(StorageError::Unavailable { attempts, .. }) => assert_eq!(3, attempts),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let pool = Pool::builder(Manager::new("redis://127.0.0.1:1/").unwrap())
+            .build()
+            .unwrap();
+        let redis = RedisFacade::new(pool.clone()).with_retries(0, Duration::from_millis(1));
+
+        redis.close().await;
+        assert!(pool.is_closed());
+        assert!(redis.get_string("key").await.is_err());
+    }
+
+    #[test]
+    fn test_connection_info() {
+        let info = ConnectionOptions::default()
+            .connection_info("127.0.0.1:6379")
+            .unwrap();
+        assert_eq!(
+            ConnectionAddr::Tcp(String::from("127.0.0.1"), 6379),
+            info.addr
+        );
+        assert_eq!(None, info.redis.password);
+        assert_eq!(0, info.redis.db);
+
+        let options = ConnectionOptions {
+            password: Some(String::from("p@ss:word/")),
+            tls: true,
+            db: 2,
+        };
+        let info = options.connection_info("redis.example.com:6380").unwrap();
+        assert_eq!(
+            ConnectionAddr::TcpTls {
+                host: String::from("redis.example.com"),
+                port: 6380,
+                insecure: false,
+            },
+            info.addr
+        );
+        assert_eq!(Some(String::from("p@ss:word/")), info.redis.password);
+        assert_eq!(2, info.redis.db);
+    }
+
+    #[test]
+    fn test_cluster_masters() {
+        let nodes = "\
+07c37dfeb235213a872192d90877d0cd55635b91 127.0.0.1:30004@31004,redis-4 slave e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca 0 1426238317239 4 connected
+67ed2db8d677e59ec4a4cefb06858cf2a1a89fa1 127.0.0.1:30002@31002,redis-2 master - 0 1426238316232 2 connected 5461-10922
+292f8b365bb7edb5e285caf0b7e6ddc7265d2f4f 127.0.0.1:30003@31003,redis-3 master,fail - 0 1426238318243 3 connected 10923-16383
+e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca 127.0.0.1:30001@31001,redis-1 myself,master - 0 0 1 connected 0-5460
+6ec23923021cf3ffec47632106199cb7f496ce01 :0@0 master,noaddr - 0 0 0 disconnected
+";
 
-    pub fn set(&self, key: &str, value: &str) -> RedisResult<()> {
-        self.0.set::<_, _, ()>(key, value)
+        assert_eq!(
+            vec!["127.0.0.1:30002", "127.0.0.1:30001"],
+            cluster_masters(nodes)
+        );
     }
 }
@@ -0,0 +1,360 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! schedule holds `Schedule`, the opening hours of a link: outside of them, the link redirects to
+//! another URL, such as a support hotline link redirecting to a "we're closed" page at night.
+//!
+//! Schedules are evaluated in the time zone of the `Shortener`, UTC by default: see
+//! `Shortener::with_time_zone`. A schedule is stored in `SCHEDULE_<id>` as JSON, expiring with its
+//! link.
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use jiff::tz::TimeZone;
+use jiff::{Timestamp, Zoned};
+
+use crate::{LinkOptions, Shortener, ShortenerError};
+
+/// The most windows a schedule can have
+pub const MAX_SCHEDULE_WINDOWS: usize = 20;
+
+/// The opening hours of a link: it redirects to its URL during any of its `windows`, and to
+/// `closed_url` otherwise
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Schedule {
+    pub windows: Vec<Window>,
+    pub closed_url: String,
+}
+
+/// A window of a `Schedule`, open on `days` from the time of day `from` to the time of day `to`,
+/// excluded. A window ending before it starts, such as from `22:00` to `06:00`, ends on the day
+/// after each of its `days`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Window {
+    pub days: Vec<Day>,
+    pub from: TimeOfDay,
+    pub to: TimeOfDay,
+}
+
+/// A day of the week, written as `mon`, `tue`, ... `sun`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Day {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Day {
+    const ALL: [Day; 7] = [
+        Day::Mon,
+        Day::Tue,
+        Day::Wed,
+        Day::Thu,
+        Day::Fri,
+        Day::Sat,
+        Day::Sun,
+    ];
+
+    fn of(time: &Zoned) -> Day {
+        Day::ALL[time.weekday().to_monday_zero_offset() as usize]
+    }
+
+    fn previous(self) -> Day {
+        Day::ALL[(self as usize + 6) % 7]
+    }
+}
+
+/// A time of day, written as `HH:MM`, from `00:00` to `24:00`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+#[cfg_attr(
+    feature = "openapi",
+    derive(utoipa::ToSchema),
+    schema(value_type = String, example = "09:00")
+)]
+pub struct TimeOfDay(u16);
+
+impl TimeOfDay {
+    fn of(time: &Zoned) -> TimeOfDay {
+        TimeOfDay(time.hour() as u16 * 60 + time.minute() as u16)
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<TimeOfDay, String> {
+        let invalid = || format!("invalid time of day '{}', expected HH:MM", value);
+        let (hours, minutes) = value.split_once(':').ok_or_else(invalid)?;
+        if hours.len() != 2 || minutes.len() != 2 {
+            return Err(invalid());
+        }
+        let hours = hours.parse::<u16>().map_err(|_| invalid())?;
+        let minutes = minutes.parse::<u16>().map_err(|_| invalid())?;
+        if minutes >= 60 || hours > 24 || (hours == 24 && minutes > 0) {
+            return Err(invalid());
+        }
+        Ok(TimeOfDay(hours * 60 + minutes))
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> String {
+        time.to_string()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+impl Window {
+    fn is_open(&self, day: Day, time: TimeOfDay) -> bool {
+        if self.from < self.to {
+            self.days.contains(&day) && self.from <= time && time < self.to
+        } else {
+            (self.days.contains(&day) && self.from <= time)
+                || (self.days.contains(&day.previous()) && time < self.to)
+        }
+    }
+}
+
+impl Schedule {
+    /// Tells whether the link is open at `time`
+    pub fn is_open(&self, time: &Zoned) -> bool {
+        let day = Day::of(time);
+        let time_of_day = TimeOfDay::of(time);
+        self.windows
+            .iter()
+            .any(|window| window.is_open(day, time_of_day))
+    }
+}
+
+impl Shortener {
+    /// Sets the time zone schedules are evaluated in, and daily clicks are counted in (see
+    /// `link_events`), UTC by default
+    pub fn with_time_zone(mut self, time_zone: TimeZone) -> Shortener {
+        self.time_zone = time_zone;
+        self
+    }
+
+    /// Checks the schedule of a new link, if any, returning `options` with its closed URL checked
+    /// as the URL of the link is: parsed, and neither blocked nor malicious. Permanent links
+    /// can't have a schedule, as they may be cached forever.
+    pub(crate) async fn check_schedule(
+        &self,
+        host: Option<&str>,
+        options: &LinkOptions,
+    ) -> Result<LinkOptions, ShortenerError> {
+        let schedule = match &options.schedule {
+            Some(schedule) => schedule,
+            None => return Ok(options.clone()),
+        };
+
+        if options.permanent {
+            return Err(ShortenerError::InvalidInput(
+                "Invalid schedule: permanent links can't have a schedule",
+            ));
+        }
+        if schedule.windows.is_empty() || schedule.windows.len() > MAX_SCHEDULE_WINDOWS {
+            return Err(ShortenerError::InvalidInput(
+                "Invalid schedule: it must have between 1 and 20 windows",
+            ));
+        }
+        for window in &schedule.windows {
+            if window.days.is_empty() || window.from == window.to {
+                return Err(ShortenerError::InvalidInput(
+                    "Invalid schedule: windows must have days, and end after they start",
+                ));
+            }
+        }
+
+        let closed_url = self.check_destination(host, &schedule.closed_url).await?;
+        Ok(LinkOptions {
+            schedule: Some(Schedule {
+                windows: schedule.windows.clone(),
+                closed_url,
+            }),
+            ..options.clone()
+        })
+    }
+
+    /// Stores the schedule of a new link, expiring with the link
+    pub(crate) async fn store_schedule(
+        &self,
+        id: &str,
+        schedule: &Schedule,
+        expires_in: Option<usize>,
+    ) -> Result<(), ShortenerError> {
+        self.store(&schedule_key(id), &schedule_json(schedule), expires_in)
+            .await
+            .map_err(ShortenerError::Storage)
+    }
+
+    /// Returns the schedule of the link `id`, if it has one
+    pub(crate) async fn link_schedule(&self, id: &str) -> Option<Schedule> {
+        let schedule = self.storage.get_string(&schedule_key(id)).await.ok()?;
+        serde_json::from_str(&schedule)
+            .map_err(|err| log::warn!("invalid schedule of '{}': {}", id, err))
+            .ok()
+    }
+
+    /// Returns the URL the link `id` redirects to now instead of `url`, the closed URL of its
+    /// schedule if closed
+    pub(crate) async fn scheduled_url(&self, id: &str, url: String) -> String {
+        match self.link_schedule(id).await {
+            Some(schedule)
+                if !schedule.is_open(&Timestamp::now().to_zoned(self.time_zone.clone())) =>
+            {
+                schedule.closed_url
+            }
+            _ => url,
+        }
+    }
+}
+
+pub(crate) fn schedule_key(id: &str) -> String {
+    format!("SCHEDULE_{}", id)
+}
+
+pub(crate) fn schedule_json(schedule: &Schedule) -> String {
+    serde_json::to_string(schedule).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+
+    use super::*;
+
+    fn schedule(json: &str) -> Schedule {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn at(time: &str) -> Zoned {
+        format!("{}[Europe/Rome]", time).parse().unwrap()
+    }
+
+    #[test]
+    fn test_is_open() {
+        let office = schedule(
+            r#"{"windows": [{"days": ["mon", "tue", "wed", "thu", "fri"], "from": "09:00", "to": "17:30"}],
+                "closed_url": "https://example.com/closed"}"#,
+        );
+        // 2024-03-04 is a monday
+        assert!(office.is_open(&at("2024-03-04T09:00")));
+        assert!(office.is_open(&at("2024-03-08T17:29")));
+        assert!(!office.is_open(&at("2024-03-08T17:30")));
+        assert!(!office.is_open(&at("2024-03-04T08:59")));
+        assert!(!office.is_open(&at("2024-03-09T12:00")));
+
+        let night = schedule(
+            r#"{"windows": [{"days": ["sun"], "from": "22:00", "to": "06:00"}],
+                "closed_url": "https://example.com/closed"}"#,
+        );
+        assert!(night.is_open(&at("2024-03-10T23:00")));
+        assert!(night.is_open(&at("2024-03-11T05:59")));
+        assert!(!night.is_open(&at("2024-03-11T06:00")));
+        assert!(!night.is_open(&at("2024-03-10T05:00")));
+
+        assert!(serde_json::from_str::<TimeOfDay>(r#""24:00""#).is_ok());
+        assert!(serde_json::from_str::<TimeOfDay>(r#""9:00""#).is_err());
+        assert!(serde_json::from_str::<TimeOfDay>(r#""12:60""#).is_err());
+        assert!(serde_json::from_str::<Day>(r#""monday""#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_link() {
+        let shortener = Shortener::new(
+            10,
+            vec!['a', 'b', 'c'],
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
+        )
+        .with_time_zone(TimeZone::get("Pacific/Kiritimati").unwrap());
+        let now = Timestamp::now().to_zoned(shortener.time_zone.clone());
+        let hour = TimeOfDay(now.hour() as u16 * 60);
+        let next_hour = TimeOfDay((now.hour() as u16 + 1) * 60);
+        let closed_now = Schedule {
+            windows: vec![Window {
+                days: Day::ALL.to_vec(),
+                from: next_hour,
+                to: hour,
+            }],
+            closed_url: String::from("example.com/closed"),
+        };
+        let options = LinkOptions {
+            schedule: Some(closed_now.clone()),
+            ..LinkOptions::default()
+        };
+
+        let result = shortener
+            .shorten(&None, None, "example.com/open", &options)
+            .await
+            .unwrap();
+        let redirect = shortener.redirect(result.id()).await.unwrap();
+        assert_eq!("http://example.com/closed", redirect.url);
+        assert_eq!(
+            "http://example.com/closed",
+            result.schedule.unwrap().closed_url
+        );
+
+        let open_now = Schedule {
+            windows: vec![Window {
+                from: hour,
+                to: next_hour,
+                ..closed_now.windows[0].clone()
+            }],
+            ..closed_now.clone()
+        };
+        let options = LinkOptions {
+            schedule: Some(open_now),
+            ..LinkOptions::default()
+        };
+        let result = shortener
+            .shorten(&None, None, "example.com/open", &options)
+            .await
+            .unwrap();
+        let redirect = shortener.redirect(result.id()).await.unwrap();
+        assert_eq!("http://example.com/open", redirect.url);
+
+        let options = LinkOptions {
+            permanent: true,
+            schedule: Some(closed_now),
+            ..LinkOptions::default()
+        };
+        let err = shortener
+            .shorten(&None, None, "example.com/open", &options)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            "Invalid schedule: permanent links can't have a schedule",
+            err.to_string()
+        );
+    }
+}
@@ -0,0 +1,501 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! shadow_storage validates a storage migration in production before cutting over: a
+//! `ShadowStorage` writes to both the storage in use, the primary, and the one being migrated to,
+//! the shadow, and compares their reads.
+//!
+//! Calls are answered by the primary alone. Writes succeeding on the primary are repeated on the
+//! shadow, whose failures are only counted and logged. Reads are repeated on the shadow in the
+//! background, after answering, and the results compared: mismatches are counted, and logged one
+//! in `MISMATCH_LOG_SAMPLING`. Scans, pages of keys and server reports are not compared, as the
+//! keyspace of a shadow being backfilled differs by design.
+//!
+//! The counters are reported by `Storage::shadow_stats`, and shown in `global_stats`.
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::storage::{Storage, StorageError, StorageResult};
+
+/// One mismatch, or failed shadow write, in how many is logged, the first one included
+pub const MISMATCH_LOG_SAMPLING: u64 = 100;
+
+/// The most reads being compared at once: past it, reads are not compared, so that a slow shadow
+/// can't pile up tasks
+pub const MAX_PENDING_COMPARISONS: usize = 1000;
+
+/// The counters of a `ShadowStorage` since it was created
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ShadowStats {
+    pub compared_reads: u64,
+    pub mismatches: u64,
+    pub skipped_reads: u64,
+    pub failed_writes: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    compared_reads: AtomicU64,
+    mismatches: AtomicU64,
+    skipped_reads: AtomicU64,
+    failed_writes: AtomicU64,
+    pending: AtomicUsize,
+}
+
+/// `ShadowStorage` is a `Storage` answering with the `primary` storage, repeating writes on the
+/// `shadow` storage and comparing reads with it
+pub struct ShadowStorage {
+    primary: Box<dyn Storage>,
+    shadow: Arc<dyn Storage>,
+    counters: Arc<Counters>,
+}
+
+impl ShadowStorage {
+    pub fn new(primary: Box<dyn Storage>, shadow: Box<dyn Storage>) -> ShadowStorage {
+        ShadowStorage {
+            primary,
+            shadow: Arc::from(shadow),
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    /// Repeats a write succeeding on the primary on the shadow, counting its failure
+    async fn mirror<T>(
+        &self,
+        command: &str,
+        key: &str,
+        write: impl Future<Output = StorageResult<T>> + Send,
+    ) {
+        if let Err(err) = write.await {
+            let failed_writes = self.counters.failed_writes.fetch_add(1, Ordering::Relaxed) + 1;
+            if failed_writes % MISMATCH_LOG_SAMPLING == 1 {
+                log::warn!(
+                    "shadow storage failed {} of '{}' ({} failed writes so far): {}",
+                    command,
+                    key,
+                    failed_writes,
+                    err
+                );
+            }
+        }
+    }
+
+    /// Compares the `primary` result of a read with the result of `shadow_read`, in the
+    /// background. Reads failing on an unavailable primary are not compared.
+    fn compare<T>(
+        &self,
+        command: &'static str,
+        key: &str,
+        primary: Result<T, &StorageError>,
+        shadow_read: impl Future<Output = StorageResult<T>> + Send + 'static,
+    ) where
+        T: PartialEq + Debug + Send + 'static,
+    {
+        let primary = match primary {
+            Err(StorageError::Unavailable { .. }) => return,
+            primary => primary.ok(),
+        };
+
+        let counters = self.counters.clone();
+        if counters.pending.fetch_add(1, Ordering::Relaxed) >= MAX_PENDING_COMPARISONS {
+            counters.pending.fetch_sub(1, Ordering::Relaxed);
+            counters.skipped_reads.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let key = key.to_owned();
+        tokio::spawn(async move {
+            let shadow = shadow_read.await.ok();
+            counters.pending.fetch_sub(1, Ordering::Relaxed);
+            counters.compared_reads.fetch_add(1, Ordering::Relaxed);
+            if shadow == primary {
+                return;
+            }
+
+            let mismatches = counters.mismatches.fetch_add(1, Ordering::Relaxed) + 1;
+            if mismatches % MISMATCH_LOG_SAMPLING == 1 {
+                log::warn!(
+                    "shadow storage mismatch on {} of '{}' ({} mismatches so far): {:?} on the primary, {:?} on the shadow",
+                    command,
+                    key,
+                    mismatches,
+                    primary,
+                    shadow
+                );
+            }
+        });
+    }
+}
+
+fn sorted(members: &[String]) -> Vec<String> {
+    let mut members = members.to_vec();
+    members.sort();
+    members
+}
+
+#[async_trait]
+impl Storage for ShadowStorage {
+    async fn get_string(&self, key: &str) -> StorageResult<String> {
+        let result = self.primary.get_string(key).await;
+        let (shadow, shadow_key) = (self.shadow.clone(), key.to_owned());
+        self.compare("get_string", key, result.as_ref().cloned(), async move {
+            shadow.get_string(&shadow_key).await
+        });
+        result
+    }
+
+    async fn exists(&self, key: &str) -> StorageResult<bool> {
+        let result = self.primary.exists(key).await;
+        let (shadow, shadow_key) = (self.shadow.clone(), key.to_owned());
+        self.compare("exists", key, result.as_ref().copied(), async move {
+            shadow.exists(&shadow_key).await
+        });
+        result
+    }
+
+    async fn increment(&self, key: &str) -> StorageResult<i64> {
+        let result = self.primary.increment(key).await;
+        if result.is_ok() {
+            self.mirror("increment", key, self.shadow.increment(key))
+                .await;
+        }
+        result
+    }
+
+    async fn increment_expiring(&self, key: &str, period: usize) -> StorageResult<i64> {
+        let result = self.primary.increment_expiring(key, period).await;
+        if result.is_ok() {
+            let write = self.shadow.increment_expiring(key, period);
+            self.mirror("increment_expiring", key, write).await;
+        }
+        result
+    }
+
+    async fn increment_expiring_by(
+        &self,
+        key: &str,
+        amount: i64,
+        period: usize,
+    ) -> StorageResult<i64> {
+        let result = self
+            .primary
+            .increment_expiring_by(key, amount, period)
+            .await;
+        if result.is_ok() {
+            let write = self.shadow.increment_expiring_by(key, amount, period);
+            self.mirror("increment_expiring_by", key, write).await;
+        }
+        result
+    }
+
+    async fn expire(&self, key: &str, period: usize) -> StorageResult<()> {
+        let result = self.primary.expire(key, period).await;
+        if result.is_ok() {
+            self.mirror("expire", key, self.shadow.expire(key, period))
+                .await;
+        }
+        result
+    }
+
+    // expirations are not compared, as they change by the second
+    async fn ttl(&self, key: &str) -> StorageResult<Option<u64>> {
+        self.primary.ttl(key).await
+    }
+
+    async fn set(&self, key: &str, value: &str) -> StorageResult<()> {
+        let result = self.primary.set(key, value).await;
+        if result.is_ok() {
+            self.mirror("set", key, self.shadow.set(key, value)).await;
+        }
+        result
+    }
+
+    async fn set_expiring(&self, key: &str, value: &str, period: usize) -> StorageResult<()> {
+        let result = self.primary.set_expiring(key, value, period).await;
+        if result.is_ok() {
+            let write = self.shadow.set_expiring(key, value, period);
+            self.mirror("set_expiring", key, write).await;
+        }
+        result
+    }
+
+    async fn set_many(&self, entries: &[(String, String, Option<usize>)]) -> StorageResult<()> {
+        let result = self.primary.set_many(entries).await;
+        if result.is_ok() {
+            let key = entries.first().map_or("", |(key, _, _)| key.as_str());
+            self.mirror("set_many", key, self.shadow.set_many(entries))
+                .await;
+        }
+        result
+    }
+
+    // the shadow may hold the key already, such as a stale copy: it's overwritten, so that both
+    // storages keep the value the primary decided on
+    async fn set_if_not_exists(&self, key: &str, value: &str) -> StorageResult<bool> {
+        let result = self.primary.set_if_not_exists(key, value).await;
+        if let Ok(true) = result {
+            self.mirror("set_if_not_exists", key, self.shadow.set(key, value))
+                .await;
+        }
+        result
+    }
+
+    async fn set_if_not_exists_expiring(
+        &self,
+        key: &str,
+        value: &str,
+        period: usize,
+    ) -> StorageResult<bool> {
+        let result = self
+            .primary
+            .set_if_not_exists_expiring(key, value, period)
+            .await;
+        if let Ok(true) = result {
+            let write = self.shadow.set_expiring(key, value, period);
+            self.mirror("set_if_not_exists_expiring", key, write).await;
+        }
+        result
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<bool> {
+        let result = self.primary.delete(key).await;
+        if result.is_ok() {
+            self.mirror("delete", key, self.shadow.delete(key)).await;
+        }
+        result
+    }
+
+    async fn rename(&self, key: &str, new_key: &str) -> StorageResult<bool> {
+        let result = self.primary.rename(key, new_key).await;
+        if let Ok(true) = result {
+            self.mirror("rename", key, self.shadow.rename(key, new_key))
+                .await;
+        }
+        result
+    }
+
+    async fn add_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        let result = self.primary.add_member(key, member).await;
+        if result.is_ok() {
+            self.mirror("add_member", key, self.shadow.add_member(key, member))
+                .await;
+        }
+        result
+    }
+
+    async fn remove_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        let result = self.primary.remove_member(key, member).await;
+        if result.is_ok() {
+            let write = self.shadow.remove_member(key, member);
+            self.mirror("remove_member", key, write).await;
+        }
+        result
+    }
+
+    async fn members(&self, key: &str) -> StorageResult<Vec<String>> {
+        let result = self.primary.members(key).await;
+        let (shadow, shadow_key) = (self.shadow.clone(), key.to_owned());
+        self.compare("members", key, result.as_deref().map(sorted), async move {
+            shadow
+                .members(&shadow_key)
+                .await
+                .map(|members| sorted(&members))
+        });
+        result
+    }
+
+    async fn is_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        let result = self.primary.is_member(key, member).await;
+        let (shadow, shadow_key, member) = (self.shadow.clone(), key.to_owned(), member.to_owned());
+        self.compare("is_member", key, result.as_ref().copied(), async move {
+            shadow.is_member(&shadow_key, &member).await
+        });
+        result
+    }
+
+    async fn add_scored_member(&self, key: &str, member: &str, score: u64) -> StorageResult<bool> {
+        let result = self.primary.add_scored_member(key, member, score).await;
+        if result.is_ok() {
+            let write = self.shadow.add_scored_member(key, member, score);
+            self.mirror("add_scored_member", key, write).await;
+        }
+        result
+    }
+
+    async fn remove_scored_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        let result = self.primary.remove_scored_member(key, member).await;
+        if result.is_ok() {
+            let write = self.shadow.remove_scored_member(key, member);
+            self.mirror("remove_scored_member", key, write).await;
+        }
+        result
+    }
+
+    async fn scored_members(
+        &self,
+        key: &str,
+        min_score: u64,
+        max_score: u64,
+        limit: usize,
+    ) -> StorageResult<Vec<(String, u64)>> {
+        let result = self
+            .primary
+            .scored_members(key, min_score, max_score, limit)
+            .await;
+        let (shadow, shadow_key) = (self.shadow.clone(), key.to_owned());
+        self.compare(
+            "scored_members",
+            key,
+            result.as_ref().cloned(),
+            async move {
+                shadow
+                    .scored_members(&shadow_key, min_score, max_score, limit)
+                    .await
+            },
+        );
+        result
+    }
+
+    async fn scored_members_ascending(
+        &self,
+        key: &str,
+        min_score: u64,
+        max_score: u64,
+        limit: usize,
+    ) -> StorageResult<Vec<(String, u64)>> {
+        let result = self
+            .primary
+            .scored_members_ascending(key, min_score, max_score, limit)
+            .await;
+        let (shadow, shadow_key) = (self.shadow.clone(), key.to_owned());
+        let primary = result.as_ref().cloned();
+        self.compare("scored_members_ascending", key, primary, async move {
+            shadow
+                .scored_members_ascending(&shadow_key, min_score, max_score, limit)
+                .await
+        });
+        result
+    }
+
+    async fn scan(&self, prefix: &str) -> StorageResult<Vec<String>> {
+        self.primary.scan(prefix).await
+    }
+
+    async fn scan_page(
+        &self,
+        prefix: &str,
+        cursor: &str,
+        count: usize,
+    ) -> StorageResult<(Vec<String>, Option<String>)> {
+        self.primary.scan_page(prefix, cursor, count).await
+    }
+
+    async fn config_get(&self, parameter: &str) -> StorageResult<Vec<String>> {
+        self.primary.config_get(parameter).await
+    }
+
+    async fn info(&self, section: &str) -> StorageResult<Vec<String>> {
+        self.primary.info(section).await
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        self.primary.ping().await
+    }
+
+    fn shadow_stats(&self) -> Option<ShadowStats> {
+        Some(ShadowStats {
+            compared_reads: self.counters.compared_reads.load(Ordering::Relaxed),
+            mismatches: self.counters.mismatches.load(Ordering::Relaxed),
+            skipped_reads: self.counters.skipped_reads.load(Ordering::Relaxed),
+            failed_writes: self.counters.failed_writes.load(Ordering::Relaxed),
+        })
+    }
+
+    async fn close(&self) {
+        tokio::join!(self.primary.close(), self.shadow.close());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::memory_storage::MemoryStorage;
+
+    use super::*;
+
+    /// Waits for the comparisons running in the background
+    async fn compared(storage: &ShadowStorage) -> ShadowStats {
+        while storage.counters.pending.load(Ordering::Relaxed) > 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        storage.shadow_stats().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_shadow_storage() {
+        let storage = ShadowStorage::new(
+            Box::new(MemoryStorage::new()),
+            Box::new(MemoryStorage::new()),
+        );
+
+        storage.set("id", "https://example.com").await.unwrap();
+        storage.add_member("set", "b").await.unwrap();
+        storage.add_member("set", "a").await.unwrap();
+        assert!(storage.set_if_not_exists("claimed", "").await.unwrap());
+        assert_eq!(1, storage.increment("HITS_id").await.unwrap());
+        assert_eq!(
+            Ok(String::from("https://example.com")),
+            storage.shadow.get_string("id").await.map_err(|_| ())
+        );
+        assert_eq!(
+            Ok(true),
+            storage.shadow.exists("claimed").await.map_err(|_| ())
+        );
+
+        assert_eq!(
+            "https://example.com",
+            storage.get_string("id").await.unwrap()
+        );
+        storage.members("set").await.unwrap();
+        storage.get_string("missing").await.unwrap_err();
+        assert_eq!(
+            ShadowStats {
+                compared_reads: 3,
+                mismatches: 0,
+                skipped_reads: 0,
+                failed_writes: 0,
+            },
+            compared(&storage).await
+        );
+
+        // the shadow drifted, such as missing a write made before shadowing started
+        storage.shadow.delete("id").await.unwrap();
+        storage.shadow.increment("HITS_id").await.unwrap();
+        assert_eq!(
+            "https://example.com",
+            storage.get_string("id").await.unwrap()
+        );
+        assert_eq!("1", storage.get_string("HITS_id").await.unwrap());
+        let stats = compared(&storage).await;
+        assert_eq!(5, stats.compared_reads);
+        assert_eq!(2, stats.mismatches);
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! snapshot holds `Snapshot`, a read-only copy of the redirects of every link, exported with
+//! `Shortener::export_snapshot`. Frontends load a recent snapshot to keep redirecting while the
+//! storage is unreachable, such as the AWS lambda loading it from S3.
+//!
+//! Snapshots are JSON lines, one `{"id", "url", "status", "permanent"}` object per link. They are
+//! stale by design: links created after the export are missing, and deleted ones still redirect.
+//!
+//! Large snapshots are exported a page at a time with `Shortener::export_snapshot_page`, so that
+//! frontends can stream them without holding every link in memory. Streamed snapshots have a
+//! `{"cursor"}` line after each page, to resume the export from if the connection drops: parsing
+//! skips them.
+
+use std::collections::HashMap;
+
+use crate::global_stats::key_type;
+use crate::{Redirect, Shortener, ShortenerError};
+
+/// The keys scanned for each page of `Shortener::export_snapshot_page`
+pub const SNAPSHOT_PAGE_SIZE: usize = 1000;
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotLine {
+    id: String,
+    #[serde(flatten)]
+    redirect: Redirect,
+}
+
+/// The line of a streamed snapshot with the cursor to resume the export from
+#[derive(Serialize, Deserialize)]
+struct CursorLine {
+    cursor: String,
+}
+
+/// A line of a streamed snapshot
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StreamedLine {
+    Link(SnapshotLine),
+    Cursor {
+        #[serde(rename = "cursor")]
+        _cursor: String,
+    },
+}
+
+/// A page of a snapshot: the JSON `lines` of its links, possibly none, and the `cursor` of the
+/// next page, `None` after the last one
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotPage {
+    pub lines: String,
+    pub cursor: Option<String>,
+}
+
+impl SnapshotPage {
+    /// Renders the page as a chunk of a streamed snapshot: its lines, followed by a
+    /// `{"cursor"}` line with the cursor of the next page, if any
+    pub fn chunk(&self) -> String {
+        match &self.cursor {
+            Some(cursor) => {
+                let cursor = CursorLine {
+                    cursor: cursor.clone(),
+                };
+                format!(
+                    "{}{}\n",
+                    self.lines,
+                    serde_json::to_string(&cursor).unwrap()
+                )
+            }
+            None => self.lines.clone(),
+        }
+    }
+}
+
+/// The redirects of a snapshot, by ID
+#[derive(Debug, Default, PartialEq)]
+pub struct Snapshot {
+    redirects: HashMap<String, Redirect>,
+}
+
+impl Snapshot {
+    /// Parses a snapshot exported by `Shortener::export_snapshot`, or streamed. Blank lines and
+    /// cursor lines are skipped, while any other invalid line fails the whole snapshot. Links
+    /// repeated by a resumed stream are kept once.
+    pub fn parse(snapshot: &str) -> Result<Snapshot, ShortenerError> {
+        let mut redirects = HashMap::new();
+        for line in snapshot.lines().filter(|line| !line.trim().is_empty()) {
+            let line = serde_json::from_str::<StreamedLine>(line)
+                .map_err(ShortenerError::InvalidSnapshot)?;
+            if let StreamedLine::Link(line) = line {
+                redirects.insert(line.id, line.redirect);
+            }
+        }
+
+        Ok(Snapshot { redirects })
+    }
+
+    /// Returns the redirect of `id`, as it was when the snapshot was exported
+    pub fn redirect(&self, id: &str) -> Option<&Redirect> {
+        self.redirects.get(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.redirects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.redirects.is_empty()
+    }
+}
+
+impl Shortener {
+    /// Exports the redirects of every link as a snapshot: see `snapshot`. Links `lookup` doesn't
+    /// find, such as quarantined ones or those of paused campaigns, are left out.
+    ///
+    /// It walks the whole keyspace, as `global_stats` does, and is meant to run periodically out
+    /// of the request path. The whole snapshot is held in memory: see `export_snapshot_page` to
+    /// stream it instead.
+    pub async fn export_snapshot(&self) -> Result<String, ShortenerError> {
+        let mut snapshot = String::new();
+        let mut cursor = String::new();
+        loop {
+            let page = self.export_snapshot_page(&cursor).await?;
+            snapshot.push_str(&page.lines);
+            match page.cursor {
+                Some(next) => cursor = next,
+                None => return Ok(snapshot),
+            }
+        }
+    }
+
+    /// Exports the page of the snapshot starting at `cursor`, empty for the first page, scanning
+    /// about `SNAPSHOT_PAGE_SIZE` keys. Cursors are those of `Storage::scan_page`: they don't
+    /// expire, and links present during the whole export are exported at least once.
+    pub async fn export_snapshot_page(&self, cursor: &str) -> Result<SnapshotPage, ShortenerError> {
+        let (keys, cursor) = self
+            .storage
+            .scan_page("", cursor, SNAPSHOT_PAGE_SIZE)
+            .await
+            .map_err(ShortenerError::Storage)?;
+
+        let mut lines = String::new();
+        for id in keys.into_iter().filter(|key| key_type(key) == "links") {
+            let url = match self.destination(&id).await {
+                Some(url) => url,
+                None => continue,
+            };
+            let redirect = self.redirect_to(&id, url).await;
+            lines.push_str(&serde_json::to_string(&SnapshotLine { id, redirect }).unwrap());
+            lines.push('\n');
+        }
+
+        Ok(SnapshotPage { lines, cursor })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_export_snapshot() {
+        let shortener = Shortener::new(
+            10,
+            vec!['a', 'b', 'c'],
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
+        );
+        let temporary = shortener
+            .shorten(&None, None, "http://example.com/", &LinkOptions::default())
+            .await
+            .unwrap();
+        let permanent_options = LinkOptions {
+            permanent: true,
+            ..LinkOptions::default()
+        };
+        let permanent = shortener
+            .shorten(&None, None, "http://example.org/", &permanent_options)
+            .await
+            .unwrap();
+
+        let snapshot = Snapshot::parse(&shortener.export_snapshot().await.unwrap()).unwrap();
+        assert_eq!(2, snapshot.len());
+        assert_eq!(
+            Some(&Redirect {
+                url: String::from("http://example.com/"),
+                status: 302,
+                permanent: false,
+                preconnect: false,
+                verified: false,
+            }),
+            snapshot.redirect(temporary.id())
+        );
+        assert_eq!(301, snapshot.redirect(permanent.id()).unwrap().status);
+        assert_eq!(None, snapshot.redirect("abc"));
+
+        assert!(Snapshot::parse("{\"id\":\"abc\"}").is_err());
+        assert!(Snapshot::parse("\n").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_export_snapshot_page() {
+        let shortener = Shortener::new(
+            10,
+            vec!['a', 'b', 'c'],
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
+        );
+        let mut ids = Vec::new();
+        for i in 0..SNAPSHOT_PAGE_SIZE {
+            let url = format!("http://example.com/{}", i);
+            let result = shortener
+                .shorten(&None, None, &url, &LinkOptions::default())
+                .await
+                .unwrap();
+            ids.push(result.id().to_owned());
+        }
+
+        // each link has more than one key, so the links span a few pages
+        let mut streamed = String::new();
+        let mut cursor = String::new();
+        let mut pages = 0;
+        let last_page = loop {
+            let page = shortener.export_snapshot_page(&cursor).await.unwrap();
+            streamed.push_str(&page.chunk());
+            pages += 1;
+            match &page.cursor {
+                Some(next) => cursor = next.clone(),
+                None => break page,
+            }
+        };
+        assert!(pages > 1);
+        assert!(streamed.contains("{\"cursor\":"));
+
+        // parsing skips the cursors, and resuming from a cursor exports the same page again
+        let snapshot = Snapshot::parse(&streamed).unwrap();
+        assert_eq!(SNAPSHOT_PAGE_SIZE, snapshot.len());
+        assert!(ids.iter().all(|id| snapshot.redirect(id).is_some()));
+        let resumed = shortener.export_snapshot_page(&cursor).await.unwrap();
+        assert_eq!(last_page, resumed);
+    }
+}
@@ -0,0 +1,792 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! sqlite_storage holds `SqliteStorage`, a `Storage` keeping its data in an embedded SQLite
+//! database, available with the `sqlite` feature.
+//!
+//! Data is stored in two tables:
+//! - `entries`: every key, with the kind of its value, the value of strings, and the Unix time in
+//!   milliseconds the key expires at, if any
+//! - `members`: the members of sets and sorted sets, with their score
+
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use redis::{ErrorKind, RedisError};
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+
+use crate::storage::{sorted_page, Storage, StorageError, StorageResult};
+
+const STRING: i64 = 0;
+const SET: i64 = 1;
+const SORTED_SET: i64 = 2;
+
+/// How often expired keys nobody reads are removed, in milliseconds
+const PURGE_INTERVAL: i64 = 60_000;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS entries (
+    key TEXT PRIMARY KEY NOT NULL,
+    kind INTEGER NOT NULL,
+    value TEXT,
+    expires_at INTEGER
+) WITHOUT ROWID;
+CREATE INDEX IF NOT EXISTS entries_expires_at ON entries (expires_at) WHERE expires_at IS NOT NULL;
+CREATE TABLE IF NOT EXISTS members (
+    key TEXT NOT NULL,
+    member TEXT NOT NULL,
+    score INTEGER,
+    PRIMARY KEY (key, member)
+) WITHOUT ROWID;
+";
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(err: rusqlite::Error) -> Self {
+        StorageError::Backend(Box::new(err))
+    }
+}
+
+fn wrong_type() -> StorageError {
+    RedisError::from((
+        ErrorKind::TypeError,
+        "operation against a key holding the wrong kind of value",
+    ))
+    .into()
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+fn expires_at(now: i64, period: usize) -> i64 {
+    now + period as i64 * 1000
+}
+
+/// `SqliteStorage` is a `Storage` keeping keys in a SQLite database file, and expiring them the
+/// way Redis does.
+///
+/// It's meant for small installs running a single shorty process, which need no Redis server:
+/// operations are serialized on one connection, and run on the calling task, as SQLite answers
+/// from a local disk in microseconds. Other processes, such as backup tools, can still open the
+/// database, which is in WAL mode.
+pub struct SqliteStorage {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    connection: Connection,
+    last_purge: i64,
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path`, creating it if it doesn't exist
+    pub fn open(path: &str) -> StorageResult<SqliteStorage> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        SqliteStorage::new(connection)
+    }
+
+    /// Opens a database living in memory, and lost when the storage is dropped
+    pub fn open_in_memory() -> StorageResult<SqliteStorage> {
+        SqliteStorage::new(Connection::open_in_memory()?)
+    }
+
+    fn new(connection: Connection) -> StorageResult<SqliteStorage> {
+        connection.busy_timeout(Duration::from_secs(5))?;
+        connection.execute_batch(SCHEMA)?;
+
+        Ok(SqliteStorage {
+            inner: Mutex::new(Inner {
+                connection,
+                last_purge: 0,
+            }),
+        })
+    }
+
+    /// Runs `operation` in a transaction, once `key`, if expired, has been removed: operations
+    /// only see live keys. Expired keys nobody reads are removed every `PURGE_INTERVAL`.
+    fn run<T>(
+        &self,
+        key: Option<&str>,
+        operation: impl FnOnce(&Transaction, i64) -> StorageResult<T>,
+    ) -> StorageResult<T> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = now();
+        let purge = now - inner.last_purge > PURGE_INTERVAL;
+
+        let transaction = inner
+            .connection
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if purge {
+            remove_expired(&transaction, None, now)?;
+        } else if let Some(key) = key {
+            remove_expired(&transaction, Some(key), now)?;
+        }
+        let result = operation(&transaction, now)?;
+        transaction.commit()?;
+
+        if purge {
+            inner.last_purge = now;
+        }
+        Ok(result)
+    }
+}
+
+/// Removes `key` if it's expired, or every expired key if `key` is `None`
+fn remove_expired(transaction: &Transaction, key: Option<&str>, now: i64) -> StorageResult<()> {
+    transaction.execute(
+        "DELETE FROM members WHERE key IN (
+            SELECT key FROM entries WHERE expires_at <= ?1 AND (?2 IS NULL OR key = ?2)
+        )",
+        params![now, key],
+    )?;
+    transaction.execute(
+        "DELETE FROM entries WHERE expires_at <= ?1 AND (?2 IS NULL OR key = ?2)",
+        params![now, key],
+    )?;
+    Ok(())
+}
+
+/// Returns the kind of the value of `key`, or `None` if it doesn't exist
+fn kind(transaction: &Transaction, key: &str) -> StorageResult<Option<i64>> {
+    Ok(transaction
+        .query_row(
+            "SELECT kind FROM entries WHERE key = ?1",
+            params![key],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+/// Deletes `key` and its members, returning whether it existed
+fn delete_entry(transaction: &Transaction, key: &str) -> StorageResult<bool> {
+    transaction.execute("DELETE FROM members WHERE key = ?1", params![key])?;
+    Ok(transaction.execute("DELETE FROM entries WHERE key = ?1", params![key])? > 0)
+}
+
+/// Replaces the value of `key` with the string `value`
+fn set_entry(
+    transaction: &Transaction,
+    key: &str,
+    value: &str,
+    expires_at: Option<i64>,
+) -> StorageResult<()> {
+    delete_entry(transaction, key)?;
+    transaction.execute(
+        "INSERT INTO entries (key, kind, value, expires_at) VALUES (?1, ?2, ?3, ?4)",
+        params![key, STRING, value, expires_at],
+    )?;
+    Ok(())
+}
+
+/// Creates the set or sorted set of `key`, as given by `expected_kind`, if it doesn't exist
+fn ensure_collection(
+    transaction: &Transaction,
+    key: &str,
+    expected_kind: i64,
+) -> StorageResult<()> {
+    match kind(transaction, key)? {
+        Some(kind) if kind == expected_kind => Ok(()),
+        Some(_) => Err(wrong_type()),
+        None => {
+            transaction.execute(
+                "INSERT INTO entries (key, kind) VALUES (?1, ?2)",
+                params![key, expected_kind],
+            )?;
+            Ok(())
+        }
+    }
+}
+
+/// Removes `member` from the set or sorted set of `key`, as given by `expected_kind`, removing the
+/// key as well if it's left empty, like Redis does
+fn remove_collection_member(
+    transaction: &Transaction,
+    key: &str,
+    member: &str,
+    expected_kind: i64,
+) -> StorageResult<bool> {
+    match kind(transaction, key)? {
+        Some(kind) if kind == expected_kind => {}
+        Some(_) => return Err(wrong_type()),
+        None => return Ok(false),
+    }
+
+    let removed = transaction.execute(
+        "DELETE FROM members WHERE key = ?1 AND member = ?2",
+        params![key, member],
+    )? > 0;
+    transaction.execute(
+        "DELETE FROM entries WHERE key = ?1 AND NOT EXISTS (SELECT 1 FROM members WHERE key = ?1)",
+        params![key],
+    )?;
+    Ok(removed)
+}
+
+/// Selects at most `limit` members of the sorted set `key` whose score is between `min_score`
+/// and `max_score`, sorted by score, then by member, in the given `order`
+fn select_scored_members(
+    transaction: &Transaction,
+    key: &str,
+    min_score: u64,
+    max_score: u64,
+    limit: usize,
+    order: &str,
+) -> StorageResult<Vec<(String, u64)>> {
+    match kind(transaction, key)? {
+        Some(SORTED_SET) => {}
+        Some(_) => return Err(wrong_type()),
+        None => return Ok(vec![]),
+    }
+
+    let mut statement = transaction.prepare(&format!(
+        "SELECT member, score FROM members WHERE key = ?1 AND score BETWEEN ?2 AND ?3
+         ORDER BY score {0}, member {0} LIMIT ?4",
+        order
+    ))?;
+    let members = statement
+        .query_map(
+            params![
+                key,
+                min_score as i64,
+                max_score.min(i64::MAX as u64) as i64,
+                limit as i64
+            ],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64)),
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(members)
+}
+
+/// Increments the integer value of `key` by `amount`, setting the expiration if `period` is
+/// present and the key has none
+fn increment_entry(
+    transaction: &Transaction,
+    key: &str,
+    amount: i64,
+    period: Option<usize>,
+    now: i64,
+) -> StorageResult<i64> {
+    let entry = transaction
+        .query_row(
+            "SELECT kind, value, expires_at FROM entries WHERE key = ?1",
+            params![key],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, Option<i64>>(2)?,
+                ))
+            },
+        )
+        .optional()?;
+
+    let (value, expiration) = match entry {
+        Some((STRING, value, expiration)) => {
+            let value = value.unwrap_or_default().parse::<i64>().map_err(|_| {
+                StorageError::from(RedisError::from((
+                    ErrorKind::TypeError,
+                    "value is not an integer",
+                )))
+            })?;
+            (value + amount, expiration)
+        }
+        Some(_) => return Err(wrong_type()),
+        None => (amount, None),
+    };
+    let expiration = expiration.or_else(|| period.map(|period| expires_at(now, period)));
+
+    set_entry(transaction, key, &value.to_string(), expiration)?;
+    Ok(value)
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn get_string(&self, key: &str) -> StorageResult<String> {
+        self.run(Some(key), |transaction, _| {
+            let entry = transaction
+                .query_row(
+                    "SELECT kind, value FROM entries WHERE key = ?1",
+                    params![key],
+                    |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Option<String>>(1)?)),
+                )
+                .optional()?;
+
+            match entry {
+                Some((STRING, value)) => Ok(value.unwrap_or_default()),
+                Some(_) => Err(wrong_type()),
+                None => Err(RedisError::from((ErrorKind::TypeError, "key not found")).into()),
+            }
+        })
+    }
+
+    async fn exists(&self, key: &str) -> StorageResult<bool> {
+        self.run(Some(key), |transaction, _| {
+            Ok(kind(transaction, key)?.is_some())
+        })
+    }
+
+    async fn increment(&self, key: &str) -> StorageResult<i64> {
+        self.run(Some(key), |transaction, now| {
+            increment_entry(transaction, key, 1, None, now)
+        })
+    }
+
+    async fn increment_expiring(&self, key: &str, period: usize) -> StorageResult<i64> {
+        self.run(Some(key), |transaction, now| {
+            increment_entry(transaction, key, 1, Some(period), now)
+        })
+    }
+
+    async fn increment_expiring_by(
+        &self,
+        key: &str,
+        amount: i64,
+        period: usize,
+    ) -> StorageResult<i64> {
+        self.run(Some(key), |transaction, now| {
+            increment_entry(transaction, key, amount, Some(period), now)
+        })
+    }
+
+    async fn expire(&self, key: &str, period: usize) -> StorageResult<()> {
+        self.run(Some(key), |transaction, now| {
+            if period == 0 {
+                delete_entry(transaction, key)?;
+            } else {
+                transaction.execute(
+                    "UPDATE entries SET expires_at = ?2 WHERE key = ?1",
+                    params![key, expires_at(now, period)],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    async fn ttl(&self, key: &str) -> StorageResult<Option<u64>> {
+        self.run(Some(key), |transaction, now| {
+            let expires_at = transaction
+                .query_row(
+                    "SELECT expires_at FROM entries WHERE key = ?1",
+                    params![key],
+                    |row| row.get::<_, Option<i64>>(0),
+                )
+                .optional()?
+                .flatten();
+            Ok(expires_at.map(|expires_at| ((expires_at - now).max(0) as u64).div_ceil(1000)))
+        })
+    }
+
+    async fn set(&self, key: &str, value: &str) -> StorageResult<()> {
+        self.run(None, |transaction, _| {
+            set_entry(transaction, key, value, None)
+        })
+    }
+
+    async fn set_expiring(&self, key: &str, value: &str, period: usize) -> StorageResult<()> {
+        self.run(None, |transaction, now| {
+            set_entry(transaction, key, value, Some(expires_at(now, period)))
+        })
+    }
+
+    async fn set_many(&self, entries: &[(String, String, Option<usize>)]) -> StorageResult<()> {
+        self.run(None, |transaction, now| {
+            for (key, value, period) in entries {
+                let expiration = period.map(|period| expires_at(now, period));
+                set_entry(transaction, key, value, expiration)?;
+            }
+            Ok(())
+        })
+    }
+
+    async fn set_if_not_exists(&self, key: &str, value: &str) -> StorageResult<bool> {
+        self.run(Some(key), |transaction, _| {
+            if kind(transaction, key)?.is_some() {
+                return Ok(false);
+            }
+            set_entry(transaction, key, value, None)?;
+            Ok(true)
+        })
+    }
+
+    async fn set_if_not_exists_expiring(
+        &self,
+        key: &str,
+        value: &str,
+        period: usize,
+    ) -> StorageResult<bool> {
+        self.run(Some(key), |transaction, now| {
+            if kind(transaction, key)?.is_some() {
+                return Ok(false);
+            }
+            set_entry(transaction, key, value, Some(expires_at(now, period)))?;
+            Ok(true)
+        })
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<bool> {
+        self.run(Some(key), |transaction, _| delete_entry(transaction, key))
+    }
+
+    async fn rename(&self, key: &str, new_key: &str) -> StorageResult<bool> {
+        self.run(Some(key), |transaction, now| {
+            remove_expired(transaction, Some(new_key), now)?;
+            let taken = transaction.query_row(
+                "SELECT EXISTS (SELECT 1 FROM entries WHERE key = ?1)",
+                params![new_key],
+                |row| row.get::<_, bool>(0),
+            )?;
+            if taken {
+                return Ok(false);
+            }
+
+            transaction.execute(
+                "UPDATE members SET key = ?2 WHERE key = ?1",
+                params![key, new_key],
+            )?;
+            Ok(transaction.execute(
+                "UPDATE entries SET key = ?2 WHERE key = ?1",
+                params![key, new_key],
+            )? > 0)
+        })
+    }
+
+    async fn add_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        self.run(Some(key), |transaction, _| {
+            ensure_collection(transaction, key, SET)?;
+            Ok(transaction.execute(
+                "INSERT OR IGNORE INTO members (key, member) VALUES (?1, ?2)",
+                params![key, member],
+            )? > 0)
+        })
+    }
+
+    async fn remove_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        self.run(Some(key), |transaction, _| {
+            remove_collection_member(transaction, key, member, SET)
+        })
+    }
+
+    async fn members(&self, key: &str) -> StorageResult<Vec<String>> {
+        self.run(Some(key), |transaction, _| {
+            match kind(transaction, key)? {
+                Some(SET) => {}
+                Some(_) => return Err(wrong_type()),
+                None => return Ok(vec![]),
+            }
+
+            let mut statement = transaction.prepare("SELECT member FROM members WHERE key = ?1")?;
+            let members = statement
+                .query_map(params![key], |row| row.get(0))?
+                .collect::<Result<Vec<String>, _>>()?;
+            Ok(members)
+        })
+    }
+
+    async fn is_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        self.run(Some(key), |transaction, _| {
+            match kind(transaction, key)? {
+                Some(SET) => {}
+                Some(_) => return Err(wrong_type()),
+                None => return Ok(false),
+            }
+
+            let found = transaction
+                .query_row(
+                    "SELECT 1 FROM members WHERE key = ?1 AND member = ?2",
+                    params![key, member],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            Ok(found.is_some())
+        })
+    }
+
+    async fn add_scored_member(&self, key: &str, member: &str, score: u64) -> StorageResult<bool> {
+        self.run(Some(key), |transaction, _| {
+            ensure_collection(transaction, key, SORTED_SET)?;
+            let existed = transaction
+                .query_row(
+                    "SELECT 1 FROM members WHERE key = ?1 AND member = ?2",
+                    params![key, member],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            transaction.execute(
+                "INSERT INTO members (key, member, score) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (key, member) DO UPDATE SET score = excluded.score",
+                params![key, member, score as i64],
+            )?;
+            Ok(!existed)
+        })
+    }
+
+    async fn remove_scored_member(&self, key: &str, member: &str) -> StorageResult<bool> {
+        self.run(Some(key), |transaction, _| {
+            remove_collection_member(transaction, key, member, SORTED_SET)
+        })
+    }
+
+    async fn scored_members(
+        &self,
+        key: &str,
+        min_score: u64,
+        max_score: u64,
+        limit: usize,
+    ) -> StorageResult<Vec<(String, u64)>> {
+        self.run(Some(key), |transaction, _| {
+            select_scored_members(transaction, key, min_score, max_score, limit, "DESC")
+        })
+    }
+
+    async fn scored_members_ascending(
+        &self,
+        key: &str,
+        min_score: u64,
+        max_score: u64,
+        limit: usize,
+    ) -> StorageResult<Vec<(String, u64)>> {
+        self.run(Some(key), |transaction, _| {
+            select_scored_members(transaction, key, min_score, max_score, limit, "ASC")
+        })
+    }
+
+    async fn scan(&self, prefix: &str) -> StorageResult<Vec<String>> {
+        self.run(None, |transaction, now| {
+            let mut statement = transaction.prepare(
+                "SELECT key FROM entries
+                 WHERE substr(key, 1, length(?1)) = ?1 AND (expires_at IS NULL OR expires_at > ?2)",
+            )?;
+            let keys = statement
+                .query_map(params![prefix, now], |row| row.get(0))?
+                .collect::<Result<Vec<String>, _>>()?;
+            Ok(keys)
+        })
+    }
+
+    async fn scan_page(
+        &self,
+        prefix: &str,
+        cursor: &str,
+        count: usize,
+    ) -> StorageResult<(Vec<String>, Option<String>)> {
+        let count = count.max(1);
+        self.run(None, |transaction, now| {
+            let mut statement = transaction.prepare(
+                "SELECT key FROM entries
+                 WHERE substr(key, 1, length(?1)) = ?1 AND key > ?2
+                   AND (expires_at IS NULL OR expires_at > ?3)
+                 ORDER BY key LIMIT ?4",
+            )?;
+            let keys = statement
+                .query_map(params![prefix, cursor, now, count as i64], |row| row.get(0))?
+                .collect::<Result<Vec<String>, _>>()?;
+            Ok(sorted_page(keys, count))
+        })
+    }
+
+    async fn ping(&self) -> StorageResult<()> {
+        self.run(None, |transaction, _| {
+            transaction.query_row("SELECT 1", [], |row| row.get::<_, i64>(0))?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_set_and_get() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        storage.set("key", "value").await.unwrap();
+        storage
+            .set_expiring("expiring", "value", 600)
+            .await
+            .unwrap();
+
+        assert_eq!("value", storage.get_string("key").await.unwrap());
+        assert!(storage.exists("expiring").await.unwrap());
+        assert!(storage.get_string("missing").await.is_err());
+        assert!(!storage.set_if_not_exists("key", "other").await.unwrap());
+        assert!(storage.set_if_not_exists("new", "value").await.unwrap());
+
+        assert!(storage.delete("key").await.unwrap());
+        assert!(!storage.delete("key").await.unwrap());
+        storage.expire("expiring", 0).await.unwrap();
+        assert!(!storage.exists("expiring").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_increment() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+
+        assert_eq!(1, storage.increment("counter").await.unwrap());
+        assert_eq!(2, storage.increment("counter").await.unwrap());
+        assert_eq!(1, storage.increment_expiring("window", 600).await.unwrap());
+        assert_eq!(2, storage.increment_expiring("window", 600).await.unwrap());
+
+        storage.set("text", "value").await.unwrap();
+        assert!(storage.increment("text").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_scan_page() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        for key in &["HITS_c", "HITS_a", "b", "HITS_b"] {
+            storage.set(key, "1").await.unwrap();
+        }
+
+        let (keys, cursor) = storage.scan_page("HITS_", "", 2).await.unwrap();
+        assert_eq!(vec!["HITS_a", "HITS_b"], keys);
+        assert_eq!(Some("HITS_b"), cursor.as_deref());
+        let (keys, cursor) = storage.scan_page("HITS_", "HITS_b", 2).await.unwrap();
+        assert_eq!(vec!["HITS_c"], keys);
+        assert_eq!(None, cursor);
+    }
+
+    #[tokio::test]
+    async fn test_rename() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        storage.add_member("set", "member").await.unwrap();
+        storage.set("taken", "value").await.unwrap();
+
+        assert!(!storage.rename("set", "taken").await.unwrap());
+        assert!(storage.rename("set", "shorty:set").await.unwrap());
+        assert_eq!(vec!["member"], storage.members("shorty:set").await.unwrap());
+        assert!(storage.members("set").await.unwrap().is_empty());
+        assert!(!storage.rename("missing", "shorty:missing").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_expired_keys() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        storage.add_member("set", "member").await.unwrap();
+        storage.set("HITS_a", "1").await.unwrap();
+        storage.set("HITS_b", "2").await.unwrap();
+
+        // expire keys in the past, as if time went by
+        storage
+            .run(None, |transaction, now| {
+                transaction.execute(
+                    "UPDATE entries SET expires_at = ?1 WHERE key IN ('set', 'HITS_b')",
+                    params![now - 1],
+                )?;
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(vec!["HITS_a"], storage.scan("HITS_").await.unwrap());
+        assert!(storage.members("set").await.unwrap().is_empty());
+        assert!(storage.add_member("set", "member").await.unwrap());
+
+        assert_eq!(None, storage.ttl("HITS_a").await.unwrap());
+        assert_eq!(None, storage.ttl("HITS_b").await.unwrap());
+        storage.expire("HITS_a", 600).await.unwrap();
+        assert_eq!(Some(600), storage.ttl("HITS_a").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_sets() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+
+        assert!(storage.add_member("set", "a").await.unwrap());
+        assert!(!storage.add_member("set", "a").await.unwrap());
+        assert!(storage.add_member("set", "b").await.unwrap());
+        let mut members = storage.members("set").await.unwrap();
+        members.sort();
+        assert_eq!(vec!["a", "b"], members);
+        assert!(storage.is_member("set", "a").await.unwrap());
+        assert!(!storage.is_member("set", "c").await.unwrap());
+
+        assert!(storage.remove_member("set", "a").await.unwrap());
+        assert!(storage.remove_member("set", "b").await.unwrap());
+        assert!(!storage.exists("set").await.unwrap());
+
+        storage.set("text", "value").await.unwrap();
+        assert!(storage.add_member("text", "a").await.is_err());
+        assert!(storage.members("text").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_scored_members() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+
+        assert!(storage.add_scored_member("sorted", "a", 1).await.unwrap());
+        assert!(storage.add_scored_member("sorted", "b", 3).await.unwrap());
+        assert!(storage.add_scored_member("sorted", "c", 3).await.unwrap());
+        assert!(!storage.add_scored_member("sorted", "a", 2).await.unwrap());
+
+        assert_eq!(
+            vec![(String::from("c"), 3), (String::from("b"), 3)],
+            storage
+                .scored_members("sorted", 2, u64::MAX, 2)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            3,
+            storage
+                .scored_members("sorted", 0, u64::MAX, 10)
+                .await
+                .unwrap()
+                .len()
+        );
+        assert_eq!(
+            vec![(String::from("a"), 2)],
+            storage.scored_members("sorted", 0, 2, 10).await.unwrap()
+        );
+        assert_eq!(
+            vec![(String::from("a"), 2), (String::from("b"), 3)],
+            storage
+                .scored_members_ascending("sorted", 0, u64::MAX, 2)
+                .await
+                .unwrap()
+        );
+
+        assert!(storage.remove_scored_member("sorted", "a").await.unwrap());
+        assert!(storage.add_member("sorted", "a").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_persistence() {
+        let dir = std::env::temp_dir().join(format!("shorty-sqlite-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("shorty.db");
+        let path = path.to_str().unwrap();
+
+        SqliteStorage::open(path)
+            .unwrap()
+            .set("key", "value")
+            .await
+            .unwrap();
+        assert_eq!(
+            "value",
+            SqliteStorage::open(path)
+                .unwrap()
+                .get_string("key")
+                .await
+                .unwrap()
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! storage holds the `Storage` trait, implemented by every backend `Shortener` can store its data
+//! on
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use redis::RedisError;
+
+use crate::shadow_storage::ShadowStats;
+
+/// The error of a `Storage` operation
+#[derive(Debug)]
+pub enum StorageError {
+    /// The operation failed, such as reading a missing key
+    Redis(RedisError),
+    /// The storage couldn't be reached, even after `attempts` attempts
+    Unavailable { attempts: u32, cause: RedisError },
+    /// The operation failed on a storage other than Redis
+    Backend(Box<dyn Error + Send + Sync>),
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            StorageError::Redis(err) => err.fmt(f),
+            StorageError::Backend(err) => err.fmt(f),
+            StorageError::Unavailable { attempts, cause } => write!(
+                f,
+                "storage unavailable after {} attempts: {}",
+                attempts, cause
+            ),
+        }
+    }
+}
+
+impl Error for StorageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StorageError::Redis(err) => Some(err),
+            StorageError::Backend(err) => Some(err.as_ref()),
+            StorageError::Unavailable { cause, .. } => Some(cause),
+        }
+    }
+}
+
+impl From<RedisError> for StorageError {
+    fn from(err: RedisError) -> Self {
+        StorageError::Redis(err)
+    }
+}
+
+/// `Storage` is the small set of key/value operations `Shortener` needs. Keys and values are
+/// strings, and keys may expire, following Redis semantics.
+///
+/// Implementations are `RedisFacade`, `MemoryStorage` and `SqliteStorage`, while
+/// `RecordingStorage` and `ReplayStorage` record and replay the calls to another `Storage`, and
+/// `ShadowStorage` repeats them on a second `Storage` being migrated to.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Gets the value of `key`, returning an error if the key doesn't exist
+    async fn get_string(&self, key: &str) -> StorageResult<String>;
+
+    /// Gets the value of `key` as a boolean. A missing key, or a value other than `true`, is
+    /// `false`.
+    async fn get_bool(&self, key: &str) -> StorageResult<bool> {
+        self.get_string(key)
+            .await
+            .map(|value| FromStr::from_str(&value).unwrap_or(false))
+            .or(Ok(false))
+    }
+
+    async fn exists(&self, key: &str) -> StorageResult<bool>;
+
+    /// Increments the integer value of `key` by one, starting from zero if the key doesn't exist,
+    /// and returns the incremented value
+    async fn increment(&self, key: &str) -> StorageResult<i64>;
+
+    /// Atomically increments `key` as `increment` does, making it expire after `period` seconds if
+    /// it has no expiration yet: counters of a time window are created with their expiration
+    async fn increment_expiring(&self, key: &str, period: usize) -> StorageResult<i64>;
+
+    /// Increments `key` by `amount` as `increment_expiring` does, such as when counting many
+    /// calls at once
+    async fn increment_expiring_by(
+        &self,
+        key: &str,
+        amount: i64,
+        period: usize,
+    ) -> StorageResult<i64> {
+        let mut value = 0;
+        for _ in 0..amount {
+            value = self.increment_expiring(key, period).await?;
+        }
+        Ok(value)
+    }
+
+    /// Makes `key` expire after `period` seconds
+    async fn expire(&self, key: &str, period: usize) -> StorageResult<()>;
+
+    /// Returns the seconds left before `key` expires, rounded up, or `None` if `key` is missing or
+    /// doesn't expire
+    async fn ttl(&self, key: &str) -> StorageResult<Option<u64>>;
+
+    /// Sets the value of `key`, removing any expiration
+    async fn set(&self, key: &str, value: &str) -> StorageResult<()>;
+
+    /// Atomically sets the value of `key` and makes it expire after `period` seconds
+    async fn set_expiring(&self, key: &str, value: &str, period: usize) -> StorageResult<()>;
+
+    /// Sets the value of many keys, as `set` does, or as `set_expiring` does for those with an
+    /// expiration period. Keys are set in order, but not atomically: an error may leave some of
+    /// them set. Storages backed by a server send them in as few round trips as possible.
+    async fn set_many(&self, entries: &[(String, String, Option<usize>)]) -> StorageResult<()> {
+        for (key, value, period) in entries {
+            match period {
+                Some(period) => self.set_expiring(key, value, *period).await?,
+                None => self.set(key, value).await?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Atomically sets the value of `key` only if it doesn't exist yet. Returns whether the value
+    /// was set.
+    async fn set_if_not_exists(&self, key: &str, value: &str) -> StorageResult<bool>;
+
+    /// Atomically sets the value of `key` only if it doesn't exist yet, as `set_if_not_exists`
+    /// does, making it expire after `period` seconds. Returns whether the value was set.
+    async fn set_if_not_exists_expiring(
+        &self,
+        key: &str,
+        value: &str,
+        period: usize,
+    ) -> StorageResult<bool>;
+
+    /// Deletes `key`, returning whether it existed
+    async fn delete(&self, key: &str) -> StorageResult<bool>;
+
+    /// Renames `key` to `new_key`, whatever its type, keeping its expiration. Returns whether it
+    /// was renamed, `false` meaning that `key` doesn't exist or that `new_key` does: existing keys
+    /// are never overwritten.
+    async fn rename(&self, key: &str, new_key: &str) -> StorageResult<bool>;
+
+    /// Adds `member` to the set stored at `key`, creating the set if it doesn't exist. Returns
+    /// whether the member was added, `false` meaning it was already in the set.
+    async fn add_member(&self, key: &str, member: &str) -> StorageResult<bool>;
+
+    /// Removes `member` from the set stored at `key`, returning whether it was in the set
+    async fn remove_member(&self, key: &str, member: &str) -> StorageResult<bool>;
+
+    /// Returns the members of the set stored at `key`, in no particular order. A missing key is an
+    /// empty set.
+    async fn members(&self, key: &str) -> StorageResult<Vec<String>>;
+
+    /// Tells whether `member` is in the set stored at `key`, without reading the whole set
+    async fn is_member(&self, key: &str, member: &str) -> StorageResult<bool>;
+
+    /// Adds `member` with `score` to the sorted set stored at `key`, creating the sorted set if it
+    /// doesn't exist, or updates its score. Returns whether the member was added.
+    async fn add_scored_member(&self, key: &str, member: &str, score: u64) -> StorageResult<bool>;
+
+    /// Removes `member` from the sorted set stored at `key`, returning whether it was in the set
+    async fn remove_scored_member(&self, key: &str, member: &str) -> StorageResult<bool>;
+
+    /// Returns at most `limit` members of the sorted set stored at `key`, with their scores, whose
+    /// score is between `min_score` and `max_score`, both included. Members are sorted by
+    /// descending score, then by descending member.
+    async fn scored_members(
+        &self,
+        key: &str,
+        min_score: u64,
+        max_score: u64,
+        limit: usize,
+    ) -> StorageResult<Vec<(String, u64)>>;
+
+    /// Returns members of the sorted set stored at `key` as `scored_members` does, sorted by
+    /// ascending score, then by ascending member, so that they can be paged from the oldest
+    async fn scored_members_ascending(
+        &self,
+        key: &str,
+        min_score: u64,
+        max_score: u64,
+        limit: usize,
+    ) -> StorageResult<Vec<(String, u64)>>;
+
+    /// Returns the keys starting with `prefix`, in no particular order. It walks the whole
+    /// keyspace, without blocking the servers, and is meant for administrative tasks only.
+    async fn scan(&self, prefix: &str) -> StorageResult<Vec<String>>;
+
+    /// Returns a page of about `count` keys starting with `prefix`, walking the keyspace as `scan`
+    /// does from `cursor`, empty for the first page, and the cursor of the next page, `None` after
+    /// the last one. Pages may be empty before the last one.
+    ///
+    /// Cursors don't expire, so that a walk can be resumed later, such as after a dropped
+    /// connection. Keys present during the whole walk are returned at least once; keys added or
+    /// deleted meanwhile may or may not be.
+    ///
+    /// By default it sorts the keys of `scan`, with the last key of the page as cursor: storages
+    /// that can page themselves should.
+    async fn scan_page(
+        &self,
+        prefix: &str,
+        cursor: &str,
+        count: usize,
+    ) -> StorageResult<(Vec<String>, Option<String>)> {
+        let mut keys = self
+            .scan(prefix)
+            .await?
+            .into_iter()
+            .filter(|key| key.as_str() > cursor)
+            .collect::<Vec<_>>();
+        keys.sort();
+        Ok(sorted_page(keys, count.max(1)))
+    }
+
+    /// Reads a configuration parameter from each server backing the storage. Storages not backed
+    /// by a server have no configuration, and return no values.
+    async fn config_get(&self, _parameter: &str) -> StorageResult<Vec<String>> {
+        Ok(vec![])
+    }
+
+    /// Reads a section of the `INFO` report of each server backing the storage, as in
+    /// `config_get`
+    async fn info(&self, _section: &str) -> StorageResult<Vec<String>> {
+        Ok(vec![])
+    }
+
+    /// Checks that every server backing the storage can be reached. Storages not backed by a
+    /// server are always reachable.
+    async fn ping(&self) -> StorageResult<()> {
+        Ok(())
+    }
+
+    /// Returns how the reads of the storage compare with those of a shadow storage, see
+    /// `shadow_storage`. Storages without a shadow return `None`.
+    fn shadow_stats(&self) -> Option<ShadowStats> {
+        None
+    }
+
+    /// Closes the connections to the servers backing the storage, once it's no longer used, such
+    /// as on exit. Calls made afterwards fail. Storages not backed by a server have nothing to
+    /// close.
+    async fn close(&self) {}
+}
+
+/// Returns the first `count` of the sorted `keys` following a cursor, with the last of them as the
+/// cursor of the next page if the page is full
+pub(crate) fn sorted_page(mut keys: Vec<String>, count: usize) -> (Vec<String>, Option<String>) {
+    keys.truncate(count);
+    let cursor = if keys.len() == count {
+        keys.last().cloned()
+    } else {
+        None
+    };
+    (keys, cursor)
+}
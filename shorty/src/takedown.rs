@@ -0,0 +1,500 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! takedown holds the takedown requests of rights holders, asking to remove links that infringe
+//! their rights, and the abuse policy page telling them how to file one.
+//!
+//! Each request gets a ticket, and the links it names are quarantined until an operator reviews
+//! them (see `quarantine`): releasing or rejecting them is recorded in the audit trail of the
+//! ticket, along with the acknowledgement sent to the rights holder.
+//!
+//! Tickets are stored in `TAKEDOWN_<ticket>` and listed in the sorted set `TAKEDOWNS`, scored by
+//! the time they were received. Their audit trail is the sorted set `TAKEDOWN_AUDIT_<ticket>`,
+//! scored by the microsecond each entry was recorded at. `TAKEDOWN_OF_<id>` tells the ticket
+//! that quarantined a link, expiring with the link. Tickets never expire.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::link_index::MAX_LINKS;
+use crate::templates::{Templates, ABUSE_TEMPLATE};
+use crate::{now, Shortener, ShortenerError};
+
+/// The most links a takedown request can name
+pub const MAX_TAKEDOWN_IDS: usize = 100;
+
+/// The longest claim a takedown request can make, in characters
+pub const MAX_CLAIM_LENGTH: usize = 10_000;
+
+/// The built-in `abuse.html` template of `abuse_page`
+pub const DEFAULT_ABUSE_TEMPLATE: &str = r#"{% extends "base.html" %}
+{% block title %}Abuse policy{% endblock title %}
+{% block content %}
+<h1>Abuse policy</h1>
+<p>Short links must not point to spam, malware, phishing, or content infringing the rights of others.</p>
+<p>To report a link, write to {% if contact %}<a href="mailto:{{ contact }}">{{ contact }}</a>{% else %}the operators of this site{% endif %}. Rights holders can file a takedown request by sending a <code>POST</code> to <code>/api/v1/takedowns</code>, with the JSON body <code>{"ids": [...], "name": "...", "email": "...", "claim": "..."}</code>: the links are taken offline while we review them, and a confirmation is sent to the given email address.</p>
+{% endblock content %}
+"#;
+
+const TAKEDOWNS: &str = "TAKEDOWNS";
+
+const TICKET_ALPHABET: [char; 32] = [
+    'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'J', 'K', 'L', 'M', 'N', 'P', 'Q', 'R', 'S', 'T', 'U',
+    'V', 'W', 'X', 'Y', 'Z', '2', '3', '4', '5', '6', '7', '8', '9',
+];
+
+const TICKET_LENGTH: usize = 10;
+
+/// A takedown request: the `ids` of the links, the `name` and `email` of the rights holder, and
+/// their `claim`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct TakedownRequest {
+    pub ids: Vec<String>,
+    pub name: String,
+    pub email: String,
+    pub claim: String,
+}
+
+/// A takedown request as filed, with its `ticket`, the time it was `received_at`, and its audit
+/// trail, oldest entry first
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Takedown {
+    pub ticket: String,
+    #[serde(flatten)]
+    pub request: TakedownRequest,
+    pub received_at: u64,
+    #[serde(default)]
+    pub audit: Vec<AuditEntry>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    /// The link was quarantined by the request
+    Quarantined,
+    /// The link was already quarantined, by the phishing screen or another request
+    AlreadyQuarantined,
+    /// The link doesn't exist
+    NotFound,
+    /// The acknowledgement was sent to the rights holder
+    Acknowledged,
+    /// The acknowledgement couldn't be sent
+    AcknowledgementFailed,
+    /// An operator released the link
+    Released,
+    /// An operator rejected the link, deleting it
+    Rejected,
+}
+
+/// An entry of the audit trail of a takedown, happened `at` a Unix timestamp, about the link `id`
+/// if any
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct AuditEntry {
+    pub action: AuditAction,
+    pub at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+}
+
+/// An audit entry as stored, with the microsecond it was `recorded_at`, as link events are
+#[derive(Serialize, Deserialize)]
+struct StoredEntry {
+    #[serde(flatten)]
+    entry: AuditEntry,
+    recorded_at: u64,
+}
+
+/// The context of the abuse policy page: the email address abuse reports are sent to, if any
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AbusePolicy {
+    pub contact: Option<String>,
+}
+
+/// Renders the abuse policy page with the `abuse.html` template, telling to write to the
+/// `contact` email address, if any
+pub fn abuse_page(templates: &Templates, contact: Option<&str>) -> String {
+    let policy = AbusePolicy {
+        contact: contact.map(str::to_owned),
+    };
+    templates.render(ABUSE_TEMPLATE, &policy)
+}
+
+impl Shortener {
+    /// Files a takedown request, quarantining the links it names, and returns its ticket. The
+    /// links are found again once an operator releases them.
+    pub async fn submit_takedown(
+        &self,
+        request: TakedownRequest,
+    ) -> Result<Takedown, ShortenerError> {
+        check_request(&request)?;
+
+        let takedown = Takedown {
+            ticket: nanoid::format(nanoid::rngs::default, &TICKET_ALPHABET, TICKET_LENGTH),
+            request,
+            received_at: now(),
+            audit: Vec::new(),
+        };
+        let stored = self
+            .storage
+            .set_if_not_exists(
+                &takedown_key(&takedown.ticket),
+                &serde_json::to_string(&takedown).unwrap(),
+            )
+            .await
+            .map_err(ShortenerError::Storage)?;
+        if !stored {
+            return Err(ShortenerError::Internal(
+                "Failed to generate a unique takedown ticket",
+            ));
+        }
+        self.storage
+            .add_scored_member(TAKEDOWNS, &takedown.ticket, takedown.received_at)
+            .await
+            .map_err(ShortenerError::Storage)?;
+        log::info!(
+            "received takedown {} of {} links",
+            takedown.ticket,
+            takedown.request.ids.len()
+        );
+
+        let mut ids = takedown.request.ids.clone();
+        ids.sort();
+        ids.dedup();
+        for id in &ids {
+            let action = self.take_down(&takedown.ticket, id).await?;
+            self.record_audit(&takedown.ticket, action, Some(id)).await;
+        }
+
+        self.takedown(&takedown.ticket)
+            .await?
+            .ok_or(ShortenerError::NotFound("Takedown not found"))
+    }
+
+    /// Returns the takedown with the given ticket, if any
+    pub async fn takedown(&self, ticket: &str) -> Result<Option<Takedown>, ShortenerError> {
+        let json = match self.storage.get_string(&takedown_key(ticket)).await {
+            Ok(json) => json,
+            Err(_) => return Ok(None),
+        };
+        let mut takedown = match serde_json::from_str::<Takedown>(&json) {
+            Ok(takedown) => takedown,
+            Err(err) => {
+                log::warn!("skipping unreadable takedown '{}': {}", ticket, err);
+                return Ok(None);
+            }
+        };
+
+        let mut stored = self
+            .storage
+            .scored_members(&audit_key(ticket), 0, u64::MAX, usize::MAX)
+            .await
+            .map_err(ShortenerError::Storage)?
+            .into_iter()
+            .filter_map(|(member, _)| serde_json::from_str::<StoredEntry>(&member).ok())
+            .collect::<Vec<_>>();
+        stored.sort_by_key(|stored| stored.recorded_at);
+        takedown.audit = stored.into_iter().map(|stored| stored.entry).collect();
+
+        Ok(Some(takedown))
+    }
+
+    /// Returns the takedowns, newest first, at most `limit` and never more than `MAX_LINKS`
+    pub async fn takedowns(&self, limit: usize) -> Result<Vec<Takedown>, ShortenerError> {
+        let tickets = self
+            .storage
+            .scored_members(TAKEDOWNS, 0, u64::MAX, limit.min(MAX_LINKS))
+            .await
+            .map_err(ShortenerError::Storage)?;
+
+        let mut takedowns = Vec::with_capacity(tickets.len());
+        for (ticket, _) in tickets {
+            if let Some(takedown) = self.takedown(&ticket).await? {
+                takedowns.push(takedown);
+            }
+        }
+        Ok(takedowns)
+    }
+
+    /// Records whether the acknowledgement of a takedown was sent to the rights holder
+    pub async fn record_acknowledgement(&self, ticket: &str, sent: bool) {
+        let action = if sent {
+            AuditAction::Acknowledged
+        } else {
+            AuditAction::AcknowledgementFailed
+        };
+        self.record_audit(ticket, action, None).await;
+    }
+
+    /// Records the review of a link quarantined by a takedown, if it was: `released`, or
+    /// rejected otherwise
+    pub(crate) async fn record_review(&self, id: &str, ticket: Option<String>, released: bool) {
+        let ticket = match ticket {
+            Some(ticket) => ticket,
+            None => return,
+        };
+        let action = if released {
+            AuditAction::Released
+        } else {
+            AuditAction::Rejected
+        };
+        self.record_audit(&ticket, action, Some(id)).await;
+    }
+
+    /// Returns the ticket of the takedown that quarantined a link, if any
+    pub(crate) async fn takedown_of(&self, id: &str) -> Option<String> {
+        self.storage.get_string(&takedown_of_key(id)).await.ok()
+    }
+
+    /// Quarantines a link named by a takedown, unless it's missing or already quarantined
+    async fn take_down(&self, ticket: &str, id: &str) -> Result<AuditAction, ShortenerError> {
+        if self.storage.get_string(id).await.is_err() {
+            return Ok(AuditAction::NotFound);
+        }
+        if self.is_quarantined(id).await {
+            return Ok(AuditAction::AlreadyQuarantined);
+        }
+
+        // the quarantine expires with the link
+        let expires_in = self
+            .storage
+            .ttl(id)
+            .await
+            .map_err(ShortenerError::Storage)?
+            .map(|ttl| ttl as usize);
+        let key = takedown_of_key(id);
+        match expires_in {
+            Some(expires_in) => self.storage.set_expiring(&key, ticket, expires_in).await,
+            None => self.storage.set(&key, ticket).await,
+        }
+        .map_err(ShortenerError::Storage)?;
+        self.quarantine(id, 0, expires_in, "takedown").await?;
+
+        Ok(AuditAction::Quarantined)
+    }
+
+    /// Appends an entry to the audit trail of a takedown. Failing to do so is logged, without
+    /// failing the change the entry tells about.
+    async fn record_audit(&self, ticket: &str, action: AuditAction, id: Option<&str>) {
+        let recorded_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_micros() as u64)
+            .unwrap_or(0);
+        let stored = StoredEntry {
+            entry: AuditEntry {
+                action,
+                at: now(),
+                id: id.map(str::to_owned),
+            },
+            recorded_at,
+        };
+        let member = serde_json::to_string(&stored).unwrap();
+
+        if let Err(err) = self
+            .storage
+            .add_scored_member(&audit_key(ticket), &member, recorded_at)
+            .await
+        {
+            log::warn!("unable to record audit entry of '{}': {}", ticket, err);
+        }
+    }
+}
+
+/// Checks that a takedown request names some links and tells who filed it and why
+fn check_request(request: &TakedownRequest) -> Result<(), ShortenerError> {
+    if request.ids.is_empty() || request.ids.len() > MAX_TAKEDOWN_IDS {
+        return Err(ShortenerError::InvalidInput(
+            "Invalid takedown: ids must name between 1 and 100 links",
+        ));
+    }
+    if request.name.trim().is_empty() {
+        return Err(ShortenerError::InvalidInput(
+            "Invalid takedown: missing name",
+        ));
+    }
+    if !is_email(&request.email) {
+        return Err(ShortenerError::InvalidInput(
+            "Invalid takedown: invalid email",
+        ));
+    }
+    if request.claim.trim().is_empty() || request.claim.chars().count() > MAX_CLAIM_LENGTH {
+        return Err(ShortenerError::InvalidInput(
+            "Invalid takedown: claim must be between 1 and 10000 characters",
+        ));
+    }
+    Ok(())
+}
+
+/// Tells whether `email` looks like an email address, a local part and a domain with a dot. The
+/// acknowledgement tells whether it actually is one.
+fn is_email(email: &str) -> bool {
+    if email.len() > 254 || email.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return false;
+    }
+    match email.rsplit_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+        }
+        None => false,
+    }
+}
+
+fn takedown_key(ticket: &str) -> String {
+    format!("TAKEDOWN_{}", ticket)
+}
+
+fn audit_key(ticket: &str) -> String {
+    format!("TAKEDOWN_AUDIT_{}", ticket)
+}
+
+pub(crate) fn takedown_of_key(id: &str) -> String {
+    format!("TAKEDOWN_OF_{}", id)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::storage::Storage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    fn request(ids: &[&str]) -> TakedownRequest {
+        TakedownRequest {
+            ids: ids.iter().map(|id| id.to_string()).collect(),
+            name: String::from("ACME Inc."),
+            email: String::from("legal@acme.example"),
+            claim: String::from("The linked page copies our catalog"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_submit_takedown() {
+        let storage = MemoryStorage::new();
+        storage.set("API_KEY_api key", "true").await.unwrap();
+        let shortener = Shortener::new(10, vec!['a', 'b', 'c'], 10, Box::new(storage), 600, 10);
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let result = shortener
+                .shorten(
+                    &Some("api key"),
+                    None,
+                    "http://example.com/catalog",
+                    &LinkOptions::default(),
+                )
+                .await
+                .unwrap();
+            ids.push(result.id);
+        }
+
+        let takedown = shortener
+            .submit_takedown(request(&[&ids[0], &ids[1], &ids[0], "missing"]))
+            .await
+            .unwrap();
+        assert_eq!(TICKET_LENGTH, takedown.ticket.len());
+        // duplicates are taken down once
+        assert_eq!(3, takedown.audit.len());
+        assert_eq!(AuditAction::NotFound, takedown.audit[2].action);
+        assert_eq!(None, shortener.lookup(&ids[0]).await);
+        assert_eq!(2, shortener.quarantined_links(10).await.unwrap().len());
+
+        let again = shortener
+            .submit_takedown(request(&[&ids[0]]))
+            .await
+            .unwrap();
+        assert_eq!(AuditAction::AlreadyQuarantined, again.audit[0].action);
+
+        shortener.release_quarantined(&ids[0]).await.unwrap();
+        shortener.reject_quarantined(&ids[1]).await.unwrap();
+        shortener
+            .record_acknowledgement(&takedown.ticket, true)
+            .await;
+
+        let takedown = shortener.takedown(&takedown.ticket).await.unwrap().unwrap();
+        let actions = takedown
+            .audit
+            .iter()
+            .map(|entry| entry.action)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                AuditAction::Quarantined,
+                AuditAction::Quarantined,
+                AuditAction::NotFound,
+                AuditAction::Released,
+                AuditAction::Rejected,
+                AuditAction::Acknowledged,
+            ],
+            actions
+        );
+        assert_eq!(Some(ids[0].clone()), takedown.audit[3].id);
+
+        let takedowns = shortener.takedowns(10).await.unwrap();
+        assert_eq!(2, takedowns.len());
+        assert_eq!(None, shortener.takedown("MISSING").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_submit_invalid_takedown() {
+        let shortener = Shortener::new(
+            10,
+            vec!['a', 'b', 'c'],
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
+        );
+
+        let err = shortener.submit_takedown(request(&[])).await.err().unwrap();
+        assert_eq!(400, err.status_code());
+
+        let mut invalid = request(&["abc"]);
+        invalid.email = String::from("legal at acme");
+        let err = shortener.submit_takedown(invalid).await.err().unwrap();
+        assert_eq!("Invalid takedown: invalid email", err.to_string());
+
+        let mut invalid = request(&["abc"]);
+        invalid.claim = String::from(" ");
+        assert!(shortener.submit_takedown(invalid).await.is_err());
+    }
+
+    #[test]
+    fn test_is_email() {
+        assert!(is_email("legal@acme.example"));
+        assert!(!is_email("legal@acme"));
+        assert!(!is_email("@acme.example"));
+        assert!(!is_email("legal @acme.example"));
+        assert!(!is_email("legal@acme.example."));
+    }
+
+    #[test]
+    fn test_abuse_page() {
+        let templates = Templates::new();
+        let page = abuse_page(&templates, Some("abuse@sho.rt"));
+        assert!(page.contains(r#"<a href="mailto:abuse@sho.rt">abuse@sho.rt</a>"#));
+        assert!(abuse_page(&templates, None).contains("the operators of this site"));
+
+        let templates = templates
+            .with_template(ABUSE_TEMPLATE, "<p>{{ contact }}</p>")
+            .unwrap();
+        let page = abuse_page(&templates, Some("x<y@sho.rt"));
+        assert_eq!("<p>x&lt;y@sho.rt</p>", page);
+    }
+}
@@ -0,0 +1,244 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! templates holds `Templates`, rendering every HTML page of shorty, from redirects to error
+//! pages, the interstitial, the human challenge and the abuse policy, with Tera and the typed
+//! context of each page: `Redirect`, `ErrorPage`, `Interstitial`, `Challenge` and `AbusePolicy`.
+//!
+//! Every page is built in, and can be replaced from a directory on disk, so that deployments
+//! brand or translate them without recompiling: a file replaces the template it is named after.
+//! The pages but the redirect extend `base.html`, holding the layout and the style shared by all
+//! of them: replacing it rebrands every page at once. Error pages are rendered with the template
+//! of their status, such as `404.html`, if any, and with `error.html` otherwise.
+//!
+//! Values are HTML escaped. A replaced template failing to render, such as for a misspelled
+//! variable, is logged, and the built-in one is rendered instead.
+
+use std::fs;
+use std::path::Path;
+
+use serde::Serialize;
+use tera::{Context, Tera};
+
+use crate::challenge::DEFAULT_CHALLENGE_TEMPLATE;
+use crate::error_page::DEFAULT_ERROR_TEMPLATE;
+use crate::interstitial::DEFAULT_INTERSTITIAL_TEMPLATE;
+use crate::takedown::DEFAULT_ABUSE_TEMPLATE;
+use crate::{escape_html, DEFAULT_REDIRECT_TEMPLATE};
+
+pub const BASE_TEMPLATE: &str = "base.html";
+pub const REDIRECT_TEMPLATE: &str = "redirect.html";
+pub const ERROR_TEMPLATE: &str = "error.html";
+pub const INTERSTITIAL_TEMPLATE: &str = "interstitial.html";
+pub const CHALLENGE_TEMPLATE: &str = "challenge.html";
+pub const ABUSE_TEMPLATE: &str = "abuse.html";
+
+/// The layout extended by the built-in pages: `title` and `content` blocks, and a `style`
+/// block to add to the shared style
+pub const DEFAULT_BASE_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+{% block head %}{% endblock head %}
+<title>{% block title %}{% endblock title %}</title>
+<style>
+body { font-family: sans-serif; line-height: 1.5; max-width: 36em; margin: 4em auto; padding: 0 1em; color: #1f2328; background: #fff; }
+h1 { font-size: 1.75em; }
+{% block style %}{% endblock style %}
+</style>
+</head>
+<body>
+<main>
+{% block content %}{% endblock content %}
+</main>
+</body>
+</html>
+"#;
+
+/// The built-in templates, by name
+const DEFAULT_TEMPLATES: [(&str, &str); 6] = [
+    (BASE_TEMPLATE, DEFAULT_BASE_TEMPLATE),
+    (REDIRECT_TEMPLATE, DEFAULT_REDIRECT_TEMPLATE),
+    (ERROR_TEMPLATE, DEFAULT_ERROR_TEMPLATE),
+    (INTERSTITIAL_TEMPLATE, DEFAULT_INTERSTITIAL_TEMPLATE),
+    (CHALLENGE_TEMPLATE, DEFAULT_CHALLENGE_TEMPLATE),
+    (ABUSE_TEMPLATE, DEFAULT_ABUSE_TEMPLATE),
+];
+
+/// The templates of the HTML pages: the built-in ones, possibly replaced
+pub struct Templates {
+    tera: Tera,
+    defaults: Tera,
+}
+
+impl Default for Templates {
+    fn default() -> Templates {
+        Templates::new()
+    }
+}
+
+impl Templates {
+    /// Creates the built-in templates
+    pub fn new() -> Templates {
+        let defaults = tera(&DEFAULT_TEMPLATES).expect("invalid built-in template");
+        Templates {
+            tera: defaults.clone(),
+            defaults,
+        }
+    }
+
+    /// Replaces the templates named after the `.html` files of `dir`, and adds the others, such
+    /// as those of error statuses or those extended by other templates. Fails if a file can't be
+    /// read or isn't a valid template.
+    pub fn with_dir(self, dir: impl AsRef<Path>) -> Result<Templates, tera::Error> {
+        let dir = dir.as_ref();
+        let read_error = |err| tera::Error::chain(format!("unable to read {:?}", dir), err);
+
+        let mut templates = Vec::new();
+        for entry in fs::read_dir(dir).map_err(read_error)? {
+            let path = entry.map_err(read_error)?.path();
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) if name.ends_with(".html") && path.is_file() => name.to_owned(),
+                _ => continue,
+            };
+            let source = fs::read_to_string(&path)
+                .map_err(|err| tera::Error::chain(format!("unable to read {:?}", path), err))?;
+            templates.push((name, source));
+        }
+        self.with_templates(templates)
+    }
+
+    /// Replaces the template `name`, or adds it, with `source`
+    pub fn with_template(self, name: &str, source: &str) -> Result<Templates, tera::Error> {
+        self.with_templates(vec![(name.to_owned(), source.to_owned())])
+    }
+
+    fn with_templates(
+        mut self,
+        templates: Vec<(String, String)>,
+    ) -> Result<Templates, tera::Error> {
+        self.tera.add_raw_templates(templates)?;
+        Ok(self)
+    }
+
+    /// Tells whether there is a template `name`
+    pub fn has(&self, name: &str) -> bool {
+        self.tera
+            .get_template_names()
+            .any(|template| template == name)
+    }
+
+    /// Renders the template `name` with `context`, falling back on the built-in template if a
+    /// replaced one fails. Panics if `name` is neither built in nor added, or if `context` isn't
+    /// an object.
+    pub fn render(&self, name: &str, context: &impl Serialize) -> String {
+        let context = Context::from_serialize(context).expect("invalid template context");
+        match self.tera.render(name, &context) {
+            Ok(html) => html,
+            Err(err) => {
+                log::error!("unable to render template {}: {}", name, error_chain(&err));
+                self.defaults
+                    .render(default_name(name), &context)
+                    .expect("unable to render built-in template")
+            }
+        }
+    }
+}
+
+/// The built-in template of `name`: the error page, for the templates of error statuses
+fn default_name(name: &str) -> &str {
+    if DEFAULT_TEMPLATES
+        .iter()
+        .any(|(default, _)| *default == name)
+    {
+        name
+    } else {
+        ERROR_TEMPLATE
+    }
+}
+
+fn tera(templates: &[(&str, &str)]) -> Result<Tera, tera::Error> {
+    let mut tera = Tera::default();
+    tera.set_escape_fn(escape_html);
+    tera.add_raw_templates(templates.to_vec())?;
+    Ok(tera)
+}
+
+/// Formats `err` with its causes, as Tera puts the details of errors in their causes
+pub fn error_chain(err: &tera::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = std::error::Error::source(err);
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use crate::error_page::ErrorPage;
+
+    use super::*;
+
+    #[test]
+    fn test_with_dir() {
+        let dir = env::temp_dir().join(format!("shorty-templates-{}", nanoid::nanoid!()));
+        fs::create_dir(&dir).unwrap();
+        fs::write(
+            dir.join("base.html"),
+            "<body class=\"acme\">{% block content %}{% endblock content %}</body>",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("404.html"),
+            "<h1>Lien introuvable</h1> ({{status}})",
+        )
+        .unwrap();
+        fs::write(dir.join("notes.txt"), "{{ ignored").unwrap();
+        let templates = Templates::new().with_dir(&dir).unwrap();
+
+        assert_eq!(
+            "<h1>Lien introuvable</h1> (404)",
+            ErrorPage::new(404).html(&templates)
+        );
+        let gone = ErrorPage::new(410).html(&templates);
+        assert!(gone.starts_with("<body class=\"acme\">"));
+        assert!(gone.contains("Link no longer available"));
+
+        fs::write(dir.join("error.html"), "{% if %}").unwrap();
+        assert!(Templates::new().with_dir(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(Templates::new().with_dir(&dir).is_err());
+    }
+
+    #[test]
+    fn test_render_fallback() {
+        let templates = Templates::new()
+            .with_template(ERROR_TEMPLATE, "{{ misspelled }}")
+            .unwrap()
+            .with_template("500.html", "{{ misspelled }}")
+            .unwrap();
+        assert!(templates.has("500.html"));
+        assert!(!templates.has("404.html"));
+
+        for status in [404, 500] {
+            let html = ErrorPage::new(status).html(&templates);
+            assert!(html.contains(&format!("Error {}", status)));
+        }
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! url_checker holds `UrlChecker`, the hook checking the destination of new links against a
+//! reputation service, such as Google Safe Browsing, so that malicious destinations are rejected
+//! when shortened.
+//!
+//! shorty doesn't make HTTP calls itself: implementations live with the frontends, such as the
+//! Safe Browsing checker of shorty-bootstrap. Without a checker, every URL is accepted.
+
+use std::error::Error;
+
+use async_trait::async_trait;
+
+use crate::{Shortener, ShortenerError};
+
+/// The verdict of a `UrlChecker` on a URL
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Safe,
+    /// The URL is malicious, `threat` telling why, such as `MALWARE`
+    Malicious {
+        threat: String,
+    },
+}
+
+/// `UrlChecker` checks the destination of each new link before it's stored.
+///
+/// A checker failing, such as a reputation service being unreachable, doesn't fail shortening:
+/// the error is logged and the URL accepted, so that an outage of the service doesn't stop
+/// shorty.
+#[async_trait]
+pub trait UrlChecker: Send + Sync {
+    async fn check(&self, url: &str) -> Result<Verdict, Box<dyn Error + Send + Sync>>;
+}
+
+/// The default `UrlChecker`, finding every URL safe
+pub struct NoUrlChecker;
+
+#[async_trait]
+impl UrlChecker for NoUrlChecker {
+    async fn check(&self, _url: &str) -> Result<Verdict, Box<dyn Error + Send + Sync>> {
+        Ok(Verdict::Safe)
+    }
+}
+
+impl Shortener {
+    /// Checks the destination of new links with `url_checker`: see `url_checker`
+    pub fn with_url_checker(mut self, url_checker: impl UrlChecker + 'static) -> Shortener {
+        self.url_checker = Box::new(url_checker);
+        self
+    }
+
+    /// Fails if the `UrlChecker` finds the destination of a new link malicious
+    pub(crate) async fn check_url(&self, url: &str) -> Result<(), ShortenerError> {
+        match self.url_checker.check(url).await {
+            Ok(Verdict::Safe) => Ok(()),
+            Ok(Verdict::Malicious { threat }) => {
+                log::info!("refused to shorten '{}': flagged as {}", url, threat);
+                Err(ShortenerError::RejectedUrl("URL flagged as malicious"))
+            }
+            Err(err) => {
+                log::warn!("unable to check '{}', accepting it: {}", url, err);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_storage::MemoryStorage;
+    use crate::LinkOptions;
+
+    use super::*;
+
+    struct TestChecker;
+
+    #[async_trait]
+    impl UrlChecker for TestChecker {
+        async fn check(&self, url: &str) -> Result<Verdict, Box<dyn Error + Send + Sync>> {
+            if url.contains("malware") {
+                Ok(Verdict::Malicious {
+                    threat: String::from("MALWARE"),
+                })
+            } else if url.contains("unreachable") {
+                Err(Box::from("service unavailable"))
+            } else {
+                Ok(Verdict::Safe)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_url() {
+        let shortener = Shortener::new(
+            10,
+            vec!['a', 'b', 'c'],
+            10,
+            Box::new(MemoryStorage::new()),
+            600,
+            10,
+        )
+        .with_url_checker(TestChecker);
+        let options = LinkOptions::default();
+
+        let err = shortener
+            .shorten(&None, None, "http://example.com/malware", &options)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("URL flagged as malicious", err.to_string());
+        let err = shortener
+            .shorten_with_id(&None, None, "abc", "http://example.com/malware", &options)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!("URL flagged as malicious", err.to_string());
+
+        assert!(shortener
+            .shorten(&None, None, "http://example.com/", &options)
+            .await
+            .is_ok());
+        assert!(shortener
+            .shorten(&None, None, "http://unreachable.example.com/", &options)
+            .await
+            .is_ok());
+    }
+}